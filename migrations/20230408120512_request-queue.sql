@@ -0,0 +1,14 @@
+ALTER TABLE requests
+	ADD COLUMN seq bigint unsigned NOT NULL AUTO_INCREMENT UNIQUE,
+	ADD COLUMN status varchar(32) NOT NULL DEFAULT 'queued',
+	ADD COLUMN nonce bigint unsigned NULL,
+	MODIFY hash varchar(66) NULL;
+
+UPDATE requests SET status = IF(mined, 'mined', 'submitted');
+UPDATE requests
+SET nonce = CONV(SUBSTRING(JSON_UNQUOTE(JSON_EXTRACT(tx, '$.nonce')), 3), 16, 10)
+WHERE JSON_EXTRACT(tx, '$.nonce') IS NOT NULL;
+
+DROP INDEX idx_requests_chain_mined ON requests;
+ALTER TABLE requests DROP COLUMN mined;
+CREATE INDEX idx_requests_chain_status ON requests (chain, status, seq);
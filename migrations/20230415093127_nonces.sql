@@ -0,0 +1,14 @@
+CREATE TABLE nonces (
+	chain int unsigned NOT NULL,
+	address varchar(42) NOT NULL,
+	next_nonce bigint unsigned NOT NULL,
+	PRIMARY KEY (chain, address)
+);
+
+INSERT INTO nonces (chain, address, next_nonce)
+SELECT chain, LOWER(JSON_UNQUOTE(JSON_EXTRACT(tx, '$.from'))), MAX(nonce) + 1
+FROM requests
+WHERE nonce IS NOT NULL AND JSON_EXTRACT(tx, '$.from') IS NOT NULL
+GROUP BY chain, LOWER(JSON_UNQUOTE(JSON_EXTRACT(tx, '$.from')));
+
+CREATE INDEX idx_requests_chain_status_nonce ON requests (chain, status, nonce);
@@ -0,0 +1,5 @@
+ALTER TABLE requests
+	ADD COLUMN idempotency_key varchar(255) NULL,
+	ADD COLUMN idempotency_fingerprint char(64) NULL;
+
+CREATE UNIQUE INDEX idx_requests_idempotency_key ON requests (idempotency_key);
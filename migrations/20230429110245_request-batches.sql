@@ -0,0 +1,5 @@
+ALTER TABLE requests
+	ADD COLUMN batch_id varchar(36) NULL,
+	ADD COLUMN batch_index int unsigned NULL;
+
+CREATE INDEX idx_requests_batch_id ON requests (batch_id);
@@ -0,0 +1,7 @@
+CREATE TABLE request_dependencies (
+	request_id varchar(255) NOT NULL,
+	depends_on varchar(255) NOT NULL,
+	PRIMARY KEY (request_id, depends_on)
+);
+
+CREATE INDEX idx_request_dependencies_depends_on ON request_dependencies (depends_on);
@@ -0,0 +1,21 @@
+CREATE TABLE api_keys (
+	id varchar(255) NOT NULL PRIMARY KEY,
+	key_hash char(66) NOT NULL,
+	tenant_id varchar(255) NOT NULL,
+	allowed_chains json NULL,
+	allowed_to json NULL,
+	rate_limit_per_minute int unsigned NULL,
+	spend_budget_gwei bigint unsigned NULL,
+	revoked boolean NOT NULL DEFAULT false
+);
+
+CREATE UNIQUE INDEX idx_api_keys_key_hash ON api_keys (key_hash);
+
+-- Requests saved before tenants existed, and the relay's own gap fillers, have an empty tenant
+ALTER TABLE requests
+	ADD COLUMN tenant_id varchar(255) NOT NULL DEFAULT '',
+	ADD COLUMN cost_gwei bigint unsigned NULL;
+
+-- Idempotency keys only need to be unique within a tenant, the tenant prefix also serves tenant lookups
+DROP INDEX idx_requests_idempotency_key ON requests;
+CREATE UNIQUE INDEX idx_requests_idempotency_key ON requests (tenant_id, idempotency_key);
@@ -0,0 +1,6 @@
+CREATE TABLE daily_value_usage (
+	tenant_id varchar(255) NOT NULL,
+	day date NOT NULL,
+	value_gwei bigint unsigned NOT NULL,
+	PRIMARY KEY (tenant_id, day)
+);
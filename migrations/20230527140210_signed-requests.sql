@@ -0,0 +1,6 @@
+-- Keys that sign requests, the secret can't be hashed since the relay recomputes the HMAC
+ALTER TABLE api_keys
+	ADD COLUMN hmac_secret varchar(255) NULL,
+	ADD COLUMN signer_address char(42) NULL;
+
+CREATE UNIQUE INDEX idx_api_keys_signer_address ON api_keys (signer_address);
@@ -0,0 +1,13 @@
+CREATE TABLE user_operations (
+	hash varchar(66) NOT NULL PRIMARY KEY,
+	seq bigint unsigned NOT NULL AUTO_INCREMENT UNIQUE,
+	chain int unsigned NOT NULL,
+	entry_point varchar(42) NOT NULL,
+	sender varchar(42) NOT NULL,
+	op json NOT NULL,
+	-- pending until bundled into a request, dropped if it stops validating
+	status varchar(32) NOT NULL DEFAULT 'pending',
+	request_id varchar(255) NULL
+);
+
+CREATE INDEX idx_user_operations_pending ON user_operations (chain, entry_point, status, seq);
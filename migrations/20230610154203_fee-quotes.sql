@@ -0,0 +1,19 @@
+-- Amounts are decimal strings, token amounts can be larger than a bigint
+CREATE TABLE fee_quotes (
+	id varchar(255) NOT NULL PRIMARY KEY,
+	tenant_id varchar(255) NOT NULL,
+	chain int unsigned NOT NULL,
+	token varchar(42) NOT NULL,
+	method varchar(32) NOT NULL,
+	amount varchar(78) NOT NULL,
+	gas_limit varchar(78) NOT NULL,
+	max_fee_per_gas varchar(78) NOT NULL,
+	-- unix seconds
+	expires_at bigint unsigned NOT NULL,
+	-- the transfer hash or permit signature that paid the fee, once redeemed
+	payment_ref varchar(255) NULL,
+	request_id varchar(255) NULL
+);
+
+CREATE UNIQUE INDEX idx_fee_quotes_payment_ref ON fee_quotes (payment_ref);
+CREATE INDEX idx_fee_quotes_request_id ON fee_quotes (request_id);
@@ -0,0 +1,8 @@
+-- Existing requests get the migration time, their order is kept by seq
+ALTER TABLE requests
+	ADD COLUMN created_at timestamp(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+	ADD COLUMN updated_at timestamp(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3) ON UPDATE CURRENT_TIMESTAMP(3);
+
+CREATE INDEX idx_requests_created_at ON requests (created_at, seq);
+CREATE INDEX idx_requests_chain_created_at ON requests (chain, created_at, seq);
+CREATE INDEX idx_requests_tenant_created_at ON requests (tenant_id, created_at, seq);
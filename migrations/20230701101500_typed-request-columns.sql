@@ -0,0 +1,41 @@
+-- The transaction gets typed columns, tx is only kept as an archive of what was sent.
+-- Wei amounts are decimals, so they can be compared and summed, up to 65 digits.
+ALTER TABLE requests
+	ADD COLUMN from_address varchar(42) NULL,
+	ADD COLUMN to_address varchar(42) NULL,
+	ADD COLUMN value decimal(65, 0) NULL,
+	ADD COLUMN max_fee decimal(65, 0) NULL,
+	ADD COLUMN priority_fee decimal(65, 0) NULL,
+	ADD COLUMN gas_limit bigint unsigned NULL,
+	ADD COLUMN data mediumblob NULL,
+	-- Only submitted requests hold their nonce, mined ones can share it with a later replacement
+	ADD COLUMN active_nonce bigint unsigned AS (IF(status = 'submitted', nonce, NULL)) VIRTUAL;
+
+-- JSON amounts are 0x prefixed hex, converted 16 digits at a time since CONV stops at 64 bits
+UPDATE requests
+SET
+	from_address = LOWER(JSON_UNQUOTE(JSON_EXTRACT(tx, '$.from'))),
+	to_address = LOWER(JSON_UNQUOTE(JSON_EXTRACT(tx, '$.to'))),
+	nonce = COALESCE(nonce, CONV(SUBSTRING(JSON_UNQUOTE(JSON_EXTRACT(tx, '$.nonce')), 3), 16, 10)),
+	gas_limit = CONV(SUBSTRING(JSON_UNQUOTE(JSON_EXTRACT(tx, '$.gas')), 3), 16, 10),
+	data = UNHEX(SUBSTRING(JSON_UNQUOTE(COALESCE(JSON_EXTRACT(tx, '$.data'), JSON_EXTRACT(tx, '$.input'))), 3));
+
+UPDATE requests
+SET
+	value = (((CAST(CONV(SUBSTRING(LPAD(SUBSTRING(JSON_UNQUOTE(JSON_EXTRACT(tx, '$.value')), 3), 64, '0'), 1, 16), 16, 10) AS DECIMAL(65, 0))
+		* 18446744073709551616 + CAST(CONV(SUBSTRING(LPAD(SUBSTRING(JSON_UNQUOTE(JSON_EXTRACT(tx, '$.value')), 3), 64, '0'), 17, 16), 16, 10) AS DECIMAL(65, 0)))
+		* 18446744073709551616 + CAST(CONV(SUBSTRING(LPAD(SUBSTRING(JSON_UNQUOTE(JSON_EXTRACT(tx, '$.value')), 3), 64, '0'), 33, 16), 16, 10) AS DECIMAL(65, 0)))
+		* 18446744073709551616 + CAST(CONV(SUBSTRING(LPAD(SUBSTRING(JSON_UNQUOTE(JSON_EXTRACT(tx, '$.value')), 3), 64, '0'), 49, 16), 16, 10) AS DECIMAL(65, 0))),
+	max_fee = (((CAST(CONV(SUBSTRING(LPAD(SUBSTRING(JSON_UNQUOTE(JSON_EXTRACT(tx, '$.maxFeePerGas')), 3), 64, '0'), 1, 16), 16, 10) AS DECIMAL(65, 0))
+		* 18446744073709551616 + CAST(CONV(SUBSTRING(LPAD(SUBSTRING(JSON_UNQUOTE(JSON_EXTRACT(tx, '$.maxFeePerGas')), 3), 64, '0'), 17, 16), 16, 10) AS DECIMAL(65, 0)))
+		* 18446744073709551616 + CAST(CONV(SUBSTRING(LPAD(SUBSTRING(JSON_UNQUOTE(JSON_EXTRACT(tx, '$.maxFeePerGas')), 3), 64, '0'), 33, 16), 16, 10) AS DECIMAL(65, 0)))
+		* 18446744073709551616 + CAST(CONV(SUBSTRING(LPAD(SUBSTRING(JSON_UNQUOTE(JSON_EXTRACT(tx, '$.maxFeePerGas')), 3), 64, '0'), 49, 16), 16, 10) AS DECIMAL(65, 0))),
+	priority_fee = (((CAST(CONV(SUBSTRING(LPAD(SUBSTRING(JSON_UNQUOTE(JSON_EXTRACT(tx, '$.maxPriorityFeePerGas')), 3), 64, '0'), 1, 16), 16, 10) AS DECIMAL(65, 0))
+		* 18446744073709551616 + CAST(CONV(SUBSTRING(LPAD(SUBSTRING(JSON_UNQUOTE(JSON_EXTRACT(tx, '$.maxPriorityFeePerGas')), 3), 64, '0'), 17, 16), 16, 10) AS DECIMAL(65, 0)))
+		* 18446744073709551616 + CAST(CONV(SUBSTRING(LPAD(SUBSTRING(JSON_UNQUOTE(JSON_EXTRACT(tx, '$.maxPriorityFeePerGas')), 3), 64, '0'), 33, 16), 16, 10) AS DECIMAL(65, 0)))
+		* 18446744073709551616 + CAST(CONV(SUBSTRING(LPAD(SUBSTRING(JSON_UNQUOTE(JSON_EXTRACT(tx, '$.maxPriorityFeePerGas')), 3), 64, '0'), 49, 16), 16, 10) AS DECIMAL(65, 0)));
+
+-- Fails if two submitted requests already share a nonce, those need to be resolved first
+CREATE UNIQUE INDEX idx_requests_active_nonce ON requests (chain, from_address, active_nonce);
+CREATE INDEX idx_requests_from_address ON requests (from_address, created_at, seq);
+CREATE INDEX idx_requests_to_address ON requests (to_address, created_at, seq);
@@ -0,0 +1,37 @@
+-- Terminal requests past their chain's retention are moved here, in the shape of requests
+CREATE TABLE requests_archive (
+	id varchar(255) NOT NULL PRIMARY KEY,
+	seq bigint unsigned NOT NULL,
+	hash varchar(66) NULL,
+	tx json NOT NULL,
+	chain int unsigned NOT NULL,
+	status varchar(32) NOT NULL,
+	nonce bigint unsigned NULL,
+	idempotency_key varchar(255) NULL,
+	idempotency_fingerprint char(64) NULL,
+	batch_id varchar(36) NULL,
+	batch_index int unsigned NULL,
+	tenant_id varchar(255) NOT NULL,
+	cost_gwei bigint unsigned NULL,
+	created_at timestamp(3) NOT NULL,
+	updated_at timestamp(3) NOT NULL,
+	from_address varchar(42) NULL,
+	to_address varchar(42) NULL,
+	value decimal(65, 0) NULL,
+	max_fee decimal(65, 0) NULL,
+	priority_fee decimal(65, 0) NULL,
+	gas_limit bigint unsigned NULL,
+	data mediumblob NULL,
+	archived_at timestamp(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3)
+);
+
+CREATE INDEX idx_requests_archive_chain_created_at ON requests_archive (chain, created_at, seq);
+CREATE INDEX idx_requests_archive_tenant_created_at ON requests_archive (tenant_id, created_at, seq);
+
+-- What removed requests cost, so spend budgets still count them
+CREATE TABLE tenant_spend (
+	tenant_id varchar(255) NOT NULL PRIMARY KEY,
+	cost_gwei bigint unsigned NOT NULL
+);
+
+CREATE INDEX idx_requests_chain_status_updated_at ON requests (chain, status, updated_at);
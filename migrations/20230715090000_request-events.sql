@@ -0,0 +1,21 @@
+-- Every state transition of a request and who made it. Rows outlive their request, they're
+-- never updated or deleted.
+CREATE TABLE request_events (
+	seq bigint unsigned NOT NULL AUTO_INCREMENT PRIMARY KEY,
+	request_id varchar(255) NOT NULL,
+	chain int unsigned NOT NULL,
+	tenant_id varchar(255) NOT NULL,
+	event varchar(32) NOT NULL,
+	actor varchar(255) NOT NULL,
+	hash varchar(66) NULL,
+	created_at timestamp(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3)
+);
+
+CREATE INDEX idx_request_events_request_id ON request_events (request_id, seq);
+CREATE INDEX idx_request_events_tenant_id ON request_events (tenant_id, seq);
+
+CREATE TRIGGER request_events_no_update BEFORE UPDATE ON request_events
+FOR EACH ROW SIGNAL SQLSTATE '45000' SET MESSAGE_TEXT = 'request_events is append-only';
+
+CREATE TRIGGER request_events_no_delete BEFORE DELETE ON request_events
+FOR EACH ROW SIGNAL SQLSTATE '45000' SET MESSAGE_TEXT = 'request_events is append-only';
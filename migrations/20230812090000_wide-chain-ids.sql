@@ -0,0 +1,18 @@
+-- Chain ids can be larger than an int, like the other databases store them in a bigint
+ALTER TABLE requests
+	MODIFY chain bigint unsigned NOT NULL;
+
+ALTER TABLE requests_archive
+	MODIFY chain bigint unsigned NOT NULL;
+
+ALTER TABLE request_events
+	MODIFY chain bigint unsigned NOT NULL;
+
+ALTER TABLE nonces
+	MODIFY chain bigint unsigned NOT NULL;
+
+ALTER TABLE user_operations
+	MODIFY chain bigint unsigned NOT NULL;
+
+ALTER TABLE fee_quotes
+	MODIFY chain bigint unsigned NOT NULL;
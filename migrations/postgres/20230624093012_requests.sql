@@ -0,0 +1,43 @@
+-- The request tables as of the MySQL migrations up to 20230617112036_request-timestamps.sql.
+-- Timestamps are unix milliseconds, set by the repository.
+CREATE TABLE requests (
+	id varchar(255) NOT NULL PRIMARY KEY,
+	seq bigserial NOT NULL UNIQUE,
+	hash varchar(66) NULL,
+	tx jsonb NOT NULL,
+	chain bigint NOT NULL,
+	status varchar(32) NOT NULL DEFAULT 'queued',
+	nonce bigint NULL,
+	idempotency_key varchar(255) NULL,
+	idempotency_fingerprint varchar(64) NULL,
+	batch_id varchar(36) NULL,
+	batch_index integer NULL,
+	-- The relay's own gap fillers have an empty tenant
+	tenant_id varchar(255) NOT NULL DEFAULT '',
+	cost_gwei bigint NULL,
+	created_at bigint NOT NULL,
+	updated_at bigint NOT NULL
+);
+
+CREATE INDEX idx_requests_chain_status ON requests (chain, status, seq);
+CREATE INDEX idx_requests_chain_status_nonce ON requests (chain, status, nonce);
+CREATE UNIQUE INDEX idx_requests_idempotency_key ON requests (tenant_id, idempotency_key);
+CREATE INDEX idx_requests_batch_id ON requests (batch_id);
+CREATE INDEX idx_requests_created_at ON requests (created_at, seq);
+CREATE INDEX idx_requests_chain_created_at ON requests (chain, created_at, seq);
+CREATE INDEX idx_requests_tenant_created_at ON requests (tenant_id, created_at, seq);
+
+CREATE TABLE nonces (
+	chain bigint NOT NULL,
+	address varchar(42) NOT NULL,
+	next_nonce bigint NOT NULL,
+	PRIMARY KEY (chain, address)
+);
+
+CREATE TABLE request_dependencies (
+	request_id varchar(255) NOT NULL,
+	depends_on varchar(255) NOT NULL,
+	PRIMARY KEY (request_id, depends_on)
+);
+
+CREATE INDEX idx_request_dependencies_depends_on ON request_dependencies (depends_on);
@@ -0,0 +1,42 @@
+-- The transaction gets typed columns, tx is only kept as an archive of what was sent.
+ALTER TABLE requests
+	ADD COLUMN from_address varchar(42) NULL,
+	ADD COLUMN to_address varchar(42) NULL,
+	ADD COLUMN value numeric(78, 0) NULL,
+	ADD COLUMN max_fee numeric(78, 0) NULL,
+	ADD COLUMN priority_fee numeric(78, 0) NULL,
+	ADD COLUMN gas_limit bigint NULL,
+	ADD COLUMN data bytea NULL;
+
+-- JSON amounts are 0x prefixed hex
+CREATE FUNCTION pg_temp.hex_to_numeric(hex text) RETURNS numeric LANGUAGE plpgsql IMMUTABLE AS $$
+DECLARE
+	result numeric := 0;
+	digit text;
+BEGIN
+	IF hex IS NULL THEN
+		RETURN NULL;
+	END IF;
+	FOREACH digit IN ARRAY regexp_split_to_array(lower(substr(hex, 3)), '') LOOP
+		result := result * 16 + strpos('0123456789abcdef', digit) - 1;
+	END LOOP;
+	RETURN result;
+END
+$$;
+
+UPDATE requests
+SET
+	from_address = lower(tx->>'from'),
+	to_address = lower(tx->>'to'),
+	nonce = COALESCE(nonce, pg_temp.hex_to_numeric(tx->>'nonce')),
+	value = pg_temp.hex_to_numeric(tx->>'value'),
+	max_fee = pg_temp.hex_to_numeric(tx->>'maxFeePerGas'),
+	priority_fee = pg_temp.hex_to_numeric(tx->>'maxPriorityFeePerGas'),
+	gas_limit = pg_temp.hex_to_numeric(tx->>'gas'),
+	data = decode(substr(COALESCE(tx->>'data', tx->>'input'), 3), 'hex');
+
+-- Only submitted requests hold their nonce, mined ones can share it with a later replacement.
+-- Fails if two submitted requests already share a nonce, those need to be resolved first.
+CREATE UNIQUE INDEX idx_requests_active_nonce ON requests (chain, from_address, nonce) WHERE status = 'submitted';
+CREATE INDEX idx_requests_from_address ON requests (from_address, created_at, seq);
+CREATE INDEX idx_requests_to_address ON requests (to_address, created_at, seq);
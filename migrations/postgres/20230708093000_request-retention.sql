@@ -0,0 +1,37 @@
+-- Terminal requests past their chain's retention are moved here, in the shape of requests
+CREATE TABLE requests_archive (
+	id varchar(255) NOT NULL PRIMARY KEY,
+	seq bigint NOT NULL,
+	hash varchar(66) NULL,
+	tx jsonb NOT NULL,
+	chain bigint NOT NULL,
+	status varchar(32) NOT NULL,
+	nonce bigint NULL,
+	idempotency_key varchar(255) NULL,
+	idempotency_fingerprint varchar(64) NULL,
+	batch_id varchar(36) NULL,
+	batch_index integer NULL,
+	tenant_id varchar(255) NOT NULL,
+	cost_gwei bigint NULL,
+	created_at bigint NOT NULL,
+	updated_at bigint NOT NULL,
+	from_address varchar(42) NULL,
+	to_address varchar(42) NULL,
+	value numeric(78, 0) NULL,
+	max_fee numeric(78, 0) NULL,
+	priority_fee numeric(78, 0) NULL,
+	gas_limit bigint NULL,
+	data bytea NULL,
+	archived_at bigint NOT NULL
+);
+
+CREATE INDEX idx_requests_archive_chain_created_at ON requests_archive (chain, created_at, seq);
+CREATE INDEX idx_requests_archive_tenant_created_at ON requests_archive (tenant_id, created_at, seq);
+
+-- What removed requests cost, so spend budgets still count them
+CREATE TABLE tenant_spend (
+	tenant_id varchar(255) NOT NULL PRIMARY KEY,
+	cost_gwei bigint NOT NULL
+);
+
+CREATE INDEX idx_requests_chain_status_updated_at ON requests (chain, status, updated_at);
@@ -0,0 +1,24 @@
+-- Every state transition of a request and who made it. Rows outlive their request, they're
+-- never updated or deleted.
+CREATE TABLE request_events (
+	seq bigserial NOT NULL PRIMARY KEY,
+	request_id varchar(255) NOT NULL,
+	chain bigint NOT NULL,
+	tenant_id varchar(255) NOT NULL,
+	event varchar(32) NOT NULL,
+	actor varchar(255) NOT NULL,
+	hash varchar(66) NULL,
+	created_at bigint NOT NULL
+);
+
+CREATE INDEX idx_request_events_request_id ON request_events (request_id, seq);
+CREATE INDEX idx_request_events_tenant_id ON request_events (tenant_id, seq);
+
+CREATE FUNCTION request_events_append_only() RETURNS trigger AS $$
+BEGIN
+	RAISE EXCEPTION 'request_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER request_events_append_only BEFORE UPDATE OR DELETE ON request_events
+FOR EACH ROW EXECUTE FUNCTION request_events_append_only();
@@ -0,0 +1,59 @@
+-- Api keys, daily value usage, user operations and fee quotes as of the MySQL migrations up to 20230610154203_fee-quotes.sql
+CREATE TABLE api_keys (
+	id varchar(255) NOT NULL PRIMARY KEY,
+	key_hash char(66) NOT NULL,
+	tenant_id varchar(255) NOT NULL,
+	allowed_chains jsonb NULL,
+	allowed_to jsonb NULL,
+	rate_limit_per_minute bigint NULL,
+	spend_budget_gwei bigint NULL,
+	revoked boolean NOT NULL DEFAULT false,
+	-- Keys that sign requests, the secret can't be hashed since the relay recomputes the HMAC
+	hmac_secret varchar(255) NULL,
+	signer_address char(42) NULL
+);
+
+CREATE UNIQUE INDEX idx_api_keys_key_hash ON api_keys (key_hash);
+CREATE UNIQUE INDEX idx_api_keys_signer_address ON api_keys (signer_address);
+
+-- The day is the UTC date, YYYY-MM-DD
+CREATE TABLE daily_value_usage (
+	tenant_id varchar(255) NOT NULL,
+	day char(10) NOT NULL,
+	value_gwei bigint NOT NULL,
+	PRIMARY KEY (tenant_id, day)
+);
+
+CREATE TABLE user_operations (
+	hash varchar(66) NOT NULL PRIMARY KEY,
+	seq bigserial NOT NULL UNIQUE,
+	chain bigint NOT NULL,
+	entry_point varchar(42) NOT NULL,
+	sender varchar(42) NOT NULL,
+	op jsonb NOT NULL,
+	-- pending until bundled into a request, dropped if it stops validating
+	status varchar(32) NOT NULL DEFAULT 'pending',
+	request_id varchar(255) NULL
+);
+
+CREATE INDEX idx_user_operations_pending ON user_operations (chain, entry_point, status, seq);
+
+-- Amounts are decimal strings, token amounts can be larger than a bigint
+CREATE TABLE fee_quotes (
+	id varchar(255) NOT NULL PRIMARY KEY,
+	tenant_id varchar(255) NOT NULL,
+	chain bigint NOT NULL,
+	token varchar(42) NOT NULL,
+	method varchar(32) NOT NULL,
+	amount varchar(78) NOT NULL,
+	gas_limit varchar(78) NOT NULL,
+	max_fee_per_gas varchar(78) NOT NULL,
+	-- unix seconds
+	expires_at bigint NOT NULL,
+	-- the transfer hash or permit signature that paid the fee, once redeemed
+	payment_ref varchar(255) NULL,
+	request_id varchar(255) NULL
+);
+
+CREATE UNIQUE INDEX idx_fee_quotes_payment_ref ON fee_quotes (payment_ref);
+CREATE INDEX idx_fee_quotes_request_id ON fee_quotes (request_id);
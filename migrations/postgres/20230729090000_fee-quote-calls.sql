@@ -0,0 +1,4 @@
+-- Quotes only pay for the call they priced, made before this they match no call
+ALTER TABLE fee_quotes
+	ADD COLUMN payer varchar(42) NOT NULL DEFAULT '',
+	ADD COLUMN call_hash varchar(66) NOT NULL DEFAULT '';
@@ -0,0 +1,4 @@
+-- HMAC secrets are stored encrypted with HMAC_SECRET_KEY, which makes them longer.
+-- Existing ones stay plaintext until `relayctl encrypt-hmac-secrets` is run.
+ALTER TABLE api_keys
+	ALTER COLUMN hmac_secret TYPE varchar(1024);
@@ -0,0 +1,13 @@
+-- Keys of removed requests, so a late retry still gets the request it made instead of a new one
+CREATE TABLE retired_idempotency_keys (
+	tenant_id varchar(255) NOT NULL,
+	idempotency_key varchar(255) NOT NULL,
+	request_id varchar(255) NOT NULL,
+	idempotency_fingerprint varchar(64) NULL,
+	PRIMARY KEY (tenant_id, idempotency_key)
+);
+
+INSERT INTO retired_idempotency_keys (tenant_id, idempotency_key, request_id, idempotency_fingerprint)
+SELECT tenant_id, idempotency_key, id, idempotency_fingerprint
+FROM requests_archive
+WHERE idempotency_key IS NOT NULL;
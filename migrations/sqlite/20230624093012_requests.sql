@@ -0,0 +1,43 @@
+-- The request tables as of the MySQL migrations up to 20230617112036_request-timestamps.sql.
+-- Timestamps are unix milliseconds, set by the repository.
+CREATE TABLE requests (
+	seq integer PRIMARY KEY AUTOINCREMENT,
+	id text NOT NULL UNIQUE,
+	hash text NULL,
+	tx text NOT NULL,
+	chain integer NOT NULL,
+	status text NOT NULL DEFAULT 'queued',
+	nonce integer NULL,
+	idempotency_key text NULL,
+	idempotency_fingerprint text NULL,
+	batch_id text NULL,
+	batch_index integer NULL,
+	-- The relay's own gap fillers have an empty tenant
+	tenant_id text NOT NULL DEFAULT '',
+	cost_gwei integer NULL,
+	created_at integer NOT NULL,
+	updated_at integer NOT NULL
+);
+
+CREATE INDEX idx_requests_chain_status ON requests (chain, status, seq);
+CREATE INDEX idx_requests_chain_status_nonce ON requests (chain, status, nonce);
+CREATE UNIQUE INDEX idx_requests_idempotency_key ON requests (tenant_id, idempotency_key);
+CREATE INDEX idx_requests_batch_id ON requests (batch_id);
+CREATE INDEX idx_requests_created_at ON requests (created_at, seq);
+CREATE INDEX idx_requests_chain_created_at ON requests (chain, created_at, seq);
+CREATE INDEX idx_requests_tenant_created_at ON requests (tenant_id, created_at, seq);
+
+CREATE TABLE nonces (
+	chain integer NOT NULL,
+	address text NOT NULL,
+	next_nonce integer NOT NULL,
+	PRIMARY KEY (chain, address)
+);
+
+CREATE TABLE request_dependencies (
+	request_id text NOT NULL,
+	depends_on text NOT NULL,
+	PRIMARY KEY (request_id, depends_on)
+);
+
+CREATE INDEX idx_request_dependencies_depends_on ON request_dependencies (depends_on);
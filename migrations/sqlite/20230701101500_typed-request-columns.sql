@@ -0,0 +1,44 @@
+-- The transaction gets typed columns, tx is only kept as an archive of what was sent.
+-- SQLite has no integer wide enough for wei, so amounts are 64 lowercase hex digits, zero padded to sort numerically.
+-- Data is lowercase hex without the 0x prefix.
+ALTER TABLE requests ADD COLUMN from_address text NULL;
+ALTER TABLE requests ADD COLUMN to_address text NULL;
+ALTER TABLE requests ADD COLUMN value text NULL;
+ALTER TABLE requests ADD COLUMN max_fee text NULL;
+ALTER TABLE requests ADD COLUMN priority_fee text NULL;
+ALTER TABLE requests ADD COLUMN gas_limit integer NULL;
+ALTER TABLE requests ADD COLUMN data text NULL;
+
+-- JSON amounts are 0x prefixed hex, the nonce and gas limit are converted a digit at a time
+WITH RECURSIVE digits(id, field, rest, result) AS (
+	SELECT id, 'nonce', lower(substr(json_extract(tx, '$.nonce'), 3)), 0
+	FROM requests
+	WHERE json_extract(tx, '$.nonce') IS NOT NULL
+	UNION ALL
+	SELECT id, 'gas', lower(substr(json_extract(tx, '$.gas'), 3)), 0
+	FROM requests
+	WHERE json_extract(tx, '$.gas') IS NOT NULL
+	UNION ALL
+	SELECT id, field, substr(rest, 2), result * 16 + instr('0123456789abcdef', substr(rest, 1, 1)) - 1
+	FROM digits
+	WHERE rest != ''
+)
+UPDATE requests
+SET
+	nonce = COALESCE(nonce, (SELECT result FROM digits WHERE digits.id = requests.id and field = 'nonce' and rest = '')),
+	gas_limit = (SELECT result FROM digits WHERE digits.id = requests.id and field = 'gas' and rest = '');
+
+UPDATE requests
+SET
+	from_address = lower(json_extract(tx, '$.from')),
+	to_address = lower(json_extract(tx, '$.to')),
+	value = substr('0000000000000000000000000000000000000000000000000000000000000000' || lower(substr(json_extract(tx, '$.value'), 3)), -64),
+	max_fee = substr('0000000000000000000000000000000000000000000000000000000000000000' || lower(substr(json_extract(tx, '$.maxFeePerGas'), 3)), -64),
+	priority_fee = substr('0000000000000000000000000000000000000000000000000000000000000000' || lower(substr(json_extract(tx, '$.maxPriorityFeePerGas'), 3)), -64),
+	data = lower(substr(COALESCE(json_extract(tx, '$.data'), json_extract(tx, '$.input')), 3));
+
+-- Only submitted requests hold their nonce, mined ones can share it with a later replacement.
+-- Fails if two submitted requests already share a nonce, those need to be resolved first.
+CREATE UNIQUE INDEX idx_requests_active_nonce ON requests (chain, from_address, nonce) WHERE status = 'submitted';
+CREATE INDEX idx_requests_from_address ON requests (from_address, created_at, seq);
+CREATE INDEX idx_requests_to_address ON requests (to_address, created_at, seq);
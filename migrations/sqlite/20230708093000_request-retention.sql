@@ -0,0 +1,37 @@
+-- Terminal requests past their chain's retention are moved here, in the shape of requests
+CREATE TABLE requests_archive (
+	id text NOT NULL PRIMARY KEY,
+	seq integer NOT NULL,
+	hash text NULL,
+	tx text NOT NULL,
+	chain integer NOT NULL,
+	status text NOT NULL,
+	nonce integer NULL,
+	idempotency_key text NULL,
+	idempotency_fingerprint text NULL,
+	batch_id text NULL,
+	batch_index integer NULL,
+	tenant_id text NOT NULL,
+	cost_gwei integer NULL,
+	created_at integer NOT NULL,
+	updated_at integer NOT NULL,
+	from_address text NULL,
+	to_address text NULL,
+	value text NULL,
+	max_fee text NULL,
+	priority_fee text NULL,
+	gas_limit integer NULL,
+	data text NULL,
+	archived_at integer NOT NULL
+);
+
+CREATE INDEX idx_requests_archive_chain_created_at ON requests_archive (chain, created_at, seq);
+CREATE INDEX idx_requests_archive_tenant_created_at ON requests_archive (tenant_id, created_at, seq);
+
+-- What removed requests cost, so spend budgets still count them
+CREATE TABLE tenant_spend (
+	tenant_id text NOT NULL PRIMARY KEY,
+	cost_gwei integer NOT NULL
+);
+
+CREATE INDEX idx_requests_chain_status_updated_at ON requests (chain, status, updated_at);
@@ -0,0 +1,25 @@
+-- Every state transition of a request and who made it. Rows outlive their request, they're
+-- never updated or deleted.
+CREATE TABLE request_events (
+	seq integer PRIMARY KEY AUTOINCREMENT,
+	request_id text NOT NULL,
+	chain integer NOT NULL,
+	tenant_id text NOT NULL,
+	event text NOT NULL,
+	actor text NOT NULL,
+	hash text NULL,
+	created_at integer NOT NULL
+);
+
+CREATE INDEX idx_request_events_request_id ON request_events (request_id, seq);
+CREATE INDEX idx_request_events_tenant_id ON request_events (tenant_id, seq);
+
+CREATE TRIGGER request_events_no_update BEFORE UPDATE ON request_events
+BEGIN
+	SELECT RAISE(ABORT, 'request_events is append-only');
+END;
+
+CREATE TRIGGER request_events_no_delete BEFORE DELETE ON request_events
+BEGIN
+	SELECT RAISE(ABORT, 'request_events is append-only');
+END;
@@ -0,0 +1,60 @@
+-- Api keys, daily value usage, user operations and fee quotes as of the MySQL migrations up to 20230610154203_fee-quotes.sql
+CREATE TABLE api_keys (
+	id text NOT NULL PRIMARY KEY,
+	key_hash text NOT NULL,
+	tenant_id text NOT NULL,
+	-- JSON arrays
+	allowed_chains text NULL,
+	allowed_to text NULL,
+	rate_limit_per_minute integer NULL,
+	spend_budget_gwei integer NULL,
+	revoked boolean NOT NULL DEFAULT false,
+	-- Keys that sign requests, the secret can't be hashed since the relay recomputes the HMAC
+	hmac_secret text NULL,
+	signer_address text NULL
+);
+
+CREATE UNIQUE INDEX idx_api_keys_key_hash ON api_keys (key_hash);
+CREATE UNIQUE INDEX idx_api_keys_signer_address ON api_keys (signer_address);
+
+-- The day is the UTC date, YYYY-MM-DD
+CREATE TABLE daily_value_usage (
+	tenant_id text NOT NULL,
+	day text NOT NULL,
+	value_gwei integer NOT NULL,
+	PRIMARY KEY (tenant_id, day)
+);
+
+CREATE TABLE user_operations (
+	seq integer PRIMARY KEY AUTOINCREMENT,
+	hash text NOT NULL UNIQUE,
+	chain integer NOT NULL,
+	entry_point text NOT NULL,
+	sender text NOT NULL,
+	op text NOT NULL,
+	-- pending until bundled into a request, dropped if it stops validating
+	status text NOT NULL DEFAULT 'pending',
+	request_id text NULL
+);
+
+CREATE INDEX idx_user_operations_pending ON user_operations (chain, entry_point, status, seq);
+
+-- Amounts are decimal strings, token amounts can be larger than an integer
+CREATE TABLE fee_quotes (
+	id text NOT NULL PRIMARY KEY,
+	tenant_id text NOT NULL,
+	chain integer NOT NULL,
+	token text NOT NULL,
+	method text NOT NULL,
+	amount text NOT NULL,
+	gas_limit text NOT NULL,
+	max_fee_per_gas text NOT NULL,
+	-- unix seconds
+	expires_at integer NOT NULL,
+	-- the transfer hash or permit signature that paid the fee, once redeemed
+	payment_ref text NULL,
+	request_id text NULL
+);
+
+CREATE UNIQUE INDEX idx_fee_quotes_payment_ref ON fee_quotes (payment_ref);
+CREATE INDEX idx_fee_quotes_request_id ON fee_quotes (request_id);
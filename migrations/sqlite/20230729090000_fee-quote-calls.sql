@@ -0,0 +1,3 @@
+-- Quotes only pay for the call they priced, made before this they match no call
+ALTER TABLE fee_quotes ADD COLUMN payer text NOT NULL DEFAULT '';
+ALTER TABLE fee_quotes ADD COLUMN call_hash text NOT NULL DEFAULT '';
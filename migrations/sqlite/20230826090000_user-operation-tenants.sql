@@ -0,0 +1,3 @@
+-- Receipts are only given to the tenant that sent the operation, earlier ones belong to none
+ALTER TABLE user_operations
+	ADD COLUMN tenant_id text NOT NULL DEFAULT '';
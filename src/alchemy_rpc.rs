@@ -1,18 +1,25 @@
 use ethers::types::Chain;
 
-fn get_prefix(chain: Chain) -> String {
-    let prefix = match chain {
+use crate::transaction_repository::ChainId;
+
+fn get_prefix(chain: ChainId) -> Option<&'static str> {
+    let prefix = match Chain::try_from(chain).ok()? {
         Chain::Mainnet => "eth-mainnet",
         Chain::Goerli => "eth-goerli",
         Chain::Polygon => "polygon-mainnet",
         Chain::PolygonMumbai => "polygon-mumbai",
         Chain::Sepolia => "eth-sepolia",
-        _ => panic!("chain {} not supported", chain),
+        _ => return None,
     };
 
-    prefix.to_owned()
+    Some(prefix)
+}
+
+pub fn is_supported(chain: ChainId) -> bool {
+    get_prefix(chain).is_some()
 }
 
-pub fn get_ws(chain: Chain, key: &str) -> String {
-    format!("wss://{}.g.alchemy.com/v2/{}", get_prefix(chain), key)
+pub fn get_ws(chain: ChainId, key: &str) -> String {
+    let prefix = get_prefix(chain).unwrap_or_else(|| panic!("chain {} not supported", chain));
+    format!("wss://{}.g.alchemy.com/v2/{}", prefix, key)
 }
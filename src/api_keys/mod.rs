@@ -0,0 +1,241 @@
+use std::fmt::Debug;
+
+use async_trait::async_trait;
+use ethers::{
+    types::{Address, U256},
+    utils::keccak256,
+};
+use sqlx::query;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::{
+    auth::SecretKey,
+    database::{text, with_pool, DbPool, Dialect},
+    transaction_repository::ChainId,
+};
+
+const GWEI: u64 = 1_000_000_000;
+
+#[async_trait]
+pub trait ApiKeyRepository: Sync + Send + Debug {
+    /// The key's settings if it exists and hasn't been revoked
+    async fn find(&self, key: &str) -> anyhow::Result<Option<ApiKey>>;
+    /// The key with its HMAC secret as stored, encrypted with `SecretKey`, for keys that sign
+    /// requests instead of sending the key
+    async fn find_hmac(&self, id: Uuid) -> anyhow::Result<Option<(ApiKey, String)>>;
+    /// The key registered to an address that signs requests with EIP-712
+    async fn find_by_signer(&self, signer: Address) -> anyhow::Result<Option<ApiKey>>;
+}
+
+/// Keys are only stored hashed, formatted like transaction hashes
+pub fn hash_key(key: &str) -> String {
+    format!("0x{}", hex::encode(keccak256(key.as_bytes())))
+}
+
+#[derive(Clone, Debug)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub tenant_id: String,
+    /// Chains the key may submit to, any if unset
+    pub allowed_chains: Option<Vec<ChainId>>,
+    /// Addresses the key may send transactions to, any if unset
+    pub allowed_to: Option<Vec<Address>>,
+    pub rate_limit_per_minute: Option<u32>,
+    /// Wei the tenant may spend in total, value plus gas
+    pub spend_budget: Option<U256>,
+}
+
+#[derive(Debug, Error)]
+pub enum KeyScopeError {
+    #[error("this api key can't submit to chain {0}")]
+    Chain(ChainId),
+
+    #[error("this api key can't send transactions to {0:?}")]
+    To(Address),
+}
+
+impl ApiKey {
+    /// What `EXPECTED_AUTH_HEADER` authenticates as, one tenant without any limits
+    pub fn bootstrap() -> Self {
+        Self {
+            id: Uuid::nil(),
+            tenant_id: "default".to_owned(),
+            allowed_chains: None,
+            allowed_to: None,
+            rate_limit_per_minute: None,
+            spend_budget: None,
+        }
+    }
+
+    pub fn authorize(&self, chain: ChainId, to: Address) -> Result<(), KeyScopeError> {
+        if let Some(chains) = &self.allowed_chains {
+            if !chains.contains(&chain) {
+                return Err(KeyScopeError::Chain(chain));
+            }
+        }
+        if let Some(addresses) = &self.allowed_to {
+            if !addresses.contains(&to) {
+                return Err(KeyScopeError::To(to));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The allowed chains and addresses are JSON arrays
+#[derive(Debug)]
+pub struct ApiKeyRecord {
+    pub id: String,
+    pub tenant_id: String,
+    pub allowed_chains: Option<String>,
+    pub allowed_to: Option<String>,
+    pub rate_limit_per_minute: Option<u32>,
+    pub spend_budget_gwei: Option<u64>,
+}
+
+impl TryFrom<ApiKeyRecord> for ApiKey {
+    type Error = anyhow::Error;
+
+    fn try_from(record: ApiKeyRecord) -> Result<Self, Self::Error> {
+        let allowed_chains = record
+            .allowed_chains
+            .map(|chains| serde_json::from_str(&chains))
+            .transpose()?;
+        let allowed_to = record
+            .allowed_to
+            .map(|addresses| serde_json::from_str(&addresses))
+            .transpose()?;
+
+        Ok(ApiKey {
+            id: Uuid::parse_str(&record.id)?,
+            tenant_id: record.tenant_id,
+            allowed_chains,
+            allowed_to,
+            rate_limit_per_minute: record.rate_limit_per_minute,
+            spend_budget: record
+                .spend_budget_gwei
+                .map(|budget| U256::from(budget) * GWEI),
+        })
+    }
+}
+
+fn api_key_columns<DB: Dialect>() -> String {
+    format!(
+        "id, tenant_id, rate_limit_per_minute, spend_budget_gwei, {} as allowed_chains, {} as allowed_to",
+        DB::json_column("allowed_chains"),
+        DB::json_column("allowed_to")
+    )
+}
+
+fn api_key_record<DB: Dialect>(row: &DB::Row) -> sqlx::Result<ApiKeyRecord> {
+    Ok(ApiKeyRecord {
+        id: text::<DB>(row, "id")?,
+        tenant_id: text::<DB>(row, "tenant_id")?,
+        allowed_chains: DB::get_text(row, "allowed_chains")?,
+        allowed_to: DB::get_text(row, "allowed_to")?,
+        rate_limit_per_minute: DB::get_u64(row, "rate_limit_per_minute")?.map(|limit| limit as u32),
+        spend_budget_gwei: DB::get_u64(row, "spend_budget_gwei")?,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct DbApiKeyRepository {
+    pool: DbPool,
+}
+
+impl DbApiKeyRepository {
+    pub fn new(pool: DbPool) -> Self {
+        Self { pool }
+    }
+
+    /// The unrevoked key whose `column` is `value`
+    async fn find_by(&self, column: &str, value: String) -> anyhow::Result<Option<ApiKey>> {
+        let record = with_pool!(&self.pool, |pool: DB| {
+            let sql = DB::sql(&format!(
+                "SELECT {} FROM api_keys WHERE {} = ? and revoked = false",
+                api_key_columns::<DB>(),
+                column
+            ));
+            query(&sql)
+                .bind(value)
+                .try_map(|row| api_key_record::<DB>(&row))
+                .fetch_optional(pool)
+                .await?
+        });
+
+        record.map(ApiKey::try_from).transpose()
+    }
+
+    /// Encrypts the HMAC secrets that are still stored in plaintext, returns how many it did
+    pub async fn encrypt_hmac_secrets(&self, key: &SecretKey) -> anyhow::Result<u64> {
+        let encrypted = with_pool!(&self.pool, |pool: DB| {
+            let sql = DB::sql("SELECT id, hmac_secret FROM api_keys WHERE hmac_secret IS NOT NULL");
+            let secrets = query(&sql)
+                .try_map(|row| Ok((text::<DB>(&row, "id")?, text::<DB>(&row, "hmac_secret")?)))
+                .fetch_all(pool)
+                .await?;
+
+            let mut encrypted = 0;
+            // Only replaces the secret it read, so running this twice at once is harmless
+            let sql =
+                DB::sql("UPDATE api_keys SET hmac_secret = ? WHERE id = ? and hmac_secret = ?");
+            for (id, secret) in secrets {
+                if SecretKey::is_encrypted(&secret) {
+                    continue;
+                }
+                let sealed = key.encrypt(Uuid::parse_str(&id)?, &secret);
+                let result = query(&sql)
+                    .bind(sealed)
+                    .bind(&id)
+                    .bind(&secret)
+                    .execute(pool)
+                    .await?;
+                encrypted += DB::rows_affected(&result);
+            }
+            encrypted
+        });
+
+        Ok(encrypted)
+    }
+}
+
+#[async_trait]
+impl ApiKeyRepository for DbApiKeyRepository {
+    async fn find(&self, key: &str) -> anyhow::Result<Option<ApiKey>> {
+        self.find_by("key_hash", hash_key(key)).await
+    }
+
+    async fn find_hmac(&self, id: Uuid) -> anyhow::Result<Option<(ApiKey, String)>> {
+        let record = with_pool!(&self.pool, |pool: DB| {
+            let sql = DB::sql(&format!(
+                r#"
+				SELECT {}, hmac_secret
+				FROM api_keys
+				WHERE id = ? and hmac_secret IS NOT NULL and revoked = false
+				"#,
+                api_key_columns::<DB>()
+            ));
+            query(&sql)
+                .bind(id.to_string())
+                .try_map(|row| {
+                    Ok((
+                        api_key_record::<DB>(&row)?,
+                        text::<DB>(&row, "hmac_secret")?,
+                    ))
+                })
+                .fetch_optional(pool)
+                .await?
+        });
+
+        record
+            .map(|(record, secret)| Ok((record.try_into()?, secret)))
+            .transpose()
+    }
+
+    async fn find_by_signer(&self, signer: Address) -> anyhow::Result<Option<ApiKey>> {
+        self.find_by("signer_address", format!("{:?}", signer))
+            .await
+    }
+}
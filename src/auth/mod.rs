@@ -0,0 +1,278 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use ethers::{
+    abi::{encode, Token},
+    types::{
+        transaction::eip712::{EIP712Domain, Eip712},
+        Address, Signature, U256,
+    },
+    utils::keccak256,
+};
+use hmac::{Hmac, Mac};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use sha2::Sha256;
+use thiserror::Error;
+use uuid::Uuid;
+
+/// How far a signed request's timestamp may be from the server's clock,
+/// signatures are remembered this long to reject replays
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(300);
+
+const DOMAIN_NAME: &str = "relay";
+const DOMAIN_VERSION: &str = "1";
+const REQUEST_TYPE: &str =
+    "RelayRequest(string method,string path,bytes32 bodyHash,uint256 timestamp)";
+/// Marks an encrypted HMAC secret, anything else in the column is still plaintext
+const ENCRYPTED_PREFIX: &str = "enc:";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthMode {
+    /// The api key itself in the `authorization` header
+    ApiKey,
+    /// An HMAC-SHA256 signature made with the api key's secret
+    Hmac,
+    /// EIP-712 typed data signed by an address registered to an api key
+    Eip712,
+}
+
+impl AuthMode {
+    /// How requests are signed, unless they send the api key itself
+    pub fn signed(self) -> Option<SignedMode> {
+        match self {
+            AuthMode::ApiKey => None,
+            AuthMode::Hmac => Some(SignedMode::Hmac),
+            AuthMode::Eip712 => Some(SignedMode::Eip712),
+        }
+    }
+}
+
+impl FromStr for AuthMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "api_key" => Ok(AuthMode::ApiKey),
+            "hmac" => Ok(AuthMode::Hmac),
+            "eip712" => Ok(AuthMode::Eip712),
+            _ => Err(anyhow!("unknown auth mode {}", s)),
+        }
+    }
+}
+
+/// The auth modes that sign requests
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignedMode {
+    Hmac,
+    Eip712,
+}
+
+#[derive(Debug, Error)]
+pub enum SignatureRejected {
+    #[error("timestamp is more than {} seconds from the server's clock", MAX_CLOCK_SKEW.as_secs())]
+    Expired,
+
+    #[error("signature was already used")]
+    Replayed,
+
+    #[error("signature does not match the request")]
+    Invalid,
+}
+
+/// What a signature covers, binding it to one route and body at one time
+#[derive(Clone, Debug)]
+pub struct SignedRequest<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub body: &'a [u8],
+    /// Unix seconds
+    pub timestamp: u64,
+}
+
+impl<'a> SignedRequest<'a> {
+    /// Checks a hex HMAC-SHA256 of `{timestamp}.{method}.{path}.{body}`
+    pub fn verify_hmac(&self, secret: &[u8], signature: &str) -> Result<(), SignatureRejected> {
+        let signature = hex::decode(signature).map_err(|_| SignatureRejected::Invalid)?;
+        // Constant time comparison
+        self.hmac_mac(secret)
+            .verify_slice(&signature)
+            .map_err(|_| SignatureRejected::Invalid)
+    }
+
+    fn hmac_mac(&self, secret: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac accepts any key length");
+        mac.update(format!("{}.{}.{}.", self.timestamp, self.method, self.path).as_bytes());
+        mac.update(self.body);
+        mac
+    }
+
+    /// The address that signed the request's typed data
+    pub fn recover(&self, signature: &str) -> Result<Address, SignatureRejected> {
+        let signature = Signature::from_str(signature).map_err(|_| SignatureRejected::Invalid)?;
+        let digest = self
+            .encode_eip712()
+            .map_err(|_| SignatureRejected::Invalid)?;
+        signature
+            .recover(digest)
+            .map_err(|_| SignatureRejected::Invalid)
+    }
+}
+
+impl<'a> Eip712 for SignedRequest<'a> {
+    type Error = std::convert::Infallible;
+
+    fn domain(&self) -> Result<EIP712Domain, Self::Error> {
+        Ok(EIP712Domain {
+            name: Some(DOMAIN_NAME.to_owned()),
+            version: Some(DOMAIN_VERSION.to_owned()),
+            chain_id: None,
+            verifying_contract: None,
+            salt: None,
+        })
+    }
+
+    fn type_hash() -> Result<[u8; 32], Self::Error> {
+        Ok(keccak256(REQUEST_TYPE))
+    }
+
+    fn struct_hash(&self) -> Result<[u8; 32], Self::Error> {
+        Ok(keccak256(encode(&[
+            Token::FixedBytes(Self::type_hash()?.to_vec()),
+            Token::FixedBytes(keccak256(self.method).to_vec()),
+            Token::FixedBytes(keccak256(self.path).to_vec()),
+            Token::FixedBytes(keccak256(self.body).to_vec()),
+            Token::Uint(U256::from(self.timestamp)),
+        ])))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SecretError {
+    #[error("secret is stored in plaintext, encrypt it with `relayctl encrypt-hmac-secrets`")]
+    Plaintext,
+
+    #[error("secret can't be decrypted with this key")]
+    Undecryptable,
+}
+
+/// Encrypts the api keys' HMAC secrets at rest with AES-256-GCM. Each secret is bound to its
+/// key's id, so a ciphertext copied to another row doesn't decrypt.
+#[derive(Clone)]
+pub struct SecretKey([u8; 32]);
+
+impl SecretKey {
+    pub fn encrypt(&self, key_id: Uuid, secret: &str) -> String {
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .expect("system randomness is available");
+        let mut sealed = secret.as_bytes().to_vec();
+        self.cipher()
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(key_id.as_bytes()),
+                &mut sealed,
+            )
+            .expect("secrets fit in a single message");
+        format!(
+            "{}{}{}",
+            ENCRYPTED_PREFIX,
+            hex::encode(nonce),
+            hex::encode(sealed)
+        )
+    }
+
+    pub fn decrypt(&self, key_id: Uuid, stored: &str) -> Result<String, SecretError> {
+        let encrypted = stored
+            .strip_prefix(ENCRYPTED_PREFIX)
+            .ok_or(SecretError::Plaintext)?;
+        let mut bytes = hex::decode(encrypted).map_err(|_| SecretError::Undecryptable)?;
+        if bytes.len() < NONCE_LEN {
+            return Err(SecretError::Undecryptable);
+        }
+        let mut sealed = bytes.split_off(NONCE_LEN);
+        let nonce =
+            Nonce::try_assume_unique_for_key(&bytes).map_err(|_| SecretError::Undecryptable)?;
+        let secret = self
+            .cipher()
+            .open_in_place(nonce, Aad::from(key_id.as_bytes()), &mut sealed)
+            .map_err(|_| SecretError::Undecryptable)?;
+        String::from_utf8(secret.to_vec()).map_err(|_| SecretError::Undecryptable)
+    }
+
+    /// Whether a stored secret is already encrypted
+    pub fn is_encrypted(stored: &str) -> bool {
+        stored.starts_with(ENCRYPTED_PREFIX)
+    }
+
+    fn cipher(&self) -> LessSafeKey {
+        LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &self.0).expect("the key is 32 bytes"))
+    }
+}
+
+/// 32 bytes as hex
+impl FromStr for SecretKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        hex::decode(s.trim_start_matches("0x"))
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(Self)
+            .ok_or_else(|| anyhow!("expected 32 hex encoded bytes"))
+    }
+}
+
+/// Keeps the key out of logs
+impl std::fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SecretKey(..)")
+    }
+}
+
+/// Remembers recent signatures so a captured request can't be sent again.
+/// Lives in memory, so it only protects a single relay instance.
+#[derive(Debug, Default)]
+pub struct ReplayGuard {
+    seen: Mutex<HashMap<[u8; 32], u64>>,
+}
+
+impl ReplayGuard {
+    /// Accepts a request once per signer, and only while its timestamp is close to now.
+    /// Keyed on what was signed rather than the signature, which can be malleated.
+    pub fn check(&self, request: &SignedRequest, signer: &str) -> Result<(), SignatureRejected> {
+        let now = unix_now();
+        if now.abs_diff(request.timestamp) > MAX_CLOCK_SKEW.as_secs() {
+            return Err(SignatureRejected::Expired);
+        }
+
+        let digest = request
+            .encode_eip712()
+            .map_err(|_| SignatureRejected::Invalid)?;
+        let key = keccak256([signer.as_bytes(), &digest].concat());
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, expires| *expires >= now);
+        // Once expired the timestamp check rejects it anyway
+        let expires = request.timestamp + MAX_CLOCK_SKEW.as_secs();
+        if seen.insert(key, expires).is_some() {
+            return Err(SignatureRejected::Replayed);
+        }
+
+        Ok(())
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("clock is after the epoch")
+        .as_secs()
+}
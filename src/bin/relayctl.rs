@@ -0,0 +1,395 @@
+//! Operator tool working straight on the request database and the chains' rpc,
+//! the relay's HTTP server doesn't have to be running
+
+use anyhow::{anyhow, bail};
+use clap::{Args, Parser, Subcommand};
+use dotenv::dotenv;
+use ethers::{
+    providers::{Middleware, Provider, Ws},
+    signers::LocalWallet,
+    types::U256,
+    utils::format_ether,
+};
+use relay::{
+    alchemy_rpc::{self, get_ws},
+    api_keys::DbApiKeyRepository,
+    auth::SecretKey,
+    config::Config,
+    transaction_monitor::TransactionMonitor,
+    transaction_repository::{
+        Actor, ChainId, DbTxRequestRepository, ListedRequest, RequestFilter, RequestStatus,
+        TransactionRepository,
+    },
+};
+use std::{env, path::PathBuf, str::FromStr};
+use uuid::Uuid;
+
+#[derive(Parser)]
+#[command(name = "relayctl", about = "Inspect and operate the relay's requests")]
+struct Cli {
+    /// The relay's TOML or YAML config, its env vars apply on top like for the server
+    #[arg(long, env = "CONFIG_FILE")]
+    config: Option<PathBuf>,
+    /// The request database, like the server's
+    #[arg(long, env = "DATABASE_URL", hide_env_values = true)]
+    database_url: Option<String>,
+    #[command(flatten)]
+    chain: ChainArgs,
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// Only needed by commands that talk to a chain, and only without a config file
+#[derive(Args)]
+struct ChainArgs {
+    /// The relayer's private key
+    #[arg(long, env = "PK", hide_env_values = true)]
+    pk: Option<String>,
+    #[arg(long, env = "ALCHEMY_KEY", hide_env_values = true)]
+    alchemy_key: Option<String>,
+    #[arg(long, env = "GAS_LIMIT_MULTIPLIER", default_value_t = 1.2)]
+    gas_limit_multiplier: f64,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// A request's status and its events
+    Status { id: Uuid },
+    /// Requests, newest first
+    List {
+        /// Only requests that haven't settled yet
+        #[arg(long)]
+        pending: bool,
+        #[arg(long)]
+        chain: Option<ChainId>,
+        #[arg(long, default_value_t = 50)]
+        limit: u32,
+    },
+    /// Cancels a request that hasn't been submitted yet
+    Cancel { id: Uuid },
+    /// Sends a submitted request again with higher fees, the ones escalation would pick next
+    /// unless they're given in wei
+    Speedup {
+        id: Uuid,
+        #[arg(long, value_parser = parse_wei, requires = "priority_fee")]
+        max_fee: Option<U256>,
+        #[arg(long, value_parser = parse_wei, requires = "max_fee")]
+        priority_fee: Option<U256>,
+    },
+    /// The relayer's next nonce
+    Nonce {
+        #[command(subcommand)]
+        command: NonceCommand,
+    },
+    /// The relayer's balance
+    Balance {
+        #[arg(long)]
+        chain: ChainId,
+    },
+    /// Applies the request database's migrations
+    Migrate,
+    /// Encrypts the api keys' HMAC secrets that are still stored in plaintext, run it after
+    /// inserting a key
+    EncryptHmacSecrets {
+        /// The server's key, taken from the config file if it isn't given
+        #[arg(long, env = "HMAC_SECRET_KEY", hide_env_values = true)]
+        key: Option<SecretKey>,
+    },
+    /// Settles submitted requests that were mined from this block on while nothing was watching
+    ReplayFromBlock {
+        #[arg(long)]
+        chain: ChainId,
+        block: u64,
+    },
+}
+
+#[derive(Subcommand)]
+enum NonceCommand {
+    /// The nonce as stored and as the node sees it
+    Show {
+        #[arg(long)]
+        chain: ChainId,
+    },
+    /// Resets the stored nonce to the node's, or past the highest submitted request
+    Resync {
+        #[arg(long)]
+        chain: ChainId,
+    },
+}
+
+type Monitor = TransactionMonitor<Ws, DbTxRequestRepository>;
+
+// In-flight statuses, what `list --pending` shows
+const PENDING: [RequestStatus; 3] = [
+    RequestStatus::Waiting,
+    RequestStatus::Queued,
+    RequestStatus::Submitted,
+];
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    dotenv().ok();
+    tracing_subscriber::fmt()
+        .compact()
+        .with_writer(std::io::stderr)
+        .with_max_level(tracing::Level::WARN)
+        .init();
+
+    let cli = Cli::parse();
+    let config = match &cli.config {
+        Some(path) => Some(Config::load(Some(path), |name| env::var(name).ok())?),
+        None => None,
+    };
+    let database_url = cli
+        .database_url
+        .clone()
+        .or_else(|| Some(config.as_ref()?.database.url.clone()))
+        .ok_or_else(|| anyhow!("DATABASE_URL or a config file is needed"))?;
+    let tx_repo = DbTxRequestRepository::connect(&database_url, 1).await?;
+    let monitor = TransactionMonitor::new(tx_repo);
+    let chain_source = match &config {
+        Some(config) => ChainSource::Config(config),
+        None => ChainSource::Args(&cli.chain),
+    };
+
+    match cli.command {
+        Command::Status { id } => status(&monitor, id).await,
+        Command::List {
+            pending,
+            chain,
+            limit,
+        } => list(&monitor, pending, chain, limit).await,
+        Command::Cancel { id } => {
+            if !monitor.tx_repo.cancel(id, &Actor::Admin).await? {
+                bail!(
+                    "request {} can't be cancelled, {}",
+                    id,
+                    current(&monitor, id).await?
+                );
+            }
+            println!("cancelled {}", id);
+            Ok(())
+        }
+        Command::Speedup {
+            id,
+            max_fee,
+            priority_fee,
+        } => {
+            let request = monitor
+                .tx_repo
+                .get(id)
+                .await?
+                .ok_or_else(|| anyhow!("request {} doesn't exist", id))?;
+            attach(&monitor, &chain_source, request.chain).await?;
+            let hash = match max_fee.zip(priority_fee) {
+                Some((max_fee, priority_fee)) => {
+                    monitor
+                        .force_rebroadcast(id, max_fee, priority_fee, &Actor::Admin)
+                        .await?
+                }
+                None => monitor.speed_up(id, &Actor::Admin).await?,
+            };
+            match hash {
+                Some(hash) => println!("{} rebroadcast as {:?}", id, hash),
+                None => bail!(
+                    "request {} can't be sped up, {}",
+                    id,
+                    current(&monitor, id).await?
+                ),
+            }
+            Ok(())
+        }
+        Command::Nonce {
+            command: NonceCommand::Show { chain },
+        } => {
+            attach(&monitor, &chain_source, chain).await?;
+            let (address, stored, pending) = monitor.nonces(chain).await?;
+            println!("relayer  {:?}", address);
+            match stored {
+                Some(stored) => println!("stored   {}", stored),
+                None => println!("stored   -"),
+            }
+            println!("node     {}", pending);
+            Ok(())
+        }
+        Command::Nonce {
+            command: NonceCommand::Resync { chain },
+        } => {
+            attach(&monitor, &chain_source, chain).await?;
+            let next_nonce = monitor.resync_nonce(chain).await?;
+            println!("next nonce {}", next_nonce);
+            Ok(())
+        }
+        Command::Balance { chain } => {
+            attach(&monitor, &chain_source, chain).await?;
+            let provider = monitor.provider(chain)?;
+            let balance = provider.get_balance(provider.address(), None).await?;
+            println!("{:?} {} ether", provider.address(), format_ether(balance));
+            Ok(())
+        }
+        Command::Migrate => {
+            monitor.tx_repo.migrate().await?;
+            println!("migrated");
+            Ok(())
+        }
+        Command::EncryptHmacSecrets { key } => {
+            let key = key
+                .or_else(|| config.as_ref()?.hmac_secret_key.clone())
+                .ok_or_else(|| anyhow!("HMAC_SECRET_KEY or a config file with it is needed"))?;
+            let api_keys = DbApiKeyRepository::new(monitor.tx_repo.pool());
+            let encrypted = api_keys.encrypt_hmac_secrets(&key).await?;
+            println!("encrypted {} secrets", encrypted);
+            Ok(())
+        }
+        Command::ReplayFromBlock { chain, block } => {
+            attach(&monitor, &chain_source, chain).await?;
+            let settled = monitor.replay_from_block(chain, block).await?;
+            println!("settled {} requests", settled);
+            Ok(())
+        }
+    }
+}
+
+/// Where the signer and rpc urls come from
+enum ChainSource<'a> {
+    Config(&'a Config),
+    Args(&'a ChainArgs),
+}
+
+/// Sets the chain up without its workers, the running relay's monitors keep doing that work
+async fn attach(monitor: &Monitor, source: &ChainSource<'_>, chain: ChainId) -> anyhow::Result<()> {
+    let (signer, rpc_url, gas_limit_multiplier) = match source {
+        ChainSource::Config(config) => {
+            let chain_config = config
+                .chains
+                .get(&chain)
+                .ok_or_else(|| anyhow!("chain {} isn't configured", chain))?;
+            (
+                config.signer.clone(),
+                chain_config.rpc_url.clone(),
+                chain_config.gas_limit_multiplier,
+            )
+        }
+        ChainSource::Args(args) => {
+            if !alchemy_rpc::is_supported(chain) {
+                bail!(
+                    "Alchemy doesn't serve chain {}, give its rpc_url in --config",
+                    chain
+                );
+            }
+            let pk = args
+                .pk
+                .as_deref()
+                .ok_or_else(|| anyhow!("PK is needed to act on chain {}", chain))?;
+            let alchemy_key = args
+                .alchemy_key
+                .as_deref()
+                .ok_or_else(|| anyhow!("ALCHEMY_KEY is needed to act on chain {}", chain))?;
+            (
+                LocalWallet::from_str(pk)?,
+                get_ws(chain, alchemy_key),
+                args.gas_limit_multiplier,
+            )
+        }
+    };
+    let provider = Provider::<Ws>::connect(rpc_url).await?;
+    monitor
+        .attach_monitor(signer, provider, chain, gas_limit_multiplier)
+        .await
+}
+
+async fn status(monitor: &Monitor, id: Uuid) -> anyhow::Result<()> {
+    let events = monitor.tx_repo.get_events(id).await?;
+    match monitor.tx_repo.get(id).await? {
+        Some(request) => {
+            println!("id       {}", request.id);
+            println!("status   {}", request.status.as_str());
+            println!("chain    {}", request.chain);
+            println!("tenant   {}", request.tenant_id.as_deref().unwrap_or("-"));
+            println!("hash     {}", display(request.hash));
+            println!("nonce    {}", display(request.tx.nonce));
+            println!(
+                "to       {}",
+                display(request.tx.to.as_ref().and_then(|to| to.as_address()))
+            );
+        }
+        // Archived requests only have their events left
+        None if !events.is_empty() => println!("id       {} (archived)", id),
+        None => bail!("request {} doesn't exist", id),
+    }
+
+    if !events.is_empty() {
+        println!("events");
+    }
+    for event in events {
+        println!(
+            "  {:>6} {} {:<10} {:<46} {}",
+            event.seq,
+            event.created_at,
+            event.event.as_str(),
+            event.actor,
+            display(event.hash)
+        );
+    }
+    Ok(())
+}
+
+async fn list(
+    monitor: &Monitor,
+    pending: bool,
+    chain: Option<ChainId>,
+    limit: u32,
+) -> anyhow::Result<()> {
+    let statuses: Vec<Option<RequestStatus>> = if pending {
+        PENDING.into_iter().map(Some).collect()
+    } else {
+        vec![None]
+    };
+
+    let mut listed: Vec<ListedRequest> = Vec::new();
+    for status in statuses {
+        let filter = RequestFilter {
+            chain,
+            status,
+            ..RequestFilter::default()
+        };
+        let (page, _) = monitor.tx_repo.list(&filter, None, limit).await?;
+        listed.extend(page);
+    }
+    listed.sort_by_key(|listed| std::cmp::Reverse(listed.created_at));
+    listed.truncate(limit as usize);
+
+    for ListedRequest {
+        request,
+        created_at,
+        ..
+    } in listed
+    {
+        println!(
+            "{} {:<10} {:<10} {:<66} {}",
+            request.id,
+            request.chain.to_string(),
+            request.status.as_str(),
+            display(request.hash),
+            created_at
+        );
+    }
+    Ok(())
+}
+
+/// Describes where the request is, for commands that couldn't act on it
+async fn current(monitor: &Monitor, id: Uuid) -> anyhow::Result<String> {
+    Ok(match monitor.tx_repo.get(id).await? {
+        Some(request) => format!("it's {}", request.status.as_str()),
+        None => "it doesn't exist".to_owned(),
+    })
+}
+
+fn display<T: std::fmt::Debug>(value: Option<T>) -> String {
+    value.map_or("-".to_owned(), |value| format!("{:?}", value))
+}
+
+/// Wei in decimal, `U256::from_str` would read it as hex
+fn parse_wei(s: &str) -> anyhow::Result<U256> {
+    Ok(U256::from_dec_str(s)?)
+}
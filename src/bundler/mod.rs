@@ -0,0 +1,472 @@
+use std::{collections::HashSet, sync::Arc};
+
+use ethers::{
+    abi::{decode, ParamType, Token},
+    providers::{JsonRpcClient, Middleware, MiddlewareError},
+    signers::Signer,
+    types::{Address, Eip1559TransactionRequest, Log, TransactionReceipt, H256, U256},
+    utils::keccak256,
+};
+use serde::Serialize;
+use sqlx::query;
+use tokio::{
+    sync::Mutex,
+    time::{sleep, Duration},
+};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::auth::unix_now;
+use crate::database::{text, with_pool, DbPool, Dialect};
+use crate::transaction_monitor::{SendOptions, SimulationError, TransactionMonitor};
+use crate::transaction_repository::{ChainId, RequestStatus, TransactionRepository};
+
+mod user_operation;
+pub use user_operation::{
+    check_validation_result, handle_ops_calldata, UserOpRejected, UserOperation,
+};
+use user_operation::{BEFORE_EXECUTION, USER_OPERATION_EVENT};
+
+/// The canonical EntryPoint v0.6 deployment
+pub const DEFAULT_ENTRY_POINT: &str = "0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789";
+const MAX_BUNDLE_SIZE: u32 = 10;
+
+/// Minimal ERC-4337 bundler. Validated operations wait in the `user_operations` table until
+/// they're bundled into one `handleOps` transaction, which the chain's monitor submits and
+/// escalates like any other request. The relayer is the beneficiary of the bundle's fees.
+#[derive(Debug)]
+pub struct Bundler<P> {
+    monitor: Arc<TransactionMonitor<P>>,
+    pool: DbPool,
+    pub entry_point: Address,
+    bundling: Mutex<()>,
+}
+
+#[derive(Debug)]
+struct PendingOperation {
+    hash: String,
+    op: UserOperation,
+}
+
+#[derive(Debug)]
+struct BundledOperation {
+    entry_point: String,
+    request_id: Option<String>,
+    op: UserOperation,
+}
+
+/// Operations are kept as JSON
+fn operation<DB: Dialect>(row: &DB::Row) -> sqlx::Result<UserOperation> {
+    serde_json::from_str(&text::<DB>(row, "op")?).map_err(|err| sqlx::Error::Decode(err.into()))
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserOperationReceipt {
+    pub user_op_hash: H256,
+    pub entry_point: Address,
+    pub sender: Address,
+    pub nonce: U256,
+    pub paymaster: Address,
+    pub actual_gas_cost: U256,
+    pub actual_gas_used: U256,
+    pub success: bool,
+    /// Logs emitted while the operation executed
+    pub logs: Vec<Log>,
+    pub receipt: TransactionReceipt,
+}
+
+impl<P> Bundler<P>
+where
+    P: JsonRpcClient + 'static,
+{
+    pub fn new(monitor: Arc<TransactionMonitor<P>>, pool: DbPool, entry_point: Address) -> Self {
+        Self {
+            monitor,
+            pool,
+            entry_point,
+            bundling: Mutex::new(()),
+        }
+    }
+
+    /// Validates the operation and holds it for the next bundle, returns its hash.
+    /// Sending the same operation again returns the same hash, it stays the first tenant's.
+    pub async fn send_user_operation(
+        &self,
+        op: UserOperation,
+        entry_point: Address,
+        chain: ChainId,
+        tenant_id: &str,
+    ) -> anyhow::Result<H256> {
+        if entry_point != self.entry_point {
+            return Err(UserOpRejected::EntryPoint(entry_point).into());
+        }
+        self.validate(&op, chain).await?;
+
+        let hash = op.hash(self.entry_point, chain.0);
+        with_pool!(&self.pool, |pool: DB| {
+            // Sending the same operation again changes nothing
+            let sql = DB::sql(&format!(
+                r#"
+				INSERT INTO user_operations (hash, chain, entry_point, sender, op, tenant_id)
+				VALUES (?, ?, ?, ?, {}, ?)
+				{} hash = user_operations.hash
+				"#,
+                DB::JSON,
+                DB::on_conflict("hash")
+            ));
+            query(&sql)
+                .bind(format!("{:?}", hash))
+                .bind(chain.0 as i64)
+                .bind(format!("{:?}", self.entry_point))
+                .bind(format!("{:?}", op.sender))
+                .bind(serde_json::to_string(&op)?)
+                .bind(tenant_id)
+                .execute(pool)
+                .await?;
+        });
+        info!("Accepted user operation {:?} on chain {}", hash, chain);
+
+        Ok(hash)
+    }
+
+    /// Checks the operation pays at least the current fees, then runs `simulateValidation`
+    /// against the latest block
+    pub async fn validate(&self, op: &UserOperation, chain: ChainId) -> anyhow::Result<()> {
+        let provider = self.monitor.provider(chain)?;
+        // The bundle is escalated like any request, but the EntryPoint only refunds the op's fees
+        let (max_fee_per_gas, max_priority_fee_per_gas) =
+            provider.estimate_eip1559_fees(None).await?;
+        if op.max_fee_per_gas < max_fee_per_gas
+            || op.max_priority_fee_per_gas < max_priority_fee_per_gas
+        {
+            return Err(UserOpRejected::FeeTooLow {
+                max_fee_per_gas,
+                max_priority_fee_per_gas,
+            }
+            .into());
+        }
+
+        let simulation = Eip1559TransactionRequest::new()
+            .to(self.entry_point)
+            .data(op.simulate_validation_calldata());
+        match provider.call(&simulation.into(), None).await {
+            Ok(_) => Err(anyhow::anyhow!(
+                "simulateValidation didn't revert, {:?} is not an EntryPoint",
+                self.entry_point
+            )),
+            Err(err) => match err.as_error_response().and_then(|err| err.as_revert_data()) {
+                Some(data) => Ok(check_validation_result(&data, unix_now())?),
+                None => Err(anyhow::anyhow!(err)),
+            },
+        }
+    }
+
+    /// Bundles the oldest pending operations into one `handleOps` request, at most one per
+    /// sender. Operations that no longer validate are dropped. Returns the request's id.
+    pub async fn bundle(&self, chain: ChainId) -> anyhow::Result<Option<Uuid>> {
+        let _bundling = self.bundling.lock().await;
+        self.settle_bundles(chain).await?;
+
+        let pending = with_pool!(&self.pool, |pool: DB| {
+            let sql = DB::sql(&format!(
+                r#"
+				SELECT hash, {} as op
+				FROM user_operations
+				WHERE chain = ? and entry_point = ? and status = 'pending'
+				ORDER BY seq
+				LIMIT ?
+				"#,
+                DB::json_column("op")
+            ));
+            query(&sql)
+                .bind(chain.0 as i64)
+                .bind(format!("{:?}", self.entry_point))
+                .bind(MAX_BUNDLE_SIZE as i64)
+                .try_map(|row| {
+                    Ok(PendingOperation {
+                        hash: text::<DB>(&row, "hash")?,
+                        op: operation::<DB>(&row)?,
+                    })
+                })
+                .fetch_all(pool)
+                .await?
+        });
+
+        // Later operations from the same sender depend on the earlier ones' state
+        let mut senders = HashSet::new();
+        let mut hashes = Vec::new();
+        let mut ops = Vec::new();
+        for PendingOperation { hash, op } in pending {
+            if !senders.insert(op.sender) {
+                continue;
+            }
+            match self.validate(&op, chain).await {
+                Ok(()) => {
+                    hashes.push(hash);
+                    ops.push(op);
+                }
+                Err(err) if err.is::<UserOpRejected>() => {
+                    warn!("Dropping user operation {}, {}", hash, err);
+                    self.drop_operations(&[hash]).await?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        if ops.is_empty() {
+            return Ok(None);
+        }
+
+        let beneficiary = self.monitor.provider(chain)?.signer().address();
+        let tx = Eip1559TransactionRequest::new()
+            .to(self.entry_point)
+            .data(handle_ops_calldata(&ops, beneficiary));
+        // Retrying after a crash between sending and marking returns the same request
+        let options = SendOptions {
+            idempotency_key: Some(format!(
+                "bundle:0x{}",
+                hex::encode(keccak256(hashes.concat()))
+            )),
+            ..SendOptions::default()
+        };
+        let id = match self
+            .monitor
+            .send_monitored_transaction(tx, chain, options)
+            .await
+        {
+            Ok(id) => id,
+            Err(err) if err.is::<SimulationError>() => {
+                warn!("Dropping bundle of {} user operations, {}", ops.len(), err);
+                self.drop_operations(&hashes).await?;
+                return Err(err);
+            }
+            Err(err) => return Err(err),
+        };
+
+        self.set_status(&hashes, "bundled", Some(id)).await?;
+        info!(
+            "Bundled {} user operations into request {:?} on chain {}",
+            hashes.len(),
+            id,
+            chain
+        );
+
+        Ok(Some(id))
+    }
+
+    /// Marks the operations of mined bundles as mined, and drops the ones whose bundle failed
+    /// or was cancelled, they'd otherwise stay bundled without ever getting a receipt
+    async fn settle_bundles(&self, chain: ChainId) -> anyhow::Result<()> {
+        let request_ids = with_pool!(&self.pool, |pool: DB| {
+            let sql = DB::sql(
+                r#"
+				SELECT DISTINCT request_id
+				FROM user_operations
+				WHERE chain = ? and entry_point = ? and status = 'bundled'
+				"#,
+            );
+            query(&sql)
+                .bind(chain.0 as i64)
+                .bind(format!("{:?}", self.entry_point))
+                .try_map(|row| DB::get_text(&row, "request_id"))
+                .fetch_all(pool)
+                .await?
+        });
+
+        for request_id in request_ids.into_iter().flatten() {
+            let request = self.monitor.tx_repo.get(request_id.parse()?).await?;
+            let status = match request.map(|request| request.status) {
+                Some(RequestStatus::Mined) => "mined",
+                Some(RequestStatus::Failed | RequestStatus::Cancelled | RequestStatus::Invalid)
+                | None => {
+                    warn!(
+                        "Dropping the user operations of bundle {}, it was never mined",
+                        request_id
+                    );
+                    "dropped"
+                }
+                Some(_) => continue,
+            };
+            with_pool!(&self.pool, |pool: DB| {
+                let sql = DB::sql(
+                    "UPDATE user_operations SET status = ? WHERE request_id = ? and status = 'bundled'",
+                );
+                query(&sql)
+                    .bind(status)
+                    .bind(&request_id)
+                    .execute(pool)
+                    .await?;
+            });
+        }
+        Ok(())
+    }
+
+    async fn drop_operations(&self, hashes: &[String]) -> anyhow::Result<()> {
+        self.set_status(hashes, "dropped", None).await
+    }
+
+    async fn set_status(
+        &self,
+        hashes: &[String],
+        status: &str,
+        request_id: Option<Uuid>,
+    ) -> anyhow::Result<()> {
+        let request_id = request_id.map(|id| id.to_string());
+        with_pool!(&self.pool, |pool: DB| {
+            let sql = DB::sql(
+                "UPDATE user_operations SET status = ?, request_id = COALESCE(?, request_id) WHERE hash = ?",
+            );
+            let mut db_tx = pool.begin().await?;
+            for hash in hashes {
+                query(&sql)
+                    .bind(status)
+                    .bind(request_id.clone())
+                    .bind(hash)
+                    .execute(&mut db_tx)
+                    .await?;
+            }
+            db_tx.commit().await?;
+        });
+        Ok(())
+    }
+
+    /// Bundles pending operations for each chain every `interval`
+    pub async fn run(self: Arc<Self>, interval: Duration) {
+        loop {
+            sleep(interval).await;
+            for chain in self.monitor.chains() {
+                if let Err(err) = self.bundle(chain).await {
+                    error!("Bundling failed on chain {}, {}", chain, err);
+                }
+            }
+        }
+    }
+
+    /// None until the bundle containing the operation is mined, or if the tenant didn't send it
+    pub async fn get_user_operation_receipt(
+        &self,
+        hash: H256,
+        tenant_id: &str,
+    ) -> anyhow::Result<Option<UserOperationReceipt>> {
+        let bundled = with_pool!(&self.pool, |pool: DB| {
+            let sql = DB::sql(&format!(
+                r#"
+				SELECT entry_point, request_id, {} as op
+				FROM user_operations
+				WHERE hash = ? and tenant_id = ? and status in ('bundled', 'mined')
+				"#,
+                DB::json_column("op")
+            ));
+            query(&sql)
+                .bind(format!("{:?}", hash))
+                .bind(tenant_id)
+                .try_map(|row| {
+                    Ok(BundledOperation {
+                        entry_point: text::<DB>(&row, "entry_point")?,
+                        request_id: DB::get_text(&row, "request_id")?,
+                        op: operation::<DB>(&row)?,
+                    })
+                })
+                .fetch_optional(pool)
+                .await?
+        });
+        let Some(bundled) = bundled else {
+            return Ok(None);
+        };
+        let Some(request_id) = bundled.request_id else {
+            return Ok(None);
+        };
+
+        let Some(request) = self.monitor.tx_repo.get(request_id.parse()?).await? else {
+            return Ok(None);
+        };
+        let (RequestStatus::Mined | RequestStatus::Failed, Some(tx_hash)) =
+            (request.status, request.hash)
+        else {
+            return Ok(None);
+        };
+        let provider = self.monitor.provider(request.chain)?;
+        let Some(receipt) = provider.get_transaction_receipt(tx_hash).await? else {
+            return Ok(None);
+        };
+
+        let entry_point: Address = bundled.entry_point.parse()?;
+        Ok(
+            find_operation_event(&receipt, entry_point, hash).map(|event| UserOperationReceipt {
+                user_op_hash: hash,
+                entry_point,
+                sender: bundled.op.sender,
+                nonce: bundled.op.nonce,
+                paymaster: event.paymaster,
+                actual_gas_cost: event.actual_gas_cost,
+                actual_gas_used: event.actual_gas_used,
+                success: event.success,
+                logs: event.logs,
+                receipt: receipt.clone(),
+            }),
+        )
+    }
+}
+
+struct OperationEvent {
+    paymaster: Address,
+    success: bool,
+    actual_gas_cost: U256,
+    actual_gas_used: U256,
+    logs: Vec<Log>,
+}
+
+/// The operation's `UserOperationEvent`, and the logs emitted since the
+/// previous operation's event (or `BeforeExecution` for the first one)
+fn find_operation_event(
+    receipt: &TransactionReceipt,
+    entry_point: Address,
+    hash: H256,
+) -> Option<OperationEvent> {
+    let event_topic = H256::from(keccak256(USER_OPERATION_EVENT));
+    let before_topic = H256::from(keccak256(BEFORE_EXECUTION));
+    let mut start = 0;
+    for (index, log) in receipt.logs.iter().enumerate() {
+        let topic = log.topics.first().copied();
+        if log.address != entry_point {
+            continue;
+        }
+        if topic == Some(before_topic) {
+            start = index + 1;
+            continue;
+        }
+        if topic != Some(event_topic) {
+            continue;
+        }
+        if log.topics.get(1) != Some(&hash) {
+            start = index + 1;
+            continue;
+        }
+
+        let paymaster = log.topics.get(3).map(|topic| Address::from(*topic))?;
+        let data = decode(
+            &[
+                ParamType::Uint(256),
+                ParamType::Bool,
+                ParamType::Uint(256),
+                ParamType::Uint(256),
+            ],
+            &log.data,
+        )
+        .ok()?;
+        let [_, Token::Bool(success), Token::Uint(actual_gas_cost), Token::Uint(actual_gas_used)] =
+            &data[..]
+        else {
+            return None;
+        };
+        return Some(OperationEvent {
+            paymaster,
+            success: *success,
+            actual_gas_cost: *actual_gas_cost,
+            actual_gas_used: *actual_gas_used,
+            logs: receipt.logs[start..index].to_vec(),
+        });
+    }
+
+    None
+}
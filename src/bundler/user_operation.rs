@@ -0,0 +1,199 @@
+use ethers::{
+    abi::{decode, encode, ParamType, Token},
+    types::{Address, Bytes, H256, U256},
+    utils::{id, keccak256},
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::transaction_monitor::RevertReason;
+
+// EntryPoint v0.6
+const USER_OPERATION: &str =
+    "(address,uint256,bytes,bytes,uint256,uint256,uint256,uint256,uint256,bytes,bytes)";
+const VALIDATION_RESULT: &str = "ValidationResult((uint256,uint256,bool,uint48,uint48,bytes),(uint256,uint256),(uint256,uint256),(uint256,uint256))";
+const VALIDATION_RESULT_WITH_AGGREGATION: &str = "ValidationResultWithAggregation((uint256,uint256,bool,uint48,uint48,bytes),(uint256,uint256),(uint256,uint256),(uint256,uint256),(address,(uint256,uint256)))";
+const FAILED_OP: &str = "FailedOp(uint256,string)";
+pub const USER_OPERATION_EVENT: &str =
+    "UserOperationEvent(bytes32,address,address,uint256,bool,uint256,uint256)";
+pub const BEFORE_EXECUTION: &str = "BeforeExecution()";
+// Don't bundle operations that could expire before they're mined
+const MIN_VALIDITY_SECONDS: u64 = 30;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserOperation {
+    pub sender: Address,
+    pub nonce: U256,
+    pub init_code: Bytes,
+    pub call_data: Bytes,
+    pub call_gas_limit: U256,
+    pub verification_gas_limit: U256,
+    pub pre_verification_gas: U256,
+    pub max_fee_per_gas: U256,
+    pub max_priority_fee_per_gas: U256,
+    pub paymaster_and_data: Bytes,
+    pub signature: Bytes,
+}
+
+/// Why `simulateValidation` turned an operation away, with its ERC-4337 rpc error code
+#[derive(Debug, Error)]
+pub enum UserOpRejected {
+    #[error("entry point {0:?} is not supported")]
+    EntryPoint(Address),
+
+    #[error("operation failed validation, {0}")]
+    FailedOp(String),
+
+    #[error("operation's signature is invalid")]
+    Signature,
+
+    #[error("operation is only valid from {valid_after} until {valid_until}")]
+    OutOfTimeRange { valid_after: u64, valid_until: u64 },
+
+    #[error("aggregated signatures are not supported")]
+    Aggregator,
+
+    #[error("operation's fees are below the current {max_fee_per_gas} max fee and {max_priority_fee_per_gas} priority fee")]
+    FeeTooLow {
+        max_fee_per_gas: U256,
+        max_priority_fee_per_gas: U256,
+    },
+
+    #[error("simulateValidation {0}")]
+    Reverted(RevertReason),
+}
+
+impl UserOpRejected {
+    pub fn code(&self) -> i64 {
+        match self {
+            UserOpRejected::EntryPoint(_) | UserOpRejected::FeeTooLow { .. } => -32602,
+            UserOpRejected::FailedOp(_) | UserOpRejected::Reverted(_) => -32500,
+            UserOpRejected::OutOfTimeRange { .. } => -32503,
+            UserOpRejected::Aggregator => -32506,
+            UserOpRejected::Signature => -32507,
+        }
+    }
+}
+
+impl UserOperation {
+    fn token(&self) -> Token {
+        Token::Tuple(vec![
+            Token::Address(self.sender),
+            Token::Uint(self.nonce),
+            Token::Bytes(self.init_code.to_vec()),
+            Token::Bytes(self.call_data.to_vec()),
+            Token::Uint(self.call_gas_limit),
+            Token::Uint(self.verification_gas_limit),
+            Token::Uint(self.pre_verification_gas),
+            Token::Uint(self.max_fee_per_gas),
+            Token::Uint(self.max_priority_fee_per_gas),
+            Token::Bytes(self.paymaster_and_data.to_vec()),
+            Token::Bytes(self.signature.to_vec()),
+        ])
+    }
+
+    /// Same as `EntryPoint.getUserOpHash`, everything but the signature bound to one deployment
+    pub fn hash(&self, entry_point: Address, chain_id: u64) -> H256 {
+        let packed = encode(&[
+            Token::Address(self.sender),
+            Token::Uint(self.nonce),
+            Token::FixedBytes(keccak256(&self.init_code).to_vec()),
+            Token::FixedBytes(keccak256(&self.call_data).to_vec()),
+            Token::Uint(self.call_gas_limit),
+            Token::Uint(self.verification_gas_limit),
+            Token::Uint(self.pre_verification_gas),
+            Token::Uint(self.max_fee_per_gas),
+            Token::Uint(self.max_priority_fee_per_gas),
+            Token::FixedBytes(keccak256(&self.paymaster_and_data).to_vec()),
+        ]);
+        keccak256(encode(&[
+            Token::FixedBytes(keccak256(packed).to_vec()),
+            Token::Address(entry_point),
+            Token::Uint(chain_id.into()),
+        ]))
+        .into()
+    }
+
+    /// Calldata for `EntryPoint.simulateValidation(op)`
+    pub fn simulate_validation_calldata(&self) -> Bytes {
+        let selector = id(format!("simulateValidation({})", USER_OPERATION));
+        [&selector[..], &encode(&[self.token()])].concat().into()
+    }
+}
+
+/// Calldata for `EntryPoint.handleOps(ops, beneficiary)`
+pub fn handle_ops_calldata(ops: &[UserOperation], beneficiary: Address) -> Bytes {
+    let selector = id(format!("handleOps({}[],address)", USER_OPERATION));
+    let ops = Token::Array(ops.iter().map(UserOperation::token).collect());
+    [&selector[..], &encode(&[ops, Token::Address(beneficiary)])]
+        .concat()
+        .into()
+}
+
+/// `simulateValidation` always reverts, with `ValidationResult` when the operation is valid.
+/// `now` is in unix seconds.
+pub fn check_validation_result(data: &[u8], now: u64) -> Result<(), UserOpRejected> {
+    if data.len() < 4 {
+        return Err(UserOpRejected::Reverted(RevertReason::decode(data)));
+    }
+
+    let (selector, args) = data.split_at(4);
+    if selector == id(FAILED_OP) {
+        let reason = decode(&[ParamType::Uint(256), ParamType::String], args)
+            .ok()
+            .and_then(|mut tokens| tokens.pop())
+            .and_then(Token::into_string)
+            .unwrap_or_default();
+        return Err(UserOpRejected::FailedOp(reason));
+    }
+    if selector == id(VALIDATION_RESULT_WITH_AGGREGATION) {
+        return Err(UserOpRejected::Aggregator);
+    }
+    if selector != id(VALIDATION_RESULT) {
+        return Err(UserOpRejected::Reverted(RevertReason::decode(data)));
+    }
+
+    let return_info = ParamType::Tuple(vec![
+        ParamType::Uint(256),
+        ParamType::Uint(256),
+        ParamType::Bool,
+        ParamType::Uint(48),
+        ParamType::Uint(48),
+        ParamType::Bytes,
+    ]);
+    let stake_info = ParamType::Tuple(vec![ParamType::Uint(256), ParamType::Uint(256)]);
+    let tokens = decode(
+        &[
+            return_info,
+            stake_info.clone(),
+            stake_info.clone(),
+            stake_info,
+        ],
+        args,
+    )
+    .map_err(|_| UserOpRejected::Reverted(RevertReason::decode(data)))?;
+    let Some(Token::Tuple(return_info)) = tokens.into_iter().next() else {
+        return Err(UserOpRejected::Reverted(RevertReason::decode(data)));
+    };
+    let (sig_failed, valid_after, valid_until) = match &return_info[..] {
+        [_, _, Token::Bool(sig_failed), Token::Uint(valid_after), Token::Uint(valid_until), _] => {
+            (*sig_failed, valid_after.as_u64(), valid_until.as_u64())
+        }
+        _ => return Err(UserOpRejected::Reverted(RevertReason::decode(data))),
+    };
+
+    if sig_failed {
+        return Err(UserOpRejected::Signature);
+    }
+    // 0 means no expiry
+    let expires_soon = valid_until != 0 && valid_until < now + MIN_VALIDITY_SECONDS;
+    if valid_after > now || expires_soon {
+        return Err(UserOpRejected::OutOfTimeRange {
+            valid_after,
+            valid_until,
+        });
+    }
+
+    Ok(())
+}
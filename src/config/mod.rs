@@ -0,0 +1,691 @@
+//! Server settings, read from an optional TOML or YAML file with env vars layered on top
+
+use crate::{
+    alchemy_rpc::{self, get_ws},
+    auth::{AuthMode, SecretKey},
+    bundler::DEFAULT_ENTRY_POINT,
+    transaction_monitor::{BalancePolicy, TopUp},
+    transaction_repository::ChainId,
+};
+use ethers::{
+    signers::LocalWallet,
+    types::{Address, Chain, U256},
+};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    env, fmt, fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use thiserror::Error;
+
+const DEFAULT_CHAINS: [Chain; 2] = [Chain::Goerli, Chain::Sepolia];
+const DEFAULT_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+const DEFAULT_PORT: u16 = 3000;
+const DEFAULT_POOL_SIZE: u32 = 5;
+const DEFAULT_BLOCK_FREQUENCY: u8 = 3;
+const DEFAULT_GAS_LIMIT_MULTIPLIER: f64 = 1.2;
+
+/// Everything that's wrong with the config, so it can all be fixed in one go
+#[derive(Debug, Error)]
+pub struct ConfigError {
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid config")?;
+        for problem in &self.problems {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub auth_mode: AuthMode,
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    /// The relayer, the same key on every chain
+    pub signer: LocalWallet,
+    pub balance_policy: BalancePolicy,
+    pub chains: BTreeMap<ChainId, ChainConfig>,
+    pub policy_file: Option<PathBuf>,
+    pub fee_config_file: Option<PathBuf>,
+    /// Transactions per minute each chain accepts, across all api keys
+    pub chain_rate_limit_per_minute: Option<u32>,
+    /// Queued and submitted requests a chain can have before new ones are turned away
+    pub max_in_flight_per_chain: Option<u64>,
+    /// EntryPoint the bundler accepts user operations for
+    pub entry_point: Address,
+    /// Export expired requests as JSONL here instead of moving them to the archive table
+    pub retention_export_dir: Option<PathBuf>,
+    /// Unlocks the `/admin` routes, they turn everyone away without it
+    pub admin_token: Option<String>,
+    /// The shared key from before api keys, it still works as an unrestricted key for the
+    /// `default` tenant until every client has its own
+    pub expected_auth_header: Option<String>,
+    /// Decrypts the api keys' HMAC secrets, required in `hmac` auth mode
+    pub hmac_secret_key: Option<SecretKey>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerConfig {
+    pub address: SocketAddr,
+    pub tls: Option<TlsConfig>,
+}
+
+/// PEM encoded certificate chain and private key
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DatabaseConfig {
+    /// MySQL, Postgres or SQLite depending on the scheme
+    pub url: String,
+    pub pool_size: u32,
+    /// Apply the database's migrations before starting
+    pub run_migrations: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChainConfig {
+    /// Websocket endpoint, Alchemy's unless one is configured
+    pub rpc_url: String,
+    /// Blocks a transaction waits before it's escalated
+    pub block_frequency: u8,
+    pub gas_limit_multiplier: f64,
+    /// Trusted ERC-2771 forwarder, only chains with one accept forwarded requests
+    pub forwarder: Option<Address>,
+    /// Days mined, failed and cancelled requests are kept, forever if it isn't set
+    pub retention_days: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawConfig {
+    auth_mode: Option<String>,
+    alchemy_key: Option<String>,
+    /// Used by chains that don't set their own
+    block_frequency: Option<u8>,
+    gas_limit_multiplier: Option<f64>,
+    policy_file: Option<PathBuf>,
+    fee_config_file: Option<PathBuf>,
+    chain_rate_limit_per_minute: Option<u32>,
+    max_in_flight_per_chain: Option<u64>,
+    entry_point: Option<String>,
+    admin_token: Option<String>,
+    expected_auth_header: Option<String>,
+    hmac_secret_key: Option<String>,
+    retention_export_dir: Option<PathBuf>,
+    server: RawServer,
+    database: RawDatabase,
+    signer: RawSigner,
+    chains: Option<BTreeMap<String, RawChain>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawServer {
+    address: Option<String>,
+    port: Option<u16>,
+    tls: RawTls,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawTls {
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawDatabase {
+    url: Option<String>,
+    pool_size: Option<u32>,
+    run_migrations: Option<bool>,
+}
+
+// Wei amounts are decimal strings, like in the policy file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawSigner {
+    private_key: Option<String>,
+    private_key_file: Option<PathBuf>,
+    low_balance_threshold: Option<String>,
+    treasury_private_key: Option<String>,
+    top_up_target: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawChain {
+    rpc_url: Option<String>,
+    block_frequency: Option<u8>,
+    gas_limit_multiplier: Option<f64>,
+    forwarder: Option<String>,
+    retention_days: Option<u64>,
+}
+
+impl Config {
+    /// Reads the file `CONFIG_FILE` points at, if any, and the process' env vars
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let file = env::var_os("CONFIG_FILE").map(PathBuf::from);
+        Self::load(file.as_deref(), |name| env::var(name).ok())
+    }
+
+    /// `.toml`, `.yaml` or `.yml` file settings, overridden by the env vars `env` returns
+    pub fn load(
+        file: Option<&Path>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, ConfigError> {
+        let mut problems = Vec::new();
+        let mut raw = match file {
+            Some(path) => read_file(path).map_err(|problem| ConfigError {
+                problems: vec![problem],
+            })?,
+            None => RawConfig::default(),
+        };
+
+        let env = |name: &str| env(name).filter(|value| !value.is_empty());
+        apply_env(&mut raw, &env, &mut problems);
+        let config = validate(raw, &mut problems);
+        match config {
+            Some(config) if problems.is_empty() => Ok(config),
+            _ => Err(ConfigError { problems }),
+        }
+    }
+}
+
+fn read_file(path: &Path) -> Result<RawConfig, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| format!("can't read config file {}, {}", path.display(), err))?;
+    let parsed = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("toml") => toml::from_str(&contents).map_err(|err| err.to_string()),
+        Some("yaml" | "yml") => serde_yaml::from_str(&contents).map_err(|err| err.to_string()),
+        _ => {
+            return Err(format!(
+                "config file {} should end in .toml, .yaml or .yml",
+                path.display()
+            ))
+        }
+    };
+    parsed.map_err(|err| format!("config file {} is invalid, {}", path.display(), err))
+}
+
+/// Env vars win over the file, they keep the names the relay has always used
+fn apply_env(
+    raw: &mut RawConfig,
+    env: &dyn Fn(&str) -> Option<String>,
+    problems: &mut Vec<String>,
+) {
+    macro_rules! set {
+        ($name:literal, $field:expr) => {
+            if let Some(value) = env($name) {
+                match value.trim().parse() {
+                    Ok(value) => $field = Some(value),
+                    Err(err) => problems.push(format!("{} env var is invalid, {}", $name, err)),
+                }
+            }
+        };
+    }
+
+    set!("AUTH_MODE", raw.auth_mode);
+    set!("ALCHEMY_KEY", raw.alchemy_key);
+    set!("BLOCK_FREQUENCY", raw.block_frequency);
+    set!("GAS_LIMIT_MULTIPLIER", raw.gas_limit_multiplier);
+    set!("POLICY_FILE", raw.policy_file);
+    set!("FEE_CONFIG", raw.fee_config_file);
+    set!(
+        "CHAIN_RATE_LIMIT_PER_MINUTE",
+        raw.chain_rate_limit_per_minute
+    );
+    set!("MAX_IN_FLIGHT_PER_CHAIN", raw.max_in_flight_per_chain);
+    set!("ENTRY_POINT", raw.entry_point);
+    set!("ADMIN_TOKEN", raw.admin_token);
+    set!("EXPECTED_AUTH_HEADER", raw.expected_auth_header);
+    set!("HMAC_SECRET_KEY", raw.hmac_secret_key);
+    set!("RETENTION_EXPORT_DIR", raw.retention_export_dir);
+    set!("LISTEN_ADDRESS", raw.server.address);
+    set!("PORT", raw.server.port);
+    set!("TLS_CERT", raw.server.tls.cert);
+    set!("TLS_KEY", raw.server.tls.key);
+    set!("DATABASE_URL", raw.database.url);
+    set!("DB_POOL_SIZE", raw.database.pool_size);
+    set!("RUN_MIGRATIONS", raw.database.run_migrations);
+    set!("PK", raw.signer.private_key);
+    set!("PK_FILE", raw.signer.private_key_file);
+    set!("LOW_BALANCE_THRESHOLD", raw.signer.low_balance_threshold);
+    set!("TREASURY_PK", raw.signer.treasury_private_key);
+    set!("TOP_UP_TARGET", raw.signer.top_up_target);
+
+    let chains = raw.chains.get_or_insert_with(|| {
+        DEFAULT_CHAINS
+            .iter()
+            .map(|chain| (chain.to_string(), RawChain::default()))
+            .collect()
+    });
+    if let Some(names) = env("CHAINS") {
+        *chains = names
+            .split(',')
+            .map(|name| {
+                let name = name.trim().to_owned();
+                let section = chains.remove(&name).unwrap_or_default();
+                (name, section)
+            })
+            .collect();
+    }
+
+    set_per_chain("FORWARDERS", env, chains, problems, |chain, value| {
+        chain.forwarder = Some(value.to_owned());
+        Ok(())
+    });
+    set_per_chain("RETENTION_DAYS", env, chains, problems, |chain, value| {
+        chain.retention_days = Some(value.parse().map_err(|err| format!("{}", err))?);
+        Ok(())
+    });
+}
+
+/// Comma separated `chain:value` pairs, like `goerli:30,sepolia:7`
+fn set_per_chain(
+    var: &str,
+    env: &dyn Fn(&str) -> Option<String>,
+    chains: &mut BTreeMap<String, RawChain>,
+    problems: &mut Vec<String>,
+    set: impl Fn(&mut RawChain, &str) -> Result<(), String>,
+) {
+    let Some(pairs) = env(var) else {
+        return;
+    };
+    for pair in pairs.split(',') {
+        let Some((name, value)) = pair.split_once(':') else {
+            problems.push(format!(
+                "{} env var is invalid, expected chain:value, got {:?}",
+                var, pair
+            ));
+            continue;
+        };
+        let Some(chain) = chains.get_mut(name.trim()) else {
+            problems.push(format!(
+                "{} env var sets chain {}, which isn't configured",
+                var,
+                name.trim()
+            ));
+            continue;
+        };
+        if let Err(err) = set(chain, value.trim()) {
+            problems.push(format!("{} env var is invalid, {}", var, err));
+        }
+    }
+}
+
+fn validate(raw: RawConfig, problems: &mut Vec<String>) -> Option<Config> {
+    let auth_mode = match raw.auth_mode.as_deref().map(AuthMode::from_str) {
+        None => Some(AuthMode::ApiKey),
+        Some(Ok(mode)) => Some(mode),
+        Some(Err(err)) => {
+            problems.push(format!(
+                "auth_mode (AUTH_MODE) is invalid, {}, expected api_key, hmac or eip712",
+                err
+            ));
+            None
+        }
+    };
+    // The key's error could echo parts of it, like the signer's
+    let hmac_secret_key = match raw.hmac_secret_key.as_deref().map(SecretKey::from_str) {
+        None if auth_mode == Some(AuthMode::Hmac) => {
+            problems.push(
+                "hmac_secret_key (HMAC_SECRET_KEY) is missing, the hmac auth mode needs it"
+                    .to_owned(),
+            );
+            None
+        }
+        None => Some(None),
+        Some(Ok(key)) => Some(Some(key)),
+        Some(Err(_)) => {
+            problems
+                .push("hmac_secret_key (HMAC_SECRET_KEY) isn't 32 hex encoded bytes".to_owned());
+            None
+        }
+    };
+    let entry_point = raw
+        .entry_point
+        .as_deref()
+        .unwrap_or(DEFAULT_ENTRY_POINT)
+        .parse()
+        .map_err(|err| problems.push(format!("entry_point (ENTRY_POINT) is invalid, {}", err)))
+        .ok();
+    for (name, path) in [
+        ("policy_file (POLICY_FILE)", &raw.policy_file),
+        ("fee_config_file (FEE_CONFIG)", &raw.fee_config_file),
+    ] {
+        if let Some(path) = path.as_ref().filter(|path| !path.is_file()) {
+            problems.push(format!("{} {} doesn't exist", name, path.display()));
+        }
+    }
+    if let Some(dir) = raw
+        .retention_export_dir
+        .as_ref()
+        .filter(|dir| !dir.is_dir())
+    {
+        problems.push(format!(
+            "retention_export_dir (RETENTION_EXPORT_DIR) {} isn't a directory",
+            dir.display()
+        ));
+    }
+
+    let server = validate_server(raw.server, problems);
+    let database = validate_database(raw.database, problems);
+    let signer = validate_signer(&raw.signer, problems);
+    let balance_policy = validate_balance_policy(&raw.signer, problems);
+    let chains = validate_chains(
+        raw.chains.unwrap_or_default(),
+        raw.alchemy_key.as_deref(),
+        raw.block_frequency,
+        raw.gas_limit_multiplier,
+        problems,
+    );
+
+    let (
+        Some(auth_mode),
+        Some(hmac_secret_key),
+        Some(entry_point),
+        Some(server),
+        Some(database),
+        Some(signer),
+        Some(balance_policy),
+        Some(chains),
+    ) = (
+        auth_mode,
+        hmac_secret_key,
+        entry_point,
+        server,
+        database,
+        signer,
+        balance_policy,
+        chains,
+    )
+    else {
+        return None;
+    };
+
+    Some(Config {
+        auth_mode,
+        server,
+        database,
+        signer,
+        balance_policy,
+        chains,
+        policy_file: raw.policy_file,
+        fee_config_file: raw.fee_config_file,
+        chain_rate_limit_per_minute: raw.chain_rate_limit_per_minute,
+        max_in_flight_per_chain: raw.max_in_flight_per_chain,
+        entry_point,
+        retention_export_dir: raw.retention_export_dir,
+        admin_token: raw.admin_token,
+        expected_auth_header: raw.expected_auth_header,
+        hmac_secret_key,
+    })
+}
+
+fn validate_server(raw: RawServer, problems: &mut Vec<String>) -> Option<ServerConfig> {
+    let ip = match raw.address.as_deref().map(IpAddr::from_str) {
+        None => Some(DEFAULT_ADDRESS),
+        Some(Ok(ip)) => Some(ip),
+        Some(Err(err)) => {
+            problems.push(format!(
+                "server.address (LISTEN_ADDRESS) is invalid, {}, expected an ip like 0.0.0.0",
+                err
+            ));
+            None
+        }
+    };
+
+    let tls = match (raw.tls.cert, raw.tls.key) {
+        (None, None) => Some(None),
+        (Some(cert), Some(key)) => {
+            let mut valid = true;
+            for (name, path) in [
+                ("server.tls.cert (TLS_CERT)", &cert),
+                ("server.tls.key (TLS_KEY)", &key),
+            ] {
+                if !path.is_file() {
+                    problems.push(format!("{} {} doesn't exist", name, path.display()));
+                    valid = false;
+                }
+            }
+            valid.then_some(Some(TlsConfig { cert, key }))
+        }
+        _ => {
+            problems.push(
+                "server.tls needs both cert (TLS_CERT) and key (TLS_KEY) to serve https".to_owned(),
+            );
+            None
+        }
+    };
+
+    Some(ServerConfig {
+        address: SocketAddr::new(ip?, raw.port.unwrap_or(DEFAULT_PORT)),
+        tls: tls?,
+    })
+}
+
+fn validate_database(raw: RawDatabase, problems: &mut Vec<String>) -> Option<DatabaseConfig> {
+    let pool_size = raw.pool_size.unwrap_or(DEFAULT_POOL_SIZE);
+    if pool_size == 0 {
+        problems.push("database.pool_size (DB_POOL_SIZE) has to be at least 1".to_owned());
+    }
+
+    let Some(url) = raw.url else {
+        problems.push("database.url (DATABASE_URL) is missing".to_owned());
+        return None;
+    };
+    let scheme = url.split_once(':').map(|(scheme, _)| scheme);
+    if !matches!(scheme, Some("mysql" | "postgres" | "postgresql" | "sqlite")) {
+        problems.push(
+            "database.url (DATABASE_URL) should be a mysql://, postgres:// or sqlite:// url"
+                .to_owned(),
+        );
+    }
+    Some(DatabaseConfig {
+        url,
+        pool_size,
+        run_migrations: raw.run_migrations.unwrap_or(false),
+    })
+}
+
+fn validate_signer(raw: &RawSigner, problems: &mut Vec<String>) -> Option<LocalWallet> {
+    let key = match (&raw.private_key, &raw.private_key_file) {
+        (Some(key), None) => key.trim().to_owned(),
+        (None, Some(path)) => match fs::read_to_string(path) {
+            Ok(key) => key.trim().to_owned(),
+            Err(err) => {
+                problems.push(format!(
+                    "signer.private_key_file (PK_FILE) {} can't be read, {}",
+                    path.display(),
+                    err
+                ));
+                return None;
+            }
+        },
+        (None, None) => {
+            problems.push(
+                "signer.private_key (PK) or signer.private_key_file (PK_FILE) is missing"
+                    .to_owned(),
+            );
+            return None;
+        }
+        (Some(_), Some(_)) => {
+            problems.push(
+                "signer has both private_key (PK) and private_key_file (PK_FILE), set only one"
+                    .to_owned(),
+            );
+            return None;
+        }
+    };
+
+    // The key's error could echo parts of it, so it's left out
+    LocalWallet::from_str(&key)
+        .map_err(|_| problems.push("signer's private key isn't a valid hex key".to_owned()))
+        .ok()
+}
+
+fn validate_balance_policy(raw: &RawSigner, problems: &mut Vec<String>) -> Option<BalancePolicy> {
+    let mut wei =
+        |name: &str, value: &Option<String>| match value.as_deref().map(U256::from_dec_str) {
+            None => Ok(None),
+            Some(Ok(value)) => Ok(Some(value)),
+            Some(Err(err)) => {
+                problems.push(format!("{} should be decimal wei, {}", name, err));
+                Err(())
+            }
+        };
+    let low_balance = wei(
+        "signer.low_balance_threshold (LOW_BALANCE_THRESHOLD)",
+        &raw.low_balance_threshold,
+    );
+    let target = wei("signer.top_up_target (TOP_UP_TARGET)", &raw.top_up_target);
+    let (Ok(low_balance), Ok(target)) = (low_balance, target) else {
+        return None;
+    };
+
+    let Some(treasury) = &raw.treasury_private_key else {
+        return Some(BalancePolicy {
+            low_balance,
+            top_up: None,
+        });
+    };
+    let treasury = LocalWallet::from_str(treasury.trim()).map_err(|_| {
+        problems.push("signer.treasury_private_key (TREASURY_PK) isn't a valid hex key".to_owned())
+    });
+    let (Some(threshold), Some(target)) = (low_balance, target) else {
+        problems.push(
+            "signer.treasury_private_key (TREASURY_PK) needs both low_balance_threshold (LOW_BALANCE_THRESHOLD) and top_up_target (TOP_UP_TARGET)"
+                .to_owned(),
+        );
+        return None;
+    };
+    if target <= threshold {
+        problems.push(
+            "signer.top_up_target (TOP_UP_TARGET) has to be above low_balance_threshold (LOW_BALANCE_THRESHOLD)"
+                .to_owned(),
+        );
+        return None;
+    }
+
+    Some(BalancePolicy {
+        low_balance,
+        top_up: Some(TopUp {
+            treasury: treasury.ok()?,
+            target,
+        }),
+    })
+}
+
+fn validate_chains(
+    raw: BTreeMap<String, RawChain>,
+    alchemy_key: Option<&str>,
+    block_frequency: Option<u8>,
+    gas_limit_multiplier: Option<f64>,
+    problems: &mut Vec<String>,
+) -> Option<BTreeMap<ChainId, ChainConfig>> {
+    if raw.is_empty() {
+        problems.push("chains (CHAINS) is empty, the relay needs at least one".to_owned());
+        return None;
+    }
+
+    let problem_count = problems.len();
+    let mut chains = BTreeMap::new();
+    for (name, section) in raw {
+        let Ok(chain) = ChainId::from_str(&name) else {
+            problems.push(format!(
+                "chains.{} is neither a chain ethers knows nor a numeric chain id",
+                name
+            ));
+            continue;
+        };
+        let rpc_url = match (section.rpc_url, alchemy_key) {
+            (Some(url), _) if url.starts_with("ws://") || url.starts_with("wss://") => url,
+            (Some(_), _) => {
+                problems.push(format!(
+                    "chains.{}.rpc_url should be a ws:// or wss:// url",
+                    name
+                ));
+                continue;
+            }
+            (None, _) if !alchemy_rpc::is_supported(chain) => {
+                problems.push(format!(
+                    "chains.{} needs an rpc_url, Alchemy doesn't serve it",
+                    name
+                ));
+                continue;
+            }
+            (None, Some(key)) => get_ws(chain, key),
+            (None, None) => {
+                problems.push(format!(
+                    "chains.{} needs an rpc_url or alchemy_key (ALCHEMY_KEY)",
+                    name
+                ));
+                continue;
+            }
+        };
+
+        let block_frequency = section
+            .block_frequency
+            .or(block_frequency)
+            .unwrap_or(DEFAULT_BLOCK_FREQUENCY);
+        if block_frequency == 0 {
+            problems.push(format!(
+                "chains.{}.block_frequency (BLOCK_FREQUENCY) has to be at least 1",
+                name
+            ));
+        }
+        let gas_limit_multiplier = section
+            .gas_limit_multiplier
+            .or(gas_limit_multiplier)
+            .unwrap_or(DEFAULT_GAS_LIMIT_MULTIPLIER);
+        if !(1.0..=10.0).contains(&gas_limit_multiplier) {
+            problems.push(format!(
+                "chains.{}.gas_limit_multiplier (GAS_LIMIT_MULTIPLIER) should be between 1 and 10",
+                name
+            ));
+        }
+        let forwarder = match section.forwarder.as_deref().map(Address::from_str) {
+            None => None,
+            Some(Ok(forwarder)) => Some(forwarder),
+            Some(Err(err)) => {
+                problems.push(format!(
+                    "chains.{}.forwarder (FORWARDERS) is invalid, {}",
+                    name, err
+                ));
+                None
+            }
+        };
+        if section.retention_days == Some(0) {
+            problems.push(format!(
+                "chains.{}.retention_days (RETENTION_DAYS) has to be at least 1",
+                name
+            ));
+        }
+
+        let config = ChainConfig {
+            rpc_url,
+            block_frequency,
+            gas_limit_multiplier,
+            forwarder,
+            retention_days: section.retention_days,
+        };
+        if chains.insert(chain, config).is_some() {
+            problems.push(format!("chain {} is configured twice", chain));
+        }
+    }
+
+    (problems.len() == problem_count).then_some(chains)
+}
@@ -0,0 +1,350 @@
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use ethers::types::U256;
+use sqlx::{
+    database::HasArguments,
+    mysql::{MySqlPoolOptions, MySqlQueryResult, MySqlRow},
+    postgres::{PgPoolOptions, PgQueryResult, PgRow},
+    query::Query,
+    sqlite::{
+        SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteQueryResult, SqliteRow,
+    },
+    Database, MySql, MySqlPool, PgPool, Postgres, Row, Sqlite, SqlitePool,
+};
+
+pub type DbQuery<'q, DB> = Query<'q, DB, <DB as HasArguments<'q>>::Arguments>;
+
+/// A pool on whichever database `DATABASE_URL` points at. Requests, api keys, policies,
+/// fee quotes and user operations all share it.
+#[derive(Clone, Debug)]
+pub enum DbPool {
+    MySql(MySqlPool),
+    Postgres(PgPool),
+    Sqlite(SqlitePool),
+}
+
+impl DbPool {
+    /// Picks the database from the url's scheme, `mysql:`, `postgres:` or `sqlite:`.
+    /// SQLite databases are created if they don't exist.
+    pub async fn connect(url: &str, max_connections: u32) -> anyhow::Result<Self> {
+        let scheme = url.split_once(':').map(|(scheme, _)| scheme);
+        match scheme {
+            Some("mysql") => {
+                let pool = MySqlPoolOptions::new()
+                    .max_connections(max_connections)
+                    .connect(url)
+                    .await?;
+                Ok(Self::MySql(pool))
+            }
+            Some("postgres" | "postgresql") => {
+                let pool = PgPoolOptions::new()
+                    .max_connections(max_connections)
+                    .connect(url)
+                    .await?;
+                Ok(Self::Postgres(pool))
+            }
+            Some("sqlite") => {
+                let options = SqliteConnectOptions::from_str(url)?
+                    .create_if_missing(true)
+                    .journal_mode(SqliteJournalMode::Wal);
+                let pool = SqlitePoolOptions::new()
+                    .max_connections(max_connections)
+                    .connect_with(options)
+                    .await?;
+                Ok(Self::Sqlite(pool))
+            }
+            _ => Err(anyhow!(
+                "unsupported database url, expected a mysql, postgres or sqlite one"
+            )),
+        }
+    }
+
+    /// Runs the migrations for this database, from `migrations/`, `migrations/postgres/`
+    /// or `migrations/sqlite/`
+    pub async fn migrate(&self) -> anyhow::Result<()> {
+        match self {
+            Self::MySql(pool) => sqlx::migrate!("./migrations").run(pool).await?,
+            Self::Postgres(pool) => sqlx::migrate!("./migrations/postgres").run(pool).await?,
+            Self::Sqlite(pool) => sqlx::migrate!("./migrations/sqlite").run(pool).await?,
+        }
+        Ok(())
+    }
+}
+
+/// Runs `$body` once for each kind of pool, with `$pool` bound to the concrete pool and `$db`
+/// to its database, so queries are written once against `Dialect`
+macro_rules! with_pool {
+    ($pool:expr, |$name:ident: $db:ident| $body:expr) => {
+        match $pool {
+            $crate::database::DbPool::MySql($name) => {
+                type $db = sqlx::MySql;
+                $body
+            }
+            $crate::database::DbPool::Postgres($name) => {
+                type $db = sqlx::Postgres;
+                $body
+            }
+            $crate::database::DbPool::Sqlite($name) => {
+                type $db = sqlx::Sqlite;
+                $body
+            }
+        }
+    };
+}
+pub(crate) use with_pool;
+
+/// A text column that can't be NULL
+pub fn text<DB: Dialect>(row: &DB::Row, column: &str) -> sqlx::Result<String> {
+    required(column, DB::get_text(row, column)?)
+}
+
+/// An integer column that can't be NULL
+pub fn number<DB: Dialect>(row: &DB::Row, column: &str) -> sqlx::Result<u64> {
+    required(column, DB::get_u64(row, column)?)
+}
+
+fn required<T>(column: &str, value: Option<T>) -> sqlx::Result<T> {
+    value.ok_or_else(|| sqlx::Error::ColumnDecode {
+        index: column.to_owned(),
+        source: "unexpected null".into(),
+    })
+}
+
+/// Whether an insert or update failed on a unique index
+pub fn is_unique_violation(err: &sqlx::Error) -> bool {
+    let sqlx::Error::Database(err) = err else {
+        return false;
+    };
+    // MySQL and Postgres report the SQLSTATE, SQLite its extended result code
+    matches!(
+        err.code().as_deref(),
+        Some("23000" | "23505" | "1555" | "2067")
+    )
+}
+
+/// What differs between the databases the relay can run on. Queries are written once with `?`
+/// placeholders, bound in the order they appear, and adapted with these fragments.
+/// Integers are bound as `i64` and text as `String` or `&str` everywhere.
+pub trait Dialect: Database {
+    /// A wei amount, bound as the text `amount` returns
+    const AMOUNT: &'static str = "?";
+    /// A JSON document, bound as text
+    const JSON: &'static str = "?";
+    /// Unix milliseconds
+    const TIME: &'static str = "?";
+
+    /// The query with its placeholders in this database's syntax
+    fn sql(query: &str) -> String {
+        query.to_owned()
+    }
+
+    /// Reads an amount column as text, `amount_text` turns it into a decimal
+    fn amount_column(column: &str) -> String {
+        column.to_owned()
+    }
+
+    /// Reads a JSON column as text
+    fn json_column(column: &str) -> String {
+        column.to_owned()
+    }
+
+    /// Reads a timestamp column as unix milliseconds
+    fn time_column(column: &str) -> String {
+        column.to_owned()
+    }
+
+    /// An integer expression as the type `get_u64` reads
+    fn integer(expression: &str) -> String {
+        expression.to_owned()
+    }
+
+    /// Starts the assignments made when an insert hits an existing row with the same `keys`
+    fn on_conflict(keys: &str) -> String {
+        format!("ON CONFLICT ({}) DO UPDATE SET", keys)
+    }
+
+    /// The value the insert tried to write to `column`, in `on_conflict` assignments
+    fn excluded(column: &str) -> String {
+        format!("excluded.{}", column)
+    }
+
+    fn greatest(a: &str, b: &str) -> String {
+        format!("GREATEST({}, {})", a, b)
+    }
+
+    fn least(a: &str, b: &str) -> String {
+        format!("LEAST({}, {})", a, b)
+    }
+
+    /// How an amount is written to the database
+    fn amount(amount: U256) -> String {
+        amount.to_string()
+    }
+
+    /// An amount column as decimal text. What can't be read is passed through,
+    /// so the row is quarantined instead of failing the query.
+    fn amount_text(stored: String) -> String {
+        stored
+    }
+
+    fn bind_bytes<'q>(query: DbQuery<'q, Self>, bytes: Option<Vec<u8>>) -> DbQuery<'q, Self>;
+
+    fn rows_affected(result: &Self::QueryResult) -> u64;
+
+    fn get_u64(row: &Self::Row, column: &str) -> sqlx::Result<Option<u64>>;
+
+    fn get_text(row: &Self::Row, column: &str) -> sqlx::Result<Option<String>>;
+
+    fn get_bytes(row: &Self::Row, column: &str) -> sqlx::Result<Option<Vec<u8>>>;
+}
+
+impl Dialect for MySql {
+    const TIME: &'static str = "FROM_UNIXTIME(? / 1000)";
+
+    fn amount_column(column: &str) -> String {
+        format!("CAST({} AS CHAR)", column)
+    }
+
+    fn json_column(column: &str) -> String {
+        format!("CAST({} AS CHAR)", column)
+    }
+
+    fn time_column(column: &str) -> String {
+        format!("CAST(UNIX_TIMESTAMP({}) * 1000 AS UNSIGNED)", column)
+    }
+
+    fn integer(expression: &str) -> String {
+        format!("CAST({} AS UNSIGNED)", expression)
+    }
+
+    fn on_conflict(_keys: &str) -> String {
+        "ON DUPLICATE KEY UPDATE".to_owned()
+    }
+
+    fn excluded(column: &str) -> String {
+        format!("VALUES({})", column)
+    }
+
+    fn bind_bytes<'q>(query: DbQuery<'q, Self>, bytes: Option<Vec<u8>>) -> DbQuery<'q, Self> {
+        query.bind(bytes)
+    }
+
+    fn rows_affected(result: &MySqlQueryResult) -> u64 {
+        result.rows_affected()
+    }
+
+    fn get_u64(row: &MySqlRow, column: &str) -> sqlx::Result<Option<u64>> {
+        row.try_get(column)
+    }
+
+    fn get_text(row: &MySqlRow, column: &str) -> sqlx::Result<Option<String>> {
+        row.try_get(column)
+    }
+
+    fn get_bytes(row: &MySqlRow, column: &str) -> sqlx::Result<Option<Vec<u8>>> {
+        row.try_get(column)
+    }
+}
+
+impl Dialect for Postgres {
+    const AMOUNT: &'static str = "?::numeric";
+    const JSON: &'static str = "?::jsonb";
+
+    /// Postgres numbers its placeholders, `$1`, `$2`...
+    fn sql(query: &str) -> String {
+        let mut sql = String::with_capacity(query.len() + 16);
+        let mut index = 0;
+        for c in query.chars() {
+            if c == '?' {
+                index += 1;
+                sql.push_str(&format!("${}", index));
+            } else {
+                sql.push(c);
+            }
+        }
+        sql
+    }
+
+    fn amount_column(column: &str) -> String {
+        format!("{}::text", column)
+    }
+
+    fn json_column(column: &str) -> String {
+        format!("{}::text", column)
+    }
+
+    fn integer(expression: &str) -> String {
+        format!("CAST({} AS bigint)", expression)
+    }
+
+    fn bind_bytes<'q>(query: DbQuery<'q, Self>, bytes: Option<Vec<u8>>) -> DbQuery<'q, Self> {
+        query.bind(bytes)
+    }
+
+    fn rows_affected(result: &PgQueryResult) -> u64 {
+        result.rows_affected()
+    }
+
+    fn get_u64(row: &PgRow, column: &str) -> sqlx::Result<Option<u64>> {
+        Ok(row
+            .try_get::<Option<i64>, _>(column)?
+            .map(|value| value as u64))
+    }
+
+    fn get_text(row: &PgRow, column: &str) -> sqlx::Result<Option<String>> {
+        row.try_get(column)
+    }
+
+    fn get_bytes(row: &PgRow, column: &str) -> sqlx::Result<Option<Vec<u8>>> {
+        row.try_get(column)
+    }
+}
+
+/// SQLite has no integer wide enough for wei, amounts are kept as 64 hex digits so they still sort.
+/// Bytes are hex too, since older SQLite versions can't convert them in a migration.
+impl Dialect for Sqlite {
+    fn greatest(a: &str, b: &str) -> String {
+        format!("MAX({}, {})", a, b)
+    }
+
+    fn least(a: &str, b: &str) -> String {
+        format!("MIN({}, {})", a, b)
+    }
+
+    fn amount(amount: U256) -> String {
+        format!("{:0>64}", format!("{:x}", amount))
+    }
+
+    fn amount_text(stored: String) -> String {
+        match U256::from_str_radix(&stored, 16) {
+            Ok(amount) => amount.to_string(),
+            Err(_) => stored,
+        }
+    }
+
+    fn bind_bytes<'q>(query: DbQuery<'q, Self>, bytes: Option<Vec<u8>>) -> DbQuery<'q, Self> {
+        query.bind(bytes.map(hex::encode))
+    }
+
+    fn rows_affected(result: &SqliteQueryResult) -> u64 {
+        result.rows_affected()
+    }
+
+    fn get_u64(row: &SqliteRow, column: &str) -> sqlx::Result<Option<u64>> {
+        Ok(row
+            .try_get::<Option<i64>, _>(column)?
+            .map(|value| value as u64))
+    }
+
+    fn get_text(row: &SqliteRow, column: &str) -> sqlx::Result<Option<String>> {
+        row.try_get(column)
+    }
+
+    fn get_bytes(row: &SqliteRow, column: &str) -> sqlx::Result<Option<Vec<u8>>> {
+        row.try_get::<Option<String>, _>(column)?
+            .map(hex::decode)
+            .transpose()
+            .map_err(|err| sqlx::Error::Decode(err.into()))
+    }
+}
@@ -0,0 +1,539 @@
+use std::{
+    fs,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::anyhow;
+use ethers::{
+    abi::{encode, Token},
+    types::{
+        serde_helpers::deserialize_stringified_numeric, Address, Bytes, Eip1559TransactionRequest,
+        Signature, TransactionReceipt, H256, U256, U64,
+    },
+    utils::{id, keccak256, WEI_IN_ETHER},
+};
+use serde::{Deserialize, Serialize};
+use sqlx::query;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::auth::unix_now;
+use crate::database::{is_unique_violation, number, text, with_pool, DbPool, Dialect};
+use crate::transaction_repository::ChainId;
+
+// Roughly what the relayer spends on `permit` and `transferFrom` before the call itself
+const PERMIT_OVERHEAD_GAS: u64 = 120_000;
+const TRANSFER_EVENT: &str = "Transfer(address,address,uint256)";
+
+#[derive(Debug, Error)]
+pub enum FeeRejected {
+    #[error("token {0:?} is not accepted for fees on this chain")]
+    UnknownToken(Address),
+
+    #[error("quote {0} does not exist")]
+    QuoteNotFound(Uuid),
+
+    #[error("quote {0} has expired, request a new one")]
+    QuoteExpired(Uuid),
+
+    #[error("quote {0} was already used")]
+    QuoteUsed(Uuid),
+
+    #[error("quote {0} was made for payment by {1}")]
+    WrongMethod(Uuid, PaymentMethod),
+
+    #[error("quote {0} was made for the fee to be paid by {1:?}")]
+    WrongPayer(Uuid, Address),
+
+    #[error("quote {0} was made for another call")]
+    WrongCall(Uuid),
+
+    #[error("quote {id} covers {quoted} gas but the call asks for {requested}")]
+    GasAboveQuote {
+        id: Uuid,
+        quoted: U256,
+        requested: U256,
+    },
+
+    #[error("the fee is {required} but the payment only covers {offered}")]
+    Insufficient { required: U256, offered: U256 },
+
+    #[error("permit deadline has passed")]
+    PermitExpired,
+
+    #[error("transfer {hash:?} can't pay this fee, {reason}")]
+    Transfer { hash: H256, reason: &'static str },
+
+    #[error("this payment already paid for another request")]
+    PaymentReused,
+
+    #[error("the payer's signature is invalid")]
+    PayerSignature,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentMethod {
+    /// An EIP-2612 permit the relayer uses to pull the fee before relaying
+    Permit,
+    /// A transfer to the relayer the user already made
+    Transfer,
+}
+
+impl PaymentMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaymentMethod::Permit => "permit",
+            PaymentMethod::Transfer => "transfer",
+        }
+    }
+}
+
+impl std::fmt::Display for PaymentMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for PaymentMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "permit" => Ok(PaymentMethod::Permit),
+            "transfer" => Ok(PaymentMethod::Transfer),
+            _ => Err(anyhow!("unknown payment method {}", s)),
+        }
+    }
+}
+
+/// Tokens the relay accepts as payment for gas, loaded from a TOML file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeeConfig {
+    /// How long a quote can be used for
+    pub quote_ttl_seconds: u64,
+    /// Added on top of the quoted gas cost
+    #[serde(default)]
+    pub margin_percent: u64,
+    pub tokens: Vec<FeeToken>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeeToken {
+    pub chain: ChainId,
+    pub address: Address,
+    /// The token's smallest units one ether is worth, i.e. "2000000000" for 2000 USDC
+    #[serde(deserialize_with = "deserialize_stringified_numeric")]
+    pub units_per_eth: U256,
+}
+
+impl FeeConfig {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        Ok(toml::from_str(&contents)?)
+    }
+
+    pub fn token(&self, chain: ChainId, address: Address) -> Option<&FeeToken> {
+        self.tokens
+            .iter()
+            .find(|token| token.chain == chain && token.address == address)
+    }
+
+    /// What relaying `gas_limit` gas at `max_fee_per_gas` costs in the token, rounded up
+    pub fn fee(
+        &self,
+        token: &FeeToken,
+        method: PaymentMethod,
+        gas_limit: U256,
+        max_fee_per_gas: U256,
+    ) -> U256 {
+        let gas = match method {
+            PaymentMethod::Permit => gas_limit + PERMIT_OVERHEAD_GAS,
+            PaymentMethod::Transfer => gas_limit,
+        };
+        let wei = gas * max_fee_per_gas * (100 + self.margin_percent);
+        let denominator = WEI_IN_ETHER * 100;
+        (wei * token.units_per_eth + denominator - 1) / denominator
+    }
+}
+
+/// A fee the relay promised to accept for one call, until it expires
+#[derive(Clone, Debug, Serialize)]
+pub struct Quote {
+    pub id: Uuid,
+    pub chain: ChainId,
+    pub token: Address,
+    pub method: PaymentMethod,
+    pub amount: U256,
+    /// Who the fee is paid to
+    pub recipient: Address,
+    /// Who pays the fee, the permit's owner or the transfer's sender
+    pub payer: Address,
+    /// The call the quote pays for, see `call_hash`
+    pub call_hash: H256,
+    /// Relaying the call can't use more gas than this
+    pub gas_limit: U256,
+    pub max_fee_per_gas: U256,
+    /// Unix seconds
+    pub expires_at: u64,
+}
+
+/// What a redeemed quote lets through, and what it's owed
+#[derive(Clone, Debug)]
+pub struct RedeemedQuote {
+    pub token: Address,
+    pub amount: U256,
+    pub payer: Address,
+    pub gas_limit: U256,
+}
+
+#[derive(Debug)]
+struct QuoteRecord {
+    token: String,
+    method: String,
+    amount: String,
+    payer: String,
+    call_hash: String,
+    gas_limit: String,
+    expires_at: u64,
+    payment_ref: Option<String>,
+}
+
+fn quote_record<DB: Dialect>(row: &DB::Row) -> sqlx::Result<QuoteRecord> {
+    Ok(QuoteRecord {
+        token: text::<DB>(row, "token")?,
+        method: text::<DB>(row, "method")?,
+        amount: text::<DB>(row, "amount")?,
+        payer: text::<DB>(row, "payer")?,
+        call_hash: text::<DB>(row, "call_hash")?,
+        gas_limit: text::<DB>(row, "gas_limit")?,
+        expires_at: number::<DB>(row, "expires_at")?,
+        payment_ref: DB::get_text(row, "payment_ref")?,
+    })
+}
+
+/// Identifies the call a quote priced by its target, value and data, the fields that decide
+/// what gas it needs
+pub fn call_hash(call: &Eip1559TransactionRequest) -> H256 {
+    let to = call
+        .to
+        .as_ref()
+        .and_then(|to| to.as_address().copied())
+        .unwrap_or_default();
+    let encoded = encode(&[
+        Token::Address(to),
+        Token::Uint(call.value.unwrap_or_default()),
+        Token::Bytes(call.data.clone().unwrap_or_default().to_vec()),
+    ]);
+    keccak256(encoded).into()
+}
+
+/// An EIP-2612 permit letting the relayer spend `value` of the owner's tokens
+#[derive(Clone, Debug, Deserialize)]
+pub struct Permit {
+    pub owner: Address,
+    pub value: U256,
+    pub deadline: U256,
+    pub signature: Bytes,
+}
+
+impl Permit {
+    /// Calldata for `token.permit(owner, spender, value, deadline, v, r, s)`
+    pub fn calldata(&self, spender: Address) -> anyhow::Result<Bytes> {
+        let signature = Signature::try_from(self.signature.as_ref())?;
+        let mut r = [0u8; 32];
+        let mut s = [0u8; 32];
+        signature.r.to_big_endian(&mut r);
+        signature.s.to_big_endian(&mut s);
+        let args = encode(&[
+            Token::Address(self.owner),
+            Token::Address(spender),
+            Token::Uint(self.value),
+            Token::Uint(self.deadline),
+            Token::Uint(signature.v.into()),
+            Token::FixedBytes(r.to_vec()),
+            Token::FixedBytes(s.to_vec()),
+        ]);
+        let selector = id("permit(address,address,uint256,uint256,uint8,bytes32,bytes32)");
+        Ok([&selector[..], &args].concat().into())
+    }
+}
+
+/// Calldata for `token.transferFrom(from, to, amount)`
+pub fn transfer_from_calldata(from: Address, to: Address, amount: U256) -> Bytes {
+    let args = encode(&[
+        Token::Address(from),
+        Token::Address(to),
+        Token::Uint(amount),
+    ]);
+    [&id("transferFrom(address,address,uint256)")[..], &args]
+        .concat()
+        .into()
+}
+
+/// What the payer signs (EIP-191) to let a transfer pay for a quote, so nobody else can redeem
+/// it after seeing it on chain
+pub fn transfer_authorization(quote_id: Uuid, hash: H256) -> String {
+    format!("Pay relay fee quote {} with transfer {:?}", quote_id, hash)
+}
+
+/// Who signed `transfer_authorization` for the quote and transfer
+pub fn transfer_signer(
+    quote_id: Uuid,
+    hash: H256,
+    signature: &Bytes,
+) -> Result<Address, FeeRejected> {
+    Signature::try_from(signature.as_ref())
+        .and_then(|signature| signature.recover(transfer_authorization(quote_id, hash)))
+        .map_err(|_| FeeRejected::PayerSignature)
+}
+
+/// Checks a mined transfer sent at least `amount` of `token` from `payer` to `recipient`
+pub fn check_transfer(
+    receipt: &TransactionReceipt,
+    token: Address,
+    payer: Address,
+    recipient: Address,
+    amount: U256,
+) -> Result<(), FeeRejected> {
+    let hash = receipt.transaction_hash;
+    if receipt.status != Some(U64::one()) {
+        return Err(FeeRejected::Transfer {
+            hash,
+            reason: "it reverted",
+        });
+    }
+
+    let transfer_topic = H256::from(keccak256(TRANSFER_EVENT));
+    let offered = receipt
+        .logs
+        .iter()
+        .filter(|log| {
+            log.address == token
+                && log.topics.len() == 3
+                && log.topics[0] == transfer_topic
+                && Address::from(log.topics[1]) == payer
+                && Address::from(log.topics[2]) == recipient
+        })
+        .fold(U256::zero(), |total, log| {
+            total + U256::from_big_endian(&log.data)
+        });
+    if offered.is_zero() {
+        return Err(FeeRejected::Transfer {
+            hash,
+            reason: "it didn't send the token from the quote's payer to the relayer",
+        });
+    }
+    if offered < amount {
+        return Err(FeeRejected::Insufficient {
+            required: amount,
+            offered,
+        });
+    }
+
+    Ok(())
+}
+
+/// Issues quotes and redeems them, each quote pays for one request
+#[derive(Debug)]
+pub struct FeeEngine {
+    config: RwLock<Arc<FeeConfig>>,
+    pool: DbPool,
+}
+
+impl FeeEngine {
+    pub fn new(config: FeeConfig, pool: DbPool) -> Self {
+        Self {
+            config: RwLock::new(Arc::new(config)),
+            pool,
+        }
+    }
+
+    pub fn config(&self) -> Arc<FeeConfig> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Quotes already issued keep the amount they were given
+    pub fn set_config(&self, config: FeeConfig) {
+        *self.config.write().unwrap() = Arc::new(config);
+    }
+
+    /// Prices the prepared `call`, with its gas limit filled in, for `payer` to pay
+    #[allow(clippy::too_many_arguments)]
+    pub async fn quote(
+        &self,
+        tenant_id: &str,
+        chain: ChainId,
+        token: Address,
+        method: PaymentMethod,
+        recipient: Address,
+        payer: Address,
+        call: &Eip1559TransactionRequest,
+        max_fee_per_gas: U256,
+    ) -> anyhow::Result<Quote> {
+        let gas_limit = call.gas.unwrap_or_default();
+        let config = self.config();
+        let fee_token = config
+            .token(chain, token)
+            .ok_or(FeeRejected::UnknownToken(token))?;
+        let quote = Quote {
+            id: Uuid::new_v4(),
+            chain,
+            token,
+            method,
+            amount: config.fee(fee_token, method, gas_limit, max_fee_per_gas),
+            recipient,
+            payer,
+            call_hash: call_hash(call),
+            gas_limit,
+            max_fee_per_gas,
+            expires_at: unix_now() + config.quote_ttl_seconds,
+        };
+
+        with_pool!(&self.pool, |pool: DB| {
+            let sql = DB::sql(
+                r#"
+				INSERT INTO fee_quotes (id, tenant_id, chain, token, method, amount, payer, call_hash, gas_limit, max_fee_per_gas, expires_at)
+				VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+				"#,
+            );
+            query(&sql)
+                .bind(quote.id.to_string())
+                .bind(tenant_id)
+                .bind(chain.0 as i64)
+                .bind(format!("{:?}", token))
+                .bind(method.as_str())
+                .bind(quote.amount.to_string())
+                .bind(format!("{:?}", payer))
+                .bind(format!("{:?}", quote.call_hash))
+                .bind(quote.gas_limit.to_string())
+                .bind(quote.max_fee_per_gas.to_string())
+                .bind(quote.expires_at as i64)
+                .execute(pool)
+                .await?;
+        });
+
+        Ok(quote)
+    }
+
+    /// Claims an unexpired quote for one payment of the call it priced, by the quote's payer.
+    /// `payment_ref` identifies the payment, a transfer can only pay for one quote.
+    /// A `call` with a gas limit can't ask for more than was quoted.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn redeem(
+        &self,
+        id: Uuid,
+        tenant_id: &str,
+        chain: ChainId,
+        method: PaymentMethod,
+        payer: Address,
+        call: &Eip1559TransactionRequest,
+        payment_ref: &str,
+    ) -> anyhow::Result<RedeemedQuote> {
+        let record = with_pool!(&self.pool, |pool: DB| {
+            let sql = DB::sql(
+                r#"
+				SELECT token, method, amount, payer, call_hash, gas_limit, expires_at, payment_ref
+				FROM fee_quotes
+				WHERE id = ? and tenant_id = ? and chain = ?
+				"#,
+            );
+            query(&sql)
+                .bind(id.to_string())
+                .bind(tenant_id)
+                .bind(chain.0 as i64)
+                .try_map(|row| quote_record::<DB>(&row))
+                .fetch_optional(pool)
+                .await?
+        })
+        .ok_or(FeeRejected::QuoteNotFound(id))?;
+
+        let quoted_method: PaymentMethod = record.method.parse()?;
+        if quoted_method != method {
+            return Err(FeeRejected::WrongMethod(id, quoted_method).into());
+        }
+        let quoted_payer: Address = record.payer.parse()?;
+        if quoted_payer != payer {
+            return Err(FeeRejected::WrongPayer(id, quoted_payer).into());
+        }
+        if record.call_hash != format!("{:?}", call_hash(call)) {
+            return Err(FeeRejected::WrongCall(id).into());
+        }
+        let gas_limit = U256::from_dec_str(&record.gas_limit)?;
+        if let Some(requested) = call.gas.filter(|requested| *requested > gas_limit) {
+            return Err(FeeRejected::GasAboveQuote {
+                id,
+                quoted: gas_limit,
+                requested,
+            }
+            .into());
+        }
+        if record.payment_ref.is_some() {
+            return Err(FeeRejected::QuoteUsed(id).into());
+        }
+        let now = unix_now();
+        if record.expires_at <= now {
+            return Err(FeeRejected::QuoteExpired(id).into());
+        }
+
+        let claimed = with_pool!(&self.pool, |pool: DB| {
+            let sql = DB::sql(
+                r#"
+				UPDATE fee_quotes
+				SET payment_ref = ?
+				WHERE id = ? and payment_ref IS NULL and expires_at > ?
+				"#,
+            );
+            query(&sql)
+                .bind(payment_ref)
+                .bind(id.to_string())
+                .bind(now as i64)
+                .execute(pool)
+                .await
+                .map(|claimed| DB::rows_affected(&claimed))
+        });
+        match claimed {
+            Ok(1) => {}
+            Ok(_) => return Err(FeeRejected::QuoteUsed(id).into()),
+            // The unique index on payment_ref, the payment already paid for another quote
+            Err(err) if is_unique_violation(&err) => return Err(FeeRejected::PaymentReused.into()),
+            Err(err) => return Err(err.into()),
+        }
+
+        Ok(RedeemedQuote {
+            token: record.token.parse()?,
+            amount: U256::from_dec_str(&record.amount)?,
+            payer,
+            gas_limit,
+        })
+    }
+
+    /// Records the request the quote paid for
+    pub async fn record(&self, id: Uuid, request_id: Uuid) -> anyhow::Result<()> {
+        with_pool!(&self.pool, |pool: DB| {
+            query(&DB::sql(
+                "UPDATE fee_quotes SET request_id = ? WHERE id = ?",
+            ))
+            .bind(request_id.to_string())
+            .bind(id.to_string())
+            .execute(pool)
+            .await?;
+        });
+        Ok(())
+    }
+
+    /// Makes a redeemed quote usable again, for when its request couldn't be sent
+    pub async fn release(&self, id: Uuid) -> anyhow::Result<()> {
+        with_pool!(&self.pool, |pool: DB| {
+            let sql = DB::sql(
+                "UPDATE fee_quotes SET payment_ref = NULL WHERE id = ? and request_id IS NULL",
+            );
+            query(&sql).bind(id.to_string()).execute(pool).await?;
+        });
+        Ok(())
+    }
+}
@@ -1,2 +1,12 @@
+pub mod alchemy_rpc;
+pub mod api_keys;
+pub mod auth;
+pub mod bundler;
+pub mod config;
+pub mod database;
+pub mod fees;
+pub mod policy;
+pub mod rate_limit;
+pub mod retention;
 pub mod transaction_monitor;
 pub mod transaction_repository;
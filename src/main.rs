@@ -1,6 +1,11 @@
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
+    body::{Body, HttpBody},
+    extract::{Extension, Path, Query, State},
+    http::{
+        header::{CONTENT_TYPE, RETRY_AFTER},
+        request::Parts,
+        HeaderMap, StatusCode,
+    },
     middleware::{from_fn_with_state, Next},
     response::IntoResponse,
     response::Response,
@@ -10,72 +15,262 @@ use axum::{
 
 use thiserror::Error;
 
+use anyhow::Context;
 use axum_macros::debug_handler;
+use axum_server::tls_rustls::RustlsConfig;
 use dotenv::dotenv;
 use ethers::{
     core::types::{serde_helpers::Numeric, Address, Eip1559TransactionRequest},
-    providers::{Provider, Ws},
-    signers::LocalWallet,
-    types::{Chain, TxHash},
+    providers::{Middleware, Provider, Ws},
+    signers::Signer,
+    types::{Bytes, Signature, TxHash, H256, U256},
 };
 
 use serde::{Deserialize, Deserializer, Serialize};
-use sqlx::mysql::MySqlPoolOptions;
-use std::{env, fmt, net::SocketAddr, str::FromStr, sync::Arc};
-use tracing::{info, Level};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    fmt, slice,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, Level};
 use uuid::Uuid;
 
-mod transaction_monitor;
-mod transaction_repository;
-use transaction_monitor::TransactionMonitor;
-use transaction_repository::DbTxRequestRepository;
-
-mod alchemy_rpc;
-pub use alchemy_rpc::get_ws;
+use relay::{
+    api_keys::{hash_key, ApiKey, ApiKeyRepository, DbApiKeyRepository},
+    auth::{self, ReplayGuard, SignedMode, SignedRequest},
+    bundler::{handle_ops_calldata, Bundler, UserOpRejected, UserOperation},
+    config::Config,
+    database::DbPool,
+    fees::{
+        self, check_transfer, transfer_from_calldata, transfer_signer, FeeConfig, FeeEngine,
+        FeeRejected, PaymentMethod, Permit,
+    },
+    policy::{Policy, PolicyEngine, PolicyViolation, Reservation},
+    rate_limit::RateLimiter,
+    retention::{Retention, RetentionTarget},
+    transaction_monitor::{
+        fingerprint, BalanceStatus, BatchRejected, ChainDraining, ChainState, ForwardRejected,
+        ForwardRequest, IdempotencyConflict, InsufficientBalance, InvalidDependency, RevertReason,
+        SendOptions, SimulationError, TransactionMonitor, TypedForwardRequest,
+    },
+    transaction_repository::{
+        invalid_records, Actor, ChainId, Cursor, DbTxRequestRepository, ListedRequest,
+        RequestEvent, RequestFilter, RequestStatus, TransactionRepository,
+    },
+};
 
-static SUPPORTED_CHAINS: [Chain; 2] = [Chain::Goerli, Chain::Sepolia];
+static IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+const MAX_BATCH_SIZE: usize = 1000;
+static TIMESTAMP_HEADER: &str = "x-relay-timestamp";
+static SIGNATURE_HEADER: &str = "x-relay-signature";
+static KEY_ID_HEADER: &str = "x-relay-key-id";
+// Same as axum's default limit for json bodies
+const MAX_SIGNED_BODY_BYTES: usize = 2 * 1024 * 1024;
+// Roughly a couple of blocks, enough for the backlog to start draining
+const BACKPRESSURE_RETRY_AFTER: Duration = Duration::from_secs(30);
+// About a block on mainnet
+const BUNDLE_INTERVAL: Duration = Duration::from_secs(12);
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_LIST_LIMIT: u32 = 50;
+const MAX_LIST_LIMIT: u32 = 500;
+const DEFAULT_EXPORT_LIMIT: u32 = 1000;
+const MAX_EXPORT_LIMIT: u32 = 10_000;
 
 #[derive(Debug, Clone)]
 struct AppState {
     monitor: Arc<TransactionMonitor<Ws>>,
-    config: Arc<Config>,
+    bundler: Arc<Bundler<Ws>>,
+    api_keys: DbApiKeyRepository,
+    replay_guard: Arc<ReplayGuard>,
+    key_limiter: Arc<RateLimiter<Uuid>>,
+    chain_limiter: Arc<RateLimiter<ChainId>>,
+    policy: Arc<PolicyEngine>,
+    /// Only set when users can pay fees in tokens
+    fees: Option<Arc<FeeEngine>>,
+    retention: Arc<Retention<DbTxRequestRepository>>,
+    /// Swapped by reloads, handlers take a snapshot with `config()`
+    config: Arc<RwLock<Arc<Config>>>,
+    /// Reloads run one at a time
+    reloading: Arc<tokio::sync::Mutex<()>>,
 }
 
-#[derive(Debug, Clone)]
-struct Config {
-    expected_auth_header: String,
-    pk_hex_string: String,
-    alchemy_key: String,
-    database_url: String,
-    port: u16,
-}
-
-fn get_config() -> Config {
-    Config {
-        expected_auth_header: env::var("EXPECTED_AUTH_HEADER")
-            .expect("Missing \"EXPECTED_AUTH_HEADER\" Env Var"),
-        pk_hex_string: env::var("PK").expect("Missing \"PK\" Env Var"),
-        alchemy_key: env::var("ALCHEMY_KEY").expect("Missing \"ALCHEMY_KEY\" Env Var"),
-        database_url: env::var("DATABASE_URL").expect("Missing \"DATABASE_URL\" Env Var"),
-        port: env::var("PORT").map_or(3000, |s| {
-            s.parse().expect("Missing or invalid \"PORT\" Env Var")
-        }),
+impl AppState {
+    fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
     }
 }
 
-async fn simple_auth<B>(
+/// Identifies the caller's api key and hands it to the handlers, which scope everything to its tenant
+async fn authenticate(
     State(state): State<AppState>,
-    request: axum::http::Request<B>,
-    next: Next<B>,
-) -> Result<axum::response::Response, StatusCode> {
-    if let Some(key) = request.headers().get("authorization") {
-        if key == &state.config.expected_auth_header {
-            let response = next.run(request).await;
-            return Ok(response);
+    request: axum::http::Request<Body>,
+    next: Next<Body>,
+) -> Result<axum::response::Response, ServerError> {
+    let (mut request, api_key) = match state.config().auth_mode.signed() {
+        None => {
+            let api_key = api_key_from_header(&state, request.headers()).await?;
+            (request, api_key)
+        }
+        Some(mode) => {
+            // The signature covers the body, so it has to be read here and put back for the handler
+            let (parts, body) = request.into_parts();
+            let body = read_body(body).await?;
+            let api_key = verify_signed_request(&state, mode, &parts, &body).await?;
+            (
+                axum::http::Request::from_parts(parts, Body::from(body)),
+                api_key,
+            )
         }
+    };
+
+    if let Some(limit) = api_key.rate_limit_per_minute {
+        state
+            .key_limiter
+            .check(api_key.id, limit, 1)
+            .map_err(|retry_after| ServerError::Throttled {
+                status: StatusCode::TOO_MANY_REQUESTS,
+                message: "Rate limit exceeded for this api key".to_owned(),
+                retry_after,
+            })?;
+    }
+
+    request.extensions_mut().insert(api_key);
+    Ok(next.run(request).await)
+}
+
+/// Operators send `ADMIN_TOKEN` in the `authorization` header, api keys don't work here
+async fn authenticate_admin(
+    State(state): State<AppState>,
+    request: axum::http::Request<Body>,
+    next: Next<Body>,
+) -> Result<axum::response::Response, ServerError> {
+    let config = state.config();
+    let Some(token) = &config.admin_token else {
+        return Err(unauthorized("Admin api is disabled"));
+    };
+    // Compared as hashes so the comparison doesn't leak how much of the token matched
+    let provided = header_str(request.headers(), "authorization").map(hash_key);
+    if provided != Some(hash_key(token)) {
+        return Err(unauthorized("Missing or invalid admin token"));
     }
 
-    Err(StatusCode::UNAUTHORIZED)
+    Ok(next.run(request).await)
+}
+
+fn unauthorized(message: impl Into<String>) -> ServerError {
+    ServerError::Status {
+        status: StatusCode::UNAUTHORIZED,
+        message: message.into(),
+    }
+}
+
+async fn api_key_from_header(state: &AppState, headers: &HeaderMap) -> Result<ApiKey, ServerError> {
+    let key = header_str(headers, "authorization")
+        .ok_or_else(|| unauthorized("Missing or invalid api key"))?;
+    // Compared as hashes like the admin token
+    if let Some(bootstrap) = &state.config().expected_auth_header {
+        if hash_key(key) == hash_key(bootstrap) {
+            return Ok(ApiKey::bootstrap());
+        }
+    }
+    state
+        .api_keys
+        .find(key)
+        .await?
+        .ok_or_else(|| unauthorized("Missing or invalid api key"))
+}
+
+async fn verify_signed_request(
+    state: &AppState,
+    mode: SignedMode,
+    parts: &Parts,
+    body: &[u8],
+) -> Result<ApiKey, ServerError> {
+    let timestamp = header_str(&parts.headers, TIMESTAMP_HEADER)
+        .and_then(|timestamp| timestamp.parse().ok())
+        .ok_or_else(|| unauthorized(format!("Missing or invalid {} header", TIMESTAMP_HEADER)))?;
+    let signature = header_str(&parts.headers, SIGNATURE_HEADER)
+        .ok_or_else(|| unauthorized(format!("Missing {} header", SIGNATURE_HEADER)))?;
+    let signed = SignedRequest {
+        method: parts.method.as_str(),
+        path: parts
+            .uri
+            .path_and_query()
+            .map_or(parts.uri.path(), |path| path.as_str()),
+        body,
+        timestamp,
+    };
+
+    let (api_key, signer) = match mode {
+        SignedMode::Hmac => {
+            let key_id = header_str(&parts.headers, KEY_ID_HEADER)
+                .and_then(|id| Uuid::parse_str(id).ok())
+                .ok_or_else(|| {
+                    unauthorized(format!("Missing or invalid {} header", KEY_ID_HEADER))
+                })?;
+            let (api_key, secret) = state
+                .api_keys
+                .find_hmac(key_id)
+                .await?
+                .ok_or_else(|| unauthorized("Unknown api key"))?;
+            // Validating the config made sure the key is there in hmac mode
+            let secret = state
+                .config()
+                .hmac_secret_key
+                .as_ref()
+                .context("hmac_secret_key isn't set")?
+                .decrypt(key_id, &secret)
+                .map_err(anyhow::Error::from)?;
+            signed
+                .verify_hmac(secret.as_bytes(), signature)
+                .map_err(|err| unauthorized(err.to_string()))?;
+            (api_key, key_id.to_string())
+        }
+        SignedMode::Eip712 => {
+            let signer = signed
+                .recover(signature)
+                .map_err(|err| unauthorized(err.to_string()))?;
+            let api_key = state
+                .api_keys
+                .find_by_signer(signer)
+                .await?
+                .ok_or_else(|| unauthorized(format!("{:?} is not an allowed signer", signer)))?;
+            (api_key, format!("{:?}", signer))
+        }
+    };
+
+    state
+        .replay_guard
+        .check(&signed, &signer)
+        .map_err(|err| unauthorized(err.to_string()))?;
+    Ok(api_key)
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+async fn read_body(mut body: Body) -> Result<Vec<u8>, ServerError> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| ServerError::Status {
+            status: StatusCode::BAD_REQUEST,
+            message: format!("Failed to read the request body, {}", err),
+        })?;
+        if bytes.len() + chunk.len() > MAX_SIGNED_BODY_BYTES {
+            return Err(ServerError::Status {
+                status: StatusCode::PAYLOAD_TOO_LARGE,
+                message: format!("Request body is over {} bytes", MAX_SIGNED_BODY_BYTES),
+            });
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(bytes)
 }
 
 #[tokio::main]
@@ -90,94 +285,1473 @@ async fn main() {
         .init();
     // console_subscriber::init();
 
-    let config = get_config();
-    let connection_pool = MySqlPoolOptions::new()
-        .max_connections(5)
-        .connect(&config.database_url)
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let connection_pool = DbPool::connect(&config.database.url, config.database.pool_size)
         .await
         .expect("Could not connect to database");
+    if config.database.run_migrations {
+        connection_pool
+            .migrate()
+            .await
+            .expect("Could not migrate the database");
+    }
+    let tx_repo = DbTxRequestRepository::new(connection_pool.clone());
 
-    let tx_repo = DbTxRequestRepository::new(connection_pool);
-    let mut monitor = TransactionMonitor::new(tx_repo);
-    let chains = [Chain::Goerli, Chain::Sepolia];
+    let policy = match &config.policy_file {
+        Some(path) => Policy::load(path).expect("Server not configured correctly, invalid policy"),
+        None => Policy::default(),
+    };
+    let policy = PolicyEngine::new(policy, connection_pool.clone());
+    let fees = config.fee_config_file.as_ref().map(|path| {
+        let fee_config =
+            FeeConfig::load(path).expect("Server not configured correctly, invalid fee config");
+        Arc::new(FeeEngine::new(fee_config, connection_pool.clone()))
+    });
+    let api_keys = DbApiKeyRepository::new(connection_pool.clone());
+    let monitor = TransactionMonitor::new(tx_repo);
 
-    let signer = LocalWallet::from_str(&config.pk_hex_string)
-        .expect("Server not configured correct, invalid private key");
-    for chain in chains {
-        let rpc_url = get_ws(chain, &config.alchemy_key);
-        let provider = Provider::<Ws>::connect(rpc_url)
-            .await
-            .expect("Server not configured correctly, invalid provider url");
-        monitor
-            .setup_monitor(signer.clone(), provider, chain, 3)
+    for &chain in config.chains.keys() {
+        setup_chain(&monitor, &config, chain)
             .await
             .expect("monitors could not be setup");
     }
 
-    let port = config.port;
+    let monitor = Arc::new(monitor);
+    let bundler = Arc::new(Bundler::new(
+        monitor.clone(),
+        connection_pool,
+        config.entry_point,
+    ));
+    tokio::spawn(bundler.clone().run(BUNDLE_INTERVAL));
+
+    let target = match &config.retention_export_dir {
+        Some(dir) => RetentionTarget::Jsonl(dir.clone()),
+        None => RetentionTarget::Archive,
+    };
+    let retention = Arc::new(Retention::new(
+        monitor.tx_repo.clone(),
+        retention_max_age(&config),
+        target,
+    ));
+    tokio::spawn(retention.clone().run(RETENTION_INTERVAL));
+
+    let server = config.server.clone();
     let shared_state = AppState {
-        monitor: Arc::new(monitor),
-        config: Arc::new(config),
+        monitor,
+        bundler,
+        api_keys,
+        replay_guard: Arc::new(ReplayGuard::default()),
+        key_limiter: Arc::new(RateLimiter::default()),
+        chain_limiter: Arc::new(RateLimiter::default()),
+        policy: Arc::new(policy),
+        fees,
+        retention,
+        config: Arc::new(RwLock::new(Arc::new(config))),
+        reloading: Arc::default(),
     };
+    tokio::spawn(reload_on_hangup(shared_state.clone()));
 
     let app = Router::new()
         .route("/transaction", post(relay_transaction))
+        .route("/transaction/forward", post(relay_forwarded))
+        .route("/fee/quote", post(quote_fee))
+        .route("/balances", get(balances))
+        .route("/metrics", get(metrics))
         .route("/transaction/:id", get(transaction_status))
-        .layer(from_fn_with_state(shared_state.clone(), simple_auth))
-        .with_state(Arc::new(shared_state));
+        .route("/transaction/:id/cancel", post(cancel_transaction))
+        .route("/transaction/:id/events", get(transaction_events))
+        .route("/events", get(export_events))
+        .route("/transactions", get(list_transactions))
+        .route("/transactions/batch", post(relay_batch))
+        .route("/rpc/:chain", post(bundler_rpc))
+        .layer(from_fn_with_state(shared_state.clone(), authenticate));
+    let admin = Router::new()
+        .route("/admin/chains", get(admin_chains))
+        .route("/admin/reload", post(reload_config))
+        .route("/admin/chain/:chain/pause", post(pause_chain))
+        .route("/admin/chain/:chain/resume", post(resume_chain))
+        .route("/admin/chain/:chain/drain", post(drain_chain))
+        .route("/admin/chain/:chain/nonce/resync", post(resync_nonce))
+        .route(
+            "/admin/transaction/:id/rebroadcast",
+            post(force_rebroadcast),
+        )
+        .route("/admin/transaction/:id/abandon", post(abandon_transaction))
+        .layer(from_fn_with_state(shared_state.clone(), authenticate_admin));
+    let app = app.merge(admin).with_state(Arc::new(shared_state));
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], port));
-    info!("Listening on port {}", port);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    info!("Listening on {}", server.address);
+    match server.tls {
+        Some(tls) => {
+            let tls = RustlsConfig::from_pem_file(&tls.cert, &tls.key)
+                .await
+                .expect("Server not configured correctly, invalid tls certificate or key");
+            axum_server::bind_rustls(server.address, tls)
+                .serve(app.into_make_service())
+                .await
+                .unwrap();
+        }
+        None => axum::Server::bind(&server.address)
+            .serve(app.into_make_service())
+            .await
+            .unwrap(),
+    }
+}
+
+/// Connects to the chain and starts its monitor, or reconfigures the one it has
+async fn setup_chain(
+    monitor: &TransactionMonitor<Ws>,
+    config: &Config,
+    chain: ChainId,
+) -> anyhow::Result<()> {
+    let chain_config = &config.chains[&chain];
+    let provider = Provider::<Ws>::connect(&chain_config.rpc_url).await?;
+    monitor
+        .setup_monitor(
+            config.signer.clone(),
+            provider,
+            chain,
+            chain_config.block_frequency,
+            chain_config.gas_limit_multiplier,
+        )
+        .await?;
+    monitor.set_balance_policy(chain, config.balance_policy.clone())
+}
+
+fn retention_max_age(config: &Config) -> HashMap<ChainId, Duration> {
+    config
+        .chains
+        .iter()
+        .filter_map(|(&chain, chain_config)| Some((chain, chain_config.retention_days?)))
+        .map(|(chain, days)| (chain, Duration::from_secs(days * 24 * 60 * 60)))
+        .collect()
+}
+
+async fn reload_on_hangup(state: AppState) {
+    let mut hangups = signal(SignalKind::hangup()).expect("Could not listen for SIGHUP");
+    while hangups.recv().await.is_some() {
+        info!("SIGHUP received, reloading the config");
+        match reload(&state).await {
+            Ok(outcome) => info!("Reloaded the config, {:?}", outcome),
+            Err(err) => error!("Could not reload the config, {}", err),
+        }
+    }
+}
+
+/// What a reload changed
+#[derive(Debug, Default, Serialize)]
+struct ReloadOutcome {
+    added: Vec<ChainId>,
+    reconfigured: Vec<ChainId>,
+    /// Their monitors keep tracking what was sent until it settles
+    removed: Vec<ChainId>,
+    /// Chains that couldn't be set up, they keep their previous settings
+    failed: HashMap<ChainId, String>,
+    /// Changed settings that only apply after a restart
+    restart_required: Vec<&'static str>,
+}
+
+/// Reads the config again and applies its chains, policy, fee config, retention and limits.
+/// A config that doesn't validate, or a policy or fee config that doesn't load, changes nothing.
+async fn reload(state: &AppState) -> anyhow::Result<ReloadOutcome> {
+    let _reloading = state.reloading.lock().await;
+    let current = state.config();
+    let mut config = Config::from_env()?;
+    let policy = match &config.policy_file {
+        Some(path) => Policy::load(path).context("invalid policy")?,
+        None => Policy::default(),
+    };
+    let fee_config = match &config.fee_config_file {
+        Some(path) => Some(FeeConfig::load(path).context("invalid fee config")?),
+        None => None,
+    };
+
+    let mut outcome = ReloadOutcome::default();
+    // Kept as they are, so the config always describes what's running
+    if config.server != current.server {
+        outcome.restart_required.push("server");
+        config.server = current.server.clone();
+    }
+    if config.database != current.database {
+        outcome.restart_required.push("database");
+        config.database = current.database.clone();
+    }
+    if config.signer.address() != current.signer.address() {
+        outcome.restart_required.push("signer");
+    }
+    config.signer = current.signer.clone();
+    if config.entry_point != current.entry_point {
+        outcome.restart_required.push("entry_point");
+        config.entry_point = current.entry_point;
+    }
+    if config.retention_export_dir != current.retention_export_dir {
+        outcome.restart_required.push("retention_export_dir");
+        config.retention_export_dir = current.retention_export_dir.clone();
+    }
+    if config.fee_config_file.is_some() != state.fees.is_some() {
+        outcome.restart_required.push("fee_config_file");
+        config.fee_config_file = current.fee_config_file.clone();
+    }
+
+    for &chain in current.chains.keys() {
+        if !config.chains.contains_key(&chain) {
+            state.monitor.remove_monitor(chain)?;
+            outcome.removed.push(chain);
+        }
+    }
+    let chains: Vec<ChainId> = config.chains.keys().copied().collect();
+    for chain in chains {
+        let previous = current.chains.get(&chain);
+        let next = &config.chains[&chain];
+        let unchanged = previous.is_some_and(|previous| {
+            previous.rpc_url == next.rpc_url
+                && previous.block_frequency == next.block_frequency
+                && previous.gas_limit_multiplier == next.gas_limit_multiplier
+        });
+        if unchanged {
+            state
+                .monitor
+                .set_balance_policy(chain, config.balance_policy.clone())?;
+            continue;
+        }
+
+        match setup_chain(&state.monitor, &config, chain).await {
+            Ok(()) if previous.is_some() => outcome.reconfigured.push(chain),
+            Ok(()) => outcome.added.push(chain),
+            Err(err) => {
+                error!("Could not set up chain {} on reload, {:?}", chain, err);
+                outcome.failed.insert(chain, err.to_string());
+                match previous {
+                    Some(previous) => config.chains.insert(chain, previous.clone()),
+                    None => config.chains.remove(&chain),
+                };
+            }
+        }
+    }
+
+    state.policy.set_policy(policy);
+    if let (Some(fees), Some(fee_config)) = (&state.fees, fee_config) {
+        fees.set_config(fee_config);
+    }
+    state.retention.set_max_age(retention_max_age(&config));
+    *state.config.write().unwrap() = Arc::new(config);
+    Ok(outcome)
 }
 
 #[debug_handler]
 async fn relay_transaction(
     State(state): State<Arc<AppState>>,
+    Extension(api_key): Extension<ApiKey>,
+    headers: HeaderMap,
     Json(payload): Json<RelayRequest>,
 ) -> Result<String, ServerError> {
-    if !SUPPORTED_CHAINS
-        .into_iter()
-        .any(|chain| chain == payload.chain)
+    let idempotency_key = get_idempotency_key(&headers, payload.idempotency_key.as_deref())?;
+    let mut request = build_transaction(&state.config(), &payload, &api_key)?;
+    // Taken before paying the fee changes the transaction, so retries match the first send
+    let client_fingerprint = fingerprint(&request, payload.chain, &payload.depends_on)?;
+    if is_retry(&state, &api_key, idempotency_key.as_deref()).await? {
+        // Hands back the saved request, or rejects a different body
+        let options = SendOptions {
+            idempotency_key,
+            fingerprint: Some(client_fingerprint),
+            depends_on: payload.depends_on.clone(),
+            tenant_id: Some(api_key.tenant_id.clone()),
+            actor: Actor::ApiKey(api_key.id),
+        };
+        let id = state
+            .monitor
+            .send_monitored_transaction(request, payload.chain, options)
+            .await?;
+        return Ok(id.to_string());
+    }
+    check_budget(&state, &api_key).await?;
+    check_capacity(&state, payload.chain, 1).await?;
+    let reservation = state
+        .policy
+        .enforce(&api_key.tenant_id, &[&request])
+        .await?;
+    info!("Transaction: {:?}", request);
+    let mut depends_on = payload.depends_on.clone();
+    let fee_requests = match &payload.fee {
+        Some(fee) => match pay_fee(&state, &api_key, payload.chain, fee, &mut request).await {
+            Ok(ids) => ids,
+            Err(err) => {
+                state.policy.release(&reservation).await?;
+                return Err(err);
+            }
+        },
+        None => vec![],
+    };
+    depends_on.extend(&fee_requests);
+    let options = SendOptions {
+        idempotency_key,
+        fingerprint: Some(client_fingerprint),
+        depends_on,
+        tenant_id: Some(api_key.tenant_id.clone()),
+        actor: Actor::ApiKey(api_key.id),
+    };
+    match state
+        .monitor
+        .send_monitored_transaction(request.clone(), payload.chain, options)
+        .await
     {
+        Ok(id) => {
+            if let (Some(fees), Some(fee)) = (&state.fees, &payload.fee) {
+                fees.record(fee.quote_id, id).await?;
+            }
+            Ok(id.to_string())
+        }
+        Err(err) => {
+            state.policy.release(&reservation).await?;
+            if let (Some(fees), Some(fee)) = (&state.fees, &payload.fee) {
+                for id in fee_requests {
+                    state
+                        .monitor
+                        .cancel_transaction(
+                            id,
+                            Some(&api_key.tenant_id),
+                            &Actor::ApiKey(api_key.id),
+                        )
+                        .await?;
+                }
+                fees.release(fee.quote_id).await?;
+            }
+            Err(err.into())
+        }
+    }
+}
+
+/// Quotes what relaying the transaction costs in a token, the quote is redeemed by
+/// sending the transaction with a `fee` before it expires
+#[debug_handler]
+async fn quote_fee(
+    State(state): State<Arc<AppState>>,
+    Extension(api_key): Extension<ApiKey>,
+    Json(payload): Json<QuoteRequest>,
+) -> Result<Json<fees::Quote>, ServerError> {
+    let fees = fee_engine(&state)?;
+    let chain = payload.call.chain;
+    let request = build_transaction(&state.config(), &payload.call, &api_key)?;
+    state.policy.policy().check(&request)?;
+    let request = state.monitor.prepare(request, chain).await?;
+
+    let provider = state.monitor.provider(chain)?;
+    let (max_fee_per_gas, _) = provider
+        .estimate_eip1559_fees(None)
+        .await
+        .map_err(anyhow::Error::from)?;
+    let quote = fees
+        .quote(
+            &api_key.tenant_id,
+            chain,
+            payload.token,
+            payload.method,
+            provider.address(),
+            payload.payer,
+            &request,
+            max_fee_per_gas,
+        )
+        .await?;
+    Ok(Json(quote))
+}
+
+fn fee_engine(state: &AppState) -> Result<&FeeEngine, ServerError> {
+    state.fees.as_deref().ok_or_else(|| ServerError::Status {
+        status: StatusCode::NOT_FOUND,
+        message: "This relay doesn't accept fees in tokens".to_owned(),
+    })
+}
+
+/// Redeems the fee's quote for `call` and returns the requests that collect the fee,
+/// which the relayed transaction has to wait for. The call's gas limit is held to the quote's.
+async fn pay_fee(
+    state: &AppState,
+    api_key: &ApiKey,
+    chain: ChainId,
+    fee: &FeePayment,
+    call: &mut Eip1559TransactionRequest,
+) -> Result<Vec<Uuid>, ServerError> {
+    let fees = fee_engine(state)?;
+    let provider = state.monitor.provider(chain)?;
+    let relayer = provider.address();
+    let tenant_id = &api_key.tenant_id;
+
+    match &fee.method {
+        FeeMethod::Permit(permit) => {
+            let payment_ref = permit.signature.to_string();
+            let quote = fees
+                .redeem(
+                    fee.quote_id,
+                    tenant_id,
+                    chain,
+                    PaymentMethod::Permit,
+                    permit.owner,
+                    call,
+                    &payment_ref,
+                )
+                .await?;
+            let pulled = pull_fee(
+                state,
+                api_key,
+                chain,
+                permit,
+                quote.token,
+                quote.amount,
+                relayer,
+            )
+            .await;
+            if pulled.is_err() {
+                fees.release(fee.quote_id).await?;
+            }
+            call.gas.get_or_insert(quote.gas_limit);
+            pulled
+        }
+        FeeMethod::Transfer { hash, signature } => {
+            // Anyone can see the transfer on chain, only the payer can say which quote it pays
+            let payer =
+                transfer_signer(fee.quote_id, *hash, signature).map_err(anyhow::Error::from)?;
+            let receipt = provider
+                .get_transaction_receipt(*hash)
+                .await
+                .map_err(anyhow::Error::from)?
+                .ok_or(FeeRejected::Transfer {
+                    hash: *hash,
+                    reason: "it isn't mined",
+                })
+                .map_err(anyhow::Error::from)?;
+            let payment_ref = format!("{:?}", hash);
+            let quote = fees
+                .redeem(
+                    fee.quote_id,
+                    tenant_id,
+                    chain,
+                    PaymentMethod::Transfer,
+                    payer,
+                    call,
+                    &payment_ref,
+                )
+                .await?;
+            if let Err(err) =
+                check_transfer(&receipt, quote.token, quote.payer, relayer, quote.amount)
+            {
+                fees.release(fee.quote_id).await?;
+                return Err(anyhow::Error::from(err).into());
+            }
+            call.gas.get_or_insert(quote.gas_limit);
+            Ok(vec![])
+        }
+    }
+}
+
+/// Queues the permit, then a `transferFrom` of the fee once it's mined.
+/// The permit is cancelled if the `transferFrom` can't be queued.
+async fn pull_fee(
+    state: &AppState,
+    api_key: &ApiKey,
+    chain: ChainId,
+    permit: &Permit,
+    token: Address,
+    amount: U256,
+    relayer: Address,
+) -> Result<Vec<Uuid>, ServerError> {
+    if permit.value < amount {
+        return Err(anyhow::Error::from(FeeRejected::Insufficient {
+            required: amount,
+            offered: permit.value,
+        })
+        .into());
+    }
+    if permit.deadline < U256::from(auth::unix_now()) {
+        return Err(anyhow::Error::from(FeeRejected::PermitExpired).into());
+    }
+
+    let options = SendOptions {
+        tenant_id: Some(api_key.tenant_id.clone()),
+        actor: Actor::ApiKey(api_key.id),
+        ..SendOptions::default()
+    };
+    let permit_tx = Eip1559TransactionRequest::new()
+        .to(token)
+        .data(permit.calldata(relayer)?);
+    let permit_id = state
+        .monitor
+        .send_monitored_transaction(permit_tx, chain, options.clone())
+        .await?;
+    let pull_tx = Eip1559TransactionRequest::new()
+        .to(token)
+        .data(transfer_from_calldata(permit.owner, relayer, amount));
+    let pull_options = SendOptions {
+        depends_on: vec![permit_id],
+        ..options
+    };
+    let pull_id = match state
+        .monitor
+        .send_monitored_transaction(pull_tx, chain, pull_options)
+        .await
+    {
+        Ok(id) => id,
+        Err(err) => {
+            state
+                .monitor
+                .cancel_transaction(
+                    permit_id,
+                    Some(&api_key.tenant_id),
+                    &Actor::ApiKey(api_key.id),
+                )
+                .await?;
+            return Err(err.into());
+        }
+    };
+
+    Ok(vec![pull_id])
+}
+
+#[debug_handler]
+async fn relay_forwarded(
+    State(state): State<Arc<AppState>>,
+    Extension(api_key): Extension<ApiKey>,
+    headers: HeaderMap,
+    Json(payload): Json<ForwardPayload>,
+) -> Result<String, ServerError> {
+    let Some(forwarder) = state
+        .config()
+        .chains
+        .get(&payload.chain)
+        .and_then(|chain| chain.forwarder)
+    else {
+        return Err(ServerError::Status {
+            status: StatusCode::BAD_REQUEST,
+            message: format!("Chain {} has no trusted forwarder", payload.chain),
+        });
+    };
+    let signature =
+        Signature::try_from(payload.signature.as_ref()).map_err(|err| ServerError::Status {
+            status: StatusCode::BAD_REQUEST,
+            message: format!("Invalid signature, {}", err),
+        })?;
+    let idempotency_key = get_idempotency_key(&headers, None)?;
+
+    // Scopes and policies apply to the call the forwarder makes, not the call to the forwarder
+    let request = &payload.request;
+    api_key
+        .authorize(payload.chain, request.to)
+        .map_err(|err| ServerError::Status {
+            status: StatusCode::FORBIDDEN,
+            message: err.to_string(),
+        })?;
+    let call = Eip1559TransactionRequest::new()
+        .to(request.to)
+        .value(request.value)
+        .data(request.data.clone());
+    let idempotency_key = idempotency_key.unwrap_or_else(|| {
+        TypedForwardRequest {
+            request,
+            chain_id: payload.chain.0,
+            forwarder,
+        }
+        .idempotency_key()
+    });
+    let reservation = if is_retry(&state, &api_key, Some(&idempotency_key)).await? {
+        Reservation::default()
+    } else {
+        check_budget(&state, &api_key).await?;
+        check_capacity(&state, payload.chain, 1).await?;
+        state.policy.enforce(&api_key.tenant_id, &[&call]).await?
+    };
+    info!("Forwarded transaction: {:?}", request);
+
+    let options = SendOptions {
+        idempotency_key: Some(idempotency_key),
+        tenant_id: Some(api_key.tenant_id.clone()),
+        actor: Actor::ApiKey(api_key.id),
+        ..SendOptions::default()
+    };
+    match state
+        .monitor
+        .send_forwarded_transaction(
+            payload.request.clone(),
+            signature,
+            payload.chain,
+            forwarder,
+            options,
+        )
+        .await
+    {
+        Ok(id) => Ok(id.to_string()),
+        Err(err) => {
+            state.policy.release(&reservation).await?;
+            Err(err.into())
+        }
+    }
+}
+
+/// Whether the tenant already saved a request with this key. Retries don't relay anything new,
+/// so they skip the budget, capacity, policy and fee.
+async fn is_retry(
+    state: &AppState,
+    api_key: &ApiKey,
+    idempotency_key: Option<&str>,
+) -> Result<bool, ServerError> {
+    let Some(key) = idempotency_key else {
+        return Ok(false);
+    };
+    let saved = state
+        .monitor
+        .tx_repo
+        .get_by_idempotency_key(Some(&api_key.tenant_id), key)
+        .await?;
+    Ok(saved.is_some())
+}
+
+#[debug_handler]
+async fn relay_batch(
+    State(state): State<Arc<AppState>>,
+    Extension(api_key): Extension<ApiKey>,
+    headers: HeaderMap,
+    Json(payloads): Json<Vec<RelayRequest>>,
+) -> Result<(StatusCode, Json<BatchResponse>), ServerError> {
+    if headers.contains_key(IDEMPOTENCY_KEY_HEADER) {
+        return Err(ServerError::Status {
+            status: StatusCode::BAD_REQUEST,
+            message: "Idempotency-Key header is not supported for batches".to_owned(),
+        });
+    }
+    if payloads.is_empty() || payloads.len() > MAX_BATCH_SIZE {
         return Err(ServerError::Status {
             status: StatusCode::BAD_REQUEST,
             message: format!(
-                "Chain {:?} is not supported, this relay is setup for {:?}",
-                payload.chain, SUPPORTED_CHAINS
+                "A batch must contain between 1 and {} transactions",
+                MAX_BATCH_SIZE
             ),
         });
     }
 
+    check_budget(&state, &api_key).await?;
+
+    // Check everything we can locally before simulating anything
+    let mut txs = Vec::with_capacity(payloads.len());
+    let mut errors = Vec::new();
+    for (index, payload) in payloads.iter().enumerate() {
+        if payload.idempotency_key.is_some() {
+            errors.push((
+                index,
+                ServerError::Status {
+                    status: StatusCode::BAD_REQUEST,
+                    message: "idempotency keys are not supported for batched transactions"
+                        .to_owned(),
+                },
+            ));
+            continue;
+        }
+        if payload.fee.is_some() {
+            errors.push((
+                index,
+                ServerError::Status {
+                    status: StatusCode::BAD_REQUEST,
+                    message: "fees are not supported for batched transactions".to_owned(),
+                },
+            ));
+            continue;
+        }
+
+        let checked = build_transaction(&state.config(), payload, &api_key).and_then(|tx| {
+            state.policy.policy().check(&tx)?;
+            Ok(tx)
+        });
+        match checked {
+            Ok(tx) => txs.push((tx, payload.chain, payload.depends_on.clone())),
+            Err(err) => errors.push((index, err)),
+        }
+    }
+
+    if errors.is_empty() {
+        info!("Batch of {} transactions", txs.len());
+        let mut per_chain: HashMap<ChainId, u32> = HashMap::new();
+        for (_, chain, _) in &txs {
+            *per_chain.entry(*chain).or_default() += 1;
+        }
+        for (chain, count) in per_chain {
+            check_capacity(&state, chain, count).await?;
+        }
+
+        let reserved: Vec<_> = txs.iter().map(|(tx, _, _)| tx.clone()).collect();
+        let reserved: Vec<_> = reserved.iter().collect();
+        let reservation = state.policy.reserve(&api_key.tenant_id, &reserved).await?;
+        let sent = state
+            .monitor
+            .send_monitored_batch(
+                txs,
+                Some(api_key.tenant_id.clone()),
+                Actor::ApiKey(api_key.id),
+            )
+            .await;
+        if sent.is_err() {
+            state.policy.release(&reservation).await?;
+        }
+        match sent {
+            Ok(ids) => {
+                let results = ids.into_iter().enumerate().map(BatchItemResult::queued);
+                return Ok((StatusCode::OK, Json(BatchResponse::new(true, results))));
+            }
+            Err(err) => match err.downcast::<BatchRejected>() {
+                Ok(BatchRejected { errors: rejected }) => errors.extend(
+                    rejected
+                        .into_iter()
+                        .map(|(index, err)| (index, ServerError::from(err))),
+                ),
+                Err(err) => return Err(err.into()),
+            },
+        }
+    }
+
+    let results = errors
+        .into_iter()
+        .map(|(index, err)| BatchItemResult::failed(index, err));
+    Ok((
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(BatchResponse::new(false, results)),
+    ))
+}
+
+fn build_transaction(
+    config: &Config,
+    payload: &RelayRequest,
+    api_key: &ApiKey,
+) -> Result<Eip1559TransactionRequest, ServerError> {
+    if !config.chains.contains_key(&payload.chain) {
+        return Err(ServerError::Status {
+            status: StatusCode::BAD_REQUEST,
+            message: format!(
+                "Chain {} is not supported, this relay is setup for {}",
+                payload.chain,
+                config
+                    .chains
+                    .keys()
+                    .map(ChainId::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        });
+    }
+
+    api_key
+        .authorize(payload.chain, payload.to)
+        .map_err(|err| ServerError::Status {
+            status: StatusCode::FORBIDDEN,
+            message: err.to_string(),
+        })?;
+
     let mut request = Eip1559TransactionRequest::new()
         .to(payload.to)
         .value(payload.value)
         .max_priority_fee_per_gas(1);
-    request.data = payload.data.map(|data| data.into());
-    info!("Transaction: {:?}", request);
-    let id = state
-        .monitor
-        .send_monitored_transaction(request, payload.chain)
-        .await?;
+    request.gas = payload.gas.map(|gas| gas.into());
+    request.data = payload.data.clone().map(|data| data.into());
+    Ok(request)
+}
+
+/// Budgets are checked against what the tenant's mined transactions cost,
+/// requests still in flight can take a tenant slightly over
+async fn check_budget(state: &AppState, api_key: &ApiKey) -> Result<(), ServerError> {
+    let Some(budget) = api_key.spend_budget else {
+        return Ok(());
+    };
+
+    let spent = state.monitor.tx_repo.get_spend(&api_key.tenant_id).await?;
+    if spent >= budget {
+        return Err(ServerError::Status {
+            status: StatusCode::FORBIDDEN,
+            message: format!(
+                "Spend budget of {} wei is used up, {} wei spent",
+                budget, spent
+            ),
+        });
+    }
+
+    Ok(())
+}
+
+/// Turns away work the chain can't keep up with, `count` is how many transactions are coming
+async fn check_capacity(state: &AppState, chain: ChainId, count: u32) -> Result<(), ServerError> {
+    if let Some(limit) = state.config().chain_rate_limit_per_minute {
+        state
+            .chain_limiter
+            .check(chain, limit, count)
+            .map_err(|retry_after| ServerError::Throttled {
+                status: StatusCode::TOO_MANY_REQUESTS,
+                message: format!("Rate limit exceeded for chain {}", chain),
+                retry_after,
+            })?;
+    }
+
+    if let Some(max_in_flight) = state.config().max_in_flight_per_chain {
+        let in_flight = state.monitor.tx_repo.count_in_flight(chain).await?;
+        if in_flight >= max_in_flight {
+            return Err(ServerError::Throttled {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                message: format!(
+                    "Chain {} has {} transactions in flight, try again later",
+                    chain, in_flight
+                ),
+                retry_after: BACKPRESSURE_RETRY_AFTER,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+static BATCH_ORDERING: &str = "The batch is queued all or nothing. \
+    Transactions for the same chain are broadcast in array order with consecutive nonces, \
+    transactions for different chains are independent of each other. \
+    Each transaction is simulated on its own against the latest block, not after the ones before it. \
+    Transactions with depends_on are held until their dependencies are mined, \
+    so they are not part of the consecutive nonce sequence.";
+
+#[derive(Serialize)]
+struct BatchResponse {
+    queued: bool,
+    ordering: &'static str,
+    results: Vec<BatchItemResult>,
+}
+
+impl BatchResponse {
+    fn new(queued: bool, results: impl Iterator<Item = BatchItemResult>) -> Self {
+        Self {
+            queued,
+            ordering: BATCH_ORDERING,
+            results: results.collect(),
+        }
+    }
+}
 
-    Ok(id.to_string())
+#[derive(Serialize)]
+struct BatchItemResult {
+    index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revert: Option<RevertReason>,
+}
+
+impl BatchItemResult {
+    fn queued((index, id): (usize, Uuid)) -> Self {
+        Self {
+            index,
+            id: Some(id),
+            error: None,
+            revert: None,
+        }
+    }
+
+    fn failed(index: usize, err: ServerError) -> Self {
+        let (error, revert) = match err {
+            ServerError::Fallback(err) => (err.to_string(), None),
+            ServerError::Status { message, .. } | ServerError::Throttled { message, .. } => {
+                (message, None)
+            }
+            ServerError::Reverted(revert) => (
+                format!("transaction would revert, {}", revert),
+                Some(revert),
+            ),
+        };
+
+        Self {
+            index,
+            id: None,
+            error: Some(error),
+            revert,
+        }
+    }
+}
+
+/// The `Idempotency-Key` header, or the body's `idempotency_key` field when the route has one
+fn get_idempotency_key(
+    headers: &HeaderMap,
+    field: Option<&str>,
+) -> Result<Option<String>, ServerError> {
+    let header = match headers.get(IDEMPOTENCY_KEY_HEADER) {
+        Some(value) => Some(
+            value
+                .to_str()
+                .map_err(|_| ServerError::Status {
+                    status: StatusCode::BAD_REQUEST,
+                    message: "Idempotency-Key header must be visible ascii".to_owned(),
+                })?
+                .to_owned(),
+        ),
+        None => None,
+    };
+
+    let key = match (header, field) {
+        (Some(header), Some(field)) if header != field => {
+            return Err(ServerError::Status {
+                status: StatusCode::BAD_REQUEST,
+                message: "Idempotency-Key header and idempotency_key field do not match".to_owned(),
+            })
+        }
+        (header, field) => header.or_else(|| field.map(str::to_owned)),
+    };
+
+    // Has to fit the requests.idempotency_key column
+    if key
+        .as_ref()
+        .is_some_and(|key| key.is_empty() || key.len() > 255)
+    {
+        return Err(ServerError::Status {
+            status: StatusCode::BAD_REQUEST,
+            message: "Idempotency key must be between 1 and 255 characters".to_owned(),
+        });
+    }
+
+    Ok(key)
+}
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Serialize)]
+struct RpcResponse {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(flatten)]
+    outcome: RpcOutcome,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum RpcOutcome {
+    Result(Value),
+    Error { code: i64, message: String },
+}
+
+impl RpcOutcome {
+    fn error(code: i64, message: impl Into<String>) -> Self {
+        RpcOutcome::Error {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<ServerError> for RpcOutcome {
+    fn from(err: ServerError) -> Self {
+        match err {
+            ServerError::Fallback(err) => err.into(),
+            ServerError::Status { message, .. } => RpcOutcome::error(-32602, message),
+            ServerError::Throttled { message, .. } => RpcOutcome::error(-32504, message),
+            ServerError::Reverted(revert) => RpcOutcome::error(-32500, revert.to_string()),
+        }
+    }
+}
+
+impl From<anyhow::Error> for RpcOutcome {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast_ref::<UserOpRejected>() {
+            Some(rejected) => RpcOutcome::error(rejected.code(), rejected.to_string()),
+            None => RpcOutcome::error(-32603, err.to_string()),
+        }
+    }
+}
+
+/// ERC-4337 bundler JSON-RPC, one endpoint per chain
+async fn bundler_rpc(
+    State(state): State<Arc<AppState>>,
+    Extension(api_key): Extension<ApiKey>,
+    Path(chain): Path<ChainId>,
+    Json(request): Json<RpcRequest>,
+) -> Json<RpcResponse> {
+    let outcome = if !state.config().chains.contains_key(&chain) {
+        RpcOutcome::error(-32602, format!("Chain {} is not supported", chain))
+    } else if let Err(err) = api_key.authorize(chain, state.bundler.entry_point) {
+        RpcOutcome::error(-32602, err.to_string())
+    } else {
+        bundler_method(&state, &api_key, chain, &request.method, request.params).await
+    };
+
+    Json(RpcResponse {
+        jsonrpc: "2.0",
+        id: request.id,
+        outcome,
+    })
+}
+
+async fn bundler_method(
+    state: &AppState,
+    api_key: &ApiKey,
+    chain: ChainId,
+    method: &str,
+    params: Value,
+) -> RpcOutcome {
+    let invalid_params = |err: serde_json::Error| RpcOutcome::error(-32602, err.to_string());
+    match method {
+        "eth_chainId" => RpcOutcome::Result(json!(U256::from(chain.0))),
+        "eth_supportedEntryPoints" => RpcOutcome::Result(json!([state.bundler.entry_point])),
+        "eth_sendUserOperation" => {
+            let (op, entry_point) = match serde_json::from_value::<(UserOperation, Address)>(params)
+            {
+                Ok(params) => params,
+                Err(err) => return invalid_params(err),
+            };
+            if let Err(err) = check_user_operation(state, api_key, chain, &op).await {
+                return err.into();
+            }
+            match state
+                .bundler
+                .send_user_operation(op, entry_point, chain, &api_key.tenant_id)
+                .await
+            {
+                Ok(hash) => RpcOutcome::Result(json!(hash)),
+                Err(err) => err.into(),
+            }
+        }
+        "eth_getUserOperationReceipt" => {
+            let (hash,) = match serde_json::from_value::<(H256,)>(params) {
+                Ok(params) => params,
+                Err(err) => return invalid_params(err),
+            };
+            match state
+                .bundler
+                .get_user_operation_receipt(hash, &api_key.tenant_id)
+                .await
+            {
+                Ok(receipt) => RpcOutcome::Result(json!(receipt)),
+                Err(err) => err.into(),
+            }
+        }
+        _ => RpcOutcome::error(-32601, format!("Method {} not found", method)),
+    }
+}
+
+/// The budget, capacity and policy checks `/transaction` runs, on the `handleOps` call that
+/// would carry the operation. Bundles mix tenants, their gas isn't counted toward any spend.
+async fn check_user_operation(
+    state: &AppState,
+    api_key: &ApiKey,
+    chain: ChainId,
+    op: &UserOperation,
+) -> Result<(), ServerError> {
+    check_budget(state, api_key).await?;
+    check_capacity(state, chain, 1).await?;
+    let beneficiary = state.monitor.provider(chain)?.address();
+    let call = Eip1559TransactionRequest::new()
+        .to(state.bundler.entry_point)
+        .data(handle_ops_calldata(slice::from_ref(op), beneficiary));
+    // handleOps sends no value, so there's nothing to reserve against the daily limit
+    state
+        .policy
+        .policy()
+        .check(&call)
+        .map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// The relayer's balance on each chain, as of the latest block
+async fn balances(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<HashMap<ChainId, BalanceStatus>>, ServerError> {
+    let mut balances = HashMap::new();
+    for &chain in state.config().chains.keys() {
+        balances.insert(chain, state.monitor.balance(chain)?);
+    }
+    Ok(Json(balances))
+}
+
+/// Prometheus text format
+async fn metrics() -> String {
+    format!(
+        "# TYPE relay_invalid_requests_total counter\nrelay_invalid_requests_total {}\n",
+        invalid_records()
+    )
 }
 
 #[derive(Deserialize, Serialize)]
 struct TransactionStatus {
+    status: RequestStatus,
     mined: bool,
-    hash: TxHash,
+    hash: Option<TxHash>,
 }
 
 async fn transaction_status(
     State(state): State<Arc<AppState>>,
+    Extension(api_key): Extension<ApiKey>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<TransactionStatus>, ServerError> {
+    let tenant_id = Some(api_key.tenant_id.as_str());
+    match state.monitor.get_transaction_status(id, tenant_id).await? {
+        Some((status, hash)) => Ok(Json(TransactionStatus {
+            status,
+            mined: status == RequestStatus::Mined,
+            hash,
+        })),
+        None => Err(ServerError::Status {
+            status: StatusCode::NOT_FOUND,
+            message: format!("Could not find transaction with id {:?}", id),
+        }),
+    }
+}
+
+async fn cancel_transaction(
+    State(state): State<Arc<AppState>>,
+    Extension(api_key): Extension<ApiKey>,
     Path(id): Path<Uuid>,
 ) -> Result<Json<TransactionStatus>, ServerError> {
-    match state.monitor.get_transaction_status(id).await? {
-        Some((mined, hash)) => Ok(Json(TransactionStatus { mined, hash })),
+    let tenant_id = Some(api_key.tenant_id.as_str());
+    let actor = Actor::ApiKey(api_key.id);
+    let cancelled = state
+        .monitor
+        .cancel_transaction(id, tenant_id, &actor)
+        .await?;
+    match state.monitor.get_transaction_status(id, tenant_id).await? {
+        Some((status, hash)) if cancelled => Ok(Json(TransactionStatus {
+            status,
+            mined: false,
+            hash,
+        })),
+        Some((status, _)) => Err(ServerError::Status {
+            status: StatusCode::CONFLICT,
+            message: format!(
+                "Transaction {:?} is {} and can no longer be cancelled",
+                id,
+                status.as_str()
+            ),
+        }),
+        None => Err(ServerError::Status {
+            status: StatusCode::NOT_FOUND,
+            message: format!("Could not find transaction with id {:?}", id),
+        }),
+    }
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    chain: Option<ChainId>,
+    status: Option<RequestStatus>,
+    to: Option<Address>,
+    sender: Option<Address>,
+    tenant: Option<String>,
+    /// Unix milliseconds, inclusive
+    created_after: Option<u64>,
+    /// Unix milliseconds, exclusive
+    created_before: Option<u64>,
+    cursor: Option<String>,
+    limit: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct ListResponse {
+    requests: Vec<ListedTransaction>,
+    /// Pass as `cursor` to get the next page, missing on the last one
+    next_cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ListedTransaction {
+    id: Uuid,
+    chain: ChainId,
+    status: RequestStatus,
+    hash: Option<TxHash>,
+    to: Option<Address>,
+    from: Option<Address>,
+    value: Option<U256>,
+    batch_id: Option<Uuid>,
+    created_at: u64,
+    updated_at: u64,
+}
+
+impl From<ListedRequest> for ListedTransaction {
+    fn from(listed: ListedRequest) -> Self {
+        let request = listed.request;
+        ListedTransaction {
+            id: request.id,
+            chain: request.chain,
+            status: request.status,
+            hash: request.hash,
+            to: request
+                .tx
+                .to
+                .as_ref()
+                .and_then(|to| to.as_address().copied()),
+            from: request.tx.from,
+            value: request.tx.value,
+            batch_id: request.batch_id,
+            created_at: listed.created_at,
+            updated_at: listed.updated_at,
+        }
+    }
+}
+
+async fn list_transactions(
+    State(state): State<Arc<AppState>>,
+    Extension(api_key): Extension<ApiKey>,
+    Query(query): Query<ListQuery>,
+) -> Result<Json<ListResponse>, ServerError> {
+    // Keys only ever see their own tenant's requests
+    if query
+        .tenant
+        .as_ref()
+        .is_some_and(|tenant| *tenant != api_key.tenant_id)
+    {
+        return Err(ServerError::Status {
+            status: StatusCode::FORBIDDEN,
+            message: "API key can't list another tenant's requests".to_string(),
+        });
+    }
+    let cursor = query
+        .cursor
+        .as_deref()
+        .map(Cursor::from_str)
+        .transpose()
+        .map_err(|err| ServerError::Status {
+            status: StatusCode::BAD_REQUEST,
+            message: err.to_string(),
+        })?;
+    let filter = RequestFilter {
+        chain: query.chain,
+        status: query.status,
+        to: query.to,
+        sender: query.sender,
+        tenant_id: Some(api_key.tenant_id.clone()),
+        created_after: query.created_after,
+        created_before: query.created_before,
+    };
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .clamp(1, MAX_LIST_LIMIT);
+
+    let (requests, next) = state.monitor.tx_repo.list(&filter, cursor, limit).await?;
+    Ok(Json(ListResponse {
+        requests: requests.into_iter().map(ListedTransaction::from).collect(),
+        next_cursor: next.map(|cursor| cursor.to_string()),
+    }))
+}
+
+/// The request's audit log, oldest first. It's still there once the request has been archived.
+async fn transaction_events(
+    State(state): State<Arc<AppState>>,
+    Extension(api_key): Extension<ApiKey>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<Vec<RequestEvent>>, ServerError> {
+    let events = state.monitor.tx_repo.get_events(id).await?;
+    // Another tenant's request is missing, like its status
+    match events.first() {
+        Some(event) if event.tenant_id.as_ref() == Some(&api_key.tenant_id) => Ok(Json(events)),
+        _ => Err(ServerError::Status {
+            status: StatusCode::NOT_FOUND,
+            message: format!("Could not find transaction with id {:?}", id),
+        }),
+    }
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    /// The `seq` of the last event already exported
+    after: Option<u64>,
+    limit: Option<u32>,
+}
+
+/// The tenant's events as JSON lines, oldest first
+async fn export_events(
+    State(state): State<Arc<AppState>>,
+    Extension(api_key): Extension<ApiKey>,
+    Query(query): Query<ExportQuery>,
+) -> Result<impl IntoResponse, ServerError> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_EXPORT_LIMIT)
+        .clamp(1, MAX_EXPORT_LIMIT);
+    let events = state
+        .monitor
+        .tx_repo
+        .list_events(
+            Some(&api_key.tenant_id),
+            query.after.unwrap_or_default(),
+            limit,
+        )
+        .await?;
+
+    let mut lines = String::new();
+    for event in events {
+        lines.push_str(&serde_json::to_string(&event).map_err(anyhow::Error::from)?);
+        lines.push('\n');
+    }
+    Ok(([(CONTENT_TYPE, "application/x-ndjson")], lines))
+}
+
+#[derive(Serialize)]
+struct AdminChainStatus {
+    #[serde(flatten)]
+    state: ChainState,
+    /// Queued and submitted requests, a draining chain is done once this is 0
+    in_flight: u64,
+    /// Removed by a reload, its monitor stops once nothing's in flight
+    retiring: bool,
+}
+
+fn configured_chain(state: &AppState, chain: ChainId) -> Result<ChainId, ServerError> {
+    if !state.monitor.chains().contains(&chain) {
+        return Err(ServerError::Status {
+            status: StatusCode::NOT_FOUND,
+            message: format!("Chain {} has no monitor", chain),
+        });
+    }
+    Ok(chain)
+}
+
+/// Every chain's switches and how much it still has in flight
+async fn admin_chains(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<HashMap<ChainId, AdminChainStatus>>, ServerError> {
+    let active = state
+        .monitor
+        .chains()
+        .into_iter()
+        .map(|chain| (chain, false));
+    let retiring = state.monitor.retiring_chains().into_iter();
+    let mut chains = HashMap::new();
+    for (chain, retiring) in active.chain(retiring.map(|chain| (chain, true))) {
+        let status = AdminChainStatus {
+            state: state.monitor.chain_state(chain)?,
+            in_flight: state.monitor.tx_repo.count_in_flight(chain).await?,
+            retiring,
+        };
+        chains.insert(chain, status);
+    }
+    Ok(Json(chains))
+}
+
+/// Applies the config file's current chains, policy, fee config, retention and limits
+async fn reload_config(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<ReloadOutcome>, ServerError> {
+    let outcome = reload(&state).await.map_err(|err| ServerError::Status {
+        status: StatusCode::UNPROCESSABLE_ENTITY,
+        message: err.to_string(),
+    })?;
+    info!("Admin reloaded the config, {:?}", outcome);
+    Ok(Json(outcome))
+}
+
+async fn pause_chain(
+    State(state): State<Arc<AppState>>,
+    Path(chain): Path<ChainId>,
+) -> Result<Json<ChainState>, ServerError> {
+    let chain = configured_chain(&state, chain)?;
+    state.monitor.set_paused(chain, true)?;
+    info!("Admin paused chain {}", chain);
+    Ok(Json(state.monitor.chain_state(chain)?))
+}
+
+/// Undoes both pausing and draining
+async fn resume_chain(
+    State(state): State<Arc<AppState>>,
+    Path(chain): Path<ChainId>,
+) -> Result<Json<ChainState>, ServerError> {
+    let chain = configured_chain(&state, chain)?;
+    state.monitor.set_draining(chain, false)?;
+    state.monitor.set_paused(chain, false)?;
+    info!("Admin resumed chain {}", chain);
+    Ok(Json(state.monitor.chain_state(chain)?))
+}
+
+async fn drain_chain(
+    State(state): State<Arc<AppState>>,
+    Path(chain): Path<ChainId>,
+) -> Result<Json<ChainState>, ServerError> {
+    let chain = configured_chain(&state, chain)?;
+    state.monitor.set_draining(chain, true)?;
+    info!("Admin is draining chain {}", chain);
+    Ok(Json(state.monitor.chain_state(chain)?))
+}
+
+#[derive(Serialize)]
+struct NonceResponse {
+    next_nonce: U256,
+}
+
+async fn resync_nonce(
+    State(state): State<Arc<AppState>>,
+    Path(chain): Path<ChainId>,
+) -> Result<Json<NonceResponse>, ServerError> {
+    let chain = configured_chain(&state, chain)?;
+    let next_nonce = state.monitor.resync_nonce(chain).await?;
+    Ok(Json(NonceResponse { next_nonce }))
+}
+
+#[derive(Deserialize)]
+struct RebroadcastRequest {
+    max_fee_per_gas: Numeric,
+    max_priority_fee_per_gas: Numeric,
+}
+
+/// Replaces a submitted transaction with one paying the given fees
+async fn force_rebroadcast(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<Uuid>,
+    Json(payload): Json<RebroadcastRequest>,
+) -> Result<Json<TransactionStatus>, ServerError> {
+    let max_fee = U256::from(payload.max_fee_per_gas);
+    let max_priority_fee = U256::from(payload.max_priority_fee_per_gas);
+    if max_priority_fee > max_fee {
+        return Err(ServerError::Status {
+            status: StatusCode::BAD_REQUEST,
+            message: "max_priority_fee_per_gas can't be above max_fee_per_gas".to_owned(),
+        });
+    }
+
+    let replaced = state
+        .monitor
+        .force_rebroadcast(id, max_fee, max_priority_fee, &Actor::Admin)
+        .await?;
+    admin_outcome(&state, id, replaced.is_some(), "rebroadcast").await
+}
+
+/// Fails the request whatever stage it's at, a submitted transaction stops being tracked
+async fn abandon_transaction(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<TransactionStatus>, ServerError> {
+    let abandoned = state.monitor.abandon_transaction(id, &Actor::Admin).await?;
+    admin_outcome(&state, id, abandoned, "abandoned").await
+}
+
+/// The request's status once an override went through, `409` if its status didn't allow it
+async fn admin_outcome(
+    state: &AppState,
+    id: Uuid,
+    done: bool,
+    action: &str,
+) -> Result<Json<TransactionStatus>, ServerError> {
+    match state.monitor.get_transaction_status(id, None).await? {
+        Some((status, hash)) if done => Ok(Json(TransactionStatus {
+            status,
+            mined: status == RequestStatus::Mined,
+            hash,
+        })),
+        Some((status, _)) => Err(ServerError::Status {
+            status: StatusCode::CONFLICT,
+            message: format!(
+                "Transaction {:?} is {} and can't be {}",
+                id,
+                status.as_str(),
+                action
+            ),
+        }),
         None => Err(ServerError::Status {
             status: StatusCode::NOT_FOUND,
             message: format!("Could not find transaction with id {:?}", id),
@@ -203,7 +1777,53 @@ struct RelayRequest {
     #[serde(default)]
     #[serde(deserialize_with = "hex_opt")]
     data: Option<Vec<u8>>,
-    chain: Chain,
+    chain: ChainId,
+    #[serde(default)]
+    gas: Option<Numeric>,
+    #[serde(default)]
+    idempotency_key: Option<String>,
+    /// Ids of requests that must be mined successfully before this one is sent
+    #[serde(default)]
+    depends_on: Vec<Uuid>,
+    /// Pays for the transaction in a token, with a quote from `POST /fee/quote`
+    #[serde(default)]
+    fee: Option<FeePayment>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FeePayment {
+    quote_id: Uuid,
+    #[serde(flatten)]
+    method: FeeMethod,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum FeeMethod {
+    Permit(Permit),
+    Transfer {
+        hash: H256,
+        /// The payer's signature over `transfer_authorization`
+        signature: Bytes,
+    },
+}
+
+#[derive(Deserialize)]
+struct QuoteRequest {
+    #[serde(flatten)]
+    call: RelayRequest,
+    token: Address,
+    method: PaymentMethod,
+    /// The permit's owner, or whoever sends the transfer
+    payer: Address,
+}
+
+#[derive(Debug, Deserialize)]
+struct ForwardPayload {
+    chain: ChainId,
+    request: ForwardRequest,
+    /// The user's signature over the request's typed data
+    signature: Bytes,
 }
 
 impl fmt::Debug for RelayRequest {
@@ -211,6 +1831,7 @@ impl fmt::Debug for RelayRequest {
         f.debug_struct("Relay Request")
             .field("to", &self.to)
             .field("data", &self.data) // TODO add value here
+            .field("gas", &self.gas.map(U256::from))
             .finish()
     }
 }
@@ -218,10 +1839,88 @@ impl fmt::Debug for RelayRequest {
 #[derive(Debug, Error)]
 pub enum ServerError {
     #[error(transparent)]
-    Fallback(#[from] anyhow::Error),
+    Fallback(anyhow::Error),
 
     #[error("status {status:?}, message {message:?}")]
     Status { status: StatusCode, message: String },
+
+    #[error("transaction reverted in simulation: {0}")]
+    Reverted(RevertReason),
+
+    #[error("status {status:?}, message {message:?}, retry after {retry_after:?}")]
+    Throttled {
+        status: StatusCode,
+        message: String,
+        retry_after: Duration,
+    },
+}
+
+impl From<anyhow::Error> for ServerError {
+    fn from(err: anyhow::Error) -> Self {
+        if err.is::<PolicyViolation>() {
+            return ServerError::Status {
+                status: StatusCode::FORBIDDEN,
+                message: err.to_string(),
+            };
+        }
+        if err.is::<InvalidDependency>() {
+            return ServerError::Status {
+                status: StatusCode::UNPROCESSABLE_ENTITY,
+                message: err.to_string(),
+            };
+        }
+        if err.is::<InsufficientBalance>() {
+            return ServerError::Throttled {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                message: err.to_string(),
+                retry_after: BACKPRESSURE_RETRY_AFTER,
+            };
+        }
+        if err.is::<FeeRejected>() {
+            return ServerError::Status {
+                status: StatusCode::PAYMENT_REQUIRED,
+                message: err.to_string(),
+            };
+        }
+        if err.is::<ForwardRejected>() {
+            return ServerError::Status {
+                status: StatusCode::UNPROCESSABLE_ENTITY,
+                message: err.to_string(),
+            };
+        }
+        if err.is::<ChainDraining>() {
+            return ServerError::Status {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                message: err.to_string(),
+            };
+        }
+        if err.is::<IdempotencyConflict>() {
+            return ServerError::Status {
+                status: StatusCode::CONFLICT,
+                message: err.to_string(),
+            };
+        }
+
+        match err.downcast::<SimulationError>() {
+            Ok(SimulationError { reason }) => ServerError::Reverted(reason),
+            Err(err) => ServerError::Fallback(err),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RevertedResponse {
+    message: String,
+    revert: RevertReason,
+}
+
+impl From<PolicyViolation> for ServerError {
+    fn from(err: PolicyViolation) -> Self {
+        ServerError::Status {
+            status: StatusCode::FORBIDDEN,
+            message: err.to_string(),
+        }
+    }
 }
 
 impl IntoResponse for ServerError {
@@ -229,10 +1928,25 @@ impl IntoResponse for ServerError {
         match self {
             ServerError::Fallback(err) => {
                 let message = format!("something went wrong: {}", err);
-                (StatusCode::INTERNAL_SERVER_ERROR, message)
+                (StatusCode::INTERNAL_SERVER_ERROR, message).into_response()
+            }
+            ServerError::Status { status, message } => (status, message).into_response(),
+            ServerError::Throttled {
+                status,
+                message,
+                retry_after,
+            } => {
+                // Retry-After is in whole seconds, round up so clients don't retry too early
+                let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                (status, [(RETRY_AFTER, seconds.to_string())], message).into_response()
+            }
+            ServerError::Reverted(revert) => {
+                let body = RevertedResponse {
+                    message: format!("transaction would revert, {}", revert),
+                    revert,
+                };
+                (StatusCode::UNPROCESSABLE_ENTITY, Json(body)).into_response()
             }
-            ServerError::Status { status, message } => (status, message),
         }
-        .into_response()
     }
 }
@@ -0,0 +1,408 @@
+use std::{
+    fs,
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use ethers::{
+    abi::{AbiParser, Function, Token},
+    types::{
+        serde_helpers::deserialize_stringified_numeric_opt, Address, Eip1559TransactionRequest,
+        U256,
+    },
+};
+use serde::Deserialize;
+use sqlx::query;
+use thiserror::Error;
+
+use crate::auth::unix_now;
+use crate::database::{with_pool, DbPool, Dialect};
+
+const GWEI: u64 = 1_000_000_000;
+
+#[derive(Debug, Error)]
+#[error("rejected by policy rule {rule:?}, {reason}")]
+pub struct PolicyViolation {
+    pub rule: String,
+    pub reason: String,
+}
+
+impl PolicyViolation {
+    fn new(rule: impl Into<String>, reason: impl Into<String>) -> Self {
+        Self {
+            rule: rule.into(),
+            reason: reason.into(),
+        }
+    }
+}
+
+/// What the relay is willing to sign, loaded from a TOML file.
+/// Every limit is optional, an empty policy allows everything.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    /// Wei a single transaction may send
+    #[serde(default, deserialize_with = "deserialize_stringified_numeric_opt")]
+    pub max_value: Option<U256>,
+    /// Wei each tenant may send per UTC day, across all of its transactions
+    #[serde(default, deserialize_with = "deserialize_stringified_numeric_opt")]
+    pub daily_value_limit: Option<U256>,
+    /// Destinations transactions may be sent to, any if unset
+    pub contracts: Option<Vec<ContractRule>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ContractRule {
+    pub name: String,
+    pub address: Address,
+    /// Functions that may be called, any calldata if unset
+    pub functions: Option<Vec<FunctionRule>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "RawFunctionRule")]
+pub struct FunctionRule {
+    pub function: Function,
+    pub args: Vec<ArgRule>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawFunctionRule {
+    /// i.e. `transfer(address to, uint256 amount)`
+    signature: String,
+    #[serde(default)]
+    args: Vec<ArgRule>,
+}
+
+impl TryFrom<RawFunctionRule> for FunctionRule {
+    type Error = String;
+
+    fn try_from(raw: RawFunctionRule) -> Result<Self, Self::Error> {
+        let function = AbiParser::default()
+            .parse_function(&raw.signature)
+            .map_err(|err| format!("invalid function signature {:?}, {}", raw.signature, err))?;
+        if let Some(arg) = raw
+            .args
+            .iter()
+            .find(|arg| arg.index >= function.inputs.len())
+        {
+            return Err(format!(
+                "{} has no argument at index {}",
+                raw.signature, arg.index
+            ));
+        }
+
+        Ok(FunctionRule {
+            function,
+            args: raw.args,
+        })
+    }
+}
+
+/// Constrains one decoded argument of a function call
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ArgRule {
+    pub index: usize,
+    /// For address arguments
+    pub allowed: Option<Vec<Address>>,
+    /// For uint arguments
+    #[serde(default, deserialize_with = "deserialize_stringified_numeric_opt")]
+    pub max: Option<U256>,
+}
+
+impl Policy {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        Ok(toml::from_str(&contents)?)
+    }
+
+    /// Checks everything that doesn't depend on what the tenant already sent
+    pub fn check(&self, tx: &Eip1559TransactionRequest) -> Result<(), PolicyViolation> {
+        let value = tx.value.unwrap_or_default();
+        if let Some(max_value) = self.max_value {
+            if value > max_value {
+                return Err(PolicyViolation::new(
+                    "max_value",
+                    format!("value {} is over the limit of {} wei", value, max_value),
+                ));
+            }
+        }
+
+        let Some(contracts) = &self.contracts else {
+            return Ok(());
+        };
+        let to = tx.to.as_ref().and_then(|to| to.as_address()).copied();
+        let Some(contract) = contracts
+            .iter()
+            .find(|contract| Some(contract.address) == to)
+        else {
+            return Err(PolicyViolation::new(
+                "contracts",
+                format!("{:?} is not an allowed destination", to.unwrap_or_default()),
+            ));
+        };
+
+        match &contract.functions {
+            Some(functions) => contract.check_call(functions, tx),
+            None => Ok(()),
+        }
+    }
+}
+
+impl ContractRule {
+    fn check_call(
+        &self,
+        functions: &[FunctionRule],
+        tx: &Eip1559TransactionRequest,
+    ) -> Result<(), PolicyViolation> {
+        let rule = format!("{}.functions", self.name);
+        let data = tx.data.as_deref().unwrap_or_default();
+        if data.len() < 4 {
+            return Err(PolicyViolation::new(
+                rule,
+                "calldata has no function selector",
+            ));
+        }
+
+        let (selector, args) = data.split_at(4);
+        let Some(allowed) = functions
+            .iter()
+            .find(|allowed| allowed.function.short_signature() == selector)
+        else {
+            return Err(PolicyViolation::new(
+                rule,
+                format!("selector 0x{} is not allowed", hex::encode(selector)),
+            ));
+        };
+        if allowed.args.is_empty() {
+            return Ok(());
+        }
+
+        let rule = format!("{}.{}", self.name, allowed.function.name);
+        let tokens = allowed
+            .function
+            .decode_input(args)
+            .map_err(|err| PolicyViolation::new(&rule, format!("invalid arguments, {}", err)))?;
+        for arg in &allowed.args {
+            arg.check(&tokens[arg.index]).map_err(|reason| {
+                PolicyViolation::new(format!("{}.args[{}]", rule, arg.index), reason)
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+impl ArgRule {
+    fn check(&self, token: &Token) -> Result<(), String> {
+        if let Some(allowed) = &self.allowed {
+            match token {
+                Token::Address(address) if allowed.contains(address) => {}
+                Token::Address(address) => return Err(format!("{:?} is not allowed", address)),
+                _ => return Err("argument is not an address".to_owned()),
+            }
+        }
+        if let Some(max) = self.max {
+            match token {
+                Token::Uint(amount) if *amount <= max => {}
+                Token::Uint(amount) => {
+                    return Err(format!("{} is over the limit of {}", amount, max))
+                }
+                _ => return Err("argument is not a uint".to_owned()),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Value held against a tenant's daily limit, released against the day it was made on.
+/// Empty when there was nothing to hold.
+#[derive(Clone, Debug, Default)]
+#[must_use]
+pub struct Reservation {
+    tenant_id: String,
+    day: String,
+    value_gwei: i64,
+}
+
+/// Runs a `Policy`, tracking what each tenant sent today for the daily value limit
+#[derive(Debug)]
+pub struct PolicyEngine {
+    policy: RwLock<Arc<Policy>>,
+    pool: DbPool,
+}
+
+impl PolicyEngine {
+    pub fn new(policy: Policy, pool: DbPool) -> Self {
+        Self {
+            policy: RwLock::new(Arc::new(policy)),
+            pool,
+        }
+    }
+
+    pub fn policy(&self) -> Arc<Policy> {
+        self.policy.read().unwrap().clone()
+    }
+
+    /// Reservations made under the old policy still count against today's limit
+    pub fn set_policy(&self, policy: Policy) {
+        *self.policy.write().unwrap() = Arc::new(policy);
+    }
+
+    /// Checks every transaction and reserves their combined value against the tenant's
+    /// daily limit. Reservations for transactions that aren't sent should be released.
+    pub async fn enforce(
+        &self,
+        tenant_id: &str,
+        txs: &[&Eip1559TransactionRequest],
+    ) -> anyhow::Result<Reservation> {
+        let policy = self.policy();
+        for tx in txs {
+            policy.check(tx)?;
+        }
+        self.reserve(tenant_id, txs).await
+    }
+
+    /// Reserves the transactions' combined value against the tenant's daily limit
+    pub async fn reserve(
+        &self,
+        tenant_id: &str,
+        txs: &[&Eip1559TransactionRequest],
+    ) -> anyhow::Result<Reservation> {
+        let Some(limit) = self.policy().daily_value_limit else {
+            return Ok(Reservation::default());
+        };
+        let value = total_value(txs);
+        if value == Some(U256::zero()) {
+            return Ok(Reservation::default());
+        }
+        let over_limit = || {
+            PolicyViolation::new(
+                "daily_value_limit",
+                match value {
+                    Some(value) => format!(
+                        "sending {} wei would go over today's limit of {} wei",
+                        value, limit
+                    ),
+                    None => "the transactions' combined value overflows".to_owned(),
+                },
+            )
+        };
+        // A limit too large to count is as good as none, a value too large to count is over it
+        let limit_gwei = to_gwei(limit).unwrap_or(i64::MAX);
+        let value_gwei = value.and_then(to_gwei).ok_or_else(over_limit)?;
+        if value_gwei > limit_gwei {
+            return Err(over_limit().into());
+        }
+
+        let day = utc_day(unix_now());
+        let reserved = with_pool!(&self.pool, |pool: DB| {
+            let mut db_tx = pool.begin().await?;
+            let sql = DB::sql(&format!(
+                r#"
+				INSERT INTO daily_value_usage (tenant_id, day, value_gwei)
+				VALUES (?, ?, 0)
+				{} value_gwei = daily_value_usage.value_gwei
+				"#,
+                DB::on_conflict("tenant_id, day")
+            ));
+            query(&sql)
+                .bind(tenant_id)
+                .bind(&day)
+                .execute(&mut db_tx)
+                .await?;
+            let sql = DB::sql(
+                r#"
+				UPDATE daily_value_usage
+				SET value_gwei = value_gwei + ?
+				WHERE tenant_id = ? and day = ? and value_gwei <= ?
+				"#,
+            );
+            let reserved = query(&sql)
+                .bind(value_gwei)
+                .bind(tenant_id)
+                .bind(&day)
+                .bind(limit_gwei - value_gwei)
+                .execute(&mut db_tx)
+                .await?;
+            db_tx.commit().await?;
+            DB::rows_affected(&reserved)
+        });
+
+        if reserved == 0 {
+            return Err(over_limit().into());
+        }
+
+        Ok(Reservation {
+            tenant_id: tenant_id.to_owned(),
+            day,
+            value_gwei,
+        })
+    }
+
+    /// Gives the value back to the day it was reserved on, even if the policy changed since
+    pub async fn release(&self, reservation: &Reservation) -> anyhow::Result<()> {
+        if reservation.value_gwei == 0 {
+            return Ok(());
+        }
+
+        with_pool!(&self.pool, |pool: DB| {
+            let sql = DB::sql(&format!(
+                r#"
+				UPDATE daily_value_usage
+				SET value_gwei = value_gwei - {}
+				WHERE tenant_id = ? and day = ?
+				"#,
+                DB::least("value_gwei", "?")
+            ));
+            query(&sql)
+                .bind(reservation.value_gwei)
+                .bind(&reservation.tenant_id)
+                .bind(&reservation.day)
+                .execute(pool)
+                .await?;
+        });
+
+        Ok(())
+    }
+}
+
+/// None if the sum overflows
+fn total_value(txs: &[&Eip1559TransactionRequest]) -> Option<U256> {
+    txs.iter().try_fold(U256::zero(), |total, tx| {
+        total.checked_add(tx.value.unwrap_or_default())
+    })
+}
+
+/// Rounds up, so limits are never undercounted. None if it doesn't fit the bigint column.
+fn to_gwei(wei: U256) -> Option<i64> {
+    let (gwei, remainder) = wei.div_mod(GWEI.into());
+    let gwei = if remainder.is_zero() { gwei } else { gwei + 1 };
+    u64::try_from(gwei)
+        .ok()
+        .and_then(|gwei| i64::try_from(gwei).ok())
+}
+
+/// `YYYY-MM-DD` of a unix time in UTC, the day limits are counted by
+fn utc_day(unix_seconds: u64) -> String {
+    // Howard Hinnant's civil_from_days, with years starting in March
+    let days = (unix_seconds / 86_400) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
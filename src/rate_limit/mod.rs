@@ -0,0 +1,79 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Allows bursts of up to a minute's worth of requests, refilling continuously
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    refill_per_second: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn per_minute(limit: u32) -> Self {
+        let capacity = f64::from(limit);
+        Self {
+            capacity,
+            refill_per_second: capacity / 60.0,
+            tokens: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Takes `cost` tokens, or returns how long until they'll be available.
+    /// Costs over the bucket's capacity are capped to it, so they aren't rejected forever.
+    pub fn try_take(&mut self, cost: u32) -> Result<(), Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_second).min(self.capacity);
+        self.updated = now;
+
+        let cost = f64::from(cost).min(self.capacity);
+        if self.tokens >= cost {
+            self.tokens -= cost;
+            return Ok(());
+        }
+
+        let missing = cost - self.tokens;
+        Err(Duration::from_secs_f64(missing / self.refill_per_second))
+    }
+}
+
+/// A token bucket per key, i.e. per api key or per chain
+#[derive(Debug)]
+pub struct RateLimiter<K> {
+    buckets: Mutex<HashMap<K, TokenBucket>>,
+}
+
+impl<K> Default for RateLimiter<K> {
+    fn default() -> Self {
+        Self {
+            buckets: Mutex::default(),
+        }
+    }
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    /// A limit of 0 rejects everything
+    pub fn check(&self, key: K, limit_per_minute: u32, cost: u32) -> Result<(), Duration> {
+        if limit_per_minute == 0 {
+            return Err(Duration::from_secs(60));
+        }
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets
+            .entry(key)
+            .or_insert_with(|| TokenBucket::per_minute(limit_per_minute));
+        // The limit was reconfigured
+        if bucket.capacity != f64::from(limit_per_minute) {
+            *bucket = TokenBucket::per_minute(limit_per_minute);
+        }
+
+        bucket.try_take(cost)
+    }
+}
@@ -0,0 +1,152 @@
+use std::{
+    collections::HashMap,
+    fs::{create_dir_all, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ethers::types::{Eip1559TransactionRequest, TxHash};
+use serde::Serialize;
+use tokio::time::sleep;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::transaction_repository::{ChainId, ListedRequest, RequestStatus, TransactionRepository};
+
+/// Requests removed per database transaction
+const BATCH_SIZE: u32 = 500;
+
+/// Where expired requests go
+#[derive(Clone, Debug)]
+pub enum RetentionTarget {
+    /// The `requests_archive` table
+    Archive,
+    /// Appended to `requests-{chain}.jsonl` in the directory, then deleted
+    Jsonl(PathBuf),
+}
+
+/// Moves mined, failed and cancelled requests out of the requests table once they're older than
+/// their chain's retention. Only requests the monitors are done with are touched, so it's safe
+/// to run alongside them.
+#[derive(Debug)]
+pub struct Retention<T> {
+    tx_repo: Arc<T>,
+    max_age: RwLock<HashMap<ChainId, Duration>>,
+    target: RetentionTarget,
+}
+
+#[derive(Serialize)]
+struct ExportedRequest<'a> {
+    id: Uuid,
+    chain: ChainId,
+    status: RequestStatus,
+    hash: Option<TxHash>,
+    tenant_id: Option<&'a str>,
+    batch_id: Option<Uuid>,
+    batch_index: Option<u32>,
+    tx: &'a Eip1559TransactionRequest,
+    /// Unix milliseconds
+    created_at: u64,
+    updated_at: u64,
+}
+
+impl<T: TransactionRepository> Retention<T> {
+    /// Chains without a max age keep their requests forever
+    pub fn new(
+        tx_repo: Arc<T>,
+        max_age: HashMap<ChainId, Duration>,
+        target: RetentionTarget,
+    ) -> Self {
+        Self {
+            tx_repo,
+            max_age: RwLock::new(max_age),
+            target,
+        }
+    }
+
+    /// Takes effect from the next run
+    pub fn set_max_age(&self, max_age: HashMap<ChainId, Duration>) {
+        *self.max_age.write().unwrap() = max_age;
+    }
+
+    /// Removes the chain's expired requests, returns how many
+    pub async fn run_once(&self, chain: ChainId) -> anyhow::Result<u64> {
+        let Some(max_age) = self.max_age.read().unwrap().get(&chain).copied() else {
+            return Ok(0);
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let before = now.saturating_sub(max_age).as_millis() as u64;
+
+        let mut removed = 0;
+        loop {
+            let expired = self.tx_repo.get_expired(chain, before, BATCH_SIZE).await?;
+            if expired.is_empty() {
+                break;
+            }
+            let keep = match &self.target {
+                RetentionTarget::Archive => true,
+                RetentionTarget::Jsonl(dir) => {
+                    // Written out before anything is deleted, a failed run exports them again
+                    export(dir, chain, &expired)?;
+                    false
+                }
+            };
+            let ids: Vec<Uuid> = expired.iter().map(|listed| listed.request.id).collect();
+            let archived = self.tx_repo.archive(&ids, keep).await?;
+            removed += archived;
+            if archived == 0 || expired.len() < BATCH_SIZE as usize {
+                break;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Removes expired requests on every configured chain every `interval`
+    pub async fn run(self: Arc<Self>, interval: Duration) {
+        loop {
+            sleep(interval).await;
+            let chains: Vec<ChainId> = self.max_age.read().unwrap().keys().copied().collect();
+            for chain in chains {
+                match self.run_once(chain).await {
+                    Ok(0) => {}
+                    Ok(removed) => {
+                        info!("Retention removed {} requests on chain {}", removed, chain)
+                    }
+                    Err(err) => error!("Retention failed on chain {}, {}", chain, err),
+                }
+            }
+        }
+    }
+}
+
+fn export(dir: &Path, chain: ChainId, requests: &[ListedRequest]) -> anyhow::Result<()> {
+    create_dir_all(dir)?;
+    let mut lines = String::new();
+    for listed in requests {
+        let request = &listed.request;
+        let exported = ExportedRequest {
+            id: request.id,
+            chain: request.chain,
+            status: request.status,
+            hash: request.hash,
+            tenant_id: request.tenant_id.as_deref(),
+            batch_id: request.batch_id,
+            batch_index: request.batch_index,
+            tx: &request.tx,
+            created_at: listed.created_at,
+            updated_at: listed.updated_at,
+        };
+        lines.push_str(&serde_json::to_string(&exported)?);
+        lines.push('\n');
+    }
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(format!("requests-{}.jsonl", chain)))?;
+    file.write_all(lines.as_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
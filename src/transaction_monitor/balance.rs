@@ -0,0 +1,121 @@
+use ethers::{
+    signers::LocalWallet,
+    types::{Address, Eip1559TransactionRequest, TxHash, U256},
+};
+use serde::Serialize;
+use std::sync::Mutex;
+use thiserror::Error;
+
+// Fees can roughly double over a few rounds of escalation before a transaction is mined
+const WORST_CASE_FEE_MULTIPLIER: u64 = 2;
+
+#[derive(Clone, Debug, Default)]
+pub struct BalancePolicy {
+    /// Warn once the relayer's balance drops below this many wei
+    pub low_balance: Option<U256>,
+    /// Refill the relayer from a treasury once it's below `low_balance`
+    pub top_up: Option<TopUp>,
+}
+
+#[derive(Clone, Debug)]
+pub struct TopUp {
+    pub treasury: LocalWallet,
+    /// Wei to refill the relayer's balance to
+    pub target: U256,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct BalanceStatus {
+    pub address: Address,
+    /// None until the first block after startup
+    pub balance: Option<U256>,
+    pub low: bool,
+    /// Hash of the treasury transfer in flight, if any
+    pub top_up: Option<TxHash>,
+}
+
+#[derive(Debug, Error)]
+#[error("relayer balance of {balance} wei can't cover the worst case cost of {required} wei")]
+pub struct InsufficientBalance {
+    pub balance: U256,
+    pub required: U256,
+}
+
+/// What a chain monitor last saw of its relayer's balance, updated every block
+#[derive(Debug, Default)]
+pub struct BalanceGuard {
+    state: Mutex<BalanceState>,
+}
+
+#[derive(Debug, Default)]
+struct BalanceState {
+    policy: BalancePolicy,
+    balance: Option<U256>,
+    max_fee: Option<U256>,
+    top_up: Option<TxHash>,
+}
+
+impl BalanceGuard {
+    pub fn policy(&self) -> BalancePolicy {
+        self.state.lock().unwrap().policy.clone()
+    }
+
+    pub fn set_policy(&self, policy: BalancePolicy) {
+        self.state.lock().unwrap().policy = policy;
+    }
+
+    pub fn observe(&self, balance: U256, max_fee: U256) {
+        let mut state = self.state.lock().unwrap();
+        state.balance = Some(balance);
+        state.max_fee = Some(max_fee);
+    }
+
+    /// The last seen balance and max fee estimate
+    pub fn latest(&self) -> (Option<U256>, Option<U256>) {
+        let state = self.state.lock().unwrap();
+        (state.balance, state.max_fee)
+    }
+
+    pub fn is_low(&self, balance: U256) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .policy
+            .low_balance
+            .is_some_and(|threshold| balance < threshold)
+    }
+
+    pub fn top_up(&self) -> Option<TxHash> {
+        self.state.lock().unwrap().top_up
+    }
+
+    pub fn set_top_up(&self, hash: Option<TxHash>) {
+        self.state.lock().unwrap().top_up = hash;
+    }
+
+    pub fn status(&self, address: Address) -> BalanceStatus {
+        let (balance, _) = self.latest();
+        BalanceStatus {
+            address,
+            balance,
+            low: balance.is_some_and(|balance| self.is_low(balance)),
+            top_up: self.top_up(),
+        }
+    }
+}
+
+/// Most the transaction can cost at its current max fee, plus the value it sends
+pub fn max_cost(tx: &Eip1559TransactionRequest) -> U256 {
+    tx.gas.unwrap_or_default() * tx.max_fee_per_gas.unwrap_or_default()
+        + tx.value.unwrap_or_default()
+}
+
+/// Most the transactions could cost if their fees escalate, plus the value they send.
+/// Transactions without a max fee yet are priced at `max_fee`.
+pub fn worst_case_cost(txs: &[&Eip1559TransactionRequest], max_fee: U256) -> U256 {
+    txs.iter().fold(U256::zero(), |total, tx| {
+        let fee = tx.max_fee_per_gas.unwrap_or(max_fee).max(max_fee);
+        total
+            + tx.gas.unwrap_or_default() * fee * WORST_CASE_FEE_MULTIPLIER
+            + tx.value.unwrap_or_default()
+    })
+}
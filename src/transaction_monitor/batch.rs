@@ -0,0 +1,38 @@
+use std::collections::HashMap;
+use thiserror::Error;
+
+use crate::transaction_repository::Request;
+
+#[derive(Debug, Error)]
+#[error("{} of the batch's transactions failed validation, none were queued", .errors.len())]
+pub struct BatchRejected {
+    /// The index of each failing transaction in the batch, with why it failed
+    pub errors: Vec<(usize, anyhow::Error)>,
+}
+
+/// Orders queued requests so each batch is submitted back to back, in batch order,
+/// at the position of its earliest request. That keeps a batch's nonces consecutive
+/// even if other requests were queued while the batch was being inserted.
+pub fn group_batches(mut requests: Vec<Request>) -> Vec<Request> {
+    let mut first_position = HashMap::new();
+    for (position, request) in requests.iter().enumerate() {
+        if let Some(batch_id) = request.batch_id {
+            first_position.entry(batch_id).or_insert(position);
+        }
+    }
+
+    let positions: HashMap<_, _> = requests
+        .iter()
+        .enumerate()
+        .map(|(position, request)| {
+            let key = match request.batch_id {
+                Some(batch_id) => (first_position[&batch_id], request.batch_index.unwrap_or(0)),
+                None => (position, 0),
+            };
+            (request.id, key)
+        })
+        .collect();
+    requests.sort_by_key(|request| positions[&request.id]);
+
+    requests
+}
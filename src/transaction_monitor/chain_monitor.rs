@@ -1,30 +1,56 @@
 use ethers::{
-    providers::{Middleware, StreamExt},
+    middleware::SignerMiddleware,
+    providers::{Middleware, MiddlewareError, StreamExt},
+    signers::Signer,
     types::{
-        transaction::eip2718::TypedTransaction, Chain, Eip1559TransactionRequest, TxHash, U256,
+        transaction::eip2718::TypedTransaction, Address, BlockNumber, Eip1559TransactionRequest,
+        Signature, Transaction, TxHash, U256,
     },
 };
 
-use std::{pin::Pin, sync::Arc};
-use tracing::info;
+use std::{cmp::max, collections::HashMap, pin::Pin, sync::Arc};
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
 use tokio::{
     spawn,
+    sync::{watch, Notify},
     time::{sleep, Duration},
 };
 
+use super::balance::{max_cost, worst_case_cost, BalanceGuard, InsufficientBalance, TopUp};
+use super::batch::group_batches;
+use super::control::ChainControl;
+use super::dependency::{check_dependencies, dedup_dependencies};
 use super::gas_escalation::bump_transaction;
-use crate::transaction_repository::{Request, RequestUpdate, TransactionRepository};
+use super::idempotency::{fingerprint, IdempotencyConflict};
+use super::simulation::{RevertReason, SimulationError};
+use super::SendOptions;
+use crate::transaction_repository::{
+    Actor, ChainId, IdempotencyKey, NewRequest, Request, RequestFilter, RequestStatus,
+    RequestUpdate, TransactionRepository,
+};
+
+const QUEUE_POLL_SECONDS: u64 = 5;
+const MONITOR_RESTART_SECONDS: u64 = 5;
+/// Blocks searched for a nonce the node says was used, about an hour on mainnet
+const MINED_NONCE_LOOKBACK: u64 = 300;
+const SELF_TRANSFER_GAS: u64 = 21_000;
 
 type WatcherFuture<'a> = Pin<Box<dyn futures_util::stream::Stream<Item = TxHash> + Send + 'a>>;
 
 #[derive(Debug)]
 pub struct ChainMonitor<M, T> {
     pub provider: Arc<M>,
-    pub chain: Chain,
+    pub chain: ChainId,
     pub block_frequency: u8,
+    pub gas_limit_multiplier: f64,
     pub tx_repo: Arc<T>,
+    pub balance: Arc<BalanceGuard>,
+    pub control: Arc<ChainControl>,
+    queue_notify: Arc<Notify>,
+    /// Set once the monitor is replaced or retired, its workers finish up
+    shutdown: Arc<watch::Sender<bool>>,
 }
 
 impl<M, T> Clone for ChainMonitor<M, T> {
@@ -33,7 +59,12 @@ impl<M, T> Clone for ChainMonitor<M, T> {
             provider: self.provider.clone(),
             chain: self.chain,
             block_frequency: self.block_frequency,
+            gas_limit_multiplier: self.gas_limit_multiplier,
             tx_repo: self.tx_repo.clone(),
+            balance: self.balance.clone(),
+            control: self.control.clone(),
+            queue_notify: self.queue_notify.clone(),
+            shutdown: self.shutdown.clone(),
         }
     }
 }
@@ -43,140 +74,899 @@ where
     M: Middleware + 'static,
     T: TransactionRepository + 'static,
 {
-    pub fn new(provider: M, chain: Chain, block_frequency: u8, tx_repo: T) -> Self {
-        let this = Self {
+    pub fn new(
+        provider: M,
+        chain: ChainId,
+        block_frequency: u8,
+        gas_limit_multiplier: f64,
+        tx_repo: Arc<T>,
+    ) -> Self {
+        Self {
             chain,
             provider: Arc::new(provider),
             block_frequency,
-            tx_repo: Arc::new(tx_repo),
-        };
+            gas_limit_multiplier,
+            tx_repo,
+            balance: Arc::new(BalanceGuard::default()),
+            control: Arc::new(ChainControl::default()),
+            queue_notify: Arc::new(Notify::new()),
+            shutdown: Arc::new(watch::channel(false).0),
+        }
+    }
 
+    /// Spawns the workers that submit queued requests and escalate submitted ones
+    pub fn start(&self) {
         {
-            let this2 = this.clone();
+            let this2 = self.clone();
             spawn(async move {
-                this2.monitor().await.unwrap();
+                // Only the block subscription failing ends the loop, subscribe again
+                while let Err(err) = this2.monitor().await {
+                    if *this2.shutdown.borrow() {
+                        break;
+                    }
+                    error!(
+                        "Escalation loop on chain {} failed, restarting it, {:?}",
+                        this2.chain, err
+                    );
+                    sleep(Duration::from_secs(MONITOR_RESTART_SECONDS)).await;
+                }
             });
         }
 
-        this
+        {
+            let this2 = self.clone();
+            spawn(async move {
+                this2.process_queue().await;
+            });
+        }
+    }
+
+    /// Stops the workers once they're done with the current block or queue pass,
+    /// the chain's requests stay as they are for whichever monitor takes over
+    pub fn stop(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Same monitor, not just one for the same chain
+    pub fn same_as(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.shutdown, &other.shutdown)
+    }
+
+    /// Whether any of the chain's requests are still waiting, queued or submitted
+    pub async fn has_unsettled(&self) -> anyhow::Result<bool> {
+        if self.tx_repo.count_in_flight(self.chain).await? > 0 {
+            return Ok(true);
+        }
+        let waiting = RequestFilter {
+            chain: Some(self.chain),
+            status: Some(RequestStatus::Waiting),
+            ..RequestFilter::default()
+        };
+        let (requests, _) = self.tx_repo.list(&waiting, None, 1).await?;
+        Ok(!requests.is_empty())
     }
 
     pub async fn send_monitored_transaction(
         &self,
         tx: Eip1559TransactionRequest,
+        options: SendOptions,
     ) -> anyhow::Result<Uuid> {
-        let mut with_gas = tx.clone();
-        if with_gas.max_fee_per_gas.is_none() || with_gas.max_priority_fee_per_gas.is_none() {
-            let (estimate_max_fee, estimate_max_priority_fee) =
-                self.provider.estimate_eip1559_fees(None).await?;
-            with_gas.max_fee_per_gas = Some(estimate_max_fee);
-            with_gas.max_priority_fee_per_gas = Some(estimate_max_priority_fee);
-        }
-        let mut filled: TypedTransaction = with_gas.clone().into();
-        self.provider.fill_transaction(&mut filled, None).await?;
-        info!("Filled Transaction {:?}", filled);
-
-        let pending_tx = self.provider.send_transaction(filled.clone(), None).await?;
+        let SendOptions {
+            idempotency_key,
+            fingerprint: client_fingerprint,
+            depends_on,
+            tenant_id,
+            actor,
+        } = options;
+        let depends_on = dedup_dependencies(depends_on);
+        self.control.check_accepting(self.chain)?;
+        let idempotency = match idempotency_key {
+            Some(key) => {
+                let fingerprint = match client_fingerprint {
+                    Some(fingerprint) => fingerprint,
+                    None => fingerprint(&tx, self.chain, &depends_on)?,
+                };
+                if let Some(id) = self
+                    .find_idempotent(tenant_id.as_deref(), &key, &fingerprint)
+                    .await?
+                {
+                    info!("Request with idempotency key {:?} already exists", key);
+                    return Ok(id);
+                }
+                Some(IdempotencyKey { key, fingerprint })
+            }
+            None => None,
+        };
+
+        // Dependent requests are simulated once their dependencies are mined,
+        // before that they'd be simulated against the wrong state
+        let tx = if depends_on.is_empty() {
+            let tx = self.prepare(tx).await?;
+            self.check_balance(&[&tx]).await?;
+            tx
+        } else {
+            check_dependencies(self.tx_repo.as_ref(), &depends_on, tenant_id.as_deref()).await?;
+            tx
+        };
         let id = Uuid::new_v4();
-        let tx_hash = pending_tx.tx_hash();
+        let request = NewRequest {
+            id,
+            tx,
+            chain: self.chain,
+            tenant_id: tenant_id.clone(),
+            depends_on,
+            actor,
+        };
+        if let Err(err) = self.tx_repo.save(request, idempotency.clone()).await {
+            // Lost a race with a concurrent retry, the unique index rejected our insert
+            if let Some(IdempotencyKey { key, fingerprint }) = idempotency {
+                if let Some(id) = self
+                    .find_idempotent(tenant_id.as_deref(), &key, &fingerprint)
+                    .await?
+                {
+                    return Ok(id);
+                }
+            }
+            return Err(err);
+        }
+        self.notify_queue();
+        info!("Queued request {:?} on chain {}", id, self.chain);
+
+        Ok(id)
+    }
+
+    /// Rejects reverting requests up front and fills in the gas limit,
+    /// the worker fills in fees and the nonce once the request is saved
+    pub async fn prepare(
+        &self,
+        tx: Eip1559TransactionRequest,
+    ) -> anyhow::Result<Eip1559TransactionRequest> {
+        let mut typed: TypedTransaction = tx.into();
+        self.simulate(&typed).await?;
+        if typed.gas().is_none() {
+            let estimate = self.provider.estimate_gas(&typed, None).await?;
+            typed.set_gas(apply_multiplier(estimate, self.gas_limit_multiplier));
+        }
+
+        Ok(typed.into())
+    }
+
+    /// Refuses transactions the relayer couldn't keep paying for as their fees escalate
+    pub async fn check_balance(&self, txs: &[&Eip1559TransactionRequest]) -> anyhow::Result<()> {
+        let (balance, max_fee) = match self.balance.latest() {
+            (Some(balance), Some(max_fee)) => (balance, max_fee),
+            // Nothing seen yet, the first block hasn't arrived
+            _ => {
+                let balance = self.provider.get_balance(self.sender()?, None).await?;
+                let (max_fee, _) = self.provider.estimate_eip1559_fees(None).await?;
+                self.balance.observe(balance, max_fee);
+                (balance, max_fee)
+            }
+        };
+
+        let required = worst_case_cost(txs, max_fee);
+        if balance < required {
+            return Err(InsufficientBalance { balance, required }.into());
+        }
+
+        Ok(())
+    }
+
+    /// Records the relayer's balance, warns when it's low and tops it up from the treasury
+    async fn watch_balance(&self, max_fee: U256) -> anyhow::Result<()> {
+        let address = self.sender()?;
+        let balance = self.provider.get_balance(address, None).await?;
+        self.balance.observe(balance, max_fee);
+
+        if let Some(hash) = self.balance.top_up() {
+            if self.provider.get_transaction_receipt(hash).await?.is_some() {
+                info!("Top up {:?} was mined on chain {}", hash, self.chain);
+                self.balance.set_top_up(None);
+            } else if self.provider.get_transaction(hash).await?.is_none() {
+                warn!("Top up {:?} was dropped on chain {}", hash, self.chain);
+                self.balance.set_top_up(None);
+            } else {
+                return Ok(());
+            }
+        }
+
+        if !self.balance.is_low(balance) {
+            return Ok(());
+        }
+        warn!(
+            "Relayer {:?} balance is low on chain {}, {} wei left",
+            address, self.chain, balance
+        );
+
+        let Some(TopUp { treasury, target }) = self.balance.policy().top_up else {
+            return Ok(());
+        };
+        if balance >= target {
+            return Ok(());
+        }
+        let treasury = treasury.with_chain_id(self.chain.0);
+        let funder = SignerMiddleware::new(self.provider.clone(), treasury);
+        let top_up = Eip1559TransactionRequest::new()
+            .to(address)
+            .value(target - balance);
+        let pending = funder
+            .send_transaction(top_up, None)
+            .await
+            .map_err(|err| anyhow::anyhow!(err))?;
+        info!(
+            "Topping up relayer {:?} on chain {} with {} wei, {:?}",
+            address,
+            self.chain,
+            target - balance,
+            pending.tx_hash()
+        );
+        self.balance.set_top_up(Some(pending.tx_hash()));
+
+        Ok(())
+    }
+
+    /// Wakes the worker so newly saved requests are submitted right away
+    pub fn notify_queue(&self) {
+        self.queue_notify.notify_one();
+    }
+
+    async fn find_idempotent(
+        &self,
+        tenant_id: Option<&str>,
+        key: &str,
+        fingerprint: &str,
+    ) -> anyhow::Result<Option<Uuid>> {
+        match self.tx_repo.get_by_idempotency_key(tenant_id, key).await? {
+            Some((id, existing)) if existing == fingerprint => Ok(Some(id)),
+            Some((id, _)) => Err(IdempotencyConflict {
+                key: key.to_owned(),
+                id,
+            }
+            .into()),
+            None => Ok(None),
+        }
+    }
+
+    async fn process_queue(&self) {
+        info!("Processing queued requests! chain = {}", self.chain);
+        let mut shutdown = self.shutdown.subscribe();
+        loop {
+            // The interval catches anything queued while the worker was busy or erroring
+            tokio::select! {
+                _ = self.queue_notify.notified() => {}
+                _ = sleep(Duration::from_secs(QUEUE_POLL_SECONDS)) => {}
+                _ = shutdown.wait_for(|&stopped| stopped) => {
+                    info!("Stopped processing queued requests! chain = {}", self.chain);
+                    return;
+                }
+            }
+            if self.control.is_paused() {
+                continue;
+            }
+
+            if let Err(err) = self.release_waiting().await {
+                error!(
+                    "Failed to release waiting requests on chain {}, {:?}",
+                    self.chain, err
+                );
+            }
+            if let Err(err) = self.submit_queued().await {
+                error!(
+                    "Failed to submit queued requests on chain {}, {:?}",
+                    self.chain, err
+                );
+            }
+        }
+    }
+
+    /// Fails requests whose dependencies failed or were cancelled, then queues the requests
+    /// whose dependencies have all been mined, failing them if they no longer simulate
+    async fn release_waiting(&self) -> anyhow::Result<()> {
+        // Each pass fails one more level of a dependency chain
+        loop {
+            let failed = self.tx_repo.fail_blocked(self.chain).await?;
+            if failed == 0 {
+                break;
+            }
+            warn!(
+                "Failed {} requests on chain {} after their dependencies failed",
+                failed, self.chain
+            );
+        }
+
+        for request in self.tx_repo.get_ready(self.chain).await? {
+            match self.prepare(request.tx).await {
+                Ok(tx) => {
+                    self.tx_repo.mark_queued(request.id, tx).await?;
+                    info!(
+                        "Dependencies of request {:?} were mined, queued",
+                        request.id
+                    );
+                }
+                Err(err) if err.is::<SimulationError>() => {
+                    warn!("Request {:?} failed once released, {}", request.id, err);
+                    self.tx_repo.mark_failed(request.id).await?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn submit_queued(&self) -> anyhow::Result<()> {
+        let requests = group_batches(self.tx_repo.get_queued(self.chain).await?);
+        if requests.is_empty() {
+            return Ok(());
+        }
+
+        let mut nonce = self.sync_nonce().await?;
+        let (estimate_max_fee, estimate_max_priority_fee) =
+            self.provider.estimate_eip1559_fees(None).await?;
+
+        for request in requests {
+            let mut tx = request.tx;
+            tx.nonce = Some(nonce);
+            tx.max_fee_per_gas = Some(estimate_max_fee);
+            tx.max_priority_fee_per_gas = Some(estimate_max_priority_fee);
+            let (typed, signature, hash) = self.sign(tx).await?;
+
+            // Persist before broadcasting, if the broadcast fails the escalation
+            // loop picks the request up as pending and retries with the same nonce
+            if !self
+                .tx_repo
+                .mark_submitted(request.id, hash, typed.clone().into())
+                .await?
+            {
+                info!("Request {:?} was cancelled before submission", request.id);
+                continue;
+            }
+            nonce += U256::one();
+
+            info!(
+                "Submitting request {:?} with nonce {} as {:?}",
+                request.id,
+                typed.nonce().copied().unwrap_or_default(),
+                hash
+            );
+            // Later nonces would be stuck behind this one until it's retried
+            if !self.broadcast(request.id, &typed, &signature).await {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Brings the stored nonce in line with the chain, returning the next nonce to use.
+    /// Nonces consumed outside of the relay (manual transactions from the same key) are skipped.
+    pub async fn sync_nonce(&self) -> anyhow::Result<U256> {
+        let from = self.sender()?;
+        let chain_nonce = self
+            .provider
+            .get_transaction_count(from, Some(BlockNumber::Pending.into()))
+            .await?;
+        let stored_nonce = self.tx_repo.get_next_nonce(self.chain, from).await?;
+        let next_nonce = max(chain_nonce, stored_nonce.unwrap_or_default());
+
+        if stored_nonce != Some(next_nonce) {
+            info!(
+                "Syncing nonce for {:?} on chain {}, stored {:?}, next {}",
+                from, self.chain, stored_nonce, next_nonce
+            );
+            self.tx_repo
+                .set_next_nonce(self.chain, from, next_nonce)
+                .await?;
+        }
+
+        Ok(next_nonce)
+    }
+
+    /// Resets the stored nonce to the node's pending nonce, or past the highest submitted request
+    /// if that's higher. Unlike `sync_nonce` it can move back, over nonces that were never sent.
+    pub async fn resync_nonce(&self) -> anyhow::Result<U256> {
+        let from = self.sender()?;
+        let pending_nonce = self
+            .provider
+            .get_transaction_count(from, Some(BlockNumber::Pending.into()))
+            .await?;
+        let tracked = self
+            .tx_repo
+            .get_submitted_nonces(self.chain, from, pending_nonce)
+            .await?;
+        let next_nonce = tracked
+            .into_iter()
+            .map(|nonce| nonce + 1)
+            .fold(pending_nonce, max);
+
+        warn!(
+            "Resyncing nonce for {:?} on chain {} to {}",
+            from, self.chain, next_nonce
+        );
         self.tx_repo
-            .save(id, tx_hash, filled.into(), false, self.chain)
+            .reset_next_nonce(self.chain, from, next_nonce)
             .await?;
+        Ok(next_nonce)
+    }
 
-        Ok(id)
+    /// Replaces a submitted request's transaction with one paying these fees, for when escalation
+    /// can't keep up. Returns None if the request isn't submitted.
+    pub async fn force_rebroadcast(
+        &self,
+        request: Request,
+        max_fee: U256,
+        max_priority_fee: U256,
+        actor: &Actor,
+    ) -> anyhow::Result<Option<TxHash>> {
+        if request.status != RequestStatus::Submitted {
+            return Ok(None);
+        }
+        let mut tx = request.tx;
+        tx.max_fee_per_gas = Some(max_fee);
+        tx.max_priority_fee_per_gas = Some(max_priority_fee);
+        let (typed, signature, hash) = self.sign(tx).await?;
+
+        // Only recorded once the node takes it, until then the old transaction is the one to watch
+        self.provider
+            .send_raw_transaction(typed.rlp_signed(&signature))
+            .await
+            .map_err(|err| anyhow::anyhow!(err))?;
+        if !self
+            .tx_repo
+            .replace(request.id, hash, typed.into(), actor)
+            .await?
+        {
+            return Ok(None);
+        }
+        info!(
+            "Request {:?} rebroadcast as {:?} by {}",
+            request.id, hash, actor
+        );
+
+        Ok(Some(hash))
+    }
+
+    /// Like `force_rebroadcast` with the fees escalation would pick next
+    pub async fn speed_up(
+        &self,
+        request: Request,
+        actor: &Actor,
+    ) -> anyhow::Result<Option<TxHash>> {
+        let (estimate_max_fee, estimate_max_priority_fee) =
+            self.provider.estimate_eip1559_fees(None).await?;
+        let mut bumped = request.tx.clone();
+        bump_transaction(&mut bumped, estimate_max_fee, estimate_max_priority_fee);
+        let max_fee = bumped.max_fee_per_gas.unwrap_or(estimate_max_fee);
+        let max_priority_fee = bumped
+            .max_priority_fee_per_gas
+            .unwrap_or(estimate_max_priority_fee);
+
+        self.force_rebroadcast(request, max_fee, max_priority_fee, actor)
+            .await
     }
 
+    /// Looks for the submitted requests' nonces in every block since `from_block`, settling the
+    /// ones that were mined while nothing was watching, under whichever of their hashes made it.
+    /// Returns how many were settled.
+    pub async fn replay_from_block(&self, from_block: u64) -> anyhow::Result<usize> {
+        let sender = self.sender()?;
+        let mut pending: HashMap<U256, Request> = self
+            .tx_repo
+            .get_pending(self.chain)
+            .await?
+            .into_iter()
+            .filter_map(|request| Some((request.tx.nonce?, request)))
+            .collect();
+        let latest = self.provider.get_block_number().await?.as_u64();
+
+        let mut updates = Vec::new();
+        for number in from_block..=latest {
+            if pending.is_empty() {
+                break;
+            }
+            let Some(block) = self.provider.get_block_with_txs(number).await? else {
+                continue;
+            };
+            for tx in block.transactions.iter().filter(|tx| tx.from == sender) {
+                let Some(request) = pending.remove(&tx.nonce) else {
+                    continue;
+                };
+                info!(
+                    "Request {:?} was mined as {:?} in block {} on chain {}",
+                    request.id, tx.hash, number, self.chain
+                );
+                let Some(read_hash) = request.hash else {
+                    continue;
+                };
+                let value = request.tx.value.unwrap_or_default();
+                updates.push(
+                    self.settle_update(request.id, read_hash, tx.hash, value)
+                        .await?,
+                );
+            }
+        }
+
+        let settled = updates.len();
+        self.tx_repo.update_many(updates).await?;
+        if settled > 0 {
+            self.notify_queue();
+        }
+        Ok(settled)
+    }
+
+    /// The node's pending nonce stops at the first missing nonce, if that's below what we've
+    /// handed out and we have no transaction for it, every later transaction is stuck behind it.
+    /// Fill those gaps with 0 value self transfers.
+    async fn fill_nonce_gaps(
+        &self,
+        estimate_max_fee: U256,
+        estimate_max_priority_fee: U256,
+    ) -> anyhow::Result<()> {
+        let from = self.sender()?;
+        let pending_nonce = self
+            .provider
+            .get_transaction_count(from, Some(BlockNumber::Pending.into()))
+            .await?;
+        let next_nonce = self.sync_nonce().await?;
+        if pending_nonce >= next_nonce {
+            return Ok(());
+        }
+
+        let tracked = self
+            .tx_repo
+            .get_submitted_nonces(self.chain, from, pending_nonce)
+            .await?;
+        let mut nonce = pending_nonce;
+        while nonce < next_nonce {
+            if !tracked.contains(&nonce) {
+                warn!(
+                    "Nonce {} for {:?} on chain {} has no transaction, filling the gap",
+                    nonce, from, self.chain
+                );
+                let filler = Eip1559TransactionRequest::new()
+                    .to(from)
+                    .value(0)
+                    .gas(SELF_TRANSFER_GAS)
+                    .nonce(nonce)
+                    .max_fee_per_gas(estimate_max_fee)
+                    .max_priority_fee_per_gas(estimate_max_priority_fee);
+                let (typed, signature, hash) = self.sign(filler).await?;
+
+                // Filler transactions are tracked like any other request so they get escalated
+                let id = Uuid::new_v4();
+                self.tx_repo
+                    .save_submitted(id, hash, typed.clone().into(), self.chain)
+                    .await?;
+                self.broadcast(id, &typed, &signature).await;
+            }
+
+            nonce += U256::one();
+        }
+
+        Ok(())
+    }
+
+    fn sender(&self) -> anyhow::Result<Address> {
+        self.provider
+            .default_sender()
+            .ok_or_else(|| anyhow::anyhow!("provider for chain {} has no signer", self.chain))
+    }
+
+    async fn sign(
+        &self,
+        mut tx: Eip1559TransactionRequest,
+    ) -> anyhow::Result<(TypedTransaction, Signature, TxHash)> {
+        let from = self.sender()?;
+        tx.from = Some(from);
+
+        let mut typed: TypedTransaction = tx.into();
+        self.provider.fill_transaction(&mut typed, None).await?;
+        let signature = self.provider.sign_transaction(&typed, from).await?;
+        let hash = typed.hash(&signature);
+
+        Ok((typed, signature, hash))
+    }
+
+    /// False if the node turned the transaction away, the escalation loop retries it
+    async fn broadcast(&self, id: Uuid, typed: &TypedTransaction, signature: &Signature) -> bool {
+        match self
+            .provider
+            .send_raw_transaction(typed.rlp_signed(signature))
+            .await
+        {
+            Ok(_) => true,
+            Err(err) => {
+                warn!(
+                    "Broadcasting request {:?} failed, it will be retried, {:?}",
+                    id, err
+                );
+                false
+            }
+        }
+    }
+
+    async fn simulate(&self, tx: &TypedTransaction) -> anyhow::Result<()> {
+        match self.provider.call(tx, None).await {
+            Ok(_) => Ok(()),
+            Err(err) => match err
+                .as_error_response()
+                .and_then(RevertReason::from_rpc_error)
+            {
+                Some(reason) => {
+                    info!("Transaction failed simulation, {}", reason);
+                    Err(SimulationError { reason }.into())
+                }
+                None => Err(anyhow::anyhow!(err)),
+            },
+        }
+    }
+
+    /// Escalates and settles submitted requests every block until the monitor is stopped.
+    /// Fails only if the block subscription does, a single request or block failing is logged.
     pub async fn monitor(&self) -> anyhow::Result<()> {
         info!("Monitoring for escalation! chain = {}", self.chain);
-        let mut watcher: WatcherFuture =
-            Box::pin(self.provider.watch_blocks().await?.map(|hash| (hash)));
-        let mut block_count = 0;
+        let mut watcher: WatcherFuture = Box::pin(self.provider.watch_blocks().await?);
+        let mut block_count: u64 = 0;
+        let mut shutdown = self.shutdown.subscribe();
 
-        while let Some(block_hash) = watcher.next().await {
-            // We know the block exists at this point
+        loop {
+            let block_hash = tokio::select! {
+                block_hash = watcher.next() => match block_hash {
+                    Some(block_hash) => block_hash,
+                    None => return Err(anyhow::anyhow!("block subscription ended")),
+                },
+                _ = shutdown.wait_for(|&stopped| stopped) => {
+                    info!("Stopped monitoring for escalation! chain = {}", self.chain);
+                    return Ok(());
+                }
+            };
             info!(
                 "Block {:?} has been mined, chain = {}",
                 block_hash, self.chain
             );
             block_count += 1;
 
-            let block = self.provider.get_block_with_txs(block_hash).await?.unwrap();
-            sleep(Duration::from_secs(1)).await; // to avoid rate limiting
+            let escalate = block_count.is_multiple_of(u64::from(self.block_frequency))
+                && !self.control.is_paused();
+            if let Err(err) = self.process_block(block_hash, escalate).await {
+                error!(
+                    "Failed to process block {:?} on chain {}, {:?}",
+                    block_hash, self.chain, err
+                );
+            }
+        }
+    }
 
-            let (estimate_max_fee, estimate_max_priority_fee) =
-                self.provider.estimate_eip1559_fees(None).await?;
-            let requests = self.tx_repo.get_pending(self.chain).await?;
-            let mut updates: Vec<RequestUpdate> = Vec::new();
+    /// Settles the requests included in the block, and escalates the rest if it's time to
+    async fn process_block(&self, block_hash: TxHash, escalate: bool) -> anyhow::Result<()> {
+        let block = self
+            .provider
+            .get_block_with_txs(block_hash)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("block {:?} isn't available yet", block_hash))?;
+        sleep(Duration::from_secs(1)).await; // to avoid rate limiting
 
-            for request in requests {
-                let Request { hash, id, .. } = request;
-                let mut replacement_tx: Eip1559TransactionRequest = request.tx;
+        let (estimate_max_fee, estimate_max_priority_fee) =
+            self.provider.estimate_eip1559_fees(None).await?;
+        if let Err(err) = self.watch_balance(estimate_max_fee).await {
+            error!(
+                "Failed to check the relayer balance on chain {}, {:?}",
+                self.chain, err
+            );
+        }
+        let (balance, _) = self.balance.latest();
+        let requests = self.tx_repo.get_pending(self.chain).await?;
+        let mut updates: Vec<RequestUpdate> = Vec::new();
+        // Any of a request's escalations may be the one mined, so they're matched by nonce
+        let sender = self.sender()?;
+        let included: HashMap<U256, &Transaction> = block
+            .transactions
+            .iter()
+            .filter(|tx| tx.from == sender)
+            .map(|tx| (tx.nonce, tx))
+            .collect();
 
-                let tx_has_been_included = block.transactions.iter().any(|tx| tx.hash == hash);
+        for request in requests {
+            let id = request.id;
+            let checked = self
+                .check_request(
+                    request,
+                    &included,
+                    block.number.unwrap_or_default().as_u64(),
+                    escalate,
+                    (estimate_max_fee, estimate_max_priority_fee),
+                    balance,
+                )
+                .await;
+            // The request stays submitted, the next block tries again
+            match checked {
+                Ok(Some(update)) => updates.push(update),
+                Ok(None) => {}
+                Err(err) => error!(
+                    "Failed to check request {:?} on chain {}, {:?}",
+                    id, self.chain, err
+                ),
+            }
+        }
 
-                if tx_has_been_included {
-                    info!(
-                        "transaction {:?} was included on chain ${:?}",
-                        hash, self.chain
-                    );
-                    updates.push(RequestUpdate {
-                        id,
-                        mined: true,
-                        hash,
-                    });
-                    continue;
-                }
+        // Mined or failed requests may release or fail their dependents
+        let settled = updates
+            .iter()
+            .any(|update| update.status != RequestStatus::Submitted);
+        self.tx_repo.update_many(updates).await?;
+        if settled {
+            self.notify_queue();
+        }
 
-                if block_count % self.block_frequency != 0 {
-                    info!(
-                        "transaction {:?} was not included, not sending replacement yet",
-                        hash
+        if escalate {
+            if let Err(err) = self
+                .fill_nonce_gaps(estimate_max_fee, estimate_max_priority_fee)
+                .await
+            {
+                error!(
+                    "Failed to fill nonce gaps on chain {}, {:?}",
+                    self.chain, err
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Settles a submitted request if the block includes its nonce, otherwise replaces it with
+    /// higher fees when `escalate` is set
+    async fn check_request(
+        &self,
+        request: Request,
+        included: &HashMap<U256, &Transaction>,
+        block_number: u64,
+        escalate: bool,
+        (estimate_max_fee, estimate_max_priority_fee): (U256, U256),
+        balance: Option<U256>,
+    ) -> anyhow::Result<Option<RequestUpdate>> {
+        let Request { hash, id, .. } = request;
+        // The repository quarantines these, there's nothing to watch for anyway
+        let Some(hash) = hash else {
+            error!("Submitted request {:?} has no hash, skipping it", id);
+            return Ok(None);
+        };
+        let mut replacement_tx: Eip1559TransactionRequest = request.tx;
+        let nonce = replacement_tx.nonce.unwrap_or_default();
+        let value = replacement_tx.value.unwrap_or_default();
+
+        if let Some(tx) = included.get(&nonce) {
+            info!(
+                "transaction {:?} was included as {:?} on chain {}",
+                hash, tx.hash, self.chain
+            );
+            return self.settle_update(id, hash, tx.hash, value).await.map(Some);
+        }
+
+        if !escalate {
+            info!(
+                "transaction {:?} was not included, not sending replacement yet",
+                hash
+            );
+            return Ok(None);
+        }
+
+        bump_transaction(
+            &mut replacement_tx,
+            estimate_max_fee,
+            estimate_max_priority_fee,
+        );
+        // Sending would fail anyway, wait for a top up instead
+        if balance.is_some_and(|balance| balance < max_cost(&replacement_tx)) {
+            warn!(
+                "Not enough balance to escalate {:?} on chain {}",
+                hash, self.chain
+            );
+            return Ok(None);
+        }
+
+        info!("Rebroadcasting {:?}", hash);
+        match self.rebroadcast(&replacement_tx).await? {
+            Some(new_hash) => {
+                info!("Transaction {:?} replaced with {:?}", hash, new_hash);
+                sleep(Duration::from_secs(1)).await; // to avoid rate limiting TODO add retries
+                Ok(Some(RequestUpdate {
+                    id,
+                    read_hash: hash,
+                    status: RequestStatus::Submitted,
+                    hash: new_hash,
+                    cost: None,
+                }))
+            }
+            // An earlier block took the nonce, find out which transaction and how it went
+            None => match self.find_mined(nonce, block_number).await? {
+                Some(mined_hash) => self
+                    .settle_update(id, hash, mined_hash, value)
+                    .await
+                    .map(Some),
+                None => {
+                    warn!(
+                        "Nonce {} of request {:?} was used in none of the last {} blocks on chain {}, \
+                         replay older blocks with relayctl replay-from-block",
+                        nonce, id, MINED_NONCE_LOOKBACK, self.chain
                     );
-                    continue;
+                    Ok(None)
                 }
+            },
+        }
+    }
 
-                info!("Rebroadcasting {:?}", hash);
-                match self
-                    .rebroadcast(
-                        &mut replacement_tx,
-                        estimate_max_fee,
-                        estimate_max_priority_fee,
-                    )
-                    .await?
-                {
-                    Some(new_hash) => {
-                        info!("Transaction {:?} replaced with {:?}", hash, new_hash);
-                        updates.push(RequestUpdate {
-                            id,
-                            mined: false,
-                            hash: new_hash,
-                        });
-                        sleep(Duration::from_secs(1)).await; // to avoid rate limiting TODO add retries
-                    }
-                    None => {
-                        updates.push(RequestUpdate {
-                            id,
-                            mined: true,
-                            hash,
-                        });
-                    }
-                }
-            }
+    /// Settles a request under the hash that was mined for its nonce
+    async fn settle_update(
+        &self,
+        id: Uuid,
+        read_hash: TxHash,
+        mined_hash: TxHash,
+        value: U256,
+    ) -> anyhow::Result<RequestUpdate> {
+        let (status, cost) = self.settle(mined_hash, value).await?;
+        Ok(RequestUpdate {
+            id,
+            read_hash,
+            status,
+            hash: mined_hash,
+            cost,
+        })
+    }
 
-            self.tx_repo.update_many(updates).await?;
+    /// The relayer's transaction with this nonce in one of the blocks before `block_number`
+    async fn find_mined(&self, nonce: U256, block_number: u64) -> anyhow::Result<Option<TxHash>> {
+        let sender = self.sender()?;
+        let oldest = block_number.saturating_sub(MINED_NONCE_LOOKBACK);
+        for number in (oldest..=block_number).rev() {
+            let Some(block) = self.provider.get_block_with_txs(number).await? else {
+                continue;
+            };
+            if let Some(tx) = block
+                .transactions
+                .iter()
+                .find(|tx| tx.from == sender && tx.nonce == nonce)
+            {
+                return Ok(Some(tx.hash));
+            }
         }
-
-        Ok(())
+        Ok(None)
     }
 
-    async fn rebroadcast(
+    /// Whether an included transaction succeeded, and what it cost including its value
+    async fn settle(
         &self,
-        tx: &mut Eip1559TransactionRequest,
-        estimate_max_fee: U256,
-        estimate_max_priority_fee: U256,
-    ) -> anyhow::Result<Option<TxHash>> {
-        bump_transaction(tx, estimate_max_fee, estimate_max_priority_fee);
+        hash: TxHash,
+        value: U256,
+    ) -> anyhow::Result<(RequestStatus, Option<U256>)> {
+        // Without the receipt a revert would pass for mined, the request is checked again later
+        let receipt = self
+            .provider
+            .get_transaction_receipt(hash)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("receipt of {:?} isn't available yet", hash))?;
+
+        let status = match receipt.status {
+            Some(status) if status.is_zero() => {
+                warn!("transaction {:?} reverted on chain {}", hash, self.chain);
+                RequestStatus::Failed
+            }
+            _ => RequestStatus::Mined,
+        };
+        let gas_cost = receipt
+            .gas_used
+            .zip(receipt.effective_gas_price)
+            .map(|(gas_used, price)| gas_used * price);
+        // Reverted transactions don't transfer their value
+        let value = match status {
+            RequestStatus::Mined => value,
+            _ => U256::zero(),
+        };
 
+        Ok((status, gas_cost.map(|gas_cost| gas_cost + value)))
+    }
+
+    /// The replacement's hash, None if the node says its nonce was already used
+    async fn rebroadcast(&self, tx: &Eip1559TransactionRequest) -> anyhow::Result<Option<TxHash>> {
         info!("Sending replacement transaction {:?}", tx);
         match self.provider.send_transaction(tx.clone(), None).await {
             Ok(pending) => {
@@ -185,7 +975,7 @@ where
             }
             Err(err) => {
                 if err.to_string().contains("nonce too low") {
-                    info!("transaction has already been included");
+                    info!("transaction's nonce has already been used");
                     return Ok(None);
                 }
 
@@ -194,3 +984,9 @@ where
         }
     }
 }
+
+fn apply_multiplier(gas: U256, multiplier: f64) -> U256 {
+    // Work in basis points to stay in integer math on U256
+    let basis_points = (multiplier * 10_000.0).round() as u64;
+    gas * basis_points / 10_000u64
+}
@@ -0,0 +1,50 @@
+use serde::Serialize;
+use std::sync::Mutex;
+use thiserror::Error;
+
+use crate::transaction_repository::ChainId;
+
+/// Operator switches for a chain's monitor, they're reset by a restart
+#[derive(Debug, Default)]
+pub struct ChainControl {
+    state: Mutex<ChainState>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ChainState {
+    /// Nothing is submitted or escalated, mined transactions are still picked up
+    pub paused: bool,
+    /// New requests are turned away, the ones already saved carry on
+    pub draining: bool,
+}
+
+#[derive(Debug, Error)]
+#[error("chain {chain} is draining and doesn't accept new requests")]
+pub struct ChainDraining {
+    pub chain: ChainId,
+}
+
+impl ChainControl {
+    pub fn state(&self) -> ChainState {
+        *self.state.lock().unwrap()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state().paused
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.state.lock().unwrap().paused = paused;
+    }
+
+    pub fn set_draining(&self, draining: bool) {
+        self.state.lock().unwrap().draining = draining;
+    }
+
+    pub fn check_accepting(&self, chain: ChainId) -> Result<(), ChainDraining> {
+        if self.state().draining {
+            return Err(ChainDraining { chain });
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,45 @@
+use std::collections::HashSet;
+
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::transaction_repository::{RequestStatus, TransactionRepository};
+
+#[derive(Debug, Error)]
+#[error("can't depend on request {id}, {reason}")]
+pub struct InvalidDependency {
+    pub id: Uuid,
+    pub reason: &'static str,
+}
+
+/// Dependencies must exist and still be able to succeed, otherwise the
+/// dependent request would be failed as soon as the worker saw it.
+/// Another tenant's requests don't exist as far as the caller can tell.
+pub async fn check_dependencies<T: TransactionRepository>(
+    tx_repo: &T,
+    depends_on: &[Uuid],
+    tenant_id: Option<&str>,
+) -> anyhow::Result<()> {
+    for &id in depends_on {
+        let request = tx_repo
+            .get(id)
+            .await?
+            .filter(|request| tenant_id.is_none() || request.tenant_id.as_deref() == tenant_id);
+        let reason = match request {
+            None => "it does not exist",
+            Some(request) if request.status == RequestStatus::Failed => "it failed",
+            Some(request) if request.status == RequestStatus::Cancelled => "it was cancelled",
+            Some(_) => continue,
+        };
+        return Err(InvalidDependency { id, reason }.into());
+    }
+
+    Ok(())
+}
+
+/// Listing a request twice means the same as listing it once, the first mention keeps its place
+pub fn dedup_dependencies(mut depends_on: Vec<Uuid>) -> Vec<Uuid> {
+    let mut seen = HashSet::new();
+    depends_on.retain(|id| seen.insert(*id));
+    depends_on
+}
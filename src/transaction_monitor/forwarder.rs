@@ -0,0 +1,147 @@
+use ethers::{
+    abi::{decode, encode, ParamType, Token},
+    types::{
+        transaction::eip712::{EIP712Domain, Eip712},
+        Address, Bytes, Signature, U256,
+    },
+    utils::{id, keccak256},
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+// OpenZeppelin's MinimalForwarder
+const DOMAIN_NAME: &str = "MinimalForwarder";
+const DOMAIN_VERSION: &str = "0.0.1";
+const REQUEST_TYPE: &str =
+    "ForwardRequest(address from,address to,uint256 value,uint256 gas,uint256 nonce,bytes data)";
+const EXECUTE: &str = "execute((address,address,uint256,uint256,uint256,bytes),bytes)";
+const GET_NONCE: &str = "getNonce(address)";
+
+/// A call signed by a user for an ERC-2771 forwarder to make on their behalf
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ForwardRequest {
+    pub from: Address,
+    pub to: Address,
+    pub value: U256,
+    /// Gas the forwarder gives the call
+    pub gas: U256,
+    /// The user's nonce on the forwarder
+    pub nonce: U256,
+    pub data: Bytes,
+}
+
+#[derive(Debug, Error)]
+pub enum ForwardRejected {
+    #[error("forward request is signed by {signer:?} instead of {from:?}")]
+    WrongSigner { from: Address, signer: Address },
+
+    #[error("forwarder nonce for {from:?} is {expected}, the request has {actual}")]
+    Nonce {
+        from: Address,
+        expected: U256,
+        actual: U256,
+    },
+
+    #[error("forwarded requests can't send value, the relayer would pay it")]
+    Value,
+}
+
+/// The request as typed data for a specific forwarder deployment
+#[derive(Clone, Debug)]
+pub struct TypedForwardRequest<'a> {
+    pub request: &'a ForwardRequest,
+    pub chain_id: u64,
+    pub forwarder: Address,
+}
+
+impl<'a> Eip712 for TypedForwardRequest<'a> {
+    type Error = std::convert::Infallible;
+
+    fn domain(&self) -> Result<EIP712Domain, Self::Error> {
+        Ok(EIP712Domain {
+            name: Some(DOMAIN_NAME.to_owned()),
+            version: Some(DOMAIN_VERSION.to_owned()),
+            chain_id: Some(self.chain_id.into()),
+            verifying_contract: Some(self.forwarder),
+            salt: None,
+        })
+    }
+
+    fn type_hash() -> Result<[u8; 32], Self::Error> {
+        Ok(keccak256(REQUEST_TYPE))
+    }
+
+    fn struct_hash(&self) -> Result<[u8; 32], Self::Error> {
+        let request = self.request;
+        Ok(keccak256(encode(&[
+            Token::FixedBytes(Self::type_hash()?.to_vec()),
+            Token::Address(request.from),
+            Token::Address(request.to),
+            Token::Uint(request.value),
+            Token::Uint(request.gas),
+            Token::Uint(request.nonce),
+            Token::FixedBytes(keccak256(&request.data).to_vec()),
+        ])))
+    }
+}
+
+impl<'a> TypedForwardRequest<'a> {
+    pub fn digest(&self) -> [u8; 32] {
+        match self.encode_eip712() {
+            Ok(digest) => digest,
+            Err(never) => match never {},
+        }
+    }
+
+    /// Forwarded requests are idempotent on what the user signed unless given another key
+    pub fn idempotency_key(&self) -> String {
+        format!("forward:0x{}", hex::encode(self.digest()))
+    }
+
+    /// Fails unless the request was signed by its `from` address
+    pub fn verify(&self, signature: &Signature) -> anyhow::Result<()> {
+        let signer = signature.recover(self.digest())?;
+        if signer != self.request.from {
+            return Err(ForwardRejected::WrongSigner {
+                from: self.request.from,
+                signer,
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+}
+
+impl ForwardRequest {
+    /// Calldata for `MinimalForwarder.execute(request, signature)`
+    pub fn execute_calldata(&self, signature: &Signature) -> Bytes {
+        let request = Token::Tuple(vec![
+            Token::Address(self.from),
+            Token::Address(self.to),
+            Token::Uint(self.value),
+            Token::Uint(self.gas),
+            Token::Uint(self.nonce),
+            Token::Bytes(self.data.to_vec()),
+        ]);
+        let args = encode(&[request, Token::Bytes(signature.to_vec())]);
+        [&id(EXECUTE)[..], &args].concat().into()
+    }
+}
+
+/// Calldata for `MinimalForwarder.getNonce(from)`
+pub fn get_nonce_calldata(from: Address) -> Bytes {
+    [&id(GET_NONCE)[..], &encode(&[Token::Address(from)])]
+        .concat()
+        .into()
+}
+
+pub fn decode_nonce(output: &[u8]) -> anyhow::Result<U256> {
+    match decode(&[ParamType::Uint(256)], output)?.pop() {
+        Some(Token::Uint(nonce)) => Ok(nonce),
+        _ => Err(anyhow::anyhow!(
+            "getNonce returned {}",
+            Bytes::from(output.to_vec())
+        )),
+    }
+}
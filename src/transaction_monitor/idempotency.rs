@@ -0,0 +1,30 @@
+use ethers::{types::Eip1559TransactionRequest, utils::keccak256};
+use thiserror::Error;
+use uuid::Uuid;
+
+use super::dependency::dedup_dependencies;
+use crate::transaction_repository::ChainId;
+
+/// Identifies the body a key was first used with, computed before gas estimation
+/// so a retry of the same request always matches
+pub fn fingerprint(
+    tx: &Eip1559TransactionRequest,
+    chain: ChainId,
+    depends_on: &[Uuid],
+) -> anyhow::Result<String> {
+    let depends_on = dedup_dependencies(depends_on.to_vec());
+    // Leave dependencies out when there are none so existing fingerprints stay valid
+    let encoded = if depends_on.is_empty() {
+        serde_json::to_vec(&(chain.0, tx))?
+    } else {
+        serde_json::to_vec(&(chain.0, tx, depends_on))?
+    };
+    Ok(hex::encode(keccak256(encoded)))
+}
+
+#[derive(Debug, Error)]
+#[error("idempotency key {key:?} was already used for request {id} with a different body")]
+pub struct IdempotencyConflict {
+    pub key: String,
+    pub id: Uuid,
+}
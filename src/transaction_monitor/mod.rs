@@ -1,80 +1,526 @@
-use anyhow::Context;
 use ethers::{
-    prelude::{
-        k256::ecdsa::SigningKey, JsonRpcClient, MiddlewareBuilder, NonceManagerMiddleware,
-        SignerMiddleware,
-    },
+    prelude::{k256::ecdsa::SigningKey, JsonRpcClient, MiddlewareBuilder, SignerMiddleware},
     providers::{Middleware, Provider},
     signers::{LocalWallet, Signer, Wallet},
-    types::{Chain, Eip1559TransactionRequest, TxHash},
+    types::{Address, BlockNumber, Eip1559TransactionRequest, Signature, TxHash, U256},
 };
 
-use std::collections::HashMap;
+use anyhow::bail;
+use futures_util::{stream, StreamExt};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock},
+};
+use tokio::{
+    spawn,
+    time::{sleep, Duration},
+};
+use tracing::{error, info};
 use uuid::Uuid;
 
-use crate::transaction_repository::{DbTxRequestRepository, TransactionRepository};
+use crate::transaction_repository::{
+    Actor, ChainId, DbTxRequestRepository, NewRequest, Request, RequestStatus,
+    TransactionRepository,
+};
+mod balance;
+mod batch;
 mod chain_monitor;
 use chain_monitor::ChainMonitor;
+mod control;
+mod dependency;
+mod forwarder;
 mod gas_escalation;
+mod idempotency;
+mod simulation;
+pub use balance::{BalancePolicy, BalanceStatus, InsufficientBalance, TopUp};
+pub use batch::BatchRejected;
+pub use control::{ChainDraining, ChainState};
+pub use dependency::InvalidDependency;
+use dependency::{check_dependencies, dedup_dependencies};
+pub use forwarder::{ForwardRejected, ForwardRequest, TypedForwardRequest};
+pub use idempotency::{fingerprint, IdempotencyConflict};
+pub use simulation::{RevertReason, SimulationError};
+
+// Nonces are assigned from the database by each ChainMonitor's queue worker
+type ConfigedProvider<P> = SignerMiddleware<Provider<P>, LocalWallet>;
+type ConfigedMonitor<P, T> = ChainMonitor<ConfigedProvider<P>, T>;
 
-type ConfigedProvider<P> = NonceManagerMiddleware<SignerMiddleware<Provider<P>, LocalWallet>>;
-type ConfigedMonitor<P> = ChainMonitor<ConfigedProvider<P>, DbTxRequestRepository>;
+// Simulations are rpc calls, keep a large batch from tripping rate limits
+const BATCH_VALIDATION_CONCURRENCY: usize = 10;
+// A removed chain's requests take a few blocks to settle
+const RETIRE_POLL_INTERVAL: Duration = Duration::from_secs(30);
 
+#[derive(Clone, Debug, Default)]
+pub struct SendOptions {
+    /// Returns the existing request instead of queueing a duplicate when reused
+    pub idempotency_key: Option<String>,
+    /// What the idempotency key is checked against, for requests the relay changes after the
+    /// client sent them. The transaction's `fingerprint` if unset.
+    pub fingerprint: Option<String>,
+    /// Requests that must be mined successfully before this one is submitted
+    pub depends_on: Vec<Uuid>,
+    /// Owner of the request, idempotency keys are scoped to it
+    pub tenant_id: Option<String>,
+    /// Recorded in the request's events as whoever created it
+    pub actor: Actor,
+}
+
+/// Every chain's monitor shares the one repository, any `TransactionRepository` works,
+/// i.e. `InMemoryTxRequestRepository` to run without a database.
+/// Chains can be added, reconfigured and removed while the relay runs.
 #[derive(Debug)]
-pub struct TransactionMonitor<P> {
-    pub tx_repo: DbTxRequestRepository,
-    monitors: HashMap<Chain, ConfigedMonitor<P>>,
+pub struct TransactionMonitor<P, T = DbTxRequestRepository> {
+    pub tx_repo: Arc<T>,
+    monitors: RwLock<HashMap<ChainId, ConfigedMonitor<P, T>>>,
+    /// Removed chains' monitors, they keep tracking what was sent until it settles
+    retiring: Arc<RwLock<HashMap<ChainId, ConfigedMonitor<P, T>>>>,
 }
 
-impl<P> TransactionMonitor<P>
+impl<P, T> TransactionMonitor<P, T>
 where
     P: JsonRpcClient + 'static,
+    T: TransactionRepository + 'static,
 {
-    pub fn new(tx_repo: DbTxRequestRepository) -> Self {
+    pub fn new(tx_repo: T) -> Self {
         Self {
-            tx_repo,
-            monitors: HashMap::new(),
+            tx_repo: Arc::new(tx_repo),
+            monitors: RwLock::default(),
+            retiring: Arc::default(),
         }
     }
 
-    pub async fn get_transaction_status(&self, id: Uuid) -> anyhow::Result<Option<(bool, TxHash)>> {
-        let request = self.tx_repo.get(id).await?;
-        Ok(request.map(|req| (req.mined, req.hash)))
+    /// Requests owned by another tenant are reported as missing,
+    /// no tenant can see every request
+    pub async fn get_transaction_status(
+        &self,
+        id: Uuid,
+        tenant_id: Option<&str>,
+    ) -> anyhow::Result<Option<(RequestStatus, Option<TxHash>)>> {
+        let request = self.get_owned(id, tenant_id).await?;
+        Ok(request.map(|req| (req.status, req.hash)))
     }
 
     pub async fn send_monitored_transaction(
         &self,
         tx: Eip1559TransactionRequest,
-        chain: Chain,
+        chain: ChainId,
+        options: SendOptions,
     ) -> anyhow::Result<Uuid> {
-        let monitor = self
-            .monitors
+        self.monitor(chain)?
+            .send_monitored_transaction(tx, options)
+            .await
+    }
+
+    /// Relays a call signed by `request.from` through a trusted ERC-2771 forwarder, the
+    /// relayer pays for gas. The signature and forwarder nonce are checked before queueing.
+    /// Unless another key is given, the request is idempotent on what the user signed.
+    pub async fn send_forwarded_transaction(
+        &self,
+        request: ForwardRequest,
+        signature: Signature,
+        chain: ChainId,
+        forwarder: Address,
+        mut options: SendOptions,
+    ) -> anyhow::Result<Uuid> {
+        let monitor = self.monitor(chain)?;
+        if !request.value.is_zero() {
+            return Err(ForwardRejected::Value.into());
+        }
+
+        let typed = TypedForwardRequest {
+            request: &request,
+            chain_id: chain.0,
+            forwarder,
+        };
+        typed.verify(&signature)?;
+
+        let get_nonce = Eip1559TransactionRequest::new()
+            .to(forwarder)
+            .data(forwarder::get_nonce_calldata(request.from));
+        let output = monitor.provider.call(&get_nonce.into(), None).await?;
+        let expected = forwarder::decode_nonce(&output)?;
+        if expected != request.nonce {
+            return Err(ForwardRejected::Nonce {
+                from: request.from,
+                expected,
+                actual: request.nonce,
+            }
+            .into());
+        }
+
+        options
+            .idempotency_key
+            .get_or_insert_with(|| typed.idempotency_key());
+        let tx = Eip1559TransactionRequest::new()
+            .to(forwarder)
+            .data(request.execute_calldata(&signature));
+        monitor.send_monitored_transaction(tx, options).await
+    }
+
+    /// Validates every transaction before queueing any of them, then queues the whole batch in
+    /// one database transaction. Each chain's worker submits the batch's requests for that chain
+    /// in order, with consecutive nonces. Fails with `BatchRejected` if any transaction is invalid.
+    /// Transactions with dependencies are held until those are mined, like single requests.
+    pub async fn send_monitored_batch(
+        &self,
+        txs: Vec<(Eip1559TransactionRequest, ChainId, Vec<Uuid>)>,
+        tenant_id: Option<String>,
+        actor: Actor,
+    ) -> anyhow::Result<Vec<Uuid>> {
+        for (_, chain, _) in &txs {
+            self.monitor(*chain)?.control.check_accepting(*chain)?;
+        }
+        let txs: Vec<_> = txs
+            .into_iter()
+            .map(|(tx, chain, depends_on)| (tx, chain, dedup_dependencies(depends_on)))
+            .collect();
+        let tenant = tenant_id.as_deref();
+        let prepared: Vec<anyhow::Result<Eip1559TransactionRequest>> =
+            stream::iter(txs.iter().cloned())
+                .map(|(tx, chain, depends_on)| async move {
+                    let monitor = self.monitor(chain)?;
+                    if depends_on.is_empty() {
+                        monitor.prepare(tx).await
+                    } else {
+                        check_dependencies(self.tx_repo.as_ref(), &depends_on, tenant).await?;
+                        Ok(tx)
+                    }
+                })
+                .buffered(BATCH_VALIDATION_CONCURRENCY)
+                .collect()
+                .await;
+
+        let mut requests = Vec::with_capacity(prepared.len());
+        let mut errors = Vec::new();
+        for (index, (result, (_, chain, depends_on))) in prepared.into_iter().zip(txs).enumerate() {
+            match result {
+                Ok(tx) => requests.push(NewRequest {
+                    id: Uuid::new_v4(),
+                    tx,
+                    chain,
+                    tenant_id: tenant_id.clone(),
+                    depends_on,
+                    actor: actor.clone(),
+                }),
+                Err(err) => errors.push((index, err)),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(BatchRejected { errors }.into());
+        }
+        let chains: HashSet<ChainId> = requests.iter().map(|request| request.chain).collect();
+        for chain in &chains {
+            let txs: Vec<_> = requests
+                .iter()
+                .filter(|request| request.chain == *chain)
+                .map(|request| &request.tx)
+                .collect();
+            self.monitor(*chain)?.check_balance(&txs).await?;
+        }
+
+        let ids = requests.iter().map(|request| request.id).collect();
+        self.tx_repo.save_batch(Uuid::new_v4(), requests).await?;
+        for chain in chains {
+            self.monitor(chain)?.notify_queue();
+        }
+
+        Ok(ids)
+    }
+
+    /// Cancels a request that hasn't been submitted yet, its dependents fail.
+    /// Returns false if it was already submitted or settled, or belongs to another tenant.
+    pub async fn cancel_transaction(
+        &self,
+        id: Uuid,
+        tenant_id: Option<&str>,
+        actor: &Actor,
+    ) -> anyhow::Result<bool> {
+        let Some(request) = self.get_owned(id, tenant_id).await? else {
+            return Ok(false);
+        };
+        let cancelled = self.tx_repo.cancel(id, actor).await?;
+        if cancelled {
+            self.monitor_for(&request)?.notify_queue();
+        }
+        Ok(cancelled)
+    }
+
+    /// Fails a request whatever stage it's at, for operators giving up on it. Its dependents fail.
+    /// Returns false if it was already settled or doesn't exist.
+    pub async fn abandon_transaction(&self, id: Uuid, actor: &Actor) -> anyhow::Result<bool> {
+        let Some(request) = self.tx_repo.get(id).await? else {
+            return Ok(false);
+        };
+        let abandoned = self.tx_repo.abandon(id, actor).await?;
+        if abandoned {
+            if let Ok(monitor) = self.monitor_for(&request) {
+                monitor.notify_queue();
+            }
+        }
+        Ok(abandoned)
+    }
+
+    /// Sends a submitted request again with these fees, returns the new hash.
+    /// Returns None if it isn't submitted or doesn't exist.
+    pub async fn force_rebroadcast(
+        &self,
+        id: Uuid,
+        max_fee: U256,
+        max_priority_fee: U256,
+        actor: &Actor,
+    ) -> anyhow::Result<Option<TxHash>> {
+        let Some(request) = self.tx_repo.get(id).await? else {
+            return Ok(None);
+        };
+        self.monitor_for(&request)?
+            .force_rebroadcast(request, max_fee, max_priority_fee, actor)
+            .await
+    }
+
+    /// Sends a submitted request again with the fees escalation would pick next, returns the
+    /// new hash. Returns None if it isn't submitted or doesn't exist.
+    pub async fn speed_up(&self, id: Uuid, actor: &Actor) -> anyhow::Result<Option<TxHash>> {
+        let Some(request) = self.tx_repo.get(id).await? else {
+            return Ok(None);
+        };
+        self.monitor_for(&request)?.speed_up(request, actor).await
+    }
+
+    /// Settles the chain's submitted requests that were mined since `from_block`,
+    /// returns how many
+    pub async fn replay_from_block(
+        &self,
+        chain: ChainId,
+        from_block: u64,
+    ) -> anyhow::Result<usize> {
+        self.monitor(chain)?.replay_from_block(from_block).await
+    }
+
+    /// The chain's relayer, its next nonce as stored and as the node sees it, pending included
+    pub async fn nonces(&self, chain: ChainId) -> anyhow::Result<(Address, Option<U256>, U256)> {
+        let provider = &self.monitor(chain)?.provider;
+        let address = provider.address();
+        let stored = self.tx_repo.get_next_nonce(chain, address).await?;
+        let pending = provider
+            .get_transaction_count(address, Some(BlockNumber::Pending.into()))
+            .await?;
+        Ok((address, stored, pending))
+    }
+
+    /// Resets the chain's stored nonce to what the node and the submitted requests say,
+    /// returns the next nonce
+    pub async fn resync_nonce(&self, chain: ChainId) -> anyhow::Result<U256> {
+        self.monitor(chain)?.resync_nonce().await
+    }
+
+    /// Chains taking new requests
+    pub fn chains(&self) -> Vec<ChainId> {
+        self.monitors.read().unwrap().keys().copied().collect()
+    }
+
+    /// Removed chains whose last requests are still being tracked
+    pub fn retiring_chains(&self) -> Vec<ChainId> {
+        self.retiring.read().unwrap().keys().copied().collect()
+    }
+
+    pub fn chain_state(&self, chain: ChainId) -> anyhow::Result<ChainState> {
+        Ok(self.any_monitor(chain)?.control.state())
+    }
+
+    /// A paused chain keeps accepting requests, but nothing is submitted or escalated
+    pub fn set_paused(&self, chain: ChainId, paused: bool) -> anyhow::Result<()> {
+        let monitor = self.any_monitor(chain)?;
+        monitor.control.set_paused(paused);
+        if !paused {
+            monitor.notify_queue();
+        }
+        Ok(())
+    }
+
+    /// A draining chain turns away new requests while the ones it has are finished
+    pub fn set_draining(&self, chain: ChainId, draining: bool) -> anyhow::Result<()> {
+        self.monitor(chain)?.control.set_draining(draining);
+        Ok(())
+    }
+
+    async fn get_owned(
+        &self,
+        id: Uuid,
+        tenant_id: Option<&str>,
+    ) -> anyhow::Result<Option<Request>> {
+        let request = self.tx_repo.get(id).await?;
+        Ok(request
+            .filter(|request| tenant_id.is_none() || request.tenant_id.as_deref() == tenant_id))
+    }
+
+    /// Simulates the transaction and fills in its gas limit, without queueing it
+    pub async fn prepare(
+        &self,
+        tx: Eip1559TransactionRequest,
+        chain: ChainId,
+    ) -> anyhow::Result<Eip1559TransactionRequest> {
+        self.monitor(chain)?.prepare(tx).await
+    }
+
+    /// Low balance warnings and top ups for the chain's relayer
+    pub fn set_balance_policy(&self, chain: ChainId, policy: BalancePolicy) -> anyhow::Result<()> {
+        self.monitor(chain)?.balance.set_policy(policy);
+        Ok(())
+    }
+
+    pub fn balance(&self, chain: ChainId) -> anyhow::Result<BalanceStatus> {
+        let monitor = self.monitor(chain)?;
+        Ok(monitor.balance.status(monitor.provider.address()))
+    }
+
+    /// The chain's provider, signing as the relayer
+    pub fn provider(&self, chain: ChainId) -> anyhow::Result<Arc<ConfigedProvider<P>>> {
+        Ok(self.monitor(chain)?.provider.clone())
+    }
+
+    /// Requests already saved are still handled on retiring chains
+    fn monitor_for(&self, request: &Request) -> anyhow::Result<ConfigedMonitor<P, T>> {
+        self.any_monitor(request.chain)
+    }
+
+    fn any_monitor(&self, chain: ChainId) -> anyhow::Result<ConfigedMonitor<P, T>> {
+        if let Some(monitor) = self.retiring.read().unwrap().get(&chain) {
+            return Ok(monitor.clone());
+        }
+        self.monitor(chain)
+    }
+
+    /// Cloned out so no lock is held across awaits, the clone shares everything
+    fn monitor(&self, chain: ChainId) -> anyhow::Result<ConfigedMonitor<P, T>> {
+        self.monitors
+            .read()
+            .unwrap()
             .get(&chain)
-            .unwrap_or_else(|| panic!("monitor for chain {} not defined", chain));
-        monitor.send_monitored_transaction(tx).await
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("monitor for chain {} not defined", chain))
     }
 
+    /// Starts monitoring the chain. A chain that's already set up is reconfigured, its
+    /// switches and balance policy carry over and the old workers stop after their current pass.
+    /// A retiring chain is taken back.
     pub async fn setup_monitor(
-        &mut self,
+        &self,
         signer: Wallet<SigningKey>,
         provider: Provider<P>,
-        chain: Chain,
+        chain: ChainId,
         block_frequency: u8,
+        gas_limit_multiplier: f64,
     ) -> anyhow::Result<()> {
-        let address = signer.address();
+        let mut monitor = self
+            .configure(
+                signer,
+                provider,
+                chain,
+                block_frequency,
+                gas_limit_multiplier,
+            )
+            .await?;
+        // Before anything is swapped, so a failure leaves the chain as it was
+        monitor.sync_nonce().await?;
+
+        let retired = self.retiring.write().unwrap().remove(&chain);
+        if let Some(retired) = &retired {
+            retired.control.set_draining(false);
+        }
+        let replaced = self.monitors.read().unwrap().get(&chain).cloned();
+        if let Some(previous) = replaced.as_ref().or(retired.as_ref()) {
+            monitor.control = previous.control.clone();
+            monitor.balance = previous.balance.clone();
+            previous.stop();
+        }
+        monitor.start();
+        self.monitors.write().unwrap().insert(chain, monitor);
+
+        Ok(())
+    }
+
+    /// Stops taking requests for the chain. Its monitor keeps submitting and escalating the
+    /// requests it already has, and stops once none are waiting, queued or submitted.
+    pub fn remove_monitor(&self, chain: ChainId) -> anyhow::Result<()> {
+        let monitor = self
+            .monitors
+            .write()
+            .unwrap()
+            .remove(&chain)
+            .ok_or_else(|| anyhow::anyhow!("monitor for chain {} not defined", chain))?;
+        monitor.control.set_draining(true);
+        self.retiring
+            .write()
+            .unwrap()
+            .insert(chain, monitor.clone());
+
+        let retiring = self.retiring.clone();
+        spawn(async move {
+            loop {
+                sleep(RETIRE_POLL_INTERVAL).await;
+                match monitor.has_unsettled().await {
+                    Ok(false) => break,
+                    Ok(true) => {}
+                    Err(err) => error!(
+                        "Failed to check retiring chain {} for requests, {:?}",
+                        chain, err
+                    ),
+                }
+            }
+
+            let mut retiring = retiring.write().unwrap();
+            // The chain could have been set up again meanwhile
+            if retiring
+                .get(&chain)
+                .is_some_and(|current| current.same_as(&monitor))
+            {
+                retiring.remove(&chain);
+                monitor.stop();
+                info!("ChainId {} retired, its requests have all settled", chain);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Like `setup_monitor` without starting the chain's workers, for tools acting on requests
+    /// that a running relay's monitors own
+    pub async fn attach_monitor(
+        &self,
+        signer: Wallet<SigningKey>,
+        provider: Provider<P>,
+        chain: ChainId,
+        gas_limit_multiplier: f64,
+    ) -> anyhow::Result<()> {
+        let monitor = self
+            .configure(signer, provider, chain, 1, gas_limit_multiplier)
+            .await?;
+        self.monitors.write().unwrap().insert(chain, monitor);
+
+        Ok(())
+    }
+
+    async fn configure(
+        &self,
+        signer: Wallet<SigningKey>,
+        provider: Provider<P>,
+        chain: ChainId,
+        block_frequency: u8,
+        gas_limit_multiplier: f64,
+    ) -> anyhow::Result<ConfigedMonitor<P, T>> {
         let chain_id = provider.get_chainid().await?;
+        if chain_id != U256::from(chain.0) {
+            bail!("the rpc for {} serves chain id {}", chain, chain_id);
+        }
         let signer = signer.with_chain_id(chain_id.as_u64());
-        let configed = provider.with_signer(signer).nonce_manager(address);
-        configed
-            .initialize_nonce(None)
-            .await
-            .with_context(|| "Could not init nonce")?;
+        let configed = provider.with_signer(signer);
 
-        self.monitors.insert(
+        Ok(ChainMonitor::new(
+            configed,
             chain,
-            ChainMonitor::new(configed, chain, block_frequency, self.tx_repo.clone()),
-        );
-
-        Ok(())
+            block_frequency,
+            gas_limit_multiplier,
+            self.tx_repo.clone(),
+        ))
     }
 }
@@ -0,0 +1,97 @@
+use ethers::{
+    abi::AbiDecode,
+    providers::JsonRpcError,
+    types::{Bytes, U256},
+};
+use serde::Serialize;
+use std::fmt;
+use thiserror::Error;
+
+// Selectors for the two revert payloads solidity emits on its own
+// Error(string) -> require/revert with a message, Panic(uint256) -> assert, overflow etc.
+const ERROR_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+const PANIC_SELECTOR: [u8; 4] = [0x4e, 0x48, 0x7b, 0x71];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RevertReason {
+    Error { message: String },
+    Panic { code: U256, description: String },
+    Custom { data: Bytes },
+    Empty,
+}
+
+impl RevertReason {
+    /// Returns None when the rpc error wasn't a revert at all (bad params, node errors etc.)
+    pub fn from_rpc_error(err: &JsonRpcError) -> Option<Self> {
+        err.as_revert_data().map(|data| Self::decode(&data))
+    }
+
+    pub fn decode(data: &[u8]) -> Self {
+        if data.is_empty() {
+            return RevertReason::Empty;
+        }
+
+        if data.len() >= 4 {
+            let (selector, args) = data.split_at(4);
+            if selector == ERROR_SELECTOR {
+                if let Ok(message) = String::decode(args) {
+                    return RevertReason::Error { message };
+                }
+            }
+
+            if selector == PANIC_SELECTOR {
+                if let Ok(code) = U256::decode(args) {
+                    return RevertReason::Panic {
+                        code,
+                        description: panic_description(code).to_owned(),
+                    };
+                }
+            }
+        }
+
+        RevertReason::Custom {
+            data: Bytes::from(data.to_vec()),
+        }
+    }
+}
+
+impl fmt::Display for RevertReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RevertReason::Error { message } => write!(f, "reverted with reason {:?}", message),
+            RevertReason::Panic { code, description } => {
+                write!(f, "panicked with code {:#x} ({})", code, description)
+            }
+            RevertReason::Custom { data } => write!(f, "reverted with custom error {}", data),
+            RevertReason::Empty => write!(f, "reverted without a reason"),
+        }
+    }
+}
+
+// https://docs.soliditylang.org/en/latest/control-structures.html#panic-via-assert-and-error-via-require
+fn panic_description(code: U256) -> &'static str {
+    if code > U256::from(u8::MAX) {
+        return "unknown panic code";
+    }
+
+    match code.as_u32() {
+        0x00 => "generic compiler inserted panic",
+        0x01 => "assertion failed",
+        0x11 => "arithmetic overflow or underflow",
+        0x12 => "division or modulo by zero",
+        0x21 => "invalid enum value",
+        0x22 => "invalid storage byte array encoding",
+        0x31 => "pop on empty array",
+        0x32 => "array index out of bounds",
+        0x41 => "out of memory",
+        0x51 => "call to zero initialized function",
+        _ => "unknown panic code",
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("transaction simulation failed, {reason}")]
+pub struct SimulationError {
+    pub reason: RevertReason,
+}
@@ -0,0 +1,176 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::anyhow;
+use ethers::types::TxHash;
+use serde::Serialize;
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use super::{ChainId, RequestStatus};
+
+/// Who made a request change state
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Actor {
+    /// The relay itself, i.e. a `ChainMonitor`
+    #[default]
+    System,
+    /// A tenant, through the api key with this id
+    ApiKey(Uuid),
+    /// An operator overriding the relay
+    Admin,
+}
+
+impl fmt::Display for Actor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Actor::System => write!(f, "system"),
+            Actor::ApiKey(id) => write!(f, "api_key:{}", id),
+            Actor::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+impl FromStr for Actor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("api_key", id)) => Ok(Actor::ApiKey(id.parse()?)),
+            None if s == "system" => Ok(Actor::System),
+            None if s == "admin" => Ok(Actor::Admin),
+            _ => Err(anyhow!("unknown actor {}", s)),
+        }
+    }
+}
+
+/// Written like it's stored, `system`, `api_key:{id}` or `admin`
+impl Serialize for Actor {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestEventKind {
+    /// Saved as waiting or queued
+    Created,
+    /// Its dependencies were mined and it was queued
+    Queued,
+    /// Signed with a nonce and sent for the first time
+    Broadcast,
+    /// Sent again with higher fees, under a new hash
+    Replaced,
+    Mined,
+    Failed,
+    Cancelled,
+    /// Given up on by an operator, it's failed from then on
+    Abandoned,
+    /// Its row couldn't be read and was quarantined
+    Invalid,
+}
+
+impl RequestEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RequestEventKind::Created => "created",
+            RequestEventKind::Queued => "queued",
+            RequestEventKind::Broadcast => "broadcast",
+            RequestEventKind::Replaced => "replaced",
+            RequestEventKind::Mined => "mined",
+            RequestEventKind::Failed => "failed",
+            RequestEventKind::Cancelled => "cancelled",
+            RequestEventKind::Abandoned => "abandoned",
+            RequestEventKind::Invalid => "invalid",
+        }
+    }
+
+    /// The event for a `RequestUpdate` to this status, a submitted request only changes its hash
+    pub(super) fn for_update(status: RequestStatus) -> Self {
+        match status {
+            RequestStatus::Waiting => RequestEventKind::Created,
+            RequestStatus::Queued => RequestEventKind::Queued,
+            RequestStatus::Submitted => RequestEventKind::Replaced,
+            RequestStatus::Mined => RequestEventKind::Mined,
+            RequestStatus::Failed => RequestEventKind::Failed,
+            RequestStatus::Cancelled => RequestEventKind::Cancelled,
+            RequestStatus::Invalid => RequestEventKind::Invalid,
+        }
+    }
+}
+
+impl FromStr for RequestEventKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "created" => Ok(RequestEventKind::Created),
+            "queued" => Ok(RequestEventKind::Queued),
+            "broadcast" => Ok(RequestEventKind::Broadcast),
+            "replaced" => Ok(RequestEventKind::Replaced),
+            "mined" => Ok(RequestEventKind::Mined),
+            "failed" => Ok(RequestEventKind::Failed),
+            "cancelled" => Ok(RequestEventKind::Cancelled),
+            "abandoned" => Ok(RequestEventKind::Abandoned),
+            "invalid" => Ok(RequestEventKind::Invalid),
+            _ => Err(anyhow!("unknown request event {}", s)),
+        }
+    }
+}
+
+/// One entry of a request's audit log
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RequestEvent {
+    /// Increases with every event, across requests
+    pub seq: u64,
+    pub request_id: Uuid,
+    pub chain: ChainId,
+    pub tenant_id: Option<String>,
+    pub event: RequestEventKind,
+    pub actor: Actor,
+    /// The request's hash after the event, once it has one
+    pub hash: Option<TxHash>,
+    /// Unix milliseconds
+    pub created_at: u64,
+}
+
+#[derive(FromRow, Clone, Debug)]
+pub(super) struct RequestEventRecord {
+    pub seq: u64,
+    pub request_id: String,
+    pub chain: u64,
+    pub tenant_id: String,
+    pub event: String,
+    pub actor: String,
+    pub hash: Option<String>,
+    pub created_at: u64,
+}
+
+impl TryFrom<RequestEventRecord> for RequestEvent {
+    type Error = anyhow::Error;
+
+    fn try_from(record: RequestEventRecord) -> anyhow::Result<Self> {
+        Ok(RequestEvent {
+            seq: record.seq,
+            request_id: record.request_id.parse()?,
+            chain: ChainId(record.chain),
+            tenant_id: Some(record.tenant_id).filter(|tenant_id| !tenant_id.is_empty()),
+            event: record.event.parse()?,
+            actor: record.actor.parse()?,
+            hash: record.hash.as_deref().map(TxHash::from_str).transpose()?,
+            created_at: record.created_at,
+        })
+    }
+}
+
+/// Reads every record, failing on the first that can't be. Events are never quarantined.
+pub(super) fn events(records: Vec<RequestEventRecord>) -> anyhow::Result<Vec<RequestEvent>> {
+    records
+        .into_iter()
+        .map(|record| {
+            let seq = record.seq;
+            RequestEvent::try_from(record)
+                .map_err(|err| anyhow!("request event {} is invalid: {}", seq, err))
+        })
+        .collect()
+}
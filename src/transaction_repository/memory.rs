@@ -0,0 +1,742 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use ethers::types::{Address, Eip1559TransactionRequest, NameOrAddress, TxHash, U256};
+use uuid::Uuid;
+
+use super::{
+    now_millis, sender_and_nonce, to_gwei, Actor, ChainId, Cursor, IdempotencyKey, ListedRequest,
+    NewRequest, Request, RequestEvent, RequestEventKind, RequestFilter, RequestStatus,
+    RequestUpdate, TransactionRepository, GWEI,
+};
+
+const TERMINAL: [RequestStatus; 3] = [
+    RequestStatus::Mined,
+    RequestStatus::Failed,
+    RequestStatus::Cancelled,
+];
+
+/// Keeps requests in memory, for tests and deployments that don't need them to outlive the process.
+/// Clones share the same requests.
+#[derive(Clone, Debug, Default)]
+pub struct InMemoryTxRequestRepository {
+    state: Arc<Mutex<MemoryState>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    /// Keyed by seq, in the order they were saved
+    requests: BTreeMap<usize, StoredRequest>,
+    next_seq: usize,
+    /// What archived requests cost, by tenant
+    archived_spend: HashMap<String, u64>,
+    ids: HashMap<Uuid, usize>,
+    /// Keyed by tenant, requests without one have an empty tenant like in the database
+    idempotency_keys: HashMap<(String, String), usize>,
+    /// The ids and fingerprints archived requests were saved with, like `idempotency_keys`
+    retired_idempotency_keys: HashMap<(String, String), (Uuid, String)>,
+    dependencies: HashMap<Uuid, Vec<Uuid>>,
+    nonces: HashMap<(u64, Address), U256>,
+    /// In the order they happened, they outlive archived requests
+    events: Vec<RequestEvent>,
+}
+
+#[derive(Debug)]
+struct StoredRequest {
+    request: Request,
+    nonce: Option<U256>,
+    fingerprint: Option<String>,
+    cost_gwei: Option<u64>,
+    created_at: u64,
+    updated_at: u64,
+}
+
+impl InMemoryTxRequestRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl MemoryState {
+    fn insert(
+        &mut self,
+        request: Request,
+        idempotency: Option<IdempotencyKey>,
+        nonce: Option<U256>,
+    ) -> anyhow::Result<()> {
+        if self.ids.contains_key(&request.id) {
+            return Err(anyhow!("request {} already exists", request.id));
+        }
+        let tenant_id = request.tenant_id.clone().unwrap_or_default();
+        let (key, fingerprint) = idempotency
+            .map(|IdempotencyKey { key, fingerprint }| (key, fingerprint))
+            .unzip();
+        if let Some(key) = &key {
+            let taken = (tenant_id.clone(), key.clone());
+            if self.idempotency_keys.contains_key(&taken)
+                || self.retired_idempotency_keys.contains_key(&taken)
+            {
+                return Err(anyhow!("idempotency key {:?} is already in use", key));
+            }
+        }
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        if let Some(key) = key {
+            self.idempotency_keys.insert((tenant_id, key), seq);
+        }
+        self.ids.insert(request.id, seq);
+        let now = now_millis() as u64;
+        self.requests.insert(
+            seq,
+            StoredRequest {
+                request,
+                nonce,
+                fingerprint,
+                cost_gwei: None,
+                created_at: now,
+                updated_at: now,
+            },
+        );
+        Ok(())
+    }
+
+    /// Like the unique index, only one submitted request can hold a sender's nonce
+    fn check_active_nonce(
+        &self,
+        id: Uuid,
+        chain: u64,
+        from: Address,
+        nonce: U256,
+    ) -> anyhow::Result<()> {
+        let taken = self.requests.values().any(|stored| {
+            stored.request.id != id
+                && stored.request.status == RequestStatus::Submitted
+                && stored.request.chain.0 == chain
+                && stored.request.tx.from == Some(from)
+                && stored.nonce == Some(nonce)
+        });
+        if taken {
+            return Err(anyhow!(
+                "nonce {} of {:?} is already held by a submitted request",
+                nonce,
+                from
+            ));
+        }
+        Ok(())
+    }
+
+    fn get_mut(&mut self, id: Uuid) -> Option<&mut StoredRequest> {
+        let seq = *self.ids.get(&id)?;
+        self.requests.get_mut(&seq)
+    }
+
+    /// Moves the request to `to` if it's in one of the `from` statuses
+    fn transition(&mut self, id: Uuid, from: &[RequestStatus], to: RequestStatus) -> bool {
+        match self.get_mut(id) {
+            Some(stored) if from.contains(&stored.request.status) => {
+                stored.request.status = to;
+                stored.updated_at = now_millis() as u64;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Appends to the request's audit log, with its chain, tenant and current hash
+    fn record(&mut self, id: Uuid, event: RequestEventKind, actor: &Actor) {
+        let Some(&seq) = self.ids.get(&id) else {
+            return;
+        };
+        let request = &self.requests[&seq].request;
+        let event = RequestEvent {
+            seq: self.events.len() as u64 + 1,
+            request_id: id,
+            chain: request.chain,
+            tenant_id: request.tenant_id.clone(),
+            event,
+            actor: actor.clone(),
+            hash: request.hash,
+            created_at: now_millis() as u64,
+        };
+        self.events.push(event);
+    }
+
+    fn parents(&self, id: Uuid) -> impl Iterator<Item = &Request> {
+        self.dependencies
+            .get(&id)
+            .into_iter()
+            .flatten()
+            .filter_map(|parent| self.ids.get(parent))
+            .map(|&seq| &self.requests[&seq].request)
+    }
+
+    /// Requests a waiting child depends on, retention leaves them alone
+    fn held(&self) -> HashSet<Uuid> {
+        self.dependencies
+            .iter()
+            .filter(|(child, _)| {
+                self.ids.get(child).is_some_and(|&seq| {
+                    self.requests[&seq].request.status == RequestStatus::Waiting
+                })
+            })
+            .flat_map(|(_, parents)| parents.iter().copied())
+            .collect()
+    }
+
+    fn with_status(
+        &self,
+        chain: ChainId,
+        status: RequestStatus,
+    ) -> impl Iterator<Item = &StoredRequest> {
+        self.requests
+            .values()
+            .filter(move |stored| stored.request.chain == chain && stored.request.status == status)
+    }
+
+    fn advance_nonce(&mut self, chain_id: u64, from: Address, nonce: U256) {
+        let next = self.nonces.entry((chain_id, from)).or_default();
+        *next = (*next).max(nonce + 1);
+    }
+}
+
+fn new_request(request: NewRequest, batch: Option<(Uuid, u32)>) -> Request {
+    Request {
+        status: request.initial_status(),
+        id: request.id,
+        tx: request.tx,
+        hash: None,
+        chain: request.chain,
+        batch_id: batch.map(|(batch_id, _)| batch_id),
+        batch_index: batch.map(|(_, batch_index)| batch_index),
+        tenant_id: request.tenant_id.filter(|tenant_id| !tenant_id.is_empty()),
+    }
+}
+
+fn to_address(to: &Option<NameOrAddress>) -> Option<Address> {
+    to.as_ref().and_then(|to| to.as_address().copied())
+}
+
+#[async_trait]
+impl TransactionRepository for InMemoryTxRequestRepository {
+    async fn save(
+        &self,
+        request: NewRequest,
+        idempotency: Option<IdempotencyKey>,
+    ) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let (id, depends_on, actor) = (
+            request.id,
+            request.depends_on.clone(),
+            request.actor.clone(),
+        );
+        state.insert(new_request(request, None), idempotency, None)?;
+        if !depends_on.is_empty() {
+            state.dependencies.insert(id, depends_on);
+        }
+        state.record(id, RequestEventKind::Created, &actor);
+        Ok(())
+    }
+
+    async fn save_batch(&self, batch_id: Uuid, requests: Vec<NewRequest>) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        // All or nothing, like the database transaction
+        let mut ids = HashSet::new();
+        for request in &requests {
+            if state.ids.contains_key(&request.id) || !ids.insert(request.id) {
+                return Err(anyhow!("request {} already exists", request.id));
+            }
+        }
+
+        for (batch_index, request) in requests.into_iter().enumerate() {
+            let (id, depends_on, actor) = (
+                request.id,
+                request.depends_on.clone(),
+                request.actor.clone(),
+            );
+            let request = new_request(request, Some((batch_id, batch_index as u32)));
+            state.insert(request, None, None)?;
+            if !depends_on.is_empty() {
+                state.dependencies.insert(id, depends_on);
+            }
+            state.record(id, RequestEventKind::Created, &actor);
+        }
+        Ok(())
+    }
+
+    async fn get(&self, id: Uuid) -> anyhow::Result<Option<Request>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .ids
+            .get(&id)
+            .map(|&seq| state.requests[&seq].request.clone()))
+    }
+
+    async fn get_by_idempotency_key(
+        &self,
+        tenant_id: Option<&str>,
+        key: &str,
+    ) -> anyhow::Result<Option<(Uuid, String)>> {
+        let state = self.state.lock().unwrap();
+        let key = (tenant_id.unwrap_or_default().to_owned(), key.to_owned());
+        let saved = state
+            .idempotency_keys
+            .get(&key)
+            .map(|&seq| &state.requests[&seq])
+            .map(|stored| {
+                (
+                    stored.request.id,
+                    stored.fingerprint.clone().unwrap_or_default(),
+                )
+            });
+        Ok(saved.or_else(|| state.retired_idempotency_keys.get(&key).cloned()))
+    }
+
+    async fn get_spend(&self, tenant_id: &str) -> anyhow::Result<U256> {
+        let state = self.state.lock().unwrap();
+        let spent: u64 = state
+            .requests
+            .values()
+            .filter(|stored| stored.request.tenant_id.as_deref().unwrap_or_default() == tenant_id)
+            .filter_map(|stored| stored.cost_gwei)
+            .sum::<u64>()
+            + state
+                .archived_spend
+                .get(tenant_id)
+                .copied()
+                .unwrap_or_default();
+        Ok(U256::from(spent) * GWEI)
+    }
+
+    async fn get_queued(&self, chain: ChainId) -> anyhow::Result<Vec<Request>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .with_status(chain, RequestStatus::Queued)
+            .map(|stored| stored.request.clone())
+            .collect())
+    }
+
+    async fn get_ready(&self, chain: ChainId) -> anyhow::Result<Vec<Request>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .with_status(chain, RequestStatus::Waiting)
+            .filter(|stored| {
+                // A parent that's gone was never seen mined
+                let dependencies = state.dependencies.get(&stored.request.id);
+                dependencies.into_iter().flatten().all(|parent| {
+                    state.ids.get(parent).is_some_and(|seq| {
+                        state.requests[seq].request.status == RequestStatus::Mined
+                    })
+                })
+            })
+            .map(|stored| stored.request.clone())
+            .collect())
+    }
+
+    async fn mark_queued(&self, id: Uuid, tx: Eip1559TransactionRequest) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(stored) = state.get_mut(id) {
+            if stored.request.status == RequestStatus::Waiting {
+                stored.request.tx = tx;
+                stored.request.status = RequestStatus::Queued;
+                stored.updated_at = now_millis() as u64;
+                state.record(id, RequestEventKind::Queued, &Actor::System);
+            }
+        }
+        Ok(())
+    }
+
+    async fn mark_failed(&self, id: Uuid) -> anyhow::Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        let failed = state.transition(
+            id,
+            &[RequestStatus::Waiting, RequestStatus::Queued],
+            RequestStatus::Failed,
+        );
+        if failed {
+            state.record(id, RequestEventKind::Failed, &Actor::System);
+        }
+        Ok(failed)
+    }
+
+    async fn fail_blocked(&self, chain: ChainId) -> anyhow::Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        let blocked: Vec<Uuid> = state
+            .with_status(chain, RequestStatus::Waiting)
+            .filter(|stored| {
+                state.parents(stored.request.id).any(|parent| {
+                    matches!(
+                        parent.status,
+                        RequestStatus::Failed | RequestStatus::Cancelled
+                    )
+                })
+            })
+            .map(|stored| stored.request.id)
+            .collect();
+        for &id in &blocked {
+            state.transition(id, &[RequestStatus::Waiting], RequestStatus::Failed);
+            state.record(id, RequestEventKind::Failed, &Actor::System);
+        }
+        Ok(blocked.len() as u64)
+    }
+
+    async fn cancel(&self, id: Uuid, actor: &Actor) -> anyhow::Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        let cancelled = state.transition(
+            id,
+            &[RequestStatus::Waiting, RequestStatus::Queued],
+            RequestStatus::Cancelled,
+        );
+        if cancelled {
+            state.record(id, RequestEventKind::Cancelled, actor);
+        }
+        Ok(cancelled)
+    }
+
+    async fn abandon(&self, id: Uuid, actor: &Actor) -> anyhow::Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        let abandoned = state.transition(
+            id,
+            &[
+                RequestStatus::Waiting,
+                RequestStatus::Queued,
+                RequestStatus::Submitted,
+            ],
+            RequestStatus::Failed,
+        );
+        if abandoned {
+            state.record(id, RequestEventKind::Abandoned, actor);
+        }
+        Ok(abandoned)
+    }
+
+    async fn get_pending(&self, chain: ChainId) -> anyhow::Result<Vec<Request>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .with_status(chain, RequestStatus::Submitted)
+            .map(|stored| stored.request.clone())
+            .collect())
+    }
+
+    async fn count_in_flight(&self, chain: ChainId) -> anyhow::Result<u64> {
+        let state = self.state.lock().unwrap();
+        let queued = state.with_status(chain, RequestStatus::Queued).count();
+        let submitted = state.with_status(chain, RequestStatus::Submitted).count();
+        Ok((queued + submitted) as u64)
+    }
+
+    async fn mark_submitted(
+        &self,
+        id: Uuid,
+        hash: TxHash,
+        tx: Eip1559TransactionRequest,
+    ) -> anyhow::Result<bool> {
+        let (from, nonce) = sender_and_nonce(id, &tx)?;
+        let chain_id = tx
+            .chain_id
+            .ok_or_else(|| anyhow!("transaction is missing a chain id"))?;
+        let mut state = self.state.lock().unwrap();
+        state.check_active_nonce(id, chain_id.as_u64(), from, nonce)?;
+
+        // Cancelled while the worker was signing it
+        match state.get_mut(id) {
+            Some(stored) if stored.request.status == RequestStatus::Queued => {
+                stored.request.hash = Some(hash);
+                stored.request.tx = tx;
+                stored.request.status = RequestStatus::Submitted;
+                stored.nonce = Some(nonce);
+                stored.updated_at = now_millis() as u64;
+            }
+            _ => return Ok(false),
+        }
+        state.advance_nonce(chain_id.as_u64(), from, nonce);
+        state.record(id, RequestEventKind::Broadcast, &Actor::System);
+        Ok(true)
+    }
+
+    async fn save_submitted(
+        &self,
+        id: Uuid,
+        hash: TxHash,
+        tx: Eip1559TransactionRequest,
+        chain: ChainId,
+    ) -> anyhow::Result<()> {
+        let (from, nonce) = sender_and_nonce(id, &tx)?;
+        let mut state = self.state.lock().unwrap();
+        state.check_active_nonce(id, chain.0, from, nonce)?;
+
+        let request = Request {
+            id,
+            tx,
+            hash: Some(hash),
+            status: RequestStatus::Submitted,
+            chain,
+            batch_id: None,
+            batch_index: None,
+            tenant_id: None,
+        };
+        state.insert(request, None, Some(nonce))?;
+        state.advance_nonce(chain.0, from, nonce);
+        state.record(id, RequestEventKind::Broadcast, &Actor::System);
+        Ok(())
+    }
+
+    async fn replace(
+        &self,
+        id: Uuid,
+        hash: TxHash,
+        tx: Eip1559TransactionRequest,
+        actor: &Actor,
+    ) -> anyhow::Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        match state.get_mut(id) {
+            Some(stored) if stored.request.status == RequestStatus::Submitted => {
+                stored.request.hash = Some(hash);
+                stored.request.tx = tx;
+                stored.updated_at = now_millis() as u64;
+            }
+            _ => return Ok(false),
+        }
+        state.record(id, RequestEventKind::Replaced, actor);
+        Ok(true)
+    }
+
+    async fn get_next_nonce(
+        &self,
+        chain: ChainId,
+        address: Address,
+    ) -> anyhow::Result<Option<U256>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.nonces.get(&(chain.0, address)).copied())
+    }
+
+    async fn set_next_nonce(
+        &self,
+        chain: ChainId,
+        address: Address,
+        nonce: U256,
+    ) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let next = state.nonces.entry((chain.0, address)).or_default();
+        *next = (*next).max(nonce);
+        Ok(())
+    }
+
+    async fn reset_next_nonce(
+        &self,
+        chain: ChainId,
+        address: Address,
+        nonce: U256,
+    ) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.nonces.insert((chain.0, address), nonce);
+        Ok(())
+    }
+
+    async fn get_submitted_nonces(
+        &self,
+        chain: ChainId,
+        address: Address,
+        from_nonce: U256,
+    ) -> anyhow::Result<Vec<U256>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .with_status(chain, RequestStatus::Submitted)
+            .filter(|stored| stored.request.tx.from == Some(address))
+            .filter_map(|stored| stored.nonce)
+            .filter(|&nonce| nonce >= from_nonce)
+            .collect())
+    }
+
+    async fn update_many(&self, updates: Vec<RequestUpdate>) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let now = now_millis() as u64;
+
+        for RequestUpdate {
+            id,
+            read_hash,
+            status,
+            hash,
+            cost,
+        } in updates
+        {
+            let Some(stored) = state.get_mut(id).filter(|stored| {
+                stored.request.status == RequestStatus::Submitted
+                    && stored.request.hash == Some(read_hash)
+            }) else {
+                continue;
+            };
+            if stored.request.hash != Some(hash) || stored.request.status != status {
+                stored.updated_at = now;
+            }
+            stored.request.hash = Some(hash);
+            stored.request.status = status;
+            if let Some(cost) = cost {
+                stored.cost_gwei = Some(to_gwei(cost));
+            }
+            state.record(id, RequestEventKind::for_update(status), &Actor::System);
+        }
+        Ok(())
+    }
+
+    async fn list(
+        &self,
+        filter: &RequestFilter,
+        cursor: Option<Cursor>,
+        limit: u32,
+    ) -> anyhow::Result<(Vec<ListedRequest>, Option<Cursor>)> {
+        let state = self.state.lock().unwrap();
+        let mut matching: Vec<(Cursor, &StoredRequest)> = state
+            .requests
+            .iter()
+            .map(|(&seq, stored)| {
+                let position = Cursor {
+                    created_at: stored.created_at,
+                    seq: seq as u64,
+                };
+                (position, stored)
+            })
+            .filter(|(position, stored)| {
+                let request = &stored.request;
+                filter.chain.is_none_or(|chain| request.chain == chain)
+                    && filter.status.is_none_or(|status| request.status == status)
+                    && filter.tenant_id.as_deref().is_none_or(|tenant_id| {
+                        request.tenant_id.as_deref().unwrap_or_default() == tenant_id
+                    })
+                    && filter
+                        .to
+                        .is_none_or(|to| to_address(&request.tx.to) == Some(to))
+                    && filter
+                        .sender
+                        .is_none_or(|sender| request.tx.from == Some(sender))
+                    && filter
+                        .created_after
+                        .is_none_or(|after| position.created_at >= after)
+                    && filter
+                        .created_before
+                        .is_none_or(|before| position.created_at < before)
+                    && cursor.is_none_or(|cursor| {
+                        (position.created_at, position.seq) < (cursor.created_at, cursor.seq)
+                    })
+            })
+            .collect();
+        matching
+            .sort_by_key(|(position, _)| std::cmp::Reverse((position.created_at, position.seq)));
+        matching.truncate(limit as usize);
+
+        let next = match matching.last() {
+            Some((position, _)) if matching.len() == limit as usize => Some(*position),
+            _ => None,
+        };
+        let requests = matching
+            .into_iter()
+            .map(|(position, stored)| ListedRequest {
+                request: stored.request.clone(),
+                created_at: position.created_at,
+                updated_at: stored.updated_at,
+            })
+            .collect();
+        Ok((requests, next))
+    }
+
+    async fn get_expired(
+        &self,
+        chain: ChainId,
+        before: u64,
+        limit: u32,
+    ) -> anyhow::Result<Vec<ListedRequest>> {
+        let state = self.state.lock().unwrap();
+        let held = state.held();
+        Ok(state
+            .requests
+            .values()
+            .filter(|stored| {
+                stored.request.chain == chain
+                    && TERMINAL.contains(&stored.request.status)
+                    && stored.updated_at < before
+                    && !held.contains(&stored.request.id)
+            })
+            .take(limit as usize)
+            .map(|stored| ListedRequest {
+                request: stored.request.clone(),
+                created_at: stored.created_at,
+                updated_at: stored.updated_at,
+            })
+            .collect())
+    }
+
+    // An archive wouldn't outlive the process either, so requests are always dropped
+    async fn archive(&self, ids: &[Uuid], _keep: bool) -> anyhow::Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        let held = state.held();
+        let mut removed = 0;
+        for id in ids {
+            let Some(&seq) = state.ids.get(id) else {
+                continue;
+            };
+            if !TERMINAL.contains(&state.requests[&seq].request.status) || held.contains(id) {
+                continue;
+            }
+            let stored = state
+                .requests
+                .remove(&seq)
+                .expect("ids point at stored requests");
+            state.ids.remove(id);
+            let keys: Vec<_> = state
+                .idempotency_keys
+                .iter()
+                .filter(|(_, &key_seq)| key_seq == seq)
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in keys {
+                state.idempotency_keys.remove(&key);
+                let fingerprint = stored.fingerprint.clone().unwrap_or_default();
+                state
+                    .retired_idempotency_keys
+                    .insert(key, (stored.request.id, fingerprint));
+            }
+            state.dependencies.remove(id);
+            if let Some(cost) = stored.cost_gwei {
+                let tenant_id = stored.request.tenant_id.unwrap_or_default();
+                *state.archived_spend.entry(tenant_id).or_default() += cost;
+            }
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    async fn get_events(&self, id: Uuid) -> anyhow::Result<Vec<RequestEvent>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .events
+            .iter()
+            .filter(|event| event.request_id == id)
+            .cloned()
+            .collect())
+    }
+
+    async fn list_events(
+        &self,
+        tenant_id: Option<&str>,
+        after: u64,
+        limit: u32,
+    ) -> anyhow::Result<Vec<RequestEvent>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .events
+            .iter()
+            .filter(|event| {
+                event.seq > after
+                    && tenant_id.is_none_or(|tenant_id| {
+                        event.tenant_id.as_deref().unwrap_or_default() == tenant_id
+                    })
+            })
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+}
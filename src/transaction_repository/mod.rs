@@ -1,180 +1,839 @@
-use std::{fmt::Debug, str::FromStr};
+use std::{
+    fmt::Debug,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+use anyhow::anyhow;
 use async_trait::async_trait;
-use ethers::types::{Chain, Eip1559TransactionRequest, TxHash};
-use serde_json::to_string;
-use sqlx::{query, query_as, types::Json, FromRow, MySqlPool};
+use ethers::types::{
+    Address, Bytes, Chain, Eip1559TransactionRequest, NameOrAddress, TxHash, U256,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use thiserror::Error;
+use tracing::error;
 use uuid::Uuid;
 
+use crate::database::DbPool;
+
+mod events;
+mod memory;
+mod sql;
+pub use events::{Actor, RequestEvent, RequestEventKind};
+pub use memory::InMemoryTxRequestRepository;
+pub use sql::{
+    MySqlTxRequestRepository, PgTxRequestRepository, SqlTxRequestRepository,
+    SqliteTxRequestRepository,
+};
+
+const GWEI: u64 = 1_000_000_000;
+
+/// Every change of a request's status is recorded as a `RequestEvent`, in the same database
+/// transaction as the change
 #[async_trait]
 pub trait TransactionRepository: Sync + Send + Debug {
+    /// Persists a request as queued, or waiting if it has dependencies.
+    /// It has no nonce or hash until a worker submits it.
+    /// Fails if the idempotency key is already in use.
     async fn save(
+        &self,
+        request: NewRequest,
+        idempotency: Option<IdempotencyKey>,
+    ) -> anyhow::Result<()>;
+    /// Persists every request like `save` in one database transaction
+    async fn save_batch(&self, batch_id: Uuid, requests: Vec<NewRequest>) -> anyhow::Result<()>;
+    async fn get(&self, id: Uuid) -> anyhow::Result<Option<Request>>;
+    /// The id and fingerprint of the request the tenant saved with this key, even once it's removed
+    async fn get_by_idempotency_key(
+        &self,
+        tenant_id: Option<&str>,
+        key: &str,
+    ) -> anyhow::Result<Option<(Uuid, String)>>;
+    /// Wei spent on the tenant's mined and failed transactions, including their value
+    async fn get_spend(&self, tenant_id: &str) -> anyhow::Result<U256>;
+    /// Queued requests in the order they were saved
+    async fn get_queued(&self, chain: ChainId) -> anyhow::Result<Vec<Request>>;
+    /// Waiting requests whose dependencies have all been mined, in the order they were saved
+    async fn get_ready(&self, chain: ChainId) -> anyhow::Result<Vec<Request>>;
+    /// Moves a waiting request to queued with its prepared transaction
+    async fn mark_queued(&self, id: Uuid, tx: Eip1559TransactionRequest) -> anyhow::Result<()>;
+    /// Fails a request that hasn't been submitted yet, returns false if it already was
+    async fn mark_failed(&self, id: Uuid) -> anyhow::Result<bool>;
+    /// Fails waiting requests that depend on a failed or cancelled request, returning how many
+    async fn fail_blocked(&self, chain: ChainId) -> anyhow::Result<u64>;
+    /// Cancels a request that hasn't been submitted yet, returns false if it already was
+    async fn cancel(&self, id: Uuid, actor: &Actor) -> anyhow::Result<bool>;
+    /// Fails a request whatever stage it's at, returns false if it was already settled.
+    /// A submitted transaction is no longer tracked, it may still be mined.
+    async fn abandon(&self, id: Uuid, actor: &Actor) -> anyhow::Result<bool>;
+    async fn get_pending(&self, chain: ChainId) -> anyhow::Result<Vec<Request>>;
+    /// How many requests on the chain are queued or submitted but not yet mined
+    async fn count_in_flight(&self, chain: ChainId) -> anyhow::Result<u64>;
+    /// Records the signed transaction for a queued request, this must happen before it's broadcast.
+    /// Advances the sender's next nonce past the transaction's nonce.
+    /// Returns false, without using the nonce, if the request is no longer queued.
+    async fn mark_submitted(
         &self,
         id: Uuid,
         hash: TxHash,
         tx: Eip1559TransactionRequest,
-        mined: bool,
-        chain: Chain,
+    ) -> anyhow::Result<bool>;
+    /// Like `mark_submitted` for transactions the relay creates itself, i.e. nonce gap fillers
+    async fn save_submitted(
+        &self,
+        id: Uuid,
+        hash: TxHash,
+        tx: Eip1559TransactionRequest,
+        chain: ChainId,
     ) -> anyhow::Result<()>;
-    async fn get(&self, id: Uuid) -> anyhow::Result<Option<Request>>;
-    async fn get_pending(&self, chain: Chain) -> anyhow::Result<Vec<Request>>;
+    /// Records a submitted request's replacement, same nonce with other fees.
+    /// Returns false if the request is no longer submitted.
+    async fn replace(
+        &self,
+        id: Uuid,
+        hash: TxHash,
+        tx: Eip1559TransactionRequest,
+        actor: &Actor,
+    ) -> anyhow::Result<bool>;
+    async fn get_next_nonce(
+        &self,
+        chain: ChainId,
+        address: Address,
+    ) -> anyhow::Result<Option<U256>>;
+    /// Raises the stored nonce to `nonce`, it's never lowered
+    async fn set_next_nonce(
+        &self,
+        chain: ChainId,
+        address: Address,
+        nonce: U256,
+    ) -> anyhow::Result<()>;
+    /// Overwrites the stored nonce even if that lowers it, only for resyncing with the node
+    async fn reset_next_nonce(
+        &self,
+        chain: ChainId,
+        address: Address,
+        nonce: U256,
+    ) -> anyhow::Result<()>;
+    /// Nonces of the address's submitted requests on the chain, starting from `from_nonce`
+    async fn get_submitted_nonces(
+        &self,
+        chain: ChainId,
+        address: Address,
+        from_nonce: U256,
+    ) -> anyhow::Result<Vec<U256>>;
+    /// Applies the updates whose requests haven't changed since they were read, skipping the rest
     async fn update_many(&self, updates: Vec<RequestUpdate>) -> anyhow::Result<()>;
+    /// Requests matching the filter, newest first. Pass the returned cursor to get the next page,
+    /// it's None once there are no more.
+    async fn list(
+        &self,
+        filter: &RequestFilter,
+        cursor: Option<Cursor>,
+        limit: u32,
+    ) -> anyhow::Result<(Vec<ListedRequest>, Option<Cursor>)>;
+    /// Mined, failed or cancelled requests on the chain that haven't changed since `before`
+    /// (unix milliseconds), oldest first. Requests that a waiting request depends on are left out.
+    async fn get_expired(
+        &self,
+        chain: ChainId,
+        before: u64,
+        limit: u32,
+    ) -> anyhow::Result<Vec<ListedRequest>>;
+    /// Moves the requests to the archive, or deletes them unless `keep`, returning how many went.
+    /// Their cost still counts toward the tenant's spend and their idempotency keys stay taken.
+    /// Requests that aren't mined, failed or
+    /// cancelled are left alone.
+    async fn archive(&self, ids: &[Uuid], keep: bool) -> anyhow::Result<u64>;
+    /// The request's audit log, oldest first. It outlives the request.
+    async fn get_events(&self, id: Uuid) -> anyhow::Result<Vec<RequestEvent>>;
+    /// Events with a seq above `after`, oldest first, only the tenant's when one is given
+    async fn list_events(
+        &self,
+        tenant_id: Option<&str>,
+        after: u64,
+        limit: u32,
+    ) -> anyhow::Result<Vec<RequestEvent>>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestStatus {
+    /// Saved, held until the requests it depends on are mined
+    Waiting,
+    /// Saved, waiting for the chain's worker to assign a nonce
+    Queued,
+    /// Signed with a nonce and broadcast, not yet included
+    Submitted,
+    /// Included and executed successfully
+    Mined,
+    /// Reverted on chain, or never sent because a dependency failed or it stopped simulating
+    Failed,
+    Cancelled,
+    /// The row couldn't be read back, it's set aside until someone looks at it
+    Invalid,
+}
+
+impl RequestStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RequestStatus::Waiting => "waiting",
+            RequestStatus::Queued => "queued",
+            RequestStatus::Submitted => "submitted",
+            RequestStatus::Mined => "mined",
+            RequestStatus::Failed => "failed",
+            RequestStatus::Cancelled => "cancelled",
+            RequestStatus::Invalid => "invalid",
+        }
+    }
+}
+
+impl FromStr for RequestStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "waiting" => Ok(RequestStatus::Waiting),
+            "queued" => Ok(RequestStatus::Queued),
+            "submitted" => Ok(RequestStatus::Submitted),
+            "mined" => Ok(RequestStatus::Mined),
+            "failed" => Ok(RequestStatus::Failed),
+            "cancelled" => Ok(RequestStatus::Cancelled),
+            "invalid" => Ok(RequestStatus::Invalid),
+            _ => Err(anyhow!("unknown request status {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct NewRequest {
+    pub id: Uuid,
+    pub tx: Eip1559TransactionRequest,
+    pub chain: ChainId,
+    pub tenant_id: Option<String>,
+    /// Requests that must be mined successfully before this one is submitted
+    pub depends_on: Vec<Uuid>,
+    /// Recorded as whoever created it
+    pub actor: Actor,
+}
+
+impl NewRequest {
+    fn initial_status(&self) -> RequestStatus {
+        if self.depends_on.is_empty() {
+            RequestStatus::Queued
+        } else {
+            RequestStatus::Waiting
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct IdempotencyKey {
+    pub key: String,
+    pub fingerprint: String,
 }
 
+/// Settles or escalates a submitted request. It's only applied if the request is still
+/// submitted under `read_hash`, so an abandon or admin replacement since it was read wins.
 pub struct RequestUpdate {
     pub id: Uuid,
-    pub mined: bool,
+    /// The hash the request had when it was read
+    pub read_hash: TxHash,
+    pub status: RequestStatus,
     pub hash: TxHash,
+    /// Wei paid once included, value plus gas
+    pub cost: Option<U256>,
 }
 
+/// A request row, the transaction is read from its typed columns
 #[derive(FromRow, Clone, Debug)]
 pub struct RequestRecord {
     pub id: String,
-    pub tx: Json<Eip1559TransactionRequest>,
-    pub hash: String,
-    pub mined: bool,
-    pub chain: u32, // TODO is this big enough? I think so
+    pub hash: Option<String>,
+    pub status: String,
+    pub chain: u64,
+    pub batch_id: Option<String>,
+    pub batch_index: Option<u32>,
+    pub tenant_id: String,
+    pub from_address: Option<String>,
+    pub to_address: Option<String>,
+    pub nonce: Option<u64>,
+    /// Wei amounts are decimal strings
+    pub value: Option<String>,
+    pub max_fee: Option<String>,
+    pub priority_fee: Option<String>,
+    pub gas_limit: Option<u64>,
+    pub data: Option<Vec<u8>>,
+}
+
+/// A chain id, which doesn't have to be one `ethers` knows about
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ChainId(pub u64);
+
+impl From<Chain> for ChainId {
+    fn from(chain: Chain) -> Self {
+        ChainId(chain as u64)
+    }
+}
+
+impl TryFrom<ChainId> for Chain {
+    type Error = anyhow::Error;
+
+    fn try_from(chain: ChainId) -> anyhow::Result<Self> {
+        Chain::try_from(chain.0).map_err(|_| anyhow!("unknown chain id {}", chain.0))
+    }
+}
+
+impl PartialEq<Chain> for ChainId {
+    fn eq(&self, chain: &Chain) -> bool {
+        self.0 == *chain as u64
+    }
+}
+
+impl std::fmt::Display for ChainId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match Chain::try_from(self.0) {
+            Ok(chain) => write!(f, "{}", chain),
+            Err(_) => write!(f, "{}", self.0),
+        }
+    }
+}
+
+/// Known chains by name like `Chain`, others by their id
+impl Serialize for ChainId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match Chain::try_from(self.0) {
+            Ok(chain) => chain.serialize(serializer),
+            Err(_) => serializer.serialize_u64(self.0),
+        }
+    }
 }
 
+/// A chain name `ethers` knows, or any numeric id
+impl FromStr for ChainId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(id) = s.parse::<u64>() {
+            return Ok(ChainId(id));
+        }
+        Chain::from_str(s)
+            .map(ChainId::from)
+            .map_err(|_| anyhow!("unknown chain {}", s))
+    }
+}
+
+/// Accepts what `Serialize` writes, and ids as strings
+impl<'de> Deserialize<'de> for ChainId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Id(u64),
+            Name(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Id(id) => Ok(ChainId(id)),
+            Raw::Name(name) => name.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Request {
     pub id: Uuid,
     pub tx: Eip1559TransactionRequest,
-    pub hash: TxHash,
-    pub mined: bool,
-    pub chain: Chain,
-}
-
-impl From<RequestRecord> for Request {
-    fn from(record: RequestRecord) -> Self {
-        Request {
-            id: Uuid::parse_str(&record.id)
-                .unwrap_or_else(|_| panic!("Failed to parse id from record {:?}", &record)),
-            hash: TxHash::from_str(&record.hash)
-                .unwrap_or_else(|_| panic!("Failed to parse TxHash from record {:?}", &record)),
-            chain: Chain::try_from(record.chain)
-                .unwrap_or_else(|_| panic!("Failed to parse chain from record {:?}", &record)),
-            tx: record.tx.0,
-            mined: record.mined,
+    pub hash: Option<TxHash>,
+    pub status: RequestStatus,
+    pub chain: ChainId,
+    pub batch_id: Option<Uuid>,
+    pub batch_index: Option<u32>,
+    pub tenant_id: Option<String>,
+}
+
+/// A row that can't be turned into a `Request`
+#[derive(Debug, Error)]
+#[error("request {id} is invalid: {reason}")]
+pub struct InvalidRecord {
+    pub id: String,
+    pub reason: String,
+}
+
+impl TryFrom<RequestRecord> for Request {
+    type Error = InvalidRecord;
+
+    fn try_from(record: RequestRecord) -> Result<Self, InvalidRecord> {
+        let invalid = |reason: String| InvalidRecord {
+            id: record.id.clone(),
+            reason,
+        };
+        let address = |address: &Option<String>| {
+            address
+                .as_deref()
+                .map(Address::from_str)
+                .transpose()
+                .map_err(|err| invalid(format!("bad address: {}", err)))
+        };
+        let amount = |amount: &Option<String>| {
+            amount
+                .as_deref()
+                .map(U256::from_dec_str)
+                .transpose()
+                .map_err(|err| invalid(format!("bad amount: {}", err)))
+        };
+        let tx = Eip1559TransactionRequest {
+            from: address(&record.from_address)?,
+            to: address(&record.to_address)?.map(NameOrAddress::Address),
+            gas: record.gas_limit.map(U256::from),
+            value: amount(&record.value)?,
+            data: record.data.clone().map(Bytes::from),
+            nonce: record.nonce.map(U256::from),
+            max_priority_fee_per_gas: amount(&record.priority_fee)?,
+            max_fee_per_gas: amount(&record.max_fee)?,
+            ..Default::default()
+        };
+        let hash = record
+            .hash
+            .as_deref()
+            .map(TxHash::from_str)
+            .transpose()
+            .map_err(|err| invalid(format!("bad hash: {}", err)))?;
+        let status =
+            RequestStatus::from_str(&record.status).map_err(|err| invalid(err.to_string()))?;
+        // The monitor would have nothing to look for in blocks
+        if status == RequestStatus::Submitted && hash.is_none() {
+            return Err(invalid("submitted without a hash".to_owned()));
         }
+        Ok(Request {
+            id: Uuid::parse_str(&record.id).map_err(|err| invalid(format!("bad id: {}", err)))?,
+            hash,
+            status,
+            chain: ChainId(record.chain),
+            batch_id: record
+                .batch_id
+                .as_deref()
+                .map(Uuid::parse_str)
+                .transpose()
+                .map_err(|err| invalid(format!("bad batch id: {}", err)))?,
+            batch_index: record.batch_index,
+            tenant_id: Some(record.tenant_id).filter(|tenant_id| !tenant_id.is_empty()),
+            tx,
+        })
     }
 }
 
-impl From<Request> for RequestRecord {
-    fn from(request: Request) -> Self {
-        RequestRecord {
-            id: request.id.to_string(),
-            tx: Json(request.tx),
-            hash: request.hash.to_string(),
-            mined: request.mined,
-            chain: request.chain as u32,
+static INVALID_RECORDS: AtomicU64 = AtomicU64::new(0);
+
+/// How many rows were quarantined as `invalid` since the process started
+pub fn invalid_records() -> u64 {
+    INVALID_RECORDS.load(Ordering::Relaxed)
+}
+
+/// Converts the records that can be read, the rest are returned to be quarantined
+fn readable<R, T>(records: Vec<R>) -> (Vec<T>, Vec<InvalidRecord>)
+where
+    T: TryFrom<R, Error = InvalidRecord>,
+{
+    let mut readable = vec![];
+    let mut invalid = vec![];
+    for record in records {
+        match T::try_from(record) {
+            Ok(request) => readable.push(request),
+            Err(err) => {
+                error!("Quarantining {}", err);
+                INVALID_RECORDS.fetch_add(1, Ordering::Relaxed);
+                invalid.push(err);
+            }
         }
     }
+    (readable, invalid)
+}
+
+impl TryFrom<Request> for RequestRecord {
+    type Error = anyhow::Error;
+
+    fn try_from(request: Request) -> anyhow::Result<Self> {
+        let columns = TxColumns::new(&request.tx)?;
+        Ok(RequestRecord {
+            id: request.id.to_string(),
+            hash: request.hash.map(|hash| format!("{:?}", hash)),
+            status: request.status.as_str().to_owned(),
+            chain: request.chain.0,
+            batch_id: request.batch_id.map(|batch_id| batch_id.to_string()),
+            batch_index: request.batch_index,
+            tenant_id: request.tenant_id.unwrap_or_default(),
+            from_address: columns.from_address,
+            to_address: columns.to_address,
+            nonce: columns.nonce,
+            value: columns.value.map(|value| value.to_string()),
+            max_fee: columns.max_fee.map(|max_fee| max_fee.to_string()),
+            priority_fee: columns.priority_fee.map(|fee| fee.to_string()),
+            gas_limit: columns.gas_limit,
+            data: columns.data,
+        })
+    }
 }
 
-#[derive(Debug)]
-pub struct DbTxRequestRepository {
-    pool: MySqlPool,
+/// What's written to the typed columns, next to the JSON archive
+#[derive(Clone, Debug, Default)]
+struct TxColumns {
+    from_address: Option<String>,
+    to_address: Option<String>,
+    nonce: Option<u64>,
+    value: Option<U256>,
+    max_fee: Option<U256>,
+    priority_fee: Option<U256>,
+    gas_limit: Option<u64>,
+    data: Option<Vec<u8>>,
 }
 
-impl Clone for DbTxRequestRepository {
-    fn clone(&self) -> Self {
-        Self {
-            pool: self.pool.clone(),
-        }
+impl TxColumns {
+    fn new(tx: &Eip1559TransactionRequest) -> anyhow::Result<Self> {
+        let to_address = match &tx.to {
+            Some(NameOrAddress::Address(to)) => Some(format!("{:?}", to)),
+            Some(NameOrAddress::Name(name)) => {
+                return Err(anyhow!("can't store the unresolved name {}", name))
+            }
+            None => None,
+        };
+        let gas_limit = match tx.gas {
+            Some(gas) if gas > U256::from(u64::MAX) => {
+                return Err(anyhow!("gas limit {} doesn't fit in 64 bits", gas))
+            }
+            gas => gas.map(|gas| gas.as_u64()),
+        };
+        Ok(TxColumns {
+            from_address: tx.from.map(|from| format!("{:?}", from)),
+            to_address,
+            nonce: tx.nonce.map(|nonce| nonce.as_u64()),
+            value: tx.value,
+            max_fee: tx.max_fee_per_gas,
+            priority_fee: tx.max_priority_fee_per_gas,
+            gas_limit,
+            data: tx.data.as_ref().map(|data| data.to_vec()),
+        })
     }
 }
 
+/// Every filter is optional, times are unix milliseconds
+#[derive(Clone, Debug, Default)]
+pub struct RequestFilter {
+    pub chain: Option<ChainId>,
+    pub status: Option<RequestStatus>,
+    pub to: Option<Address>,
+    /// The relayer address that signed the transaction, only known once submitted
+    pub sender: Option<Address>,
+    pub tenant_id: Option<String>,
+    pub created_after: Option<u64>,
+    pub created_before: Option<u64>,
+}
+
+/// Where a page of listed requests ended, formatted as `{created_at}-{seq}`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cursor {
+    pub created_at: u64,
+    pub seq: u64,
+}
+
+impl std::fmt::Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.created_at, self.seq)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (created_at, seq) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow!("invalid cursor {:?}", s))?;
+        Ok(Cursor {
+            created_at: created_at.parse()?,
+            seq: seq.parse()?,
+        })
+    }
+}
+
+pub struct ListedRequest {
+    pub request: Request,
+    /// Unix milliseconds
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+/// A request row with what's only read when listing
+#[derive(Clone, Debug)]
+struct ListedRequestRecord {
+    request: RequestRecord,
+    seq: u64,
+    created_at: u64,
+    updated_at: u64,
+}
+
+impl TryFrom<ListedRequestRecord> for ListedRequest {
+    type Error = InvalidRecord;
+
+    fn try_from(record: ListedRequestRecord) -> Result<Self, InvalidRecord> {
+        Ok(ListedRequest {
+            request: record.request.try_into()?,
+            created_at: record.created_at,
+            updated_at: record.updated_at,
+        })
+    }
+}
+
+/// A request repository on whichever database `DATABASE_URL` points at
+#[derive(Clone, Debug)]
+pub enum DbTxRequestRepository {
+    MySql(MySqlTxRequestRepository),
+    Postgres(PgTxRequestRepository),
+    Sqlite(SqliteTxRequestRepository),
+}
+
 impl DbTxRequestRepository {
-    pub fn new(pool: MySqlPool) -> Self {
-        Self { pool }
+    pub fn new(pool: DbPool) -> Self {
+        match pool {
+            DbPool::MySql(pool) => Self::MySql(SqlTxRequestRepository::new(pool)),
+            DbPool::Postgres(pool) => Self::Postgres(SqlTxRequestRepository::new(pool)),
+            DbPool::Sqlite(pool) => Self::Sqlite(SqlTxRequestRepository::new(pool)),
+        }
+    }
+
+    /// Connects to the database the url points at, see `DbPool::connect`
+    pub async fn connect(url: &str, max_connections: u32) -> anyhow::Result<Self> {
+        Ok(Self::new(DbPool::connect(url, max_connections).await?))
     }
+
+    /// The repository's pool, for the other stores to share
+    pub fn pool(&self) -> DbPool {
+        match self {
+            Self::MySql(repo) => DbPool::MySql(repo.pool().clone()),
+            Self::Postgres(repo) => DbPool::Postgres(repo.pool().clone()),
+            Self::Sqlite(repo) => DbPool::Sqlite(repo.pool().clone()),
+        }
+    }
+
+    pub async fn migrate(&self) -> anyhow::Result<()> {
+        self.pool().migrate().await
+    }
+}
+
+// Forwards a trait method to whichever implementation is in use
+macro_rules! dispatch {
+    ($self:ident, $method:ident($($arg:expr),*)) => {
+        match $self {
+            DbTxRequestRepository::MySql(repo) => repo.$method($($arg),*).await,
+            DbTxRequestRepository::Postgres(repo) => repo.$method($($arg),*).await,
+            DbTxRequestRepository::Sqlite(repo) => repo.$method($($arg),*).await,
+        }
+    };
 }
 
 #[async_trait]
 impl TransactionRepository for DbTxRequestRepository {
     async fn save(
+        &self,
+        request: NewRequest,
+        idempotency: Option<IdempotencyKey>,
+    ) -> anyhow::Result<()> {
+        dispatch!(self, save(request, idempotency))
+    }
+
+    async fn save_batch(&self, batch_id: Uuid, requests: Vec<NewRequest>) -> anyhow::Result<()> {
+        dispatch!(self, save_batch(batch_id, requests))
+    }
+
+    async fn get(&self, id: Uuid) -> anyhow::Result<Option<Request>> {
+        dispatch!(self, get(id))
+    }
+
+    async fn get_by_idempotency_key(
+        &self,
+        tenant_id: Option<&str>,
+        key: &str,
+    ) -> anyhow::Result<Option<(Uuid, String)>> {
+        dispatch!(self, get_by_idempotency_key(tenant_id, key))
+    }
+
+    async fn get_spend(&self, tenant_id: &str) -> anyhow::Result<U256> {
+        dispatch!(self, get_spend(tenant_id))
+    }
+
+    async fn get_queued(&self, chain: ChainId) -> anyhow::Result<Vec<Request>> {
+        dispatch!(self, get_queued(chain))
+    }
+
+    async fn get_ready(&self, chain: ChainId) -> anyhow::Result<Vec<Request>> {
+        dispatch!(self, get_ready(chain))
+    }
+
+    async fn mark_queued(&self, id: Uuid, tx: Eip1559TransactionRequest) -> anyhow::Result<()> {
+        dispatch!(self, mark_queued(id, tx))
+    }
+
+    async fn mark_failed(&self, id: Uuid) -> anyhow::Result<bool> {
+        dispatch!(self, mark_failed(id))
+    }
+
+    async fn fail_blocked(&self, chain: ChainId) -> anyhow::Result<u64> {
+        dispatch!(self, fail_blocked(chain))
+    }
+
+    async fn cancel(&self, id: Uuid, actor: &Actor) -> anyhow::Result<bool> {
+        dispatch!(self, cancel(id, actor))
+    }
+
+    async fn abandon(&self, id: Uuid, actor: &Actor) -> anyhow::Result<bool> {
+        dispatch!(self, abandon(id, actor))
+    }
+
+    async fn get_pending(&self, chain: ChainId) -> anyhow::Result<Vec<Request>> {
+        dispatch!(self, get_pending(chain))
+    }
+
+    async fn count_in_flight(&self, chain: ChainId) -> anyhow::Result<u64> {
+        dispatch!(self, count_in_flight(chain))
+    }
+
+    async fn mark_submitted(
         &self,
         id: Uuid,
         hash: TxHash,
         tx: Eip1559TransactionRequest,
-        mined: bool,
-        chain: Chain,
+    ) -> anyhow::Result<bool> {
+        dispatch!(self, mark_submitted(id, hash, tx))
+    }
+
+    async fn save_submitted(
+        &self,
+        id: Uuid,
+        hash: TxHash,
+        tx: Eip1559TransactionRequest,
+        chain: ChainId,
     ) -> anyhow::Result<()> {
-        query!(
-            r#"
-			INSERT INTO requests (id, hash, tx, mined, chain) 
-			VALUES (?, ?, ?, ?, ?)
-			"#,
-            id.to_string(),
-            format!("{:?}", hash),
-            to_string(&tx)?,
-            mined,
-            chain as u32
-        )
-        .execute(&self.pool)
-        .await?;
-        Ok(())
+        dispatch!(self, save_submitted(id, hash, tx, chain))
     }
 
-    async fn get(&self, id: Uuid) -> anyhow::Result<Option<Request>> {
-        let request = query_as!(
-            RequestRecord,
-            r#"
-		SELECT id, hash, chain, mined as "mined: bool", tx as "tx: Json<Eip1559TransactionRequest>"
-		FROM requests 
-		WHERE id = ?
-		"#,
-            id.to_string()
-        )
-        .fetch_optional(&self.pool)
-        .await?;
-        Ok(request.map(|r| r.into()))
-    }
-
-    async fn get_pending(&self, chain: Chain) -> anyhow::Result<Vec<Request>> {
-        let records = query_as!(
-            RequestRecord,
-            r#"
-			SELECT id, hash, chain, mined as "mined: bool", tx as "tx: Json<Eip1559TransactionRequest>"
-			FROM requests 
-			WHERE mined = ? and chain = ?
-			"#,
-            false,
-            chain as u32
-        )
-        .fetch_all(&self.pool)
-        .await?;
-
-        let requests: Vec<Request> = records
-            .iter()
-            .map(|record| Request::from(record.clone()))
-            .collect();
-
-        Ok(requests)
+    async fn replace(
+        &self,
+        id: Uuid,
+        hash: TxHash,
+        tx: Eip1559TransactionRequest,
+        actor: &Actor,
+    ) -> anyhow::Result<bool> {
+        dispatch!(self, replace(id, hash, tx, actor))
+    }
+
+    async fn get_next_nonce(
+        &self,
+        chain: ChainId,
+        address: Address,
+    ) -> anyhow::Result<Option<U256>> {
+        dispatch!(self, get_next_nonce(chain, address))
+    }
+
+    async fn set_next_nonce(
+        &self,
+        chain: ChainId,
+        address: Address,
+        nonce: U256,
+    ) -> anyhow::Result<()> {
+        dispatch!(self, set_next_nonce(chain, address, nonce))
+    }
+
+    async fn reset_next_nonce(
+        &self,
+        chain: ChainId,
+        address: Address,
+        nonce: U256,
+    ) -> anyhow::Result<()> {
+        dispatch!(self, reset_next_nonce(chain, address, nonce))
+    }
+
+    async fn get_submitted_nonces(
+        &self,
+        chain: ChainId,
+        address: Address,
+        from_nonce: U256,
+    ) -> anyhow::Result<Vec<U256>> {
+        dispatch!(self, get_submitted_nonces(chain, address, from_nonce))
     }
 
     async fn update_many(&self, updates: Vec<RequestUpdate>) -> anyhow::Result<()> {
-        if !updates.is_empty() {
-            let mut tx = self.pool.begin().await?;
-
-            for RequestUpdate { id, mined, hash } in updates {
-                query!(
-                    r#"
-						UPDATE requests
-						SET hash = ?, mined = ?
-						WHERE id = ?;
-						"#,
-                    format!("{:?}", hash),
-                    mined,
-                    id.to_string()
-                )
-                .execute(&mut tx)
-                .await?;
-            }
+        dispatch!(self, update_many(updates))
+    }
 
-            tx.commit().await?;
-        }
+    async fn list(
+        &self,
+        filter: &RequestFilter,
+        cursor: Option<Cursor>,
+        limit: u32,
+    ) -> anyhow::Result<(Vec<ListedRequest>, Option<Cursor>)> {
+        dispatch!(self, list(filter, cursor, limit))
+    }
+
+    async fn get_expired(
+        &self,
+        chain: ChainId,
+        before: u64,
+        limit: u32,
+    ) -> anyhow::Result<Vec<ListedRequest>> {
+        dispatch!(self, get_expired(chain, before, limit))
+    }
+
+    async fn archive(&self, ids: &[Uuid], keep: bool) -> anyhow::Result<u64> {
+        dispatch!(self, archive(ids, keep))
+    }
 
-        Ok(())
+    async fn get_events(&self, id: Uuid) -> anyhow::Result<Vec<RequestEvent>> {
+        dispatch!(self, get_events(id))
     }
+
+    async fn list_events(
+        &self,
+        tenant_id: Option<&str>,
+        after: u64,
+        limit: u32,
+    ) -> anyhow::Result<Vec<RequestEvent>> {
+        dispatch!(self, list_events(tenant_id, after, limit))
+    }
+}
+
+/// Timestamps are set by the repository, as unix milliseconds
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time is after the unix epoch")
+        .as_millis() as i64
+}
+
+/// Next page's cursor, when the page was full
+fn next_cursor(records: &[ListedRequestRecord], limit: u32) -> Option<Cursor> {
+    match records.last() {
+        Some(last) if records.len() == limit as usize => Some(Cursor {
+            created_at: last.created_at,
+            seq: last.seq,
+        }),
+        _ => None,
+    }
+}
+
+/// Rounds up, so budgets are never undercounted. Saturates at what the bigint column holds.
+fn to_gwei(wei: U256) -> u64 {
+    let (gwei, remainder) = wei.div_mod(GWEI.into());
+    let gwei = if remainder.is_zero() { gwei } else { gwei + 1 };
+    gwei.min(U256::from(i64::MAX)).as_u64()
+}
+
+fn sender_and_nonce(id: Uuid, tx: &Eip1559TransactionRequest) -> anyhow::Result<(Address, U256)> {
+    let from = tx
+        .from
+        .ok_or_else(|| anyhow!("request {} was submitted without a sender", id))?;
+    let nonce = tx
+        .nonce
+        .ok_or_else(|| anyhow!("request {} was submitted without a nonce", id))?;
+    Ok((from, nonce))
 }
@@ -0,0 +1,1192 @@
+use std::fmt;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use ethers::types::{Address, Eip1559TransactionRequest, TxHash, U256, U64};
+use serde_json::to_string;
+use sqlx::{
+    database::HasArguments, query, Connection, Database, Encode, Executor, IntoArguments, MySql,
+    Pool, Postgres, Sqlite, Type,
+};
+use uuid::Uuid;
+
+use super::{
+    events::{events, RequestEventRecord},
+    next_cursor, now_millis, readable, sender_and_nonce, to_gwei, Actor, ChainId, Cursor,
+    IdempotencyKey, InvalidRecord, ListedRequest, ListedRequestRecord, NewRequest, Request,
+    RequestEvent, RequestEventKind, RequestFilter, RequestRecord, RequestStatus, RequestUpdate,
+    TransactionRepository, TxColumns, GWEI,
+};
+use crate::database::{number, text, DbQuery, Dialect};
+
+/// Queries are checked at runtime, the `query!` macros only check against one database
+pub struct SqlTxRequestRepository<DB: Database> {
+    pool: Pool<DB>,
+}
+
+pub type MySqlTxRequestRepository = SqlTxRequestRepository<MySql>;
+pub type PgTxRequestRepository = SqlTxRequestRepository<Postgres>;
+pub type SqliteTxRequestRepository = SqlTxRequestRepository<Sqlite>;
+
+impl<DB: Database> Clone for SqlTxRequestRepository<DB> {
+    fn clone(&self) -> Self {
+        Self {
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+impl<DB: Database> fmt::Debug for SqlTxRequestRepository<DB> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SqlTxRequestRepository")
+            .field("pool", &self.pool)
+            .finish()
+    }
+}
+
+impl<DB: Database> SqlTxRequestRepository<DB> {
+    pub fn new(pool: Pool<DB>) -> Self {
+        Self { pool }
+    }
+
+    pub fn pool(&self) -> &Pool<DB> {
+        &self.pool
+    }
+}
+
+/// The typed columns, bound by `bind_columns` in this order
+const TX_COLUMNS: &str =
+    "from_address, to_address, nonce, value, max_fee, priority_fee, gas_limit, data";
+
+fn tx_values<DB: Dialect>() -> String {
+    let amount = DB::AMOUNT;
+    format!("?, ?, ?, {amount}, {amount}, {amount}, ?, ?")
+}
+
+fn tx_assignments<DB: Dialect>() -> String {
+    let amount = DB::AMOUNT;
+    format!(
+        "from_address = ?, to_address = ?, nonce = ?, value = {amount}, max_fee = {amount}, \
+		priority_fee = {amount}, gas_limit = ?, data = ?"
+    )
+}
+
+/// The columns a `RequestRecord` is read from, of `requests` as `r`
+fn request_columns<DB: Dialect>() -> String {
+    format!(
+        "r.id, r.hash, r.chain, r.status, r.batch_id, {} as batch_index, r.tenant_id, r.from_address, \
+		r.to_address, r.nonce, {} as value, {} as max_fee, {} as priority_fee, r.gas_limit, r.data",
+        DB::integer("r.batch_index"),
+        DB::amount_column("r.value"),
+        DB::amount_column("r.max_fee"),
+        DB::amount_column("r.priority_fee"),
+    )
+}
+
+fn listed_request_columns<DB: Dialect>() -> String {
+    format!(
+        "{}, r.seq, {} as created_at, {} as updated_at",
+        request_columns::<DB>(),
+        DB::time_column("r.created_at"),
+        DB::time_column("r.updated_at"),
+    )
+}
+
+fn request_event_columns<DB: Dialect>() -> String {
+    format!(
+        "seq, request_id, chain, tenant_id, event, actor, hash, {} as created_at",
+        DB::time_column("created_at")
+    )
+}
+
+/// `?, ?, ?` for `count` values
+fn placeholders(count: usize) -> String {
+    vec!["?"; count].join(", ")
+}
+
+fn request_record<DB: Dialect>(row: &DB::Row) -> sqlx::Result<RequestRecord> {
+    let amount = |column| -> sqlx::Result<Option<String>> {
+        Ok(DB::get_text(row, column)?.map(DB::amount_text))
+    };
+    Ok(RequestRecord {
+        id: text::<DB>(row, "id")?,
+        hash: DB::get_text(row, "hash")?,
+        status: text::<DB>(row, "status")?,
+        chain: number::<DB>(row, "chain")?,
+        batch_id: DB::get_text(row, "batch_id")?,
+        batch_index: DB::get_u64(row, "batch_index")?.map(|index| index as u32),
+        tenant_id: text::<DB>(row, "tenant_id")?,
+        from_address: DB::get_text(row, "from_address")?,
+        to_address: DB::get_text(row, "to_address")?,
+        nonce: DB::get_u64(row, "nonce")?,
+        value: amount("value")?,
+        max_fee: amount("max_fee")?,
+        priority_fee: amount("priority_fee")?,
+        gas_limit: DB::get_u64(row, "gas_limit")?,
+        data: DB::get_bytes(row, "data")?,
+    })
+}
+
+fn listed_request_record<DB: Dialect>(row: &DB::Row) -> sqlx::Result<ListedRequestRecord> {
+    Ok(ListedRequestRecord {
+        request: request_record::<DB>(row)?,
+        seq: number::<DB>(row, "seq")?,
+        created_at: number::<DB>(row, "created_at")?,
+        updated_at: number::<DB>(row, "updated_at")?,
+    })
+}
+
+fn request_event_record<DB: Dialect>(row: &DB::Row) -> sqlx::Result<RequestEventRecord> {
+    Ok(RequestEventRecord {
+        seq: number::<DB>(row, "seq")?,
+        request_id: text::<DB>(row, "request_id")?,
+        chain: number::<DB>(row, "chain")?,
+        tenant_id: text::<DB>(row, "tenant_id")?,
+        event: text::<DB>(row, "event")?,
+        actor: text::<DB>(row, "actor")?,
+        hash: DB::get_text(row, "hash")?,
+        created_at: number::<DB>(row, "created_at")?,
+    })
+}
+
+impl<DB> SqlTxRequestRepository<DB>
+where
+    DB: Dialect,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+    for<'q> <DB as HasArguments<'q>>::Arguments: IntoArguments<'q, DB>,
+    for<'q> &'q str: Encode<'q, DB>,
+    for<'q> String: Encode<'q, DB>,
+    for<'q> Option<String>: Encode<'q, DB>,
+    for<'q> i64: Encode<'q, DB>,
+    for<'q> Option<i64>: Encode<'q, DB>,
+    str: Type<DB>,
+    String: Type<DB>,
+    i64: Type<DB>,
+{
+    /// Binds the typed columns, in the order of `TX_COLUMNS`
+    fn bind_columns(query: DbQuery<'_, DB>, columns: TxColumns) -> DbQuery<'_, DB> {
+        let query = query
+            .bind(columns.from_address)
+            .bind(columns.to_address)
+            .bind(columns.nonce.map(|nonce| nonce as i64))
+            .bind(columns.value.map(DB::amount))
+            .bind(columns.max_fee.map(DB::amount))
+            .bind(columns.priority_fee.map(DB::amount))
+            .bind(columns.gas_limit.map(|gas_limit| gas_limit as i64));
+        DB::bind_bytes(query, columns.data)
+    }
+
+    /// Marks the rows that couldn't be read as invalid, so they're skipped from now on
+    async fn quarantine<T>(
+        &self,
+        (requests, invalid): (Vec<T>, Vec<InvalidRecord>),
+    ) -> anyhow::Result<Vec<T>> {
+        let sql = DB::sql(&format!(
+            "UPDATE requests SET status = ?, updated_at = {} WHERE id = ?",
+            DB::TIME
+        ));
+        for InvalidRecord { id, .. } in invalid {
+            let mut db_tx = self.pool.begin().await?;
+            query(&sql)
+                .bind(RequestStatus::Invalid.as_str())
+                .bind(now_millis())
+                .bind(&id)
+                .execute(&mut *db_tx)
+                .await?;
+            Self::record_event(&mut db_tx, &id, RequestEventKind::Invalid, &Actor::System).await?;
+            db_tx.commit().await?;
+        }
+        Ok(requests)
+    }
+
+    /// Inserts a new request as waiting or queued, with its dependencies
+    async fn insert(
+        db_tx: &mut DB::Connection,
+        request: NewRequest,
+        idempotency: Option<IdempotencyKey>,
+        batch: Option<(Uuid, usize)>,
+    ) -> anyhow::Result<()> {
+        let (idempotency_key, idempotency_fingerprint) = idempotency
+            .map(|IdempotencyKey { key, fingerprint }| (key, fingerprint))
+            .unzip();
+        let (batch_id, batch_index) = batch.unzip();
+        let status = request.initial_status();
+        let NewRequest {
+            id,
+            tx,
+            chain,
+            tenant_id,
+            depends_on,
+            actor,
+        } = request;
+        let tenant_id = tenant_id.unwrap_or_default();
+        let now = now_millis();
+        let columns = TxColumns::new(&tx)?;
+
+        // The unique index only covers the keys of requests that haven't been removed
+        if let Some(key) = &idempotency_key {
+            let sql = DB::sql(
+                "SELECT request_id FROM retired_idempotency_keys WHERE tenant_id = ? and idempotency_key = ?",
+            );
+            let retired = query(&sql)
+                .bind(&tenant_id)
+                .bind(key)
+                .fetch_optional(&mut *db_tx)
+                .await?;
+            if retired.is_some() {
+                return Err(anyhow!("idempotency key {:?} is already in use", key));
+            }
+        }
+
+        let sql = DB::sql(&format!(
+            r#"
+			INSERT INTO requests (id, tx, status, chain, tenant_id, idempotency_key, idempotency_fingerprint,
+				batch_id, batch_index, created_at, updated_at, {TX_COLUMNS})
+			VALUES (?, {}, ?, ?, ?, ?, ?, ?, ?, {}, {}, {})
+			"#,
+            DB::JSON,
+            DB::TIME,
+            DB::TIME,
+            tx_values::<DB>()
+        ));
+        let statement = query(&sql)
+            .bind(id.to_string())
+            .bind(to_string(&tx)?)
+            .bind(status.as_str())
+            .bind(chain.0 as i64)
+            .bind(tenant_id)
+            .bind(idempotency_key)
+            .bind(idempotency_fingerprint)
+            .bind(batch_id.map(|batch_id| batch_id.to_string()))
+            .bind(batch_index.map(|batch_index| batch_index as i64))
+            .bind(now)
+            .bind(now);
+        Self::bind_columns(statement, columns)
+            .execute(&mut *db_tx)
+            .await?;
+
+        let sql =
+            DB::sql("INSERT INTO request_dependencies (request_id, depends_on) VALUES (?, ?)");
+        for parent in depends_on {
+            query(&sql)
+                .bind(id.to_string())
+                .bind(parent.to_string())
+                .execute(&mut *db_tx)
+                .await?;
+        }
+        Self::record_event(db_tx, &id.to_string(), RequestEventKind::Created, &actor).await
+    }
+
+    /// Moves a request to `status` if it's in one of `from`, recording the event.
+    /// Returns false if it wasn't.
+    async fn transition(
+        db_tx: &mut DB::Connection,
+        id: &str,
+        status: RequestStatus,
+        from: &[RequestStatus],
+        event: RequestEventKind,
+        actor: &Actor,
+    ) -> anyhow::Result<bool> {
+        let sql = DB::sql(&format!(
+            "UPDATE requests SET status = ?, updated_at = {} WHERE id = ? and status IN ({})",
+            DB::TIME,
+            placeholders(from.len())
+        ));
+        let mut statement = query(&sql)
+            .bind(status.as_str())
+            .bind(now_millis())
+            .bind(id);
+        for from in from {
+            statement = statement.bind(from.as_str());
+        }
+        if DB::rows_affected(&statement.execute(&mut *db_tx).await?) == 0 {
+            return Ok(false);
+        }
+        Self::record_event(db_tx, id, event, actor).await?;
+        Ok(true)
+    }
+
+    /// Like `transition` in a database transaction of its own
+    async fn transition_one(
+        &self,
+        id: Uuid,
+        status: RequestStatus,
+        from: &[RequestStatus],
+        event: RequestEventKind,
+        actor: &Actor,
+    ) -> anyhow::Result<bool> {
+        let mut db_tx = self.pool.begin().await?;
+        let changed =
+            Self::transition(&mut db_tx, &id.to_string(), status, from, event, actor).await?;
+        db_tx.commit().await?;
+        Ok(changed)
+    }
+
+    /// Requests read with `request_columns`, the unreadable ones are quarantined
+    async fn fetch_requests(&self, statement: DbQuery<'_, DB>) -> anyhow::Result<Vec<Request>> {
+        let records = statement
+            .try_map(|row| request_record::<DB>(&row))
+            .fetch_all(&self.pool)
+            .await?;
+        self.quarantine(readable(records)).await
+    }
+
+    /// Requests read with `listed_request_columns`, with the cursor of the next page if `limit`
+    /// rows were read
+    async fn fetch_listed(
+        &self,
+        statement: DbQuery<'_, DB>,
+        limit: u32,
+    ) -> anyhow::Result<(Vec<ListedRequest>, Option<Cursor>)> {
+        let records = statement
+            .try_map(|row| listed_request_record::<DB>(&row))
+            .fetch_all(&self.pool)
+            .await?;
+        let cursor = next_cursor(&records, limit);
+        Ok((self.quarantine(readable(records)).await?, cursor))
+    }
+
+    async fn fetch_events(&self, statement: DbQuery<'_, DB>) -> anyhow::Result<Vec<RequestEvent>> {
+        let records = statement
+            .try_map(|row| request_event_record::<DB>(&row))
+            .fetch_all(&self.pool)
+            .await?;
+        events(records)
+    }
+
+    /// Appends to the request's audit log, with its chain, tenant and current hash
+    async fn record_event(
+        db_tx: &mut DB::Connection,
+        id: &str,
+        event: RequestEventKind,
+        actor: &Actor,
+    ) -> anyhow::Result<()> {
+        let sql = DB::sql(&format!(
+            r#"
+			INSERT INTO request_events (request_id, chain, tenant_id, event, actor, hash, created_at)
+			SELECT id, chain, tenant_id, ?, ?, hash, {}
+			FROM requests
+			WHERE id = ?
+			"#,
+            DB::TIME
+        ));
+        query(&sql)
+            .bind(event.as_str())
+            .bind(actor.to_string())
+            .bind(now_millis())
+            .bind(id)
+            .execute(db_tx)
+            .await?;
+        Ok(())
+    }
+
+    /// Stores the address's next nonce, `next_nonce` is the value kept when it already has one
+    async fn upsert_nonce(
+        &self,
+        chain: ChainId,
+        address: Address,
+        nonce: U256,
+        next_nonce: &str,
+    ) -> anyhow::Result<()> {
+        let sql = DB::sql(&format!(
+            r#"
+			INSERT INTO nonces (chain, address, next_nonce)
+			VALUES (?, ?, ?)
+			{} next_nonce = {}
+			"#,
+            DB::on_conflict("chain, address"),
+            next_nonce
+        ));
+        query(&sql)
+            .bind(chain.0 as i64)
+            .bind(format!("{:?}", address))
+            .bind(nonce.as_u64() as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Moves the sender's next nonce past `nonce`, it's never lowered
+    async fn advance_nonce(
+        db_tx: &mut DB::Connection,
+        chain_id: Option<U64>,
+        from: Address,
+        nonce: U256,
+    ) -> anyhow::Result<()> {
+        let chain_id = chain_id.ok_or_else(|| anyhow!("transaction is missing a chain id"))?;
+        let sql = DB::sql(&format!(
+            r#"
+			INSERT INTO nonces (chain, address, next_nonce)
+			VALUES (?, ?, ?)
+			{} next_nonce = {}
+			"#,
+            DB::on_conflict("chain, address"),
+            DB::greatest("nonces.next_nonce", &DB::excluded("next_nonce"))
+        ));
+        query(&sql)
+            .bind(chain_id.as_u64() as i64)
+            .bind(format!("{:?}", from))
+            .bind(nonce.as_u64() as i64 + 1)
+            .execute(db_tx)
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<DB> TransactionRepository for SqlTxRequestRepository<DB>
+where
+    DB: Dialect,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+    for<'q> <DB as HasArguments<'q>>::Arguments: IntoArguments<'q, DB>,
+    for<'q> &'q str: Encode<'q, DB>,
+    for<'q> String: Encode<'q, DB>,
+    for<'q> Option<String>: Encode<'q, DB>,
+    for<'q> i64: Encode<'q, DB>,
+    for<'q> Option<i64>: Encode<'q, DB>,
+    str: Type<DB>,
+    String: Type<DB>,
+    i64: Type<DB>,
+{
+    async fn save(
+        &self,
+        request: NewRequest,
+        idempotency: Option<IdempotencyKey>,
+    ) -> anyhow::Result<()> {
+        let mut db_tx = self.pool.begin().await?;
+        Self::insert(&mut db_tx, request, idempotency, None).await?;
+        db_tx.commit().await?;
+        Ok(())
+    }
+
+    async fn save_batch(&self, batch_id: Uuid, requests: Vec<NewRequest>) -> anyhow::Result<()> {
+        let mut db_tx = self.pool.begin().await?;
+        for (batch_index, request) in requests.into_iter().enumerate() {
+            Self::insert(&mut db_tx, request, None, Some((batch_id, batch_index))).await?;
+        }
+        db_tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get(&self, id: Uuid) -> anyhow::Result<Option<Request>> {
+        let sql = DB::sql(&format!(
+            "SELECT {} FROM requests r WHERE r.id = ?",
+            request_columns::<DB>()
+        ));
+        let statement = query(&sql).bind(id.to_string());
+        Ok(self.fetch_requests(statement).await?.pop())
+    }
+
+    async fn get_by_idempotency_key(
+        &self,
+        tenant_id: Option<&str>,
+        key: &str,
+    ) -> anyhow::Result<Option<(Uuid, String)>> {
+        let sql = DB::sql(
+            r#"
+			SELECT id, idempotency_fingerprint
+			FROM requests
+			WHERE tenant_id = ? and idempotency_key = ?
+			UNION ALL
+			SELECT request_id as id, idempotency_fingerprint
+			FROM retired_idempotency_keys
+			WHERE tenant_id = ? and idempotency_key = ?
+			"#,
+        );
+        let tenant_id = tenant_id.unwrap_or_default();
+        let record = query(&sql)
+            .bind(tenant_id)
+            .bind(key)
+            .bind(tenant_id)
+            .bind(key)
+            .try_map(|row| {
+                Ok((
+                    text::<DB>(&row, "id")?,
+                    text::<DB>(&row, "idempotency_fingerprint")?,
+                ))
+            })
+            .fetch_optional(&self.pool)
+            .await?;
+
+        record
+            .map(|(id, fingerprint)| Ok((Uuid::parse_str(&id)?, fingerprint)))
+            .transpose()
+    }
+
+    async fn get_spend(&self, tenant_id: &str) -> anyhow::Result<U256> {
+        let sql = DB::sql(&format!(
+            "SELECT {} as spent",
+            DB::integer(
+                "COALESCE((SELECT SUM(cost_gwei) FROM requests WHERE tenant_id = ?), 0) \
+				+ COALESCE((SELECT cost_gwei FROM tenant_spend WHERE tenant_id = ?), 0)"
+            )
+        ));
+        let spent = query(&sql)
+            .bind(tenant_id)
+            .bind(tenant_id)
+            .try_map(|row| number::<DB>(&row, "spent"))
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(U256::from(spent) * GWEI)
+    }
+
+    async fn get_queued(&self, chain: ChainId) -> anyhow::Result<Vec<Request>> {
+        let sql = DB::sql(&format!(
+            r#"
+			SELECT {}
+			FROM requests r
+			WHERE r.status = ? and r.chain = ?
+			ORDER BY r.seq
+			"#,
+            request_columns::<DB>()
+        ));
+        let statement = query(&sql)
+            .bind(RequestStatus::Queued.as_str())
+            .bind(chain.0 as i64);
+        self.fetch_requests(statement).await
+    }
+
+    async fn get_ready(&self, chain: ChainId) -> anyhow::Result<Vec<Request>> {
+        let sql = DB::sql(&format!(
+            r#"
+			SELECT {}
+			FROM requests r
+			WHERE r.status = ? and r.chain = ? and NOT EXISTS (
+				SELECT 1
+				FROM request_dependencies d
+				LEFT JOIN requests parent ON parent.id = d.depends_on
+				WHERE d.request_id = r.id and (parent.status IS NULL or parent.status != ?)
+			)
+			ORDER BY r.seq
+			"#,
+            request_columns::<DB>()
+        ));
+        let statement = query(&sql)
+            .bind(RequestStatus::Waiting.as_str())
+            .bind(chain.0 as i64)
+            .bind(RequestStatus::Mined.as_str());
+        self.fetch_requests(statement).await
+    }
+
+    async fn mark_queued(&self, id: Uuid, tx: Eip1559TransactionRequest) -> anyhow::Result<()> {
+        let columns = TxColumns::new(&tx)?;
+        let sql = DB::sql(&format!(
+            r#"
+			UPDATE requests
+			SET tx = {}, status = ?, updated_at = {}, {}
+			WHERE id = ? and status = ?
+			"#,
+            DB::JSON,
+            DB::TIME,
+            tx_assignments::<DB>()
+        ));
+        let statement = query(&sql)
+            .bind(to_string(&tx)?)
+            .bind(RequestStatus::Queued.as_str())
+            .bind(now_millis());
+        let statement = Self::bind_columns(statement, columns)
+            .bind(id.to_string())
+            .bind(RequestStatus::Waiting.as_str());
+        let mut db_tx = self.pool.begin().await?;
+        if DB::rows_affected(&statement.execute(&mut *db_tx).await?) > 0 {
+            Self::record_event(
+                &mut db_tx,
+                &id.to_string(),
+                RequestEventKind::Queued,
+                &Actor::System,
+            )
+            .await?;
+        }
+
+        db_tx.commit().await?;
+        Ok(())
+    }
+
+    async fn mark_failed(&self, id: Uuid) -> anyhow::Result<bool> {
+        self.transition_one(
+            id,
+            RequestStatus::Failed,
+            &[RequestStatus::Waiting, RequestStatus::Queued],
+            RequestEventKind::Failed,
+            &Actor::System,
+        )
+        .await
+    }
+
+    async fn fail_blocked(&self, chain: ChainId) -> anyhow::Result<u64> {
+        let sql = DB::sql(
+            r#"
+			SELECT r.id
+			FROM requests r
+			WHERE r.status = ? and r.chain = ? and EXISTS (
+				SELECT 1
+				FROM request_dependencies d
+				JOIN requests parent ON parent.id = d.depends_on
+				WHERE d.request_id = r.id and parent.status IN (?, ?)
+			)
+			ORDER BY r.seq
+			"#,
+        );
+        let mut db_tx = self.pool.begin().await?;
+        let blocked = query(&sql)
+            .bind(RequestStatus::Waiting.as_str())
+            .bind(chain.0 as i64)
+            .bind(RequestStatus::Failed.as_str())
+            .bind(RequestStatus::Cancelled.as_str())
+            .try_map(|row| text::<DB>(&row, "id"))
+            .fetch_all(&mut *db_tx)
+            .await?;
+        let mut failed = 0;
+        for id in blocked {
+            if Self::transition(
+                &mut db_tx,
+                &id,
+                RequestStatus::Failed,
+                &[RequestStatus::Waiting],
+                RequestEventKind::Failed,
+                &Actor::System,
+            )
+            .await?
+            {
+                failed += 1;
+            }
+        }
+
+        db_tx.commit().await?;
+        Ok(failed)
+    }
+
+    async fn cancel(&self, id: Uuid, actor: &Actor) -> anyhow::Result<bool> {
+        self.transition_one(
+            id,
+            RequestStatus::Cancelled,
+            &[RequestStatus::Waiting, RequestStatus::Queued],
+            RequestEventKind::Cancelled,
+            actor,
+        )
+        .await
+    }
+
+    async fn abandon(&self, id: Uuid, actor: &Actor) -> anyhow::Result<bool> {
+        self.transition_one(
+            id,
+            RequestStatus::Failed,
+            &[
+                RequestStatus::Waiting,
+                RequestStatus::Queued,
+                RequestStatus::Submitted,
+            ],
+            RequestEventKind::Abandoned,
+            actor,
+        )
+        .await
+    }
+
+    async fn count_in_flight(&self, chain: ChainId) -> anyhow::Result<u64> {
+        let sql = DB::sql(&format!(
+            r#"
+			SELECT {} as count
+			FROM requests
+			WHERE chain = ? and status IN (?, ?)
+			"#,
+            DB::integer("COUNT(*)")
+        ));
+        let count = query(&sql)
+            .bind(chain.0 as i64)
+            .bind(RequestStatus::Queued.as_str())
+            .bind(RequestStatus::Submitted.as_str())
+            .try_map(|row| number::<DB>(&row, "count"))
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(count)
+    }
+
+    async fn get_pending(&self, chain: ChainId) -> anyhow::Result<Vec<Request>> {
+        let sql = DB::sql(&format!(
+            "SELECT {} FROM requests r WHERE r.status = ? and r.chain = ?",
+            request_columns::<DB>()
+        ));
+        let statement = query(&sql)
+            .bind(RequestStatus::Submitted.as_str())
+            .bind(chain.0 as i64);
+        self.fetch_requests(statement).await
+    }
+
+    async fn mark_submitted(
+        &self,
+        id: Uuid,
+        hash: TxHash,
+        tx: Eip1559TransactionRequest,
+    ) -> anyhow::Result<bool> {
+        let (from, nonce) = sender_and_nonce(id, &tx)?;
+        let columns = TxColumns::new(&tx)?;
+        let sql = DB::sql(&format!(
+            r#"
+			UPDATE requests
+			SET hash = ?, tx = {}, status = ?, updated_at = {}, {}
+			WHERE id = ? and status = ?
+			"#,
+            DB::JSON,
+            DB::TIME,
+            tx_assignments::<DB>()
+        ));
+        let statement = query(&sql)
+            .bind(format!("{:?}", hash))
+            .bind(to_string(&tx)?)
+            .bind(RequestStatus::Submitted.as_str())
+            .bind(now_millis());
+        // Cancelled while the worker was signing it
+        let statement = Self::bind_columns(statement, columns)
+            .bind(id.to_string())
+            .bind(RequestStatus::Queued.as_str());
+        let mut db_tx = self.pool.begin().await?;
+        if DB::rows_affected(&statement.execute(&mut *db_tx).await?) == 0 {
+            db_tx.rollback().await?;
+            return Ok(false);
+        }
+        Self::advance_nonce(&mut db_tx, tx.chain_id, from, nonce).await?;
+        Self::record_event(
+            &mut db_tx,
+            &id.to_string(),
+            RequestEventKind::Broadcast,
+            &Actor::System,
+        )
+        .await?;
+
+        db_tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn save_submitted(
+        &self,
+        id: Uuid,
+        hash: TxHash,
+        tx: Eip1559TransactionRequest,
+        chain: ChainId,
+    ) -> anyhow::Result<()> {
+        let (from, nonce) = sender_and_nonce(id, &tx)?;
+        let columns = TxColumns::new(&tx)?;
+        let now = now_millis();
+        let sql = DB::sql(&format!(
+            r#"
+			INSERT INTO requests (id, hash, tx, status, chain, created_at, updated_at, {TX_COLUMNS})
+			VALUES (?, ?, {}, ?, ?, {}, {}, {})
+			"#,
+            DB::JSON,
+            DB::TIME,
+            DB::TIME,
+            tx_values::<DB>()
+        ));
+        let statement = query(&sql)
+            .bind(id.to_string())
+            .bind(format!("{:?}", hash))
+            .bind(to_string(&tx)?)
+            .bind(RequestStatus::Submitted.as_str())
+            .bind(chain.0 as i64)
+            .bind(now)
+            .bind(now);
+        let mut db_tx = self.pool.begin().await?;
+        Self::bind_columns(statement, columns)
+            .execute(&mut *db_tx)
+            .await?;
+        Self::advance_nonce(&mut db_tx, Some(chain.0.into()), from, nonce).await?;
+        Self::record_event(
+            &mut db_tx,
+            &id.to_string(),
+            RequestEventKind::Broadcast,
+            &Actor::System,
+        )
+        .await?;
+
+        db_tx.commit().await?;
+        Ok(())
+    }
+
+    async fn replace(
+        &self,
+        id: Uuid,
+        hash: TxHash,
+        tx: Eip1559TransactionRequest,
+        actor: &Actor,
+    ) -> anyhow::Result<bool> {
+        let columns = TxColumns::new(&tx)?;
+        let sql = DB::sql(&format!(
+            r#"
+			UPDATE requests
+			SET hash = ?, tx = {}, max_fee = {}, priority_fee = {}, updated_at = {}
+			WHERE id = ? and status = ?
+			"#,
+            DB::JSON,
+            DB::AMOUNT,
+            DB::AMOUNT,
+            DB::TIME
+        ));
+        let mut db_tx = self.pool.begin().await?;
+        let result = query(&sql)
+            .bind(format!("{:?}", hash))
+            .bind(to_string(&tx)?)
+            .bind(columns.max_fee.map(DB::amount))
+            .bind(columns.priority_fee.map(DB::amount))
+            .bind(now_millis())
+            .bind(id.to_string())
+            .bind(RequestStatus::Submitted.as_str())
+            .execute(&mut *db_tx)
+            .await?;
+        if DB::rows_affected(&result) == 0 {
+            db_tx.rollback().await?;
+            return Ok(false);
+        }
+        Self::record_event(
+            &mut db_tx,
+            &id.to_string(),
+            RequestEventKind::Replaced,
+            actor,
+        )
+        .await?;
+
+        db_tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn get_next_nonce(
+        &self,
+        chain: ChainId,
+        address: Address,
+    ) -> anyhow::Result<Option<U256>> {
+        let sql = DB::sql("SELECT next_nonce FROM nonces WHERE chain = ? and address = ?");
+        let nonce = query(&sql)
+            .bind(chain.0 as i64)
+            .bind(format!("{:?}", address))
+            .try_map(|row| number::<DB>(&row, "next_nonce"))
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(nonce.map(U256::from))
+    }
+
+    async fn set_next_nonce(
+        &self,
+        chain: ChainId,
+        address: Address,
+        nonce: U256,
+    ) -> anyhow::Result<()> {
+        // Both chain tasks sync the nonce, a stale write mustn't move it back
+        let next_nonce = DB::greatest("nonces.next_nonce", &DB::excluded("next_nonce"));
+        self.upsert_nonce(chain, address, nonce, &next_nonce).await
+    }
+
+    async fn reset_next_nonce(
+        &self,
+        chain: ChainId,
+        address: Address,
+        nonce: U256,
+    ) -> anyhow::Result<()> {
+        self.upsert_nonce(chain, address, nonce, &DB::excluded("next_nonce"))
+            .await
+    }
+
+    async fn get_submitted_nonces(
+        &self,
+        chain: ChainId,
+        address: Address,
+        from_nonce: U256,
+    ) -> anyhow::Result<Vec<U256>> {
+        let sql = DB::sql(
+            "SELECT nonce FROM requests WHERE chain = ? and from_address = ? and status = ? and nonce >= ?",
+        );
+        let nonces = query(&sql)
+            .bind(chain.0 as i64)
+            .bind(format!("{:?}", address))
+            .bind(RequestStatus::Submitted.as_str())
+            .bind(from_nonce.as_u64() as i64)
+            .try_map(|row| number::<DB>(&row, "nonce"))
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(nonces.into_iter().map(U256::from).collect())
+    }
+
+    async fn update_many(&self, updates: Vec<RequestUpdate>) -> anyhow::Result<()> {
+        if updates.is_empty() {
+            return Ok(());
+        }
+
+        // Keeps updated_at when nothing changed. It's assigned first, MySQL
+        // compares against the columns as they're updated.
+        let sql = DB::sql(&format!(
+            r#"
+			UPDATE requests
+			SET updated_at = CASE WHEN hash IS NULL or hash != ? or status != ? THEN {} ELSE updated_at END,
+				hash = ?, status = ?, cost_gwei = COALESCE(?, cost_gwei)
+			WHERE id = ? and status = ? and hash = ?
+			"#,
+            DB::TIME
+        ));
+        let now = now_millis();
+        let mut db_tx = self.pool.begin().await?;
+        for RequestUpdate {
+            id,
+            read_hash,
+            status,
+            hash,
+            cost,
+        } in updates
+        {
+            let hash = format!("{:?}", hash);
+            let result = query(&sql)
+                .bind(&hash)
+                .bind(status.as_str())
+                .bind(now)
+                .bind(&hash)
+                .bind(status.as_str())
+                .bind(cost.map(|cost| to_gwei(cost) as i64))
+                .bind(id.to_string())
+                .bind(RequestStatus::Submitted.as_str())
+                .bind(format!("{:?}", read_hash))
+                .execute(&mut *db_tx)
+                .await?;
+            // Abandoned, replaced or settled since it was read
+            if DB::rows_affected(&result) != 1 {
+                continue;
+            }
+            let event = RequestEventKind::for_update(status);
+            Self::record_event(&mut db_tx, &id.to_string(), event, &Actor::System).await?;
+        }
+
+        db_tx.commit().await?;
+        Ok(())
+    }
+
+    async fn list(
+        &self,
+        filter: &RequestFilter,
+        cursor: Option<Cursor>,
+        limit: u32,
+    ) -> anyhow::Result<(Vec<ListedRequest>, Option<Cursor>)> {
+        let time = DB::TIME;
+        let sql = DB::sql(&format!(
+            r#"
+			SELECT {}
+			FROM requests r
+			WHERE (? IS NULL OR r.chain = ?)
+				and (? IS NULL OR r.status = ?)
+				and (? IS NULL OR r.tenant_id = ?)
+				and (? IS NULL OR r.to_address = ?)
+				and (? IS NULL OR r.from_address = ?)
+				and (? IS NULL OR r.created_at >= {time})
+				and (? IS NULL OR r.created_at < {time})
+				and (? IS NULL OR (r.created_at, r.seq) < ({time}, ?))
+			ORDER BY r.created_at DESC, r.seq DESC
+			LIMIT ?
+			"#,
+            listed_request_columns::<DB>()
+        ));
+        let chain = filter.chain.map(|chain| chain.0 as i64);
+        let status = filter.status.map(|status| status.as_str().to_owned());
+        let to = filter.to.map(|to| format!("{:?}", to));
+        let sender = filter.sender.map(|sender| format!("{:?}", sender));
+        let created_after = filter.created_after.map(|time| time as i64);
+        let created_before = filter.created_before.map(|time| time as i64);
+        let cursor_created_at = cursor.map(|cursor| cursor.created_at as i64);
+        let statement = query(&sql)
+            .bind(chain)
+            .bind(chain)
+            .bind(status.clone())
+            .bind(status)
+            .bind(filter.tenant_id.clone())
+            .bind(filter.tenant_id.clone())
+            .bind(to.clone())
+            .bind(to)
+            .bind(sender.clone())
+            .bind(sender)
+            .bind(created_after)
+            .bind(created_after)
+            .bind(created_before)
+            .bind(created_before)
+            .bind(cursor_created_at)
+            .bind(cursor_created_at)
+            .bind(cursor.map(|cursor| cursor.seq as i64))
+            .bind(limit as i64);
+
+        self.fetch_listed(statement, limit).await
+    }
+
+    async fn get_expired(
+        &self,
+        chain: ChainId,
+        before: u64,
+        limit: u32,
+    ) -> anyhow::Result<Vec<ListedRequest>> {
+        let sql = DB::sql(&format!(
+            r#"
+			SELECT {}
+			FROM requests r
+			WHERE r.chain = ? and r.status IN (?, ?, ?) and r.updated_at < {}
+				and NOT EXISTS (
+					SELECT 1
+					FROM request_dependencies d
+					JOIN requests child ON child.id = d.request_id
+					WHERE d.depends_on = r.id and child.status = ?
+				)
+			ORDER BY r.seq
+			LIMIT ?
+			"#,
+            listed_request_columns::<DB>(),
+            DB::TIME
+        ));
+        let statement = query(&sql)
+            .bind(chain.0 as i64)
+            .bind(RequestStatus::Mined.as_str())
+            .bind(RequestStatus::Failed.as_str())
+            .bind(RequestStatus::Cancelled.as_str())
+            .bind(before as i64)
+            .bind(RequestStatus::Waiting.as_str())
+            .bind(limit as i64);
+        Ok(self.fetch_listed(statement, limit).await?.0)
+    }
+
+    async fn archive(&self, ids: &[Uuid], keep: bool) -> anyhow::Result<u64> {
+        const ARCHIVED_COLUMNS: &str = "id, seq, hash, tx, chain, status, nonce, idempotency_key, \
+			idempotency_fingerprint, batch_id, batch_index, tenant_id, cost_gwei, created_at, updated_at, \
+			from_address, to_address, value, max_fee, priority_fee, gas_limit, data";
+        let terminal = [
+            RequestStatus::Mined.as_str(),
+            RequestStatus::Failed.as_str(),
+            RequestStatus::Cancelled.as_str(),
+        ];
+        let add_spend = DB::sql(&format!(
+            r#"
+			INSERT INTO tenant_spend (tenant_id, cost_gwei)
+			SELECT tenant_id, cost_gwei
+			FROM requests
+			WHERE id = ? and status IN (?, ?, ?) and cost_gwei IS NOT NULL
+			{} cost_gwei = tenant_spend.cost_gwei + {}
+			"#,
+            DB::on_conflict("tenant_id"),
+            DB::excluded("cost_gwei")
+        ));
+        let copy = DB::sql(&format!(
+            r#"
+			INSERT INTO requests_archive ({ARCHIVED_COLUMNS}, archived_at)
+			SELECT {ARCHIVED_COLUMNS}, {}
+			FROM requests
+			WHERE id = ? and status IN (?, ?, ?)
+			"#,
+            DB::TIME
+        ));
+        let retire_key = DB::sql(
+            r#"
+			INSERT INTO retired_idempotency_keys (tenant_id, idempotency_key, request_id, idempotency_fingerprint)
+			SELECT tenant_id, idempotency_key, id, idempotency_fingerprint
+			FROM requests
+			WHERE id = ? and status IN (?, ?, ?) and idempotency_key IS NOT NULL
+			"#,
+        );
+        // A child saved since `get_expired` still needs its parent. MySQL can't read the table
+        // it deletes from in a subquery unless it's materialized first, which DISTINCT forces.
+        let delete = DB::sql(
+            r#"
+			DELETE FROM requests
+			WHERE id = ? and status IN (?, ?, ?) and NOT EXISTS (
+				SELECT 1
+				FROM (
+					SELECT DISTINCT d.depends_on
+					FROM request_dependencies d
+					JOIN requests child ON child.id = d.request_id
+					WHERE d.depends_on = ? and child.status = ?
+				) held
+			)
+			"#,
+        );
+        let delete_dependencies = DB::sql("DELETE FROM request_dependencies WHERE request_id = ?");
+        let now = now_millis();
+        let mut db_tx = self.pool.begin().await?;
+        let mut removed = 0;
+
+        for id in ids {
+            let id = id.to_string();
+            // Undone if the request turns out to be held, its spend and copy would be counted twice
+            let mut savepoint = Connection::begin(&mut *db_tx).await?;
+            query(&add_spend)
+                .bind(&id)
+                .bind(terminal[0])
+                .bind(terminal[1])
+                .bind(terminal[2])
+                .execute(&mut *savepoint)
+                .await?;
+            query(&retire_key)
+                .bind(&id)
+                .bind(terminal[0])
+                .bind(terminal[1])
+                .bind(terminal[2])
+                .execute(&mut *savepoint)
+                .await?;
+            if keep {
+                query(&copy)
+                    .bind(now)
+                    .bind(&id)
+                    .bind(terminal[0])
+                    .bind(terminal[1])
+                    .bind(terminal[2])
+                    .execute(&mut *savepoint)
+                    .await?;
+            }
+            let deleted = query(&delete)
+                .bind(&id)
+                .bind(terminal[0])
+                .bind(terminal[1])
+                .bind(terminal[2])
+                .bind(&id)
+                .bind(RequestStatus::Waiting.as_str())
+                .execute(&mut *savepoint)
+                .await?;
+            let deleted = DB::rows_affected(&deleted);
+            if deleted == 0 {
+                savepoint.rollback().await?;
+                continue;
+            }
+            query(&delete_dependencies)
+                .bind(&id)
+                .execute(&mut *savepoint)
+                .await?;
+            savepoint.commit().await?;
+            removed += deleted;
+        }
+
+        db_tx.commit().await?;
+        Ok(removed)
+    }
+
+    async fn get_events(&self, id: Uuid) -> anyhow::Result<Vec<RequestEvent>> {
+        let sql = DB::sql(&format!(
+            "SELECT {} FROM request_events WHERE request_id = ? ORDER BY seq",
+            request_event_columns::<DB>()
+        ));
+        self.fetch_events(query(&sql).bind(id.to_string())).await
+    }
+
+    async fn list_events(
+        &self,
+        tenant_id: Option<&str>,
+        after: u64,
+        limit: u32,
+    ) -> anyhow::Result<Vec<RequestEvent>> {
+        let sql = DB::sql(&format!(
+            r#"
+			SELECT {}
+			FROM request_events
+			WHERE (? IS NULL OR tenant_id = ?) and seq > ?
+			ORDER BY seq
+			LIMIT ?
+			"#,
+            request_event_columns::<DB>()
+        ));
+        let tenant_id = tenant_id.map(str::to_owned);
+        let statement = query(&sql)
+            .bind(tenant_id.clone())
+            .bind(tenant_id)
+            .bind(after as i64)
+            .bind(limit as i64);
+        self.fetch_events(statement).await
+    }
+}
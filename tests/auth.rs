@@ -0,0 +1,140 @@
+use ethers::{
+    signers::{LocalWallet, Signer},
+    types::transaction::eip712::Eip712,
+};
+use hmac::{Hmac, Mac};
+use relay::{
+    api_keys::{hash_key, ApiKeyRepository, DbApiKeyRepository},
+    auth::{unix_now, ReplayGuard, SecretError, SecretKey, SignatureRejected, SignedRequest},
+    database::DbPool,
+};
+use sha2::Sha256;
+use sqlx::{query, SqlitePool};
+use std::env;
+use uuid::Uuid;
+
+const SECRET_KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+fn request(body: &[u8]) -> SignedRequest<'_> {
+    SignedRequest {
+        method: "POST",
+        path: "/transaction",
+        body,
+        timestamp: unix_now(),
+    }
+}
+
+#[test]
+fn hmac_covers_the_body_and_timestamp() {
+    let body = br#"{"to":"0x0000000000000000000000000000000000000001","value":"1","chain":5}"#;
+    let signed = request(body);
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+    mac.update(format!("{}.POST./transaction.", signed.timestamp).as_bytes());
+    mac.update(body);
+    let signature = hex::encode(mac.finalize().into_bytes());
+
+    assert!(signed.verify_hmac(b"secret", &signature).is_ok());
+    assert!(signed.verify_hmac(b"other", &signature).is_err());
+    assert!(request(b"{}").verify_hmac(b"secret", &signature).is_err());
+    let later = SignedRequest {
+        timestamp: signed.timestamp + 1,
+        ..signed.clone()
+    };
+    assert!(later.verify_hmac(b"secret", &signature).is_err());
+}
+
+#[tokio::test]
+async fn eip712_recovers_the_signer() {
+    let wallet = LocalWallet::new(&mut ethers::core::rand::thread_rng());
+    let signed = request(b"{}");
+
+    let signature = wallet.sign_typed_data(&signed).await.unwrap();
+    assert_eq!(
+        signed.recover(&signature.to_string()).unwrap(),
+        wallet.address()
+    );
+
+    let tampered = request(b"{\"value\":1}");
+    assert_ne!(
+        tampered.encode_eip712().unwrap(),
+        signed.encode_eip712().unwrap()
+    );
+    assert_ne!(
+        tampered.recover(&signature.to_string()).unwrap(),
+        wallet.address()
+    );
+}
+
+#[test]
+fn replay_guard_accepts_a_request_once() {
+    let guard = ReplayGuard::default();
+    let signed = request(b"{}");
+
+    assert!(guard.check(&signed, "client").is_ok());
+    assert!(matches!(
+        guard.check(&signed, "client"),
+        Err(SignatureRejected::Replayed)
+    ));
+    // Another client can send the same request
+    assert!(guard.check(&signed, "other").is_ok());
+
+    let stale = SignedRequest {
+        timestamp: signed.timestamp - 3600,
+        ..signed
+    };
+    assert!(matches!(
+        guard.check(&stale, "client"),
+        Err(SignatureRejected::Expired)
+    ));
+}
+
+#[test]
+fn hmac_secrets_only_decrypt_for_their_key() {
+    let key: SecretKey = SECRET_KEY.parse().unwrap();
+    let id = Uuid::new_v4();
+    let stored = key.encrypt(id, "secret");
+    assert!(!stored.contains("secret"));
+    assert_ne!(stored, key.encrypt(id, "secret"));
+    assert_eq!(key.decrypt(id, &stored).unwrap(), "secret");
+
+    assert!(matches!(
+        key.decrypt(Uuid::new_v4(), &stored),
+        Err(SecretError::Undecryptable)
+    ));
+    let other: SecretKey = SECRET_KEY.replace("00", "ff").parse().unwrap();
+    assert!(matches!(
+        other.decrypt(id, &stored),
+        Err(SecretError::Undecryptable)
+    ));
+    assert!(matches!(
+        key.decrypt(id, "secret"),
+        Err(SecretError::Plaintext)
+    ));
+    assert!("00".parse::<SecretKey>().is_err());
+}
+
+#[tokio::test]
+async fn plaintext_hmac_secrets_are_encrypted_in_place() {
+    let path = env::temp_dir().join(format!("relay_test_{}.db", Uuid::new_v4().simple()));
+    let url = format!("sqlite://{}", path.display());
+    let pool = DbPool::connect(&url, 1).await.unwrap();
+    pool.migrate().await.unwrap();
+    let id = Uuid::new_v4();
+    query("INSERT INTO api_keys (id, key_hash, tenant_id, hmac_secret) VALUES (?1, ?2, 'acme', 'secret')")
+        .bind(id.to_string())
+        .bind(hash_key("key"))
+        .execute(&SqlitePool::connect(&url).await.unwrap())
+        .await
+        .unwrap();
+
+    let key: SecretKey = SECRET_KEY.parse().unwrap();
+    let api_keys = DbApiKeyRepository::new(pool.clone());
+    assert_eq!(api_keys.encrypt_hmac_secrets(&key).await.unwrap(), 1);
+    assert_eq!(api_keys.encrypt_hmac_secrets(&key).await.unwrap(), 0);
+
+    let (api_key, stored) = api_keys.find_hmac(id).await.unwrap().unwrap();
+    assert_eq!(api_key.tenant_id, "acme");
+    assert!(SecretKey::is_encrypted(&stored));
+    assert_eq!(key.decrypt(id, &stored).unwrap(), "secret");
+}
@@ -0,0 +1,326 @@
+use ethers::{
+    abi::{encode, Token},
+    providers::{Http, Provider},
+    signers::{LocalWallet, Signer},
+    types::{Address, Bytes, H256, U256},
+    utils::{id, keccak256, Anvil},
+};
+use relay::bundler::{check_validation_result, Bundler, UserOpRejected, UserOperation};
+use relay::database::DbPool;
+use relay::transaction_monitor::TransactionMonitor;
+use relay::transaction_repository::{ChainId, DbTxRequestRepository, RequestStatus};
+use sqlx::query_scalar;
+use std::{env, sync::Arc};
+use tokio::time::{sleep, Duration};
+use uuid::Uuid;
+
+const NOW: u64 = 1_700_000_000;
+const VALIDATION_RESULT: &str = "ValidationResult((uint256,uint256,bool,uint48,uint48,bytes),(uint256,uint256),(uint256,uint256),(uint256,uint256))";
+const SIMULATE_VALIDATION: &str = "simulateValidation((address,uint256,bytes,bytes,uint256,uint256,uint256,uint256,uint256,bytes,bytes))";
+const USER_OPERATION_EVENT: &str =
+    "UserOperationEvent(bytes32,address,address,uint256,bool,uint256,uint256)";
+const ANVIL: ChainId = ChainId(31337);
+// Runtime code that always reverts with Error("nope")
+const REVERT_WITH_NOPE: &str = "0x7f08c379a0000000000000000000000000000000000000000000000000000000006000527f00000020000000000000000000000000000000000000000000000000000000006020527f000000046e6f70650000000000000000000000000000000000000000000000006040527f000000000000000000000000000000000000000000000000000000000000000060605260646000fd";
+
+fn validation_result(sig_failed: bool, valid_after: u64, valid_until: u64) -> Vec<u8> {
+    let stake_info = Token::Tuple(vec![Token::Uint(0.into()), Token::Uint(0.into())]);
+    let args = encode(&[
+        Token::Tuple(vec![
+            Token::Uint(50_000.into()),
+            Token::Uint(1_000_000.into()),
+            Token::Bool(sig_failed),
+            Token::Uint(valid_after.into()),
+            Token::Uint(valid_until.into()),
+            Token::Bytes(vec![]),
+        ]),
+        stake_info.clone(),
+        stake_info.clone(),
+        stake_info,
+    ]);
+    [&id(VALIDATION_RESULT)[..], &args].concat()
+}
+
+fn user_operation() -> UserOperation {
+    UserOperation {
+        sender: Address::from_low_u64_be(1),
+        nonce: U256::zero(),
+        init_code: vec![].into(),
+        call_data: vec![0xab].into(),
+        call_gas_limit: 100_000.into(),
+        verification_gas_limit: 100_000.into(),
+        pre_verification_gas: 50_000.into(),
+        max_fee_per_gas: 100.into(),
+        max_priority_fee_per_gas: 1.into(),
+        paymaster_and_data: vec![].into(),
+        signature: vec![0x01].into(),
+    }
+}
+
+/// Runtime code for a stand-in EntryPoint. `simulateValidation` reverts with `result`, any
+/// other call emits a successful `UserOperationEvent` for `op`.
+fn entry_point_code(result: &[u8], hash: H256, op: &UserOperation) -> Bytes {
+    let event = encode(&[
+        Token::Uint(op.nonce),
+        Token::Bool(true),
+        Token::Uint(21_000.into()),
+        Token::Uint(21_000.into()),
+    ]);
+    let topics = [
+        H256::from(keccak256(USER_OPERATION_EVENT)),
+        hash,
+        H256::from(op.sender),
+        H256::zero(),
+    ];
+    let push2 = |code: &mut Vec<u8>, value: usize| {
+        code.push(0x61);
+        code.extend((value as u16).to_be_bytes());
+    };
+    // 16 bytes to dispatch on the selector, 148 to log the event, 16 to revert
+    let (revert_at, result_at) = (164, 180);
+    let event_at = result_at + result.len();
+
+    // PUSH1 0 CALLDATALOAD PUSH1 0xe0 SHR PUSH4 selector EQ PUSH2 revert_at JUMPI
+    let mut code = vec![0x60, 0x00, 0x35, 0x60, 0xe0, 0x1c, 0x63];
+    code.extend(id(SIMULATE_VALIDATION));
+    code.push(0x14);
+    push2(&mut code, revert_at);
+    code.push(0x57);
+
+    // CODECOPY the event's data to memory, LOG4 it and STOP
+    push2(&mut code, event.len());
+    push2(&mut code, event_at);
+    code.extend([0x60, 0x00, 0x39]);
+    for topic in topics.iter().rev() {
+        code.push(0x7f);
+        code.extend(topic.as_bytes());
+    }
+    push2(&mut code, event.len());
+    code.extend([0x60, 0x00, 0xa4, 0x00]);
+
+    // JUMPDEST, CODECOPY the validation result to memory and REVERT with it
+    assert_eq!(code.len(), revert_at);
+    code.push(0x5b);
+    push2(&mut code, result.len());
+    push2(&mut code, result_at);
+    code.extend([0x60, 0x00, 0x39]);
+    push2(&mut code, result.len());
+    code.extend([0x60, 0x00, 0xfd]);
+    assert_eq!(code.len(), result_at);
+
+    [&code[..], result, &event].concat().into()
+}
+
+async fn operation_status(pool: &DbPool, hash: H256) -> String {
+    let DbPool::Sqlite(pool) = pool else {
+        panic!("the bundler test runs on SQLite");
+    };
+    query_scalar("SELECT status FROM user_operations WHERE hash = ?")
+        .bind(format!("{:?}", hash))
+        .fetch_one(pool)
+        .await
+        .expect("the operation should be saved")
+}
+
+async fn wait_for_status(monitor: &TransactionMonitor<Http>, id: Uuid, status: RequestStatus) {
+    for _ in 0..30 {
+        let (current, _) = monitor
+            .get_transaction_status(id, None)
+            .await
+            .expect("Grabbing transaction status not error")
+            .expect("Status should exist");
+        if current == status {
+            return;
+        }
+        sleep(Duration::from_millis(500)).await;
+    }
+
+    panic!("Request {:?} never became {:?}", id, status);
+}
+
+#[tokio::test]
+async fn bundler_bundles_operations_for_a_deployed_entry_point() {
+    let path = env::temp_dir().join(format!("relay_test_{}.db", Uuid::new_v4().simple()));
+    let repo = DbTxRequestRepository::connect(&format!("sqlite://{}", path.display()), 5)
+        .await
+        .unwrap();
+    repo.migrate().await.expect("migrations should apply");
+    let pool = repo.pool();
+    let monitor = Arc::new(TransactionMonitor::new(repo));
+
+    let anvil = Anvil::new()
+        .chain_id(31337u64)
+        .port(8545u16)
+        .args(vec!["--no-mining", "--base-fee", "50"])
+        .spawn();
+    let provider = Provider::<Http>::try_from(anvil.endpoint()).unwrap();
+    let wallet: LocalWallet = anvil.keys()[0].clone().into();
+    monitor
+        .setup_monitor(
+            wallet.with_chain_id(anvil.chain_id()),
+            provider.clone(),
+            ANVIL,
+            1,
+            1.2,
+        )
+        .await
+        .unwrap();
+
+    let entry_point = Address::from_low_u64_be(0xe4);
+    let bundler = Bundler::new(monitor.clone(), pool.clone(), entry_point);
+    let op = UserOperation {
+        sender: anvil.addresses()[2],
+        max_fee_per_gas: 10_000_000_000u64.into(),
+        max_priority_fee_per_gas: 5_000_000_000u64.into(),
+        ..user_operation()
+    };
+    let hash = op.hash(entry_point, ANVIL.0);
+    let code = entry_point_code(&validation_result(false, 0, 0), hash, &op);
+    provider
+        .request::<_, ()>("anvil_setCode", (entry_point, code))
+        .await
+        .expect("setting code should work");
+
+    // Operations that pay less than the chain's current fees are turned away
+    let cheap = UserOperation {
+        sender: anvil.addresses()[3],
+        ..user_operation()
+    };
+    let err = bundler
+        .send_user_operation(cheap, entry_point, ANVIL, "acme")
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast::<UserOpRejected>().unwrap(),
+        UserOpRejected::FeeTooLow { .. }
+    ));
+
+    assert_eq!(
+        bundler
+            .send_user_operation(op.clone(), entry_point, ANVIL, "acme")
+            .await
+            .unwrap(),
+        hash
+    );
+    assert!(bundler
+        .get_user_operation_receipt(hash, "acme")
+        .await
+        .unwrap()
+        .is_none());
+
+    let id = bundler.bundle(ANVIL).await.unwrap().expect("one bundle");
+    assert_eq!(
+        bundler.bundle(ANVIL).await.unwrap(),
+        None,
+        "nothing pending"
+    );
+    wait_for_status(&monitor, id, RequestStatus::Submitted).await;
+    provider
+        .request::<_, U256>("evm_mine", None::<()>)
+        .await
+        .expect("mining should work");
+    wait_for_status(&monitor, id, RequestStatus::Mined).await;
+
+    let receipt = bundler
+        .get_user_operation_receipt(hash, "acme")
+        .await
+        .unwrap()
+        .expect("the bundle was mined");
+    assert_eq!(receipt.sender, op.sender);
+    assert_eq!(receipt.entry_point, entry_point);
+    assert!(receipt.success);
+    assert_eq!(receipt.actual_gas_used, 21_000.into());
+    assert!(
+        bundler
+            .get_user_operation_receipt(hash, "globex")
+            .await
+            .unwrap()
+            .is_none(),
+        "only the tenant that sent it sees the receipt"
+    );
+    bundler.bundle(ANVIL).await.unwrap();
+    assert_eq!(operation_status(&pool, hash).await, "mined");
+
+    // When the bundle reverts its operations are dropped instead of waiting forever
+    let next = UserOperation {
+        nonce: 1.into(),
+        ..op.clone()
+    };
+    let next_hash = bundler
+        .send_user_operation(next, entry_point, ANVIL, "acme")
+        .await
+        .unwrap();
+    let id = bundler.bundle(ANVIL).await.unwrap().expect("one bundle");
+    wait_for_status(&monitor, id, RequestStatus::Submitted).await;
+    provider
+        .request::<_, ()>("anvil_setCode", (entry_point, REVERT_WITH_NOPE))
+        .await
+        .expect("setting code should work");
+    provider
+        .request::<_, U256>("evm_mine", None::<()>)
+        .await
+        .expect("mining should work");
+    wait_for_status(&monitor, id, RequestStatus::Failed).await;
+
+    assert_eq!(bundler.bundle(ANVIL).await.unwrap(), None);
+    assert_eq!(operation_status(&pool, next_hash).await, "dropped");
+    assert!(bundler
+        .get_user_operation_receipt(next_hash, "acme")
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn bundler_accepts_valid_operations() {
+    assert!(check_validation_result(&validation_result(false, 0, 0), NOW).is_ok());
+    assert!(check_validation_result(&validation_result(false, NOW - 60, NOW + 3600), NOW).is_ok());
+}
+
+#[test]
+fn bundler_rejects_invalid_operations() {
+    let rejected = |data: Vec<u8>| check_validation_result(&data, NOW).unwrap_err();
+
+    assert!(matches!(
+        rejected(validation_result(true, 0, 0)),
+        UserOpRejected::Signature
+    ));
+    assert!(matches!(
+        rejected(validation_result(false, NOW + 60, 0)),
+        UserOpRejected::OutOfTimeRange { .. }
+    ));
+    assert!(matches!(
+        rejected(validation_result(false, 0, NOW + 1)),
+        UserOpRejected::OutOfTimeRange { .. }
+    ));
+
+    let failed_op = [
+        &id("FailedOp(uint256,string)")[..],
+        &encode(&[
+            Token::Uint(0.into()),
+            Token::String("AA21 didn't pay prefund".into()),
+        ]),
+    ]
+    .concat();
+    let err = rejected(failed_op);
+    assert_eq!(err.code(), -32500);
+    assert_eq!(
+        err.to_string(),
+        "operation failed validation, AA21 didn't pay prefund"
+    );
+}
+
+#[test]
+fn bundler_hash_binds_deployment_not_signature() {
+    let op = user_operation();
+    let entry_point = Address::from_low_u64_be(0xe4);
+    let hash = op.hash(entry_point, 1);
+
+    let resigned = UserOperation {
+        signature: vec![0x02].into(),
+        ..op.clone()
+    };
+    assert_eq!(resigned.hash(entry_point, 1), hash);
+    assert_ne!(op.hash(entry_point, 5), hash);
+    assert_ne!(op.hash(Address::from_low_u64_be(0xe5), 1), hash);
+}
@@ -0,0 +1,61 @@
+//! Fixtures shared by the test binaries, each uses some of them
+#![allow(dead_code)]
+
+use ethers::types::{Address, Eip1559TransactionRequest, TxHash, U256};
+use relay::transaction_repository::{
+    Actor, ChainId, NewRequest, RequestStatus, RequestUpdate, TransactionRepository,
+};
+use uuid::Uuid;
+
+pub const GOERLI: ChainId = ChainId(5);
+/// Signs every fixture transaction
+pub const RELAYER: u64 = 9;
+
+/// A 1 wei transfer on goerli for the `acme` tenant
+pub fn new_request(depends_on: Vec<Uuid>) -> NewRequest {
+    NewRequest {
+        id: Uuid::new_v4(),
+        tx: Eip1559TransactionRequest::new()
+            .to(Address::from_low_u64_be(1))
+            .value(1),
+        chain: GOERLI,
+        tenant_id: Some("acme".to_owned()),
+        depends_on,
+        actor: Actor::System,
+    }
+}
+
+/// The request's transaction as the relayer signs it
+pub fn signed(request: &NewRequest, nonce: u64) -> Eip1559TransactionRequest {
+    request
+        .tx
+        .clone()
+        .from(Address::from_low_u64_be(RELAYER))
+        .nonce(nonce)
+        .chain_id(GOERLI.0)
+}
+
+/// Broadcasts a saved request with nonce 0 and marks it mined, returns its hash
+pub async fn mine<T: TransactionRepository>(repo: &T, id: Uuid, cost: Option<U256>) -> TxHash {
+    let hash = TxHash::random();
+    let tx = repo
+        .get(id)
+        .await
+        .unwrap()
+        .expect("the request should be saved")
+        .tx
+        .from(Address::from_low_u64_be(RELAYER))
+        .nonce(0)
+        .chain_id(GOERLI.0);
+    assert!(repo.mark_submitted(id, hash, tx).await.unwrap());
+    repo.update_many(vec![RequestUpdate {
+        id,
+        read_hash: hash,
+        status: RequestStatus::Mined,
+        hash,
+        cost,
+    }])
+    .await
+    .unwrap();
+    hash
+}
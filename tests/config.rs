@@ -0,0 +1,322 @@
+use ethers::{
+    signers::Signer,
+    types::{Address, Chain},
+};
+use relay::{auth::AuthMode, config::Config, transaction_repository::ChainId};
+use std::{collections::HashMap, env, fs, net::SocketAddr, path::PathBuf};
+use uuid::Uuid;
+
+// Anvil's first account
+const PK: &str = "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+const FORWARDER: &str = "0x3000000000000000000000000000000000000003";
+
+const TOML: &str = r#"
+auth_mode = "hmac"
+hmac_secret_key = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"
+alchemy_key = "key"
+block_frequency = 2
+
+[server]
+address = "0.0.0.0"
+port = 8080
+
+[database]
+url = "postgres://localhost/relay"
+pool_size = 10
+
+[signer]
+private_key = "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80"
+low_balance_threshold = "1000"
+
+[chains.goerli]
+block_frequency = 5
+forwarder = "0x3000000000000000000000000000000000000003"
+
+[chains.polygon]
+rpc_url = "wss://polygon.example.com"
+retention_days = 30
+"#;
+
+const YAML: &str = r#"
+auth_mode: hmac
+hmac_secret_key: "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"
+alchemy_key: key
+block_frequency: 2
+server:
+  address: 0.0.0.0
+  port: 8080
+database:
+  url: postgres://localhost/relay
+  pool_size: 10
+signer:
+  private_key: ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80
+  low_balance_threshold: "1000"
+chains:
+  goerli:
+    block_frequency: 5
+    forwarder: "0x3000000000000000000000000000000000000003"
+  polygon:
+    rpc_url: wss://polygon.example.com
+    retention_days: 30
+"#;
+
+fn write(extension: &str, contents: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!(
+        "relay_config_{}.{}",
+        Uuid::new_v4().simple(),
+        extension
+    ));
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+fn vars(pairs: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+    let vars: HashMap<String, String> = pairs
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    move |name| vars.get(name).cloned()
+}
+
+fn assert_file_config(config: &Config) {
+    assert_eq!(config.auth_mode, AuthMode::Hmac);
+    assert!(config.hmac_secret_key.is_some());
+    assert_eq!(
+        config.server.address,
+        "0.0.0.0:8080".parse::<SocketAddr>().unwrap()
+    );
+    assert!(config.server.tls.is_none());
+    assert_eq!(config.database.url, "postgres://localhost/relay");
+    assert_eq!(config.database.pool_size, 10);
+    assert_eq!(
+        config.signer.address(),
+        "0xf39Fd6e51aad88F6F4ce6aB8827279cffFb92266"
+            .parse::<Address>()
+            .unwrap()
+    );
+    assert_eq!(config.balance_policy.low_balance, Some(1000.into()));
+    assert!(config.balance_policy.top_up.is_none());
+
+    assert_eq!(
+        config.chains.keys().copied().collect::<Vec<_>>(),
+        vec![Chain::Goerli, Chain::Polygon]
+    );
+    let goerli = &config.chains[&ChainId::from(Chain::Goerli)];
+    assert_eq!(goerli.rpc_url, "wss://eth-goerli.g.alchemy.com/v2/key");
+    assert_eq!(goerli.block_frequency, 5);
+    assert_eq!(goerli.gas_limit_multiplier, 1.2);
+    assert_eq!(goerli.forwarder, Some(FORWARDER.parse().unwrap()));
+    assert_eq!(goerli.retention_days, None);
+    let polygon = &config.chains[&ChainId::from(Chain::Polygon)];
+    assert_eq!(polygon.rpc_url, "wss://polygon.example.com");
+    assert_eq!(polygon.block_frequency, 2);
+    assert_eq!(polygon.forwarder, None);
+    assert_eq!(polygon.retention_days, Some(30));
+}
+
+#[test]
+fn config_reads_toml_and_yaml() {
+    for (extension, contents) in [("toml", TOML), ("yaml", YAML)] {
+        let path = write(extension, contents);
+        let config = Config::load(Some(&path), vars(&[])).unwrap();
+        assert_file_config(&config);
+    }
+}
+
+#[test]
+fn config_env_vars_override_the_file() {
+    let path = write("toml", TOML);
+    let config = Config::load(
+        Some(&path),
+        vars(&[
+            ("PORT", "9000"),
+            ("DATABASE_URL", "sqlite://relay.db"),
+            ("DB_POOL_SIZE", "2"),
+            ("CHAINS", "goerli,sepolia"),
+            ("RETENTION_DAYS", "sepolia:7"),
+            ("GAS_LIMIT_MULTIPLIER", "1.5"),
+            // Empty vars count as unset
+            ("AUTH_MODE", ""),
+        ]),
+    )
+    .unwrap();
+
+    assert_eq!(config.auth_mode, AuthMode::Hmac);
+    assert_eq!(config.server.address.port(), 9000);
+    assert_eq!(config.database.url, "sqlite://relay.db");
+    assert_eq!(config.database.pool_size, 2);
+    assert_eq!(
+        config.chains.keys().copied().collect::<Vec<_>>(),
+        vec![Chain::Goerli, Chain::Sepolia]
+    );
+    // The goerli section is kept, sepolia only has defaults and env vars
+    assert_eq!(
+        config.chains[&ChainId::from(Chain::Goerli)].block_frequency,
+        5
+    );
+    assert_eq!(
+        config.chains[&ChainId::from(Chain::Goerli)].forwarder,
+        Some(FORWARDER.parse().unwrap())
+    );
+    assert_eq!(
+        config.chains[&ChainId::from(Chain::Sepolia)].block_frequency,
+        2
+    );
+    assert_eq!(
+        config.chains[&ChainId::from(Chain::Sepolia)].gas_limit_multiplier,
+        1.5
+    );
+    assert_eq!(
+        config.chains[&ChainId::from(Chain::Sepolia)].retention_days,
+        Some(7)
+    );
+}
+
+#[test]
+fn config_works_from_env_vars_alone() {
+    let config = Config::load(
+        None,
+        vars(&[
+            ("PK", PK),
+            ("ALCHEMY_KEY", "key"),
+            ("DATABASE_URL", "mysql://localhost/relay"),
+            ("FORWARDERS", &format!("sepolia:{}", FORWARDER)),
+            ("EXPECTED_AUTH_HEADER", "legacy"),
+        ]),
+    )
+    .unwrap();
+
+    assert_eq!(config.auth_mode, AuthMode::ApiKey);
+    assert_eq!(config.expected_auth_header.as_deref(), Some("legacy"));
+    assert!(config.hmac_secret_key.is_none());
+    assert_eq!(
+        config.server.address,
+        "127.0.0.1:3000".parse::<SocketAddr>().unwrap()
+    );
+    assert_eq!(config.database.url, "mysql://localhost/relay");
+    assert_eq!(config.database.pool_size, 5);
+    assert!(!config.database.run_migrations);
+    assert_eq!(
+        config.chains.keys().copied().collect::<Vec<_>>(),
+        vec![Chain::Goerli, Chain::Sepolia]
+    );
+    assert_eq!(
+        config.chains[&ChainId::from(Chain::Goerli)].block_frequency,
+        3
+    );
+    assert_eq!(
+        config.chains[&ChainId::from(Chain::Sepolia)].forwarder,
+        Some(FORWARDER.parse().unwrap())
+    );
+}
+
+#[test]
+fn config_takes_chain_ids_ethers_doesnt_know() {
+    let path = write(
+        "toml",
+        r#"
+[signer]
+private_key = "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80"
+
+[chains.5]
+rpc_url = "wss://goerli.example.com"
+
+[chains.424242]
+rpc_url = "wss://devnet.example.com"
+"#,
+    );
+    let config = Config::load(
+        Some(&path),
+        vars(&[
+            ("RETENTION_DAYS", "424242:3"),
+            ("DATABASE_URL", "sqlite://relay.db"),
+        ]),
+    )
+    .unwrap();
+
+    assert_eq!(
+        config.chains.keys().copied().collect::<Vec<_>>(),
+        vec![ChainId::from(Chain::Goerli), ChainId(424242)]
+    );
+    let devnet = &config.chains[&ChainId(424242)];
+    assert_eq!(devnet.rpc_url, "wss://devnet.example.com");
+    assert_eq!(devnet.retention_days, Some(3));
+}
+
+#[test]
+fn config_reports_every_problem() {
+    let path = write(
+        "toml",
+        r#"
+[server]
+address = "localhost"
+
+[server.tls]
+cert = "cert.pem"
+
+[database]
+url = "postgres://localhost/relay"
+pool_size = 0
+
+[signer]
+treasury_private_key = "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80"
+
+[chains.goerli]
+block_frequency = 0
+
+[chains.moon]
+
+[chains.424242]
+"#,
+    );
+    let err = Config::load(
+        Some(&path),
+        vars(&[
+            ("FORWARDERS", "sepolia:0x00"),
+            ("PORT", "http"),
+            ("AUTH_MODE", "hmac"),
+        ]),
+    )
+    .unwrap_err();
+
+    let expected = [
+        "FORWARDERS env var sets chain sepolia",
+        "hmac_secret_key (HMAC_SECRET_KEY) is missing",
+        "PORT env var is invalid",
+        "server.address (LISTEN_ADDRESS) is invalid",
+        "server.tls needs both cert (TLS_CERT) and key (TLS_KEY)",
+        "database.pool_size (DB_POOL_SIZE) has to be at least 1",
+        "signer.private_key (PK) or signer.private_key_file (PK_FILE) is missing",
+        "signer.treasury_private_key (TREASURY_PK) needs both",
+        "chains.goerli needs an rpc_url or alchemy_key (ALCHEMY_KEY)",
+        "chains.moon is neither a chain ethers knows nor a numeric chain id",
+        "chains.424242 needs an rpc_url, Alchemy doesn't serve it",
+    ];
+    for problem in expected {
+        assert!(
+            err.problems
+                .iter()
+                .any(|reported| reported.starts_with(problem)),
+            "{:?} wasn't reported in {}",
+            problem,
+            err
+        );
+    }
+    assert_eq!(err.problems.len(), expected.len(), "{}", err);
+}
+
+#[test]
+fn config_rejects_unknown_keys() {
+    let path = write("toml", "[server]\nprot = 3000\n");
+    let err = Config::load(Some(&path), vars(&[])).unwrap_err();
+    assert_eq!(err.problems.len(), 1);
+    assert!(err.problems[0].contains("unknown field `prot`"), "{}", err);
+
+    let path = write("json", "{}");
+    let err = Config::load(Some(&path), vars(&[])).unwrap_err();
+    assert!(
+        err.problems[0].ends_with("should end in .toml, .yaml or .yml"),
+        "{}",
+        err
+    );
+}
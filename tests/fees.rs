@@ -0,0 +1,183 @@
+use ethers::{
+    signers::{LocalWallet, Signer},
+    types::{
+        Address, Bytes, Chain, Eip1559TransactionRequest, Log, TransactionReceipt, H256, U256, U64,
+    },
+    utils::{hash_message, keccak256},
+};
+use relay::{
+    fees::{
+        call_hash, check_transfer, transfer_authorization, transfer_signer, FeeConfig, FeeRejected,
+        PaymentMethod,
+    },
+    transaction_repository::ChainId,
+};
+use uuid::Uuid;
+
+const TOKEN: &str = "0x1000000000000000000000000000000000000001";
+const RELAYER: &str = "0x2000000000000000000000000000000000000002";
+
+const CONFIG: &str = r#"
+quote_ttl_seconds = 120
+margin_percent = 10
+
+[[tokens]]
+chain = "goerli"
+address = "0x1000000000000000000000000000000000000001"
+units_per_eth = "2000000000"
+
+[[tokens]]
+chain = 424242
+address = "0x1000000000000000000000000000000000000001"
+units_per_eth = "1000000000"
+"#;
+
+fn transfer_receipt(to: Address, amount: u64) -> TransactionReceipt {
+    let from = Address::from_low_u64_be(3);
+    TransactionReceipt {
+        status: Some(U64::one()),
+        logs: vec![Log {
+            address: TOKEN.parse().unwrap(),
+            topics: vec![
+                keccak256("Transfer(address,address,uint256)").into(),
+                H256::from(from),
+                H256::from(to),
+            ],
+            data: ethers::abi::encode(&[ethers::abi::Token::Uint(amount.into())]).into(),
+            ..Log::default()
+        }],
+        ..TransactionReceipt::default()
+    }
+}
+
+#[test]
+fn fees_are_quoted_in_token_units() {
+    let config: FeeConfig = toml::from_str(CONFIG).unwrap();
+    let token = config
+        .token(Chain::Goerli.into(), TOKEN.parse().unwrap())
+        .expect("token is configured");
+    assert!(config
+        .token(Chain::Sepolia.into(), TOKEN.parse().unwrap())
+        .is_none());
+    // Chains ethers doesn't know are configured by id
+    let devnet = config
+        .token(ChainId(424242), TOKEN.parse().unwrap())
+        .expect("token is configured");
+    assert_eq!(devnet.units_per_eth, U256::from(1_000_000_000));
+
+    // 100k gas at 10 gwei is 0.001 ether, 2 USDC, plus the 10% margin
+    let gwei = U256::exp10(9);
+    let fee = config.fee(token, PaymentMethod::Transfer, 100_000.into(), gwei * 10);
+    assert_eq!(fee, U256::from(2_200_000));
+
+    // Collecting the fee with a permit costs the relayer gas too
+    let fee = config.fee(token, PaymentMethod::Permit, 100_000.into(), gwei * 10);
+    assert!(fee > U256::from(2_200_000));
+}
+
+#[test]
+fn fees_check_transfers_to_the_relayer() {
+    let token: Address = TOKEN.parse().unwrap();
+    let relayer: Address = RELAYER.parse().unwrap();
+    let payer = Address::from_low_u64_be(3);
+
+    let receipt = transfer_receipt(relayer, 100);
+    assert!(check_transfer(&receipt, token, payer, relayer, 100.into()).is_ok());
+    assert!(matches!(
+        check_transfer(
+            &transfer_receipt(relayer, 99),
+            token,
+            payer,
+            relayer,
+            100.into()
+        ),
+        Err(FeeRejected::Insufficient { .. })
+    ));
+    assert!(matches!(
+        check_transfer(
+            &transfer_receipt(Address::from_low_u64_be(4), 100),
+            token,
+            payer,
+            relayer,
+            100.into()
+        ),
+        Err(FeeRejected::Transfer { .. })
+    ));
+    // Someone else's transfer can't pay the quote
+    assert!(matches!(
+        check_transfer(
+            &receipt,
+            token,
+            Address::from_low_u64_be(5),
+            relayer,
+            100.into()
+        ),
+        Err(FeeRejected::Transfer { .. })
+    ));
+
+    let reverted = TransactionReceipt {
+        status: Some(U64::zero()),
+        ..transfer_receipt(relayer, 100)
+    };
+    assert!(matches!(
+        check_transfer(&reverted, token, payer, relayer, 100.into()),
+        Err(FeeRejected::Transfer { .. })
+    ));
+}
+
+#[test]
+fn fees_bind_quotes_to_the_call() {
+    let call = Eip1559TransactionRequest::new()
+        .to(Address::from_low_u64_be(1))
+        .value(1)
+        .data(vec![1, 2, 3]);
+    // Gas and fees are filled in later, they don't change which call it is
+    assert_eq!(call_hash(&call), call_hash(&call.clone().gas(21_000)));
+    assert_ne!(
+        call_hash(&call),
+        call_hash(&call.clone().data(vec![1, 2, 4]))
+    );
+    assert_ne!(call_hash(&call), call_hash(&call.clone().value(2)));
+    assert_ne!(
+        call_hash(&call),
+        call_hash(&call.clone().to(Address::from_low_u64_be(2)))
+    );
+}
+
+#[test]
+fn fees_bind_transfers_to_the_quote_by_the_payers_signature() {
+    let payer = LocalWallet::new(&mut ethers::core::rand::thread_rng());
+    let quote_id = Uuid::new_v4();
+    let hash = H256::random();
+    let signature: Bytes = payer
+        .sign_hash(hash_message(transfer_authorization(quote_id, hash)))
+        .unwrap()
+        .to_vec()
+        .into();
+
+    assert_eq!(
+        transfer_signer(quote_id, hash, &signature).unwrap(),
+        payer.address()
+    );
+    // Another quote or transfer recovers someone else
+    assert_ne!(
+        transfer_signer(Uuid::new_v4(), hash, &signature).unwrap(),
+        payer.address()
+    );
+    assert_ne!(
+        transfer_signer(quote_id, H256::random(), &signature).unwrap(),
+        payer.address()
+    );
+    assert!(matches!(
+        transfer_signer(quote_id, hash, &Bytes::from(vec![1, 2, 3])),
+        Err(FeeRejected::PayerSignature)
+    ));
+}
+
+#[test]
+fn fees_reject_invalid_config() {
+    assert!(
+        toml::from_str::<FeeConfig>("quote_ttl_seconds = 60\ntokens = []\nmargin = 1").is_err()
+    );
+    assert!(toml::from_str::<FeeConfig>("tokens = []").is_err());
+}
@@ -0,0 +1,111 @@
+use ethers::{
+    abi::{AbiParser, Token},
+    types::{Address, Eip1559TransactionRequest, U256},
+};
+use relay::{
+    database::DbPool,
+    policy::{Policy, PolicyEngine},
+};
+use std::env;
+use uuid::Uuid;
+
+const TOKEN: &str = "0x1000000000000000000000000000000000000001";
+const TREASURY: &str = "0x2000000000000000000000000000000000000002";
+
+const POLICY: &str = r#"
+max_value = "1000"
+
+[[contracts]]
+name = "token"
+address = "0x1000000000000000000000000000000000000001"
+
+[[contracts.functions]]
+signature = "transfer(address to, uint256 amount)"
+args = [
+	{ index = 0, allowed = ["0x2000000000000000000000000000000000000002"] },
+	{ index = 1, max = "500" },
+]
+
+[[contracts.functions]]
+signature = "approve(address spender, uint256 amount)"
+"#;
+
+fn transfer(to: &str, amount: u64) -> Eip1559TransactionRequest {
+    let function = AbiParser::default()
+        .parse_function("transfer(address,uint256)")
+        .unwrap();
+    let data = function
+        .encode_input(&[
+            Token::Address(to.parse().unwrap()),
+            Token::Uint(amount.into()),
+        ])
+        .unwrap();
+
+    Eip1559TransactionRequest::new()
+        .to(TOKEN.parse::<Address>().unwrap())
+        .data(data)
+}
+
+#[test]
+fn policy_allows_matching_calls() {
+    let policy: Policy = toml::from_str(POLICY).unwrap();
+
+    assert!(policy.check(&transfer(TREASURY, 500)).is_ok());
+    assert!(Policy::default()
+        .check(&Eip1559TransactionRequest::new().value(U256::MAX))
+        .is_ok());
+}
+
+#[test]
+fn policy_rejections_name_the_rule() {
+    let policy: Policy = toml::from_str(POLICY).unwrap();
+
+    let rule = |tx: Eip1559TransactionRequest| policy.check(&tx).unwrap_err().rule;
+    assert_eq!(rule(transfer(TREASURY, 501)), "token.transfer.args[1]");
+    assert_eq!(rule(transfer(TOKEN, 1)), "token.transfer.args[0]");
+    assert_eq!(rule(transfer(TREASURY, 1).value(1001)), "max_value");
+    assert_eq!(
+        rule(transfer(TREASURY, 1).data(vec![0xde, 0xad, 0xbe, 0xef])),
+        "token.functions"
+    );
+    assert_eq!(
+        rule(transfer(TREASURY, 1).to(TREASURY.parse::<Address>().unwrap())),
+        "contracts"
+    );
+}
+
+#[test]
+fn policy_rejects_invalid_config() {
+    let out_of_range = r#"
+[[contracts]]
+name = "token"
+address = "0x1000000000000000000000000000000000000001"
+
+[[contracts.functions]]
+signature = "approve(address spender, uint256 amount)"
+args = [{ index = 2, max = "1" }]
+"#;
+    assert!(toml::from_str::<Policy>(out_of_range).is_err());
+    assert!(toml::from_str::<Policy>("max_valu = \"1\"").is_err());
+}
+
+#[tokio::test]
+async fn policy_daily_limit_survives_huge_values() {
+    let path = env::temp_dir().join(format!("relay_test_{}.db", Uuid::new_v4().simple()));
+    let pool = DbPool::connect(&format!("sqlite://{}", path.display()), 1)
+        .await
+        .unwrap();
+    pool.migrate().await.unwrap();
+    let policy: Policy = toml::from_str("daily_value_limit = \"3000000000\"").unwrap();
+    let engine = PolicyEngine::new(policy, pool);
+
+    let send = |value: U256| Eip1559TransactionRequest::new().value(value);
+    let (two_gwei, max) = (send(U256::exp10(9) * 2), send(U256::MAX));
+    assert!(engine.reserve("acme", &[&max]).await.is_err());
+    assert!(engine.reserve("acme", &[&two_gwei, &max]).await.is_err());
+
+    let reservation = engine.reserve("acme", &[&two_gwei]).await.unwrap();
+    assert!(engine.reserve("acme", &[&two_gwei]).await.is_err());
+    engine.release(&reservation).await.unwrap();
+    let _ = engine.reserve("acme", &[&two_gwei]).await.unwrap();
+}
@@ -0,0 +1,23 @@
+use relay::rate_limit::{RateLimiter, TokenBucket};
+
+#[test]
+fn token_bucket_allows_a_burst_then_waits() {
+    let mut bucket = TokenBucket::per_minute(60);
+
+    assert!(bucket.try_take(60).is_ok());
+    let wait = bucket.try_take(1).expect_err("The bucket should be empty");
+    assert!(wait.as_secs_f64() > 0.9 && wait.as_secs_f64() <= 1.0);
+}
+
+#[test]
+fn rate_limiter_tracks_keys_separately() {
+    let limiter = RateLimiter::default();
+
+    assert!(limiter.check("a", 2, 2).is_ok());
+    assert!(limiter.check("a", 2, 1).is_err());
+    assert!(limiter.check("b", 2, 1).is_ok());
+
+    // Costs over the capacity are capped instead of never fitting
+    assert!(limiter.check("c", 2, 10).is_ok());
+    assert!(limiter.check("d", 0, 1).is_err());
+}
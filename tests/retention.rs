@@ -0,0 +1,129 @@
+use ethers::types::{Chain, U256};
+use relay::{
+    retention::{Retention, RetentionTarget},
+    transaction_repository::{
+        ChainId, DbTxRequestRepository, IdempotencyKey, InMemoryTxRequestRepository,
+        TransactionRepository,
+    },
+};
+use sqlx::{query_scalar, SqlitePool};
+use std::{collections::HashMap, env, fs, sync::Arc, time::Duration};
+use tokio::time::sleep;
+use uuid::Uuid;
+
+mod common;
+use common::{mine, new_request};
+
+/// Saves a request and takes it through to mined, costing 1 gwei
+async fn mined<T: TransactionRepository>(repo: &T) -> Uuid {
+    let request = new_request(vec![]);
+    repo.save(request.clone(), None).await.unwrap();
+    mine(repo, request.id, Some(U256::exp10(9))).await;
+    request.id
+}
+
+fn expire_immediately() -> HashMap<ChainId, Duration> {
+    HashMap::from([(Chain::Goerli.into(), Duration::ZERO)])
+}
+
+#[tokio::test]
+async fn retention_exports_expired_requests_as_jsonl() {
+    let repo = Arc::new(InMemoryTxRequestRepository::new());
+    let dir = env::temp_dir().join(format!("relay_retention_{}", Uuid::new_v4().simple()));
+    let done = mined(repo.as_ref()).await;
+    let pending = new_request(vec![]);
+    repo.save(pending.clone(), None).await.unwrap();
+    sleep(Duration::from_millis(5)).await;
+
+    let retention = Retention::new(
+        repo.clone(),
+        expire_immediately(),
+        RetentionTarget::Jsonl(dir.clone()),
+    );
+    assert_eq!(retention.run_once(Chain::Sepolia.into()).await.unwrap(), 0);
+    assert_eq!(retention.run_once(Chain::Goerli.into()).await.unwrap(), 1);
+
+    assert!(repo.get(done).await.unwrap().is_none());
+    assert!(repo.get(pending.id).await.unwrap().is_some());
+    assert_eq!(repo.get_spend("acme").await.unwrap(), U256::exp10(9));
+    let exported = fs::read_to_string(dir.join("requests-goerli.jsonl")).unwrap();
+    assert_eq!(exported.lines().count(), 1);
+    assert!(exported.contains(&done.to_string()));
+}
+
+#[tokio::test]
+async fn retention_archives_requests_nothing_waits_on() {
+    let path = env::temp_dir().join(format!("relay_test_{}.db", Uuid::new_v4().simple()));
+    let url = format!("sqlite://{}", path.display());
+    let repo = Arc::new(DbTxRequestRepository::connect(&url, 1).await.unwrap());
+    repo.migrate().await.unwrap();
+    let done = mined(repo.as_ref()).await;
+    let parent = new_request(vec![]);
+    let child = new_request(vec![parent.id]);
+    repo.save(parent.clone(), None).await.unwrap();
+    repo.save(child.clone(), None).await.unwrap();
+    assert!(repo.mark_failed(parent.id).await.unwrap());
+    sleep(Duration::from_millis(5)).await;
+
+    let retention = Retention::new(repo.clone(), expire_immediately(), RetentionTarget::Archive);
+    assert_eq!(retention.run_once(Chain::Goerli.into()).await.unwrap(), 1);
+    assert!(repo.get(done).await.unwrap().is_none());
+    assert!(
+        repo.get(parent.id).await.unwrap().is_some(),
+        "its child is still waiting on it"
+    );
+    assert_eq!(repo.get_spend("acme").await.unwrap(), U256::exp10(9));
+
+    let pool = SqlitePool::connect(&url).await.unwrap();
+    let status: String = query_scalar("SELECT status FROM requests_archive WHERE id = ?1")
+        .bind(done.to_string())
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(status, "mined");
+}
+
+/// A retry after retention still gets the original request instead of relaying a second one
+async fn assert_keys_outlive_retention<T: TransactionRepository + 'static>(
+    repo: Arc<T>,
+    target: RetentionTarget,
+) {
+    let request = new_request(vec![]);
+    let key = IdempotencyKey {
+        key: format!("key-{}", request.id),
+        fingerprint: "f".repeat(64),
+    };
+    repo.save(request.clone(), Some(key.clone())).await.unwrap();
+    mine(repo.as_ref(), request.id, None).await;
+    sleep(Duration::from_millis(5)).await;
+
+    let retention = Retention::new(repo.clone(), expire_immediately(), target);
+    assert_eq!(retention.run_once(Chain::Goerli.into()).await.unwrap(), 1);
+    assert!(repo.get(request.id).await.unwrap().is_none());
+    assert_eq!(
+        repo.get_by_idempotency_key(Some("acme"), &key.key)
+            .await
+            .unwrap(),
+        Some((request.id, key.fingerprint.clone()))
+    );
+    assert!(repo.save(new_request(vec![]), Some(key)).await.is_err());
+}
+
+#[tokio::test]
+async fn retention_keeps_idempotency_keys() {
+    let dir = env::temp_dir().join(format!("relay_retention_{}", Uuid::new_v4().simple()));
+    assert_keys_outlive_retention(
+        Arc::new(InMemoryTxRequestRepository::new()),
+        RetentionTarget::Jsonl(dir.clone()),
+    )
+    .await;
+
+    for target in [RetentionTarget::Archive, RetentionTarget::Jsonl(dir)] {
+        let path = env::temp_dir().join(format!("relay_test_{}.db", Uuid::new_v4().simple()));
+        let repo = DbTxRequestRepository::connect(&format!("sqlite://{}", path.display()), 1)
+            .await
+            .unwrap();
+        repo.migrate().await.unwrap();
+        assert_keys_outlive_retention(Arc::new(repo), target).await;
+    }
+}
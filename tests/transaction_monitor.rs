@@ -1,4 +1,5 @@
 use ethers::{
+    middleware::SignerMiddleware,
     prelude::k256::ecdsa::SigningKey,
     providers::{Http, Middleware, Provider},
     signers::{LocalWallet, Signer, Wallet},
@@ -7,14 +8,41 @@ use ethers::{
 };
 use tracing::Level;
 
-use relay::transaction_monitor::TransactionMonitor;
-use relay::transaction_repository::DbTxRequestRepository;
-use sqlx::{MySql, Pool};
-use std::sync::Once;
+use relay::api_keys::{hash_key, ApiKeyRepository, DbApiKeyRepository};
+use relay::database::DbPool;
+use relay::transaction_monitor::{
+    BalancePolicy, BatchRejected, ChainDraining, ForwardRejected, ForwardRequest,
+    IdempotencyConflict, InsufficientBalance, InvalidDependency, RevertReason, SendOptions,
+    SimulationError, TopUp, TransactionMonitor, TypedForwardRequest,
+};
+use relay::transaction_repository::{
+    Actor, ChainId, Cursor, DbTxRequestRepository, IdempotencyKey, InMemoryTxRequestRepository,
+    MySqlTxRequestRepository, NewRequest, PgTxRequestRepository, RequestEventKind, RequestFilter,
+    RequestStatus, TransactionRepository,
+};
+use sqlx::{mysql::MySqlConnectOptions, postgres::PgConnectOptions, query, MySqlPool, PgPool};
+use std::{
+    env,
+    str::FromStr,
+    sync::Once,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use tokio::time::{sleep, Duration};
+use uuid::Uuid;
 
 static INIT: Once = Once::new();
 
+mod common;
+use common::{mine, new_request, signed, GOERLI, RELAYER};
+
+const ANVIL: ChainId = ChainId(31337);
+
+// Runtime code that always reverts with Error("nope")
+const REVERT_WITH_NOPE: &str = "0x7f08c379a0000000000000000000000000000000000000000000000000000000006000527f00000020000000000000000000000000000000000000000000000000000000006020527f000000046e6f70650000000000000000000000000000000000000000000000006040527f000000000000000000000000000000000000000000000000000000000000000060605260646000fd";
+
+// Runtime code that returns 32 zero bytes for any call, a forwarder whose nonces are all 0
+const ZERO_NONCE_FORWARDER: &str = "0x60206000f3";
+
 pub fn initialize() {
     INIT.call_once(|| {
         tracing_subscriber::fmt()
@@ -27,16 +55,16 @@ pub fn initialize() {
     });
 }
 
-#[sqlx::test]
-async fn transaction_monitor_happy_path(pool: Pool<MySql>) {
+#[tokio::test]
+async fn transaction_monitor_happy_path() {
     initialize();
-    let mut monitor = TransactionMonitor::new(DbTxRequestRepository::new(pool));
+    let monitor = TransactionMonitor::new(test_repository().await);
 
     let (anvil, provider, wallet) = setup_chain(31337, 8545).await;
     let recipient = anvil.addresses()[1];
 
     monitor
-        .setup_monitor(wallet, provider.clone(), Chain::AnvilHardhat, 1)
+        .setup_monitor(wallet, provider.clone(), ANVIL, 1, 1.2)
         .await
         .unwrap();
 
@@ -45,35 +73,27 @@ async fn transaction_monitor_happy_path(pool: Pool<MySql>) {
     let id = monitor
         .send_monitored_transaction(
             Eip1559TransactionRequest::new().to(recipient).value(1),
-            Chain::AnvilHardhat,
+            ANVIL,
+            SendOptions::default(),
         )
         .await
         .unwrap();
 
-    let (mined, hash) = monitor
-        .get_transaction_status(id)
-        .await
-        .expect("Grabbing transaction status not error")
-        .expect("Status should exist");
-    assert!(!mined);
-    println!("mined {}, hash {}", mined, hash);
+    let hash = wait_for_submission(&monitor, id).await;
+    println!("submitted, hash {:?}", hash);
 
     // Send a request to the other
     let id = monitor
         .send_monitored_transaction(
             Eip1559TransactionRequest::new().to(recipient).value(1),
-            Chain::AnvilHardhat,
+            ANVIL,
+            SendOptions::default(),
         )
         .await
         .unwrap();
 
-    let (mined, hash) = monitor
-        .get_transaction_status(id)
-        .await
-        .expect("Grabbing transaction status not error")
-        .expect("Status should exist");
-    assert!(!mined);
-    println!("mined {}, hash {}", mined, hash);
+    let hash = wait_for_submission(&monitor, id).await;
+    println!("submitted, hash {:?}", hash);
 
     println!("Mine the block");
     provider
@@ -84,11 +104,12 @@ async fn transaction_monitor_happy_path(pool: Pool<MySql>) {
     println!("Sleeping, waiting for the monitor to process");
     sleep(Duration::from_secs(15)).await; // let some blocks get mined
 
-    let (mined, hash) = monitor
-        .get_transaction_status(id)
+    let (status, hash) = monitor
+        .get_transaction_status(id, None)
         .await
         .expect("Grabbing transaction status not error")
         .expect("Status should exist");
+    let hash = hash.expect("Submitted requests should have a hash");
 
     let receipt = provider
         .get_transaction_receipt(hash)
@@ -96,19 +117,19 @@ async fn transaction_monitor_happy_path(pool: Pool<MySql>) {
         .expect("Grabbing the transaction hash should work");
     println!("Here's the receipt to show the tx was mined\n{:?}", receipt);
 
-    println!("mined {}, hash {}", mined, hash);
-    assert!(mined);
+    println!("status {:?}, hash {:?}", status, hash);
+    assert_eq!(status, RequestStatus::Mined);
 }
 
-#[sqlx::test]
-async fn transaction_monitor_multiple_chains(pool: Pool<MySql>) {
+#[tokio::test]
+async fn transaction_monitor_multiple_chains() {
     initialize();
-    let mut monitor = TransactionMonitor::new(DbTxRequestRepository::new(pool));
+    let monitor = TransactionMonitor::new(test_repository().await);
 
     let (anvil, provider, wallet) = setup_chain(31337, 8545).await;
     let recipient = anvil.addresses()[1];
     monitor
-        .setup_monitor(wallet, provider.clone(), Chain::AnvilHardhat, 1)
+        .setup_monitor(wallet, provider.clone(), ANVIL, 1, 1.2)
         .await
         .expect("monitor setup should work");
 
@@ -119,8 +140,9 @@ async fn transaction_monitor_multiple_chains(pool: Pool<MySql>) {
         .setup_monitor(
             mock_goerli_wallet,
             mock_goerli_provider.clone(),
-            Chain::Goerli,
+            GOERLI,
             1,
+            1.2,
         )
         .await
         .expect("monitor setup should work");
@@ -129,16 +151,13 @@ async fn transaction_monitor_multiple_chains(pool: Pool<MySql>) {
     let id = monitor
         .send_monitored_transaction(
             Eip1559TransactionRequest::new().to(recipient).value(1),
-            Chain::AnvilHardhat,
+            ANVIL,
+            SendOptions::default(),
         )
         .await
         .unwrap();
-    let (mined, hash) = monitor
-        .get_transaction_status(id)
-        .await
-        .expect("Grabbing transaction status not error")
-        .expect("Status should exist");
-    println!("mined {}, hash {:?}", mined, hash);
+    let hash = wait_for_submission(&monitor, id).await;
+    println!("submitted, hash {:?}", hash);
 
     // Send a request on the second chain
     let goerli_request_id = monitor
@@ -146,17 +165,13 @@ async fn transaction_monitor_multiple_chains(pool: Pool<MySql>) {
             Eip1559TransactionRequest::new()
                 .to(mock_goerli_recipient)
                 .value(1),
-            Chain::Goerli,
+            GOERLI,
+            SendOptions::default(),
         )
         .await
         .expect("Sending the transaction should work");
-    let (goerli_mined, goerli_hash) = monitor
-        .get_transaction_status(goerli_request_id)
-        .await
-        .expect("Grabbing transaction status not error")
-        .expect("Status should exist");
-    assert!(!goerli_mined);
-    println!("goerli: mined {}, hash {:?}", goerli_mined, goerli_hash);
+    let goerli_hash = wait_for_submission(&monitor, goerli_request_id).await;
+    println!("goerli: submitted, hash {:?}", goerli_hash);
 
     // Drop the transactions on both chains so they must be resubmitted
     println!(
@@ -212,69 +227,65 @@ async fn transaction_monitor_multiple_chains(pool: Pool<MySql>) {
 
     println!(
         "Checking that tx {:?} has been mined on chain {:?}",
-        hash,
-        Chain::AnvilHardhat
+        hash, ANVIL
     );
-    let (mined, hash) = monitor
-        .get_transaction_status(id)
+    let (status, hash) = monitor
+        .get_transaction_status(id, None)
         .await
         .expect("Grabbing transaction status not error")
         .expect("Status should exist");
+    let hash = hash.expect("Submitted requests should have a hash");
     let receipt = provider
         .get_transaction_receipt(hash)
         .await
         .expect("Grabbing the transaction hash should work");
     assert!(receipt.is_some());
-    assert!(mined);
+    assert_eq!(status, RequestStatus::Mined);
 
-    let (goerli_mined, goerli_hash) = monitor
-        .get_transaction_status(goerli_request_id)
+    let (goerli_status, goerli_hash) = monitor
+        .get_transaction_status(goerli_request_id, None)
         .await
         .expect("Grabbing transaction status not error")
         .expect("Status should exist");
-    println!("mined {}, hash {}", goerli_mined, goerli_hash);
+    let goerli_hash = goerli_hash.expect("Submitted requests should have a hash");
+    println!("status {:?}, hash {:?}", goerli_status, goerli_hash);
     println!(
         "Checking that tx {:?} has been mined on chain {:?}",
-        goerli_hash,
-        Chain::Goerli
+        goerli_hash, GOERLI
     );
     let goerli_receipt = mock_goerli_provider
         .get_transaction_receipt(goerli_hash)
         .await
         .expect("Grabbing the transaction hash should work");
     assert!(goerli_receipt.is_some());
-    assert!(goerli_mined);
+    assert_eq!(goerli_status, RequestStatus::Mined);
 }
 
-#[sqlx::test]
-async fn transaction_monitor_resubmission(pool: Pool<MySql>) {
+#[tokio::test]
+async fn transaction_monitor_resubmission() {
     initialize();
-    let mut monitor = TransactionMonitor::new(DbTxRequestRepository::new(pool));
+    let monitor = TransactionMonitor::new(test_repository().await);
 
     let (anvil, provider, wallet) = setup_chain(31337, 8545).await;
     let recipient = anvil.addresses()[1];
 
     monitor
-        .setup_monitor(wallet, provider.clone(), Chain::AnvilHardhat, 1)
+        .setup_monitor(wallet, provider.clone(), ANVIL, 1, 1.2)
         .await
         .unwrap();
 
     let id = monitor
         .send_monitored_transaction(
             Eip1559TransactionRequest::new().to(recipient).value(1),
-            Chain::AnvilHardhat,
+            ANVIL,
+            SendOptions::default(),
         )
         .await
         .unwrap();
 
     // Send the first request
-    let (mined, hash) = monitor
-        .get_transaction_status(id)
-        .await
-        .expect("Grabbing transaction status not error")
-        .expect("Status should exist");
-    assert!(!mined);
-    println!("mined {}, hash {}", mined, hash);
+    let hash = wait_for_submission(&monitor, id).await;
+    println!("submitted, hash {:?}", hash);
 
     // Drop the transaction so it doesn't get mined
     provider
@@ -300,19 +311,829 @@ async fn transaction_monitor_resubmission(pool: Pool<MySql>) {
     println!("Sleeping, waiting for the monitor to process");
     sleep(Duration::from_secs(15)).await; // let some blocks get mined
 
-    let (mined, hash) = monitor
-        .get_transaction_status(id)
+    let (status, hash) = monitor
+        .get_transaction_status(id, None)
         .await
         .expect("Grabbing transaction status not error")
         .expect("Status should exist");
+    let hash = hash.expect("Submitted requests should have a hash");
     let receipt = provider
         .get_transaction_receipt(hash)
         .await
         .expect("Grabbing the transaction hash should work");
     println!("Here's the receipt to show the tx was mined\n{:?}", receipt);
 
-    println!("mined {}, hash {}", mined, hash);
-    assert!(mined);
+    println!("status {:?}, hash {:?}", status, hash);
+    assert_eq!(status, RequestStatus::Mined);
+}
+
+#[tokio::test]
+async fn transaction_monitor_fills_nonce_gaps() {
+    initialize();
+    let monitor = TransactionMonitor::new(test_repository().await);
+
+    let (anvil, provider, wallet) = setup_chain(31337, 8545).await;
+    let sender = wallet.address();
+    let recipient = anvil.addresses()[1];
+
+    monitor
+        .setup_monitor(wallet, provider.clone(), ANVIL, 1, 1.2)
+        .await
+        .unwrap();
+
+    // Pretend nonces 0 and 1 were handed out but their transactions were lost
+    monitor
+        .tx_repo
+        .set_next_nonce(ANVIL, sender, 2.into())
+        .await
+        .expect("Setting the nonce should work");
+
+    let id = monitor
+        .send_monitored_transaction(
+            Eip1559TransactionRequest::new().to(recipient).value(1),
+            ANVIL,
+            SendOptions::default(),
+        )
+        .await
+        .unwrap();
+    let hash = wait_for_submission(&monitor, id).await;
+    println!("submitted, hash {:?}", hash);
+
+    println!("Mining a block so the monitor notices the gap");
+    provider
+        .request::<_, U256>("evm_mine", None::<()>)
+        .await
+        .expect("mining should work");
+
+    println!("Sleeping, waiting for the monitor to fill the gap");
+    sleep(Duration::from_secs(15)).await;
+
+    println!("Mining a block with the fillers");
+    provider
+        .request::<_, U256>("evm_mine", None::<()>)
+        .await
+        .expect("mining should work");
+
+    println!("Sleeping, waiting for the monitor to process");
+    sleep(Duration::from_secs(15)).await;
+
+    let confirmed_nonce = provider
+        .get_transaction_count(sender, None)
+        .await
+        .expect("Grabbing the nonce should work");
+    assert_eq!(confirmed_nonce, 3.into());
+
+    let (status, _) = monitor
+        .get_transaction_status(id, None)
+        .await
+        .expect("Grabbing transaction status not error")
+        .expect("Status should exist");
+    assert_eq!(status, RequestStatus::Mined);
+}
+
+#[tokio::test]
+async fn transaction_monitor_idempotency_keys() {
+    initialize();
+    let monitor = TransactionMonitor::new(test_repository().await);
+
+    let (anvil, provider, wallet) = setup_chain(31337, 8545).await;
+    let recipient = anvil.addresses()[1];
+
+    monitor
+        .setup_monitor(wallet, provider.clone(), ANVIL, 1, 1.2)
+        .await
+        .unwrap();
+
+    let id = monitor
+        .send_monitored_transaction(
+            Eip1559TransactionRequest::new().to(recipient).value(1),
+            ANVIL,
+            SendOptions {
+                idempotency_key: Some("retry-me".to_owned()),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+    // A retry with the same body gets the original request back
+    let retried_id = monitor
+        .send_monitored_transaction(
+            Eip1559TransactionRequest::new().to(recipient).value(1),
+            ANVIL,
+            SendOptions {
+                idempotency_key: Some("retry-me".to_owned()),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(id, retried_id);
+
+    // Reusing the key for something else is rejected
+    let err = monitor
+        .send_monitored_transaction(
+            Eip1559TransactionRequest::new().to(recipient).value(2),
+            ANVIL,
+            SendOptions {
+                idempotency_key: Some("retry-me".to_owned()),
+                ..Default::default()
+            },
+        )
+        .await
+        .expect_err("Reusing a key with a different body should fail");
+    let conflict = err
+        .downcast::<IdempotencyConflict>()
+        .expect("Error should be an idempotency conflict");
+    assert_eq!(conflict.id, id);
+}
+
+#[tokio::test]
+async fn transaction_monitor_batch() {
+    initialize();
+    let monitor = TransactionMonitor::new(test_repository().await);
+
+    let (anvil, provider, wallet) = setup_chain(31337, 8545).await;
+    let recipient = anvil.addresses()[1];
+
+    monitor
+        .setup_monitor(wallet, provider.clone(), ANVIL, 1, 1.2)
+        .await
+        .unwrap();
+
+    let ids = monitor
+        .send_monitored_batch(
+            (1..=3)
+                .map(|value| {
+                    (
+                        Eip1559TransactionRequest::new().to(recipient).value(value),
+                        ANVIL,
+                        vec![],
+                    )
+                })
+                .collect(),
+            None,
+            Actor::System,
+        )
+        .await
+        .expect("Sending the batch should work");
+    assert_eq!(ids.len(), 3);
+
+    let mut nonces = Vec::new();
+    for id in ids {
+        wait_for_submission(&monitor, id).await;
+        let request = monitor
+            .tx_repo
+            .get(id)
+            .await
+            .expect("Grabbing the request should work")
+            .expect("Request should exist");
+        nonces.push(request.tx.nonce.expect("Submitted requests have a nonce"));
+    }
+    assert_eq!(nonces, vec![0.into(), 1.into(), 2.into()]);
+
+    // One bad transaction rejects the whole batch
+    let reverter = Address::from_low_u64_be(0xdead);
+    provider
+        .request::<_, ()>("anvil_setCode", (reverter, REVERT_WITH_NOPE))
+        .await
+        .expect("setting code should work");
+    let err = monitor
+        .send_monitored_batch(
+            vec![
+                (
+                    Eip1559TransactionRequest::new().to(recipient).value(1),
+                    ANVIL,
+                    vec![],
+                ),
+                (Eip1559TransactionRequest::new().to(reverter), ANVIL, vec![]),
+            ],
+            None,
+            Actor::System,
+        )
+        .await
+        .expect_err("A batch with a reverting transaction should fail");
+    let rejected = err
+        .downcast::<BatchRejected>()
+        .expect("Error should be a batch rejection");
+    assert_eq!(rejected.errors.len(), 1);
+    assert_eq!(rejected.errors[0].0, 1);
+}
+
+#[tokio::test]
+async fn transaction_monitor_dependencies() {
+    initialize();
+    let monitor = TransactionMonitor::new(test_repository().await);
+
+    let (anvil, provider, wallet) = setup_chain(31337, 8545).await;
+    let recipient = anvil.addresses()[1];
+    monitor
+        .setup_monitor(wallet, provider.clone(), ANVIL, 1, 1.2)
+        .await
+        .unwrap();
+
+    let parent = monitor
+        .send_monitored_transaction(
+            Eip1559TransactionRequest::new().to(recipient).value(1),
+            ANVIL,
+            SendOptions::default(),
+        )
+        .await
+        .unwrap();
+    // Naming the parent twice is the same as naming it once
+    let child = monitor
+        .send_monitored_transaction(
+            Eip1559TransactionRequest::new().to(recipient).value(2),
+            ANVIL,
+            SendOptions {
+                depends_on: vec![parent, parent],
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    let cancelled = monitor
+        .send_monitored_transaction(
+            Eip1559TransactionRequest::new().to(recipient).value(3),
+            ANVIL,
+            SendOptions {
+                depends_on: vec![parent],
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    let grandchild = monitor
+        .send_monitored_transaction(
+            Eip1559TransactionRequest::new().to(recipient).value(4),
+            ANVIL,
+            SendOptions {
+                depends_on: vec![cancelled],
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert!(monitor
+        .cancel_transaction(cancelled, None, &Actor::System)
+        .await
+        .unwrap());
+
+    // The child is held until its parent is mined
+    wait_for_submission(&monitor, parent).await;
+    let (status, _) = monitor
+        .get_transaction_status(child, None)
+        .await
+        .expect("Grabbing transaction status not error")
+        .expect("Status should exist");
+    assert_eq!(status, RequestStatus::Waiting);
+
+    provider
+        .request::<_, U256>("evm_mine", None::<()>)
+        .await
+        .expect("mining should work");
+    wait_for_submission(&monitor, child).await;
+
+    // Cancelling a request fails everything that depends on it
+    let (status, _) = monitor
+        .get_transaction_status(grandchild, None)
+        .await
+        .expect("Grabbing transaction status not error")
+        .expect("Status should exist");
+    assert_eq!(status, RequestStatus::Failed);
+
+    // Only requests that can still succeed can be depended on
+    let err = monitor
+        .send_monitored_transaction(
+            Eip1559TransactionRequest::new().to(recipient).value(5),
+            ANVIL,
+            SendOptions {
+                depends_on: vec![grandchild],
+                ..Default::default()
+            },
+        )
+        .await
+        .expect_err("Depending on a failed request should fail");
+    assert!(err.is::<InvalidDependency>());
+}
+
+#[tokio::test]
+async fn transaction_monitor_tenants() {
+    initialize();
+    let repo = test_repository().await;
+    let pool = repo.pool();
+    let api_keys = DbApiKeyRepository::new(pool.clone());
+    let monitor = TransactionMonitor::new(repo);
+
+    let (anvil, provider, wallet) = setup_chain(31337, 8545).await;
+    let recipient = anvil.addresses()[1];
+    monitor
+        .setup_monitor(wallet, provider.clone(), ANVIL, 1, 1.2)
+        .await
+        .unwrap();
+
+    execute(
+        &pool,
+        &format!(
+            "INSERT INTO api_keys (id, key_hash, tenant_id, allowed_chains) VALUES ('{}', '{}', 'acme', '[31337]')",
+            Uuid::new_v4(),
+            hash_key("secret")
+        ),
+    )
+    .await;
+
+    let api_key = api_keys
+        .find("secret")
+        .await
+        .unwrap()
+        .expect("Key should exist");
+    assert_eq!(api_key.tenant_id, "acme");
+    assert!(api_key.authorize(ANVIL, recipient).is_ok());
+    assert!(api_key.authorize(GOERLI, recipient).is_err());
+    assert!(api_keys.find("wrong").await.unwrap().is_none());
+
+    let send = |tenant: &str, value: u64| {
+        monitor.send_monitored_transaction(
+            Eip1559TransactionRequest::new().to(recipient).value(value),
+            ANVIL,
+            SendOptions {
+                idempotency_key: Some("shared-key".to_owned()),
+                tenant_id: Some(tenant.to_owned()),
+                ..Default::default()
+            },
+        )
+    };
+    let id = send("acme", 1).await.unwrap();
+
+    // Keys are scoped to the tenant, another tenant can use the same one
+    let other_id = send("globex", 2).await.unwrap();
+    assert_ne!(id, other_id);
+
+    // Tenants can only see their own requests
+    assert!(monitor
+        .get_transaction_status(id, Some("acme"))
+        .await
+        .unwrap()
+        .is_some());
+    assert!(monitor
+        .get_transaction_status(id, Some("globex"))
+        .await
+        .unwrap()
+        .is_none());
+    assert!(!monitor
+        .cancel_transaction(id, Some("globex"), &Actor::System)
+        .await
+        .unwrap());
+
+    // Or depend on them, another tenant's request looks like it doesn't exist
+    let err = monitor
+        .send_monitored_transaction(
+            Eip1559TransactionRequest::new().to(recipient).value(3),
+            ANVIL,
+            SendOptions {
+                depends_on: vec![id],
+                tenant_id: Some("globex".to_owned()),
+                ..Default::default()
+            },
+        )
+        .await
+        .expect_err("Depending on another tenant's request should fail");
+    let err = err.downcast::<InvalidDependency>().unwrap();
+    assert_eq!(err.reason, "it does not exist");
+}
+
+#[tokio::test]
+async fn transaction_monitor_rejects_reverting_transaction() {
+    initialize();
+    let monitor = TransactionMonitor::new(test_repository().await);
+
+    let (_anvil, provider, wallet) = setup_chain(31337, 8545).await;
+    monitor
+        .setup_monitor(wallet, provider.clone(), ANVIL, 1, 1.2)
+        .await
+        .unwrap();
+
+    let reverter = Address::from_low_u64_be(0xdead);
+    provider
+        .request::<_, ()>("anvil_setCode", (reverter, REVERT_WITH_NOPE))
+        .await
+        .expect("setting code should work");
+
+    let err = monitor
+        .send_monitored_transaction(
+            Eip1559TransactionRequest::new().to(reverter),
+            ANVIL,
+            SendOptions::default(),
+        )
+        .await
+        .expect_err("Sending a reverting transaction should fail");
+
+    let SimulationError { reason } = err
+        .downcast::<SimulationError>()
+        .expect("Error should be a simulation error");
+    assert_eq!(
+        reason,
+        RevertReason::Error {
+            message: "nope".to_owned()
+        }
+    );
+}
+
+#[tokio::test]
+async fn transaction_monitor_forwarded_requests() {
+    initialize();
+    let monitor = TransactionMonitor::new(test_repository().await);
+
+    let (anvil, provider, wallet) = setup_chain(31337, 8545).await;
+    monitor
+        .setup_monitor(wallet, provider.clone(), ANVIL, 1, 1.2)
+        .await
+        .unwrap();
+
+    let forwarder = Address::from_low_u64_be(0xf0);
+    provider
+        .request::<_, ()>("anvil_setCode", (forwarder, ZERO_NONCE_FORWARDER))
+        .await
+        .expect("setting code should work");
+
+    // The user never sends a transaction themselves
+    let user: LocalWallet = anvil.keys()[2].clone().into();
+    let request = ForwardRequest {
+        from: user.address(),
+        to: anvil.addresses()[1],
+        value: U256::zero(),
+        gas: 100_000.into(),
+        nonce: U256::zero(),
+        data: vec![0xab, 0xcd].into(),
+    };
+    let sign = |request: &ForwardRequest, signer: &LocalWallet| {
+        let typed = TypedForwardRequest {
+            request,
+            chain_id: 31337,
+            forwarder,
+        };
+        signer.sign_hash(typed.digest().into()).unwrap()
+    };
+    let send = |request: ForwardRequest, signature: Signature| {
+        monitor.send_forwarded_transaction(
+            request,
+            signature,
+            ANVIL,
+            forwarder,
+            SendOptions::default(),
+        )
+    };
+
+    let signature = sign(&request, &user);
+    let id = send(request.clone(), signature).await.unwrap();
+    let hash = wait_for_submission(&monitor, id).await;
+    let tx = provider.get_transaction(hash).await.unwrap().unwrap();
+    assert_eq!(tx.to, Some(forwarder));
+    assert_eq!(tx.input, request.execute_calldata(&signature));
+
+    // Sending the same signed request again returns the same id
+    assert_eq!(send(request.clone(), signature).await.unwrap(), id);
+
+    let stranger: LocalWallet = anvil.keys()[3].clone().into();
+    let err = send(request.clone(), sign(&request, &stranger))
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast::<ForwardRejected>().unwrap(),
+        ForwardRejected::WrongSigner { .. }
+    ));
+
+    let stale = ForwardRequest {
+        nonce: 1.into(),
+        ..request.clone()
+    };
+    let err = send(stale.clone(), sign(&stale, &user)).await.unwrap_err();
+    assert!(matches!(
+        err.downcast::<ForwardRejected>().unwrap(),
+        ForwardRejected::Nonce { .. }
+    ));
+}
+
+#[tokio::test]
+async fn transaction_monitor_balance_top_up() {
+    initialize();
+    let monitor = TransactionMonitor::new(test_repository().await);
+
+    let (anvil, provider, wallet) = setup_chain(31337, 8545).await;
+    let relayer = wallet.address();
+    let recipient = anvil.addresses()[1];
+    monitor
+        .setup_monitor(wallet, provider.clone(), ANVIL, 1, 1.2)
+        .await
+        .unwrap();
+
+    let ether = U256::exp10(18);
+    let treasury: LocalWallet = anvil.keys()[5].clone().into();
+    monitor
+        .set_balance_policy(
+            ANVIL,
+            BalancePolicy {
+                low_balance: Some(ether),
+                top_up: Some(TopUp {
+                    treasury,
+                    target: ether * 2,
+                }),
+            },
+        )
+        .unwrap();
+    provider
+        .request::<_, ()>("anvil_setBalance", (relayer, U256::exp10(14)))
+        .await
+        .expect("setting the balance should work");
+
+    let err = monitor
+        .send_monitored_transaction(
+            Eip1559TransactionRequest::new().to(recipient).value(ether),
+            ANVIL,
+            SendOptions::default(),
+        )
+        .await
+        .expect_err("The relayer can't afford the transaction");
+    assert!(err.is::<InsufficientBalance>());
+
+    // The next block shows the balance is low, the treasury tops it up
+    for _ in 0..2 {
+        provider
+            .request::<_, U256>("evm_mine", None::<()>)
+            .await
+            .expect("mining should work");
+        sleep(Duration::from_secs(15)).await;
+    }
+    let balance = provider.get_balance(relayer, None).await.unwrap();
+    assert!(balance >= ether * 2);
+
+    let status = monitor.balance(ANVIL).unwrap();
+    assert!(!status.low);
+    assert_eq!(status.top_up, None);
+    monitor
+        .send_monitored_transaction(
+            Eip1559TransactionRequest::new()
+                .to(recipient)
+                .value(ether / 2),
+            ANVIL,
+            SendOptions::default(),
+        )
+        .await
+        .expect("The relayer can afford the transaction after the top up");
+}
+
+#[tokio::test]
+async fn transaction_monitor_admin_overrides() {
+    initialize();
+    let monitor = TransactionMonitor::new(test_repository().await);
+
+    let (anvil, provider, wallet) = setup_chain(31337, 8545).await;
+    let recipient = anvil.addresses()[1];
+    monitor
+        .setup_monitor(wallet, provider.clone(), ANVIL, 1, 1.2)
+        .await
+        .unwrap();
+
+    // Paused chains take requests but don't submit them
+    monitor.set_paused(ANVIL, true).unwrap();
+    let id = monitor
+        .send_monitored_transaction(
+            Eip1559TransactionRequest::new().to(recipient).value(1),
+            ANVIL,
+            SendOptions::default(),
+        )
+        .await
+        .unwrap();
+    sleep(Duration::from_secs(6)).await;
+    let (status, _) = monitor
+        .get_transaction_status(id, None)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(status, RequestStatus::Queued);
+
+    // Draining chains turn new requests away
+    monitor.set_draining(ANVIL, true).unwrap();
+    let err = monitor
+        .send_monitored_transaction(
+            Eip1559TransactionRequest::new().to(recipient).value(2),
+            ANVIL,
+            SendOptions::default(),
+        )
+        .await
+        .expect_err("A draining chain should refuse requests");
+    assert!(err.is::<ChainDraining>());
+
+    monitor.set_draining(ANVIL, false).unwrap();
+    monitor.set_paused(ANVIL, false).unwrap();
+    let hash = wait_for_submission(&monitor, id).await;
+
+    let max_fee = U256::exp10(10);
+    let replaced = monitor
+        .force_rebroadcast(id, max_fee, U256::exp10(9), &Actor::Admin)
+        .await
+        .unwrap()
+        .expect("Submitted requests can be rebroadcast");
+    assert_ne!(replaced, hash);
+    let request = monitor.tx_repo.get(id).await.unwrap().unwrap();
+    assert_eq!(request.hash, Some(replaced));
+    assert_eq!(request.tx.max_fee_per_gas, Some(max_fee));
+    assert_eq!(monitor.resync_nonce(ANVIL).await.unwrap(), 1.into());
+
+    assert!(monitor
+        .abandon_transaction(id, &Actor::Admin)
+        .await
+        .unwrap());
+    assert!(!monitor
+        .abandon_transaction(id, &Actor::Admin)
+        .await
+        .unwrap());
+    let events = monitor.tx_repo.get_events(id).await.unwrap();
+    let last = events.last().unwrap();
+    assert_eq!(last.event, RequestEventKind::Abandoned);
+    assert_eq!(last.actor, Actor::Admin);
+}
+
+#[tokio::test]
+async fn transaction_monitor_reconfigures_and_retires_chains() {
+    initialize();
+    let monitor = TransactionMonitor::new(test_repository().await);
+
+    let (anvil, provider, wallet) = setup_chain(31337, 8545).await;
+    let recipient = anvil.addresses()[1];
+    monitor
+        .setup_monitor(wallet.clone(), provider.clone(), ANVIL, 1, 1.2)
+        .await
+        .unwrap();
+
+    // Reconfiguring keeps the operator's switches
+    monitor.set_paused(ANVIL, true).unwrap();
+    monitor
+        .setup_monitor(wallet.clone(), provider.clone(), ANVIL, 2, 1.5)
+        .await
+        .unwrap();
+    assert!(monitor.chain_state(ANVIL).unwrap().paused);
+    let id = monitor
+        .send_monitored_transaction(
+            Eip1559TransactionRequest::new().to(recipient).value(1),
+            ANVIL,
+            SendOptions::default(),
+        )
+        .await
+        .unwrap();
+
+    // Removed chains turn new requests away but finish the ones they have
+    monitor.remove_monitor(ANVIL).unwrap();
+    assert!(monitor.chains().is_empty());
+    assert_eq!(monitor.retiring_chains(), vec![ANVIL]);
+    let err = monitor
+        .send_monitored_transaction(
+            Eip1559TransactionRequest::new().to(recipient).value(2),
+            ANVIL,
+            SendOptions::default(),
+        )
+        .await
+        .expect_err("A removed chain should refuse requests");
+    assert!(err.to_string().contains("not defined"), "{}", err);
+    monitor.set_paused(ANVIL, false).unwrap();
+    wait_for_submission(&monitor, id).await;
+
+    // Setting the chain up again takes it back
+    monitor
+        .setup_monitor(wallet, provider.clone(), ANVIL, 1, 1.2)
+        .await
+        .unwrap();
+    assert_eq!(monitor.chains(), vec![ANVIL]);
+    assert!(monitor.retiring_chains().is_empty());
+    assert!(!monitor.chain_state(ANVIL).unwrap().draining);
+}
+
+#[tokio::test]
+async fn transaction_monitor_replays_missed_blocks() {
+    initialize();
+    let repo = InMemoryTxRequestRepository::new();
+    let monitor = TransactionMonitor::new(repo.clone());
+
+    let (anvil, provider, wallet) = setup_chain(31337, 8545).await;
+    let recipient = anvil.addresses()[1];
+    // Nothing watches the chain, like a relay that was down
+    monitor
+        .attach_monitor(wallet.clone(), provider.clone(), ANVIL, 1.2)
+        .await
+        .unwrap();
+
+    let id = Uuid::new_v4();
+    let tx = Eip1559TransactionRequest::new().to(recipient).value(1);
+    repo.save(
+        NewRequest {
+            id,
+            tx: tx.clone(),
+            chain: ANVIL,
+            tenant_id: None,
+            depends_on: vec![],
+            actor: Actor::System,
+        },
+        None,
+    )
+    .await
+    .unwrap();
+    let client = SignerMiddleware::new(provider.clone(), wallet.clone());
+    let mined = client
+        .send_transaction(tx.clone().nonce(0), None)
+        .await
+        .expect("sending should work")
+        .tx_hash();
+    // The stored hash is a replacement that never made it
+    let signed = tx.from(wallet.address()).nonce(0).chain_id(ANVIL.0);
+    repo.mark_submitted(id, TxHash::random(), signed)
+        .await
+        .unwrap();
+    provider
+        .request::<_, U256>("evm_mine", None::<()>)
+        .await
+        .expect("mining should work");
+
+    assert_eq!(monitor.replay_from_block(ANVIL, 0).await.unwrap(), 1);
+    let request = repo.get(id).await.unwrap().unwrap();
+    assert_eq!(request.status, RequestStatus::Mined);
+    assert_eq!(request.hash, Some(mined));
+    assert_eq!(monitor.replay_from_block(ANVIL, 0).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn transaction_monitor_in_memory_repository() {
+    initialize();
+    let repo = InMemoryTxRequestRepository::new();
+    let monitor = TransactionMonitor::new(repo.clone());
+
+    let (anvil, provider, wallet) = setup_chain(31337, 8545).await;
+    let recipient = anvil.addresses()[1];
+    monitor
+        .setup_monitor(wallet, provider.clone(), ANVIL, 1, 1.2)
+        .await
+        .unwrap();
+
+    let id = monitor
+        .send_monitored_transaction(
+            Eip1559TransactionRequest::new().to(recipient).value(1),
+            ANVIL,
+            SendOptions::default(),
+        )
+        .await
+        .unwrap();
+    wait_for_submission(&monitor, id).await;
+
+    provider
+        .request::<_, U256>("evm_mine", None::<()>)
+        .await
+        .expect("mining should work");
+    sleep(Duration::from_secs(5)).await;
+
+    // Clones share requests, the monitor's updates show up in ours
+    let request = repo.get(id).await.unwrap().expect("request should exist");
+    assert_eq!(request.status, RequestStatus::Mined);
+}
+
+/// A fresh database for each test on the server `DATABASE_URL` points at,
+/// or a new SQLite file for `sqlite:` urls and when it isn't set
+async fn test_repository() -> DbTxRequestRepository {
+    let url = env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite:".to_owned());
+    let name = format!("relay_test_{}", Uuid::new_v4().simple());
+    let create = format!("CREATE DATABASE {}", name);
+    let repo = match url.split_once(':').map(|(scheme, _)| scheme) {
+        Some("mysql") => {
+            let admin = MySqlPool::connect(&url).await.unwrap();
+            query(&create).execute(&admin).await.unwrap();
+            let options = MySqlConnectOptions::from_str(&url).unwrap().database(&name);
+            let pool = MySqlPool::connect_with(options).await.unwrap();
+            DbTxRequestRepository::MySql(MySqlTxRequestRepository::new(pool))
+        }
+        Some("postgres" | "postgresql") => {
+            let admin = PgPool::connect(&url).await.unwrap();
+            query(&create).execute(&admin).await.unwrap();
+            let options = PgConnectOptions::from_str(&url).unwrap().database(&name);
+            let pool = PgPool::connect_with(options).await.unwrap();
+            DbTxRequestRepository::Postgres(PgTxRequestRepository::new(pool))
+        }
+        _ => {
+            let path = env::temp_dir().join(format!("{}.db", name));
+            DbTxRequestRepository::connect(&format!("sqlite://{}", path.display()), 5)
+                .await
+                .unwrap()
+        }
+    };
+    repo.migrate().await.expect("migrations should apply");
+    repo
+}
+
+/// Runs a statement without parameters on any of the databases
+async fn execute(pool: &DbPool, sql: &str) {
+    match pool {
+        DbPool::MySql(pool) => query(sql).execute(pool).await.map(|_| ()),
+        DbPool::Postgres(pool) => query(sql).execute(pool).await.map(|_| ()),
+        DbPool::Sqlite(pool) => query(sql).execute(pool).await.map(|_| ()),
+    }
+    .expect("the statement should work");
 }
 
 async fn setup_chain(
@@ -329,5 +1150,311 @@ async fn setup_chain(
     let wallet: LocalWallet = anvil.keys().first().unwrap().clone().into();
     let wallet = wallet.with_chain_id(anvil.chain_id());
 
-    return (anvil, provider, wallet);
+    (anvil, provider, wallet)
+}
+
+async fn wait_for_submission<T: TransactionRepository + 'static>(
+    monitor: &TransactionMonitor<Http, T>,
+    id: Uuid,
+) -> TxHash {
+    for _ in 0..20 {
+        let (status, hash) = monitor
+            .get_transaction_status(id, None)
+            .await
+            .expect("Grabbing transaction status not error")
+            .expect("Status should exist");
+
+        if let Some(hash) = hash {
+            assert_eq!(status, RequestStatus::Submitted);
+            return hash;
+        }
+
+        sleep(Duration::from_millis(500)).await;
+    }
+
+    panic!("Request {:?} was never submitted", id);
+}
+
+#[tokio::test]
+async fn transaction_repository_lists_with_filters_and_cursor() {
+    initialize();
+    let repo = test_repository().await;
+    let alice = Address::from_low_u64_be(1);
+    let bob = Address::from_low_u64_be(2);
+
+    let mut ids = vec![];
+    for (to, chain, tenant) in [
+        (alice, ANVIL, "acme"),
+        (bob, ANVIL, "acme"),
+        (alice, GOERLI, "acme"),
+        (alice, ANVIL, "acme"),
+        (alice, ANVIL, "globex"),
+    ] {
+        let id = Uuid::new_v4();
+        repo.save(
+            NewRequest {
+                id,
+                tx: Eip1559TransactionRequest::new().to(to).value(1),
+                chain,
+                tenant_id: Some(tenant.to_owned()),
+                depends_on: vec![],
+                actor: Actor::System,
+            },
+            None,
+        )
+        .await
+        .unwrap();
+        ids.push(id);
+    }
+
+    let filter = RequestFilter {
+        chain: Some(ANVIL),
+        to: Some(alice),
+        tenant_id: Some("acme".to_owned()),
+        ..Default::default()
+    };
+    let (page, cursor) = repo.list(&filter, None, 1).await.unwrap();
+    assert_eq!(page.len(), 1);
+    assert_eq!(page[0].request.id, ids[3], "newest first");
+    let cursor = cursor.expect("a full page has a cursor");
+    assert_eq!(cursor.to_string().parse::<Cursor>().unwrap(), cursor);
+
+    let (page, cursor) = repo.list(&filter, Some(cursor), 1).await.unwrap();
+    assert_eq!(page[0].request.id, ids[0]);
+    let (page, cursor) = repo.list(&filter, cursor, 1).await.unwrap();
+    assert!(page.is_empty());
+    assert!(cursor.is_none());
+
+    let submitted = RequestFilter {
+        status: Some(RequestStatus::Submitted),
+        ..Default::default()
+    };
+    let (page, _) = repo.list(&submitted, None, 10).await.unwrap();
+    assert!(page.is_empty());
+}
+
+#[tokio::test]
+async fn transaction_repository_stores_typed_columns() {
+    initialize();
+    let repo = test_repository().await;
+    let relayer = Address::from_low_u64_be(RELAYER);
+    // Wider than 64 bits
+    let value = U256::exp10(30) + 1;
+
+    let requests = [new_request(vec![]), new_request(vec![])].map(|request| NewRequest {
+        tx: Eip1559TransactionRequest::new()
+            .to(Address::from_low_u64_be(1))
+            .value(value)
+            .gas(21_000)
+            .max_fee_per_gas(U256::exp10(12))
+            .max_priority_fee_per_gas(2)
+            .data(vec![0xde, 0xad]),
+        tenant_id: None,
+        ..request
+    });
+    for request in &requests {
+        repo.save(request.clone(), None).await.unwrap();
+    }
+    let ids = requests.each_ref().map(|request| request.id);
+    let signed = signed(&requests[0], 7);
+    assert!(repo
+        .mark_submitted(ids[0], TxHash::random(), signed.clone())
+        .await
+        .unwrap());
+
+    let tx = repo.get(ids[0]).await.unwrap().unwrap().tx;
+    assert_eq!(tx.from, Some(relayer));
+    assert_eq!(tx.to, Some(Address::from_low_u64_be(1).into()));
+    assert_eq!(tx.nonce, Some(7.into()));
+    assert_eq!(tx.value, Some(value));
+    assert_eq!(tx.gas, Some(21_000.into()));
+    assert_eq!(tx.max_fee_per_gas, Some(U256::exp10(12)));
+    assert_eq!(tx.max_priority_fee_per_gas, Some(2.into()));
+    assert_eq!(tx.data, Some(vec![0xde, 0xad].into()));
+
+    let (page, _) = repo
+        .list(
+            &RequestFilter {
+                sender: Some(relayer),
+                ..Default::default()
+            },
+            None,
+            10,
+        )
+        .await
+        .unwrap();
+    assert_eq!(page.len(), 1);
+
+    // Another submitted request can't take the same nonce
+    assert!(repo
+        .mark_submitted(ids[1], TxHash::random(), signed)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn transaction_repository_archives_expired_requests() {
+    initialize();
+    let repo = test_repository().await;
+    let parent = new_request(vec![]);
+    let child = new_request(vec![parent.id]);
+    let key = IdempotencyKey {
+        key: "archived".to_owned(),
+        fingerprint: "f".repeat(64),
+    };
+    repo.save(parent.clone(), Some(key.clone())).await.unwrap();
+    repo.save(child.clone(), None).await.unwrap();
+    let (parent, child) = (parent.id, child.id);
+    mine(&repo, parent, Some(U256::exp10(9))).await;
+    sleep(Duration::from_millis(5)).await;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+
+    // Held while its child still waits on it
+    assert!(repo.get_expired(GOERLI, now, 10).await.unwrap().is_empty());
+    let child_tx = repo.get(child).await.unwrap().unwrap().tx;
+    repo.mark_queued(child, child_tx).await.unwrap();
+    let expired = repo.get_expired(GOERLI, now, 10).await.unwrap();
+    assert_eq!(expired.len(), 1);
+    assert_eq!(expired[0].request.id, parent);
+    assert!(repo
+        .get_expired(GOERLI, now - 60_000, 10)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // A child saved since then holds its parent again, nothing is counted or copied
+    let late = new_request(vec![parent]);
+    repo.save(late.clone(), None).await.unwrap();
+    assert_eq!(repo.archive(&[parent], true).await.unwrap(), 0);
+    assert!(repo.cancel(late.id, &Actor::System).await.unwrap());
+
+    // Requests that aren't done yet stay put
+    assert_eq!(repo.archive(&[parent, child], true).await.unwrap(), 1);
+    assert!(repo.get(parent).await.unwrap().is_none());
+    assert!(repo.get(child).await.unwrap().is_some());
+    assert_eq!(repo.get_spend("acme").await.unwrap(), U256::exp10(9));
+    assert_eq!(
+        repo.get_by_idempotency_key(Some("acme"), &key.key)
+            .await
+            .unwrap(),
+        Some((parent, key.fingerprint.clone()))
+    );
+    assert_eq!(repo.archive(&[parent], true).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn transaction_repository_records_request_events() {
+    initialize();
+    let repo = test_repository().await;
+    let key_id = Uuid::new_v4();
+    let mut ids = Vec::new();
+    for tenant in ["acme", "globex"] {
+        let request = NewRequest {
+            tenant_id: Some(tenant.to_owned()),
+            actor: Actor::ApiKey(key_id),
+            ..new_request(vec![])
+        };
+        repo.save(request.clone(), None).await.unwrap();
+        ids.push(request.id);
+    }
+    let hash = mine(&repo, ids[0], None).await;
+    assert!(repo.cancel(ids[1], &Actor::Admin).await.unwrap());
+
+    let events = repo.get_events(ids[0]).await.unwrap();
+    assert_eq!(
+        events
+            .iter()
+            .map(|event| (event.event, event.actor.clone()))
+            .collect::<Vec<_>>(),
+        vec![
+            (RequestEventKind::Created, Actor::ApiKey(key_id)),
+            (RequestEventKind::Broadcast, Actor::System),
+            (RequestEventKind::Mined, Actor::System),
+        ]
+    );
+    assert_eq!(events[0].hash, None);
+    assert_eq!(events[2].hash, Some(hash));
+    assert_eq!(events[0].tenant_id.as_deref(), Some("acme"));
+
+    // Exported per tenant, resuming after the last seq seen
+    let acme = repo.list_events(Some("acme"), 0, 10).await.unwrap();
+    assert_eq!(acme, events);
+    let rest = repo
+        .list_events(Some("acme"), events[0].seq, 10)
+        .await
+        .unwrap();
+    assert_eq!(rest, events[1..]);
+    let globex = repo.list_events(Some("globex"), 0, 10).await.unwrap();
+    assert_eq!(globex.len(), 2);
+    assert_eq!(globex[1].event, RequestEventKind::Cancelled);
+    assert_eq!(globex[1].actor, Actor::Admin);
+    assert_eq!(repo.list_events(None, 0, 1).await.unwrap().len(), 1);
+
+    // The log outlives the request
+    assert_eq!(repo.archive(&[ids[0]], false).await.unwrap(), 1);
+    assert_eq!(repo.get_events(ids[0]).await.unwrap(), events);
+}
+
+#[tokio::test]
+async fn transaction_repository_records_admin_overrides() {
+    initialize();
+    let repo = test_repository().await;
+    let requests = [new_request(vec![]), new_request(vec![])].map(|request| NewRequest {
+        tenant_id: None,
+        ..request
+    });
+    for request in &requests {
+        repo.save(request.clone(), None).await.unwrap();
+    }
+    let ids = requests.each_ref().map(|request| request.id);
+    let signed = signed(&requests[0], 0)
+        .max_fee_per_gas(100)
+        .max_priority_fee_per_gas(1);
+
+    // Only submitted requests can be replaced
+    assert!(!repo
+        .replace(ids[0], TxHash::random(), signed.clone(), &Actor::Admin)
+        .await
+        .unwrap());
+    repo.mark_submitted(ids[0], TxHash::random(), signed.clone())
+        .await
+        .unwrap();
+    let hash = TxHash::random();
+    let replacement = signed.max_fee_per_gas(U256::exp10(10));
+    assert!(repo
+        .replace(ids[0], hash, replacement, &Actor::Admin)
+        .await
+        .unwrap());
+    let request = repo.get(ids[0]).await.unwrap().unwrap();
+    assert_eq!(request.status, RequestStatus::Submitted);
+    assert_eq!(request.hash, Some(hash));
+    assert_eq!(request.tx.max_fee_per_gas, Some(U256::exp10(10)));
+    assert_eq!(request.tx.nonce, Some(0.into()));
+
+    // Abandoned at any stage until it settles
+    for id in &ids {
+        assert!(repo.abandon(*id, &Actor::Admin).await.unwrap());
+        assert!(!repo.abandon(*id, &Actor::Admin).await.unwrap());
+        let request = repo.get(*id).await.unwrap().unwrap();
+        assert_eq!(request.status, RequestStatus::Failed);
+    }
+    assert_eq!(repo.count_in_flight(GOERLI).await.unwrap(), 0);
+
+    let events = repo.get_events(ids[0]).await.unwrap();
+    assert_eq!(
+        events
+            .iter()
+            .map(|event| (event.event, event.actor.clone()))
+            .collect::<Vec<_>>(),
+        vec![
+            (RequestEventKind::Created, Actor::System),
+            (RequestEventKind::Broadcast, Actor::System),
+            (RequestEventKind::Replaced, Actor::Admin),
+            (RequestEventKind::Abandoned, Actor::Admin),
+        ]
+    );
+    assert_eq!(events[2].hash, Some(hash));
 }
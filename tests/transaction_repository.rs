@@ -0,0 +1,373 @@
+use ethers::types::{Address, TxHash, U256};
+use relay::transaction_repository::{
+    invalid_records, Actor, ChainId, DbTxRequestRepository, IdempotencyKey,
+    InMemoryTxRequestRepository, NewRequest, RequestEventKind, RequestFilter, RequestStatus,
+    RequestUpdate, TransactionRepository,
+};
+use sqlx::{query, query_scalar, SqlitePool};
+use std::env;
+use uuid::Uuid;
+
+mod common;
+use common::{mine, new_request, signed, GOERLI, RELAYER};
+
+#[tokio::test]
+async fn in_memory_repository_follows_the_request_lifecycle() {
+    let repo = InMemoryTxRequestRepository::new();
+    let relayer = Address::from_low_u64_be(RELAYER);
+    let parent = new_request(vec![]);
+    let child = new_request(vec![parent.id]);
+    let key = IdempotencyKey {
+        key: "key".to_owned(),
+        fingerprint: "fingerprint".to_owned(),
+    };
+
+    repo.save(parent.clone(), Some(key.clone())).await.unwrap();
+    repo.save(child.clone(), None).await.unwrap();
+    // Never released, its parent doesn't exist
+    repo.save(new_request(vec![Uuid::new_v4()]), None)
+        .await
+        .unwrap();
+    assert!(repo.save(new_request(vec![]), Some(key)).await.is_err());
+    assert_eq!(
+        repo.get_by_idempotency_key(Some("acme"), "key")
+            .await
+            .unwrap(),
+        Some((parent.id, "fingerprint".to_owned()))
+    );
+    assert_eq!(repo.get_queued(GOERLI).await.unwrap().len(), 1);
+    assert!(repo.get_ready(GOERLI).await.unwrap().is_empty());
+
+    let hash = TxHash::from_low_u64_be(1);
+    assert!(repo
+        .mark_submitted(parent.id, hash, signed(&parent, 4))
+        .await
+        .unwrap());
+    assert_eq!(
+        repo.get_next_nonce(GOERLI, relayer).await.unwrap(),
+        Some(5.into())
+    );
+    assert_eq!(
+        repo.get_submitted_nonces(GOERLI, relayer, 0.into())
+            .await
+            .unwrap(),
+        vec![U256::from(4)]
+    );
+    // Another signer's nonces aren't tracked for the relayer
+    assert!(repo
+        .get_submitted_nonces(GOERLI, Address::from_low_u64_be(RELAYER + 1), 0.into())
+        .await
+        .unwrap()
+        .is_empty());
+    // A stale sync can't move the stored nonce back, only a resync can
+    repo.set_next_nonce(GOERLI, relayer, 3.into())
+        .await
+        .unwrap();
+    assert_eq!(
+        repo.get_next_nonce(GOERLI, relayer).await.unwrap(),
+        Some(5.into())
+    );
+    repo.reset_next_nonce(GOERLI, relayer, 3.into())
+        .await
+        .unwrap();
+    assert_eq!(
+        repo.get_next_nonce(GOERLI, relayer).await.unwrap(),
+        Some(3.into())
+    );
+    repo.set_next_nonce(GOERLI, relayer, 5.into())
+        .await
+        .unwrap();
+
+    repo.update_many(vec![RequestUpdate {
+        id: parent.id,
+        read_hash: hash,
+        status: RequestStatus::Mined,
+        hash,
+        cost: Some(U256::exp10(9) * 3),
+    }])
+    .await
+    .unwrap();
+    assert_eq!(repo.get_spend("acme").await.unwrap(), U256::exp10(9) * 3);
+    assert_eq!(repo.get_ready(GOERLI).await.unwrap().len(), 1);
+
+    repo.mark_queued(child.id, child.tx.clone()).await.unwrap();
+    assert!(repo.cancel(child.id, &Actor::System).await.unwrap());
+    assert!(!repo.mark_failed(child.id).await.unwrap());
+    assert_eq!(repo.count_in_flight(GOERLI).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn in_memory_repository_fails_blocked_requests() {
+    let repo = InMemoryTxRequestRepository::new();
+    let parent = new_request(vec![]);
+    let child = new_request(vec![parent.id]);
+    repo.save_batch(Uuid::new_v4(), vec![parent.clone(), child.clone()])
+        .await
+        .unwrap();
+
+    // Batches are all or nothing
+    assert!(repo
+        .save_batch(Uuid::new_v4(), vec![new_request(vec![]), parent.clone()])
+        .await
+        .is_err());
+    assert_eq!(repo.get_queued(GOERLI).await.unwrap().len(), 1);
+
+    assert!(repo.mark_failed(parent.id).await.unwrap());
+    assert_eq!(repo.fail_blocked(GOERLI).await.unwrap(), 1);
+    let child = repo.get(child.id).await.unwrap().unwrap();
+    assert_eq!(child.status, RequestStatus::Failed);
+    assert_eq!(child.batch_index, Some(1));
+}
+
+#[tokio::test]
+async fn sqlite_repository_quarantines_invalid_rows() {
+    let path = env::temp_dir().join(format!("relay_test_{}.db", Uuid::new_v4().simple()));
+    let url = format!("sqlite://{}", path.display());
+    let repo = DbTxRequestRepository::connect(&url, 1).await.unwrap();
+    repo.migrate().await.unwrap();
+    let (good, corrupt, unknown_chain) = (
+        new_request(vec![]),
+        new_request(vec![]),
+        new_request(vec![]),
+    );
+    for request in [&good, &corrupt, &unknown_chain] {
+        repo.save(request.clone(), None).await.unwrap();
+    }
+
+    let pool = SqlitePool::connect(&url).await.unwrap();
+    query("UPDATE requests SET hash = 'not a hash' WHERE id = ?1")
+        .bind(corrupt.id.to_string())
+        .execute(&pool)
+        .await
+        .unwrap();
+    query("UPDATE requests SET chain = 424242 WHERE id = ?1")
+        .bind(unknown_chain.id.to_string())
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let before = invalid_records();
+    let queued = repo.get_queued(GOERLI).await.unwrap();
+    assert_eq!(queued.len(), 1);
+    assert_eq!(queued[0].id, good.id);
+    assert!(invalid_records() > before);
+    let status: String = query_scalar("SELECT status FROM requests WHERE id = ?1")
+        .bind(corrupt.id.to_string())
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(status, "invalid");
+
+    let unknown = repo.get(unknown_chain.id).await.unwrap().unwrap();
+    assert_eq!(unknown.chain, ChainId(424242));
+    let queued = repo.get_queued(ChainId(424242)).await.unwrap();
+    assert_eq!(queued.len(), 1);
+    assert_eq!(queued[0].id, unknown_chain.id);
+    let filter = RequestFilter {
+        chain: Some(ChainId(424242)),
+        ..RequestFilter::default()
+    };
+    let (listed, _) = repo.list(&filter, None, 10).await.unwrap();
+    assert_eq!(listed.len(), 1);
+    let (listed, _) = repo
+        .list(&RequestFilter::default(), None, 10)
+        .await
+        .unwrap();
+    assert_eq!(listed.len(), 2, "the corrupt row is still skipped");
+
+    // The monitor couldn't look for a submitted request without a hash
+    repo.mark_submitted(good.id, TxHash::random(), signed(&good, 0))
+        .await
+        .unwrap();
+    query("UPDATE requests SET hash = NULL WHERE id = ?1")
+        .bind(good.id.to_string())
+        .execute(&pool)
+        .await
+        .unwrap();
+    assert!(repo.get_pending(GOERLI).await.unwrap().is_empty());
+    let good = repo.get(good.id).await.unwrap().unwrap();
+    assert_eq!(good.status, RequestStatus::Invalid);
+}
+
+#[tokio::test]
+async fn sqlite_repository_holds_requests_whose_parent_is_gone() {
+    let path = env::temp_dir().join(format!("relay_test_{}.db", Uuid::new_v4().simple()));
+    let url = format!("sqlite://{}", path.display());
+    let repo = DbTxRequestRepository::connect(&url, 1).await.unwrap();
+    repo.migrate().await.unwrap();
+    let parent = new_request(vec![]);
+    let child = new_request(vec![parent.id]);
+    let orphan = new_request(vec![Uuid::new_v4()]);
+    for request in [&parent, &child, &orphan] {
+        repo.save(request.clone(), None).await.unwrap();
+    }
+
+    mine(&repo, parent.id, None).await;
+    let ready = repo.get_ready(GOERLI).await.unwrap();
+    assert_eq!(ready.len(), 1);
+    assert_eq!(ready[0].id, child.id);
+
+    let pool = SqlitePool::connect(&url).await.unwrap();
+    query("DELETE FROM requests WHERE id = ?1")
+        .bind(parent.id.to_string())
+        .execute(&pool)
+        .await
+        .unwrap();
+    assert!(repo.get_ready(GOERLI).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn sqlite_repository_scopes_nonces_to_the_sender() {
+    let path = env::temp_dir().join(format!("relay_test_{}.db", Uuid::new_v4().simple()));
+    let url = format!("sqlite://{}", path.display());
+    let repo = DbTxRequestRepository::connect(&url, 1).await.unwrap();
+    repo.migrate().await.unwrap();
+    let relayer = Address::from_low_u64_be(RELAYER);
+    let old_signer = Address::from_low_u64_be(RELAYER + 1);
+    let request = new_request(vec![]);
+    repo.save(request.clone(), None).await.unwrap();
+    let tx = signed(&request, 3).from(old_signer);
+    assert!(repo
+        .mark_submitted(request.id, TxHash::random(), tx)
+        .await
+        .unwrap());
+    assert_eq!(
+        repo.get_submitted_nonces(GOERLI, old_signer, 0.into())
+            .await
+            .unwrap(),
+        vec![U256::from(3)]
+    );
+    assert!(repo
+        .get_submitted_nonces(GOERLI, relayer, 0.into())
+        .await
+        .unwrap()
+        .is_empty());
+
+    repo.set_next_nonce(GOERLI, relayer, 7.into())
+        .await
+        .unwrap();
+    repo.set_next_nonce(GOERLI, relayer, 2.into())
+        .await
+        .unwrap();
+    assert_eq!(
+        repo.get_next_nonce(GOERLI, relayer).await.unwrap(),
+        Some(7.into())
+    );
+    repo.reset_next_nonce(GOERLI, relayer, 2.into())
+        .await
+        .unwrap();
+    assert_eq!(
+        repo.get_next_nonce(GOERLI, relayer).await.unwrap(),
+        Some(2.into())
+    );
+}
+
+#[tokio::test]
+async fn sqlite_repository_keeps_chain_ids_wider_than_32_bits() {
+    let path = env::temp_dir().join(format!("relay_test_{}.db", Uuid::new_v4().simple()));
+    let url = format!("sqlite://{}", path.display());
+    let repo = DbTxRequestRepository::connect(&url, 1).await.unwrap();
+    repo.migrate().await.unwrap();
+    let chain = ChainId(11297108109);
+    let request = NewRequest {
+        chain,
+        ..new_request(vec![])
+    };
+    repo.save(request.clone(), None).await.unwrap();
+
+    let saved = repo.get(request.id).await.unwrap().unwrap();
+    assert_eq!(saved.chain, chain);
+    assert_eq!(repo.get_queued(chain).await.unwrap().len(), 1);
+    let events = repo.get_events(request.id).await.unwrap();
+    assert!(events.iter().all(|event| event.chain == chain));
+}
+
+/// The monitor's updates lose to an abandon or replacement made after it read the requests
+async fn assert_stale_updates_are_skipped<T: TransactionRepository>(repo: &T) {
+    let abandoned = new_request(vec![]);
+    let replaced = new_request(vec![]);
+    let read_hash = TxHash::random();
+    for (request, nonce) in [(&abandoned, 0), (&replaced, 1)] {
+        repo.save(request.clone(), None).await.unwrap();
+        assert!(repo
+            .mark_submitted(request.id, read_hash, signed(request, nonce))
+            .await
+            .unwrap());
+    }
+    assert!(repo.abandon(abandoned.id, &Actor::Admin).await.unwrap());
+    let admin_hash = TxHash::random();
+    assert!(repo
+        .replace(replaced.id, admin_hash, signed(&replaced, 1), &Actor::Admin)
+        .await
+        .unwrap());
+
+    let loop_hash = TxHash::random();
+    repo.update_many(vec![
+        RequestUpdate {
+            id: abandoned.id,
+            read_hash,
+            status: RequestStatus::Mined,
+            hash: read_hash,
+            cost: None,
+        },
+        RequestUpdate {
+            id: replaced.id,
+            read_hash,
+            status: RequestStatus::Submitted,
+            hash: loop_hash,
+            cost: None,
+        },
+    ])
+    .await
+    .unwrap();
+
+    let abandoned = repo.get(abandoned.id).await.unwrap().unwrap();
+    assert_eq!(abandoned.status, RequestStatus::Failed);
+    let replaced = repo.get(replaced.id).await.unwrap().unwrap();
+    assert_eq!(replaced.hash, Some(admin_hash));
+    for id in [abandoned.id, replaced.id] {
+        let events = repo.get_events(id).await.unwrap();
+        let last = events.last().unwrap();
+        assert_eq!(last.actor, Actor::Admin);
+        assert!(matches!(
+            last.event,
+            RequestEventKind::Abandoned | RequestEventKind::Replaced
+        ));
+    }
+}
+
+#[tokio::test]
+async fn repositories_skip_stale_updates() {
+    assert_stale_updates_are_skipped(&InMemoryTxRequestRepository::new()).await;
+
+    let path = env::temp_dir().join(format!("relay_test_{}.db", Uuid::new_v4().simple()));
+    let repo = DbTxRequestRepository::connect(&format!("sqlite://{}", path.display()), 1)
+        .await
+        .unwrap();
+    repo.migrate().await.unwrap();
+    assert_stale_updates_are_skipped(&repo).await;
+}
+
+#[tokio::test]
+async fn sqlite_request_events_are_append_only() {
+    let path = env::temp_dir().join(format!("relay_test_{}.db", Uuid::new_v4().simple()));
+    let url = format!("sqlite://{}", path.display());
+    let repo = DbTxRequestRepository::connect(&url, 1).await.unwrap();
+    repo.migrate().await.unwrap();
+    let request = new_request(vec![]);
+    repo.save(request.clone(), None).await.unwrap();
+    assert!(repo.cancel(request.id, &Actor::Admin).await.unwrap());
+
+    let pool = SqlitePool::connect(&url).await.unwrap();
+    assert!(query("UPDATE request_events SET actor = 'system'")
+        .execute(&pool)
+        .await
+        .is_err());
+    assert!(query("DELETE FROM request_events")
+        .execute(&pool)
+        .await
+        .is_err());
+    let events = repo.get_events(request.id).await.unwrap();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[1].actor, Actor::Admin);
+}